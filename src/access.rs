@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, Result, SharedData, now_millis};
+
+const ACCESS_STATS_FILE: &str = "access.stats";
+
+// How recently and how often a key has been read or written since access
+// tracking was turned on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStats {
+    // Milliseconds since the Unix epoch.
+    pub last_access_ms: u64,
+    pub access_count: u64,
+}
+
+impl KvStore {
+    // `None` if access tracking is off, or the key has not been read or
+    // written since it was turned on.
+    pub fn key_stats(&self, key: &str) -> Result<Option<KeyStats>> {
+        let inner = self.inner.read();
+        Ok(inner.access.lock().get(key).copied())
+    }
+
+    pub(crate) fn record_access(&self, inner: &SharedData, key: &str) {
+        if !self.options.tracks_access() {
+            return;
+        }
+        let now = now_millis();
+        let mut access = inner.access.lock();
+        match access.get_mut(key) {
+            Some(stats) => {
+                stats.last_access_ms = now;
+                stats.access_count += 1;
+            }
+            None => {
+                access.insert(
+                    key.to_string(),
+                    KeyStats {
+                        last_access_ms: now,
+                        access_count: 1,
+                    },
+                );
+            }
+        }
+    }
+
+    // Restores the persisted stats of keys still in the index, then saves
+    // them every interval on a background thread that exits once every
+    // handle to the store has been dropped.
+    pub(crate) fn start_access_stats(&self) -> Result<()> {
+        let Some(interval) = self.options.access_stats else {
+            return Ok(());
+        };
+        {
+            let mut inner = self.inner.write();
+            let saved = load_access_stats(&inner.directory)?;
+            let live = saved
+                .into_iter()
+                .filter(|(key, _)| inner.index.contains_key(key))
+                .collect();
+            *inner.access.get_mut() = live;
+        }
+        if self.options.read_only {
+            return Ok(());
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let (directory, access) = {
+                    let inner = inner.read();
+                    (inner.directory.clone(), inner.access.lock().clone())
+                };
+                if let Err(e) = save_access_stats(&directory, &access) {
+                    eprintln!("Failed to save access stats: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn load_access_stats(directory: &Path) -> io::Result<HashMap<String, KeyStats>> {
+    match fs::read(directory.join(ACCESS_STATS_FILE)) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Written to a temporary file and renamed, like the manifest. Losing the
+// last interval's updates in a crash is acceptable for statistics.
+fn save_access_stats(directory: &Path, access: &HashMap<String, KeyStats>) -> io::Result<()> {
+    let tmp_path = directory.join(format!("{}.tmp", ACCESS_STATS_FILE));
+    let mut file = fs::File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, access)?;
+    file.write_all(b"\n")?;
+    fs::rename(tmp_path, directory.join(ACCESS_STATS_FILE))
+}
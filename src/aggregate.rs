@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+// Running totals over the live keys under one prefix. `sum` only counts
+// values that parse as numbers; `count` counts every key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Aggregate {
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Aggregate {
+    pub(crate) fn add(&mut self, value: &str) {
+        self.count += 1;
+        if let Some(n) = numeric(value) {
+            self.sum += n;
+        }
+    }
+
+    pub(crate) fn subtract(&mut self, value: &str) {
+        self.count = self.count.saturating_sub(1);
+        if let Some(n) = numeric(value) {
+            self.sum -= n;
+        }
+    }
+
+    // Incremental float sums drift, so sums only need to agree closely.
+    pub(crate) fn matches(&self, other: &Aggregate) -> bool {
+        let tolerance = 1e-9 * self.sum.abs().max(other.sum.abs()).max(1.0);
+        self.count == other.count && (self.sum - other.sum).abs() <= tolerance
+    }
+}
+
+fn numeric(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+}
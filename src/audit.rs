@@ -0,0 +1,124 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::now_millis;
+
+// Hash that the first entry chains from.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub client: String,
+    pub request: String,
+    pub ok: bool,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    // Covers every field but `hash` itself, so editing, dropping or
+    // reordering entries breaks the chain from that point on.
+    fn compute_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash.as_bytes());
+        hasher.update(self.seq.to_le_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        for field in [&self.client, &self.request] {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update([self.ok as u8]);
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+// Append-only log of mutating requests, one JSON entry per line, each
+// carrying the hash of the one before it.
+pub struct AuditLog {
+    writer: BufWriter<File>,
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    // Verifies any existing entries and continues their chain.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (next_seq, last_hash) = if path.exists() {
+            match verify(path)? {
+                Some(last) => (last.seq + 1, last.hash),
+                None => (0, GENESIS_HASH.to_string()),
+            }
+        } else {
+            (0, GENESIS_HASH.to_string())
+        };
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            writer: BufWriter::new(file),
+            next_seq,
+            last_hash,
+        })
+    }
+
+    pub fn record(&mut self, client: &str, request: &str, ok: bool) -> io::Result<()> {
+        let mut entry = AuditEntry {
+            seq: self.next_seq,
+            timestamp: now_millis(),
+            client: client.to_string(),
+            request: request.to_string(),
+            ok,
+            prev_hash: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.next_seq += 1;
+        self.last_hash = entry.hash;
+        Ok(())
+    }
+}
+
+// Walks the chain and returns its last entry, or `InvalidData` naming the
+// first entry that does not check out.
+pub fn verify(path: &Path) -> io::Result<Option<AuditEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut last: Option<AuditEntry> = None;
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: malformed audit entry: {}", line_no + 1, e),
+            )
+        })?;
+        let (expected_seq, expected_prev) = match &last {
+            Some(prev) => (prev.seq + 1, prev.hash.as_str()),
+            None => (0, GENESIS_HASH),
+        };
+        if entry.seq != expected_seq || entry.prev_hash != expected_prev {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: audit chain broken before entry {}", line_no + 1, entry.seq),
+            ));
+        }
+        if entry.hash != entry.compute_hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: audit entry {} was modified", line_no + 1, entry.seq),
+            ));
+        }
+        last = Some(entry);
+    }
+    Ok(last)
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Clock skew tolerated on `exp` and `nbf`.
+const LEEWAY: Duration = Duration::from_secs(30);
+
+// The JWT claims the server looks at; any others are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Claims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    // Seconds since the Unix epoch. Tokens without an expiry are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(one) => one == audience,
+            Audience::Many(many) => many.iter().any(|one| one == audience),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Malformed(String),
+    UnsupportedAlgorithm(String),
+    UnknownKey(String),
+    BadSignature,
+    Expired,
+    NotYetValid,
+    WrongIssuer,
+    WrongAudience,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Malformed(reason) => write!(f, "malformed token: {}", reason),
+            AuthError::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {:?}", alg),
+            AuthError::UnknownKey(kid) => write!(f, "unknown key id {:?}", kid),
+            AuthError::BadSignature => write!(f, "bad signature"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::NotYetValid => write!(f, "token is not valid yet"),
+            AuthError::WrongIssuer => write!(f, "token is from another issuer"),
+            AuthError::WrongAudience => write!(f, "token is for another audience"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+struct SigningKey {
+    kid: Option<String>,
+    secret: Vec<u8>,
+}
+
+// Checks HS256 bearer tokens against shared secrets. Asymmetric keys are
+// not supported.
+pub struct TokenValidator {
+    keys: Vec<SigningKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl TokenValidator {
+    pub fn with_secret(secret: impl Into<Vec<u8>>) -> Self {
+        TokenValidator {
+            keys: vec![SigningKey {
+                kid: None,
+                secret: secret.into(),
+            }],
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    // Takes the symmetric (`"kty": "oct"`) keys of a JWKS document, so
+    // keys can be rotated by id. Other key types are skipped.
+    pub fn from_jwks(jwks: &str) -> io::Result<Self> {
+        #[derive(Deserialize)]
+        struct Jwks {
+            keys: Vec<Jwk>,
+        }
+        #[derive(Deserialize)]
+        struct Jwk {
+            kty: String,
+            kid: Option<String>,
+            k: Option<String>,
+        }
+        let jwks: Jwks = serde_json::from_str(jwks)?;
+        let mut keys = Vec::new();
+        for jwk in jwks.keys {
+            let Some(k) = jwk.k.filter(|_| jwk.kty == "oct") else {
+                continue;
+            };
+            let secret = URL_SAFE_NO_PAD
+                .decode(k.trim_end_matches('='))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad key {:?}: {}", jwk.kid, e)))?;
+            keys.push(SigningKey { kid: jwk.kid, secret });
+        }
+        if keys.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "JWKS has no symmetric (\"kty\": \"oct\") keys",
+            ));
+        }
+        Ok(TokenValidator {
+            keys,
+            issuer: None,
+            audience: None,
+        })
+    }
+
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    pub fn validate(&self, token: &str) -> Result<Claims, AuthError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::Malformed("expected three parts".to_string()));
+        };
+        let header: Header = decode_part(header)?;
+        if header.alg != "HS256" {
+            return Err(AuthError::UnsupportedAlgorithm(header.alg));
+        }
+        let signed = &token[..token.len() - signature.len() - 1];
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|e| AuthError::Malformed(e.to_string()))?;
+        let mut candidates = self
+            .keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(AuthError::UnknownKey(header.kid.unwrap_or_default()));
+        }
+        if !candidates.any(|key| constant_time_eq(&hmac_sha256(&key.secret, signed.as_bytes()), &signature)) {
+            return Err(AuthError::BadSignature);
+        }
+
+        let claims: Claims = decode_part(payload)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let exp = claims.exp.ok_or_else(|| AuthError::Malformed("no exp claim".to_string()))?;
+        if now > Duration::from_secs(exp) + LEEWAY {
+            return Err(AuthError::Expired);
+        }
+        if claims.nbf.is_some_and(|nbf| now + LEEWAY < Duration::from_secs(nbf)) {
+            return Err(AuthError::NotYetValid);
+        }
+        if self.issuer.is_some() && claims.iss != self.issuer {
+            return Err(AuthError::WrongIssuer);
+        }
+        if let Some(audience) = &self.audience
+            && !claims.aud.as_ref().is_some_and(|aud| aud.contains(audience))
+        {
+            return Err(AuthError::WrongAudience);
+        }
+        Ok(claims)
+    }
+}
+
+// Signs `claims` as an HS256 token, for clients that mint their own.
+pub fn sign_hs256(claims: &Claims, secret: &[u8], kid: Option<&str>) -> String {
+    let header = Header {
+        alg: "HS256".to_string(),
+        kid: kid.map(str::to_string),
+    };
+    let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).expect("header serializes"));
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+    let signed = format!("{}.{}", header, payload);
+    let signature = URL_SAFE_NO_PAD.encode(hmac_sha256(secret, signed.as_bytes()));
+    format!("{}.{}", signed, signature)
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|e| AuthError::Malformed(e.to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|e| AuthError::Malformed(e.to_string()))
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bitkv_rs::{KvStore, RecordFormat};
+use bitkv_rs::audit;
+use bitkv_rs::doctor::{self, Severity};
+use sha2::{Digest, Sha256};
+
+const USAGE: &str = "Usage:
+    kvs-admin keyspace-stats [--depth N] [--separator C] [DATA_DIR]
+    kvs-admin audit-verify AUDIT_LOG
+    kvs-admin diff [--quiet] DIR_A DIR_B
+    kvs-admin doctor [DATA_DIR]
+    kvs-admin migrate [--format json|binary] [DATA_DIR]
+    kvs-admin repair [DATA_DIR]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("keyspace-stats") => keyspace_stats(&args[1..]),
+        Some("audit-verify") => audit_verify(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("doctor") => run_doctor(&args[1..]),
+        Some("migrate") => migrate(&args[1..]),
+        Some("repair") => repair(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("kvs-admin: {}", e);
+        process::exit(1);
+    }
+}
+
+fn keyspace_stats(args: &[String]) -> Result<(), String> {
+    let mut depth = 1;
+    let mut separator = '/';
+    let mut dir = PathBuf::from("./data");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--depth" => {
+                depth = flag_value(&mut args, "--depth")?
+                    .parse()
+                    .map_err(|e| format!("invalid --depth: {}", e))?;
+            }
+            "--separator" => {
+                let value = flag_value(&mut args, "--separator")?;
+                let mut chars = value.chars();
+                separator = match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return Err("--separator must be a single character".to_string()),
+                };
+            }
+            other if other.starts_with("--") => return Err(format!("unknown flag {}\n{}", other, USAGE)),
+            other => dir = PathBuf::from(other),
+        }
+    }
+
+    let store = KvStore::open_read_only(dir).map_err(|e| e.to_string())?;
+    let stats = store
+        .keyspace_stats(depth, separator)
+        .map_err(|e| e.to_string())?;
+
+    println!("{:<40} {:>12} {:>14}", "PREFIX", "KEYS", "BYTES");
+    for entry in stats {
+        let label = if entry.depth == 0 {
+            "(all)".to_string()
+        } else {
+            format!("{}{}", "  ".repeat(entry.depth - 1), entry.prefix)
+        };
+        println!("{:<40} {:>12} {:>14}", label, entry.keys, entry.bytes);
+    }
+    Ok(())
+}
+
+fn audit_verify(args: &[String]) -> Result<(), String> {
+    let path = match args {
+        [path] => PathBuf::from(path),
+        _ => return Err(USAGE.to_string()),
+    };
+    match audit::verify(&path).map_err(|e| e.to_string())? {
+        Some(last) => println!("ok: {} entries, head {}", last.seq + 1, last.hash),
+        None => println!("ok: empty audit log"),
+    }
+    Ok(())
+}
+
+// Compares the live keys of two stores by value hash, listing keys only
+// in B as added, only in A as removed, and with different values as
+// changed. Exits with status 1 if the stores differ.
+fn diff(args: &[String]) -> Result<(), String> {
+    let mut quiet = false;
+    let mut dirs = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--quiet" => quiet = true,
+            other if other.starts_with("--") => return Err(format!("unknown flag {}\n{}", other, USAGE)),
+            other => dirs.push(PathBuf::from(other)),
+        }
+    }
+    let [a, b] = dirs.as_slice() else {
+        return Err(USAGE.to_string());
+    };
+    let a = value_hashes(a)?;
+    let mut b = value_hashes(b)?;
+
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (key, hash) in a {
+        match b.remove(&key) {
+            None => {
+                removed += 1;
+                if !quiet {
+                    println!("- {}", key);
+                }
+            }
+            Some(other) if other != hash => {
+                changed += 1;
+                if !quiet {
+                    println!("~ {}", key);
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    for key in b.keys() {
+        added += 1;
+        if !quiet {
+            println!("+ {}", key);
+        }
+    }
+    println!("{} added, {} removed, {} changed", added, removed, changed);
+    if added + removed + changed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Exits with status 1 if any finding is an error.
+fn run_doctor(args: &[String]) -> Result<(), String> {
+    let dir = match args {
+        [] => PathBuf::from("./data"),
+        [dir] if !dir.starts_with("--") => PathBuf::from(dir),
+        _ => return Err(USAGE.to_string()),
+    };
+    let findings = doctor::examine(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if findings.is_empty() {
+        println!("ok: no problems found in {}", dir.display());
+        return Ok(());
+    }
+    for finding in &findings {
+        let label = match finding.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("{}: {}
+    fix: {}", label, finding.problem, finding.remedy);
+    }
+    if findings.iter().any(|finding| finding.severity == Severity::Error) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Rewrites every segment in the given record format. The store must not
+// be open elsewhere.
+fn migrate(args: &[String]) -> Result<(), String> {
+    let mut format = RecordFormat::default();
+    let mut dir = PathBuf::from("./data");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match flag_value(&mut args, "--format")?.as_str() {
+                    "json" => RecordFormat::Json,
+                    "binary" => RecordFormat::Binary,
+                    other => return Err(format!("unknown record format {:?}", other)),
+                };
+            }
+            other if other.starts_with("--") => return Err(format!("unknown flag {}\n{}", other, USAGE)),
+            other => dir = PathBuf::from(other),
+        }
+    }
+    let summary = KvStore::migrate(dir.clone(), format).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    println!(
+        "ok: {} segments ({} bytes) rewritten as {} segments ({} bytes)",
+        summary.segments_before, summary.bytes_before, summary.segments_after, summary.bytes_after
+    );
+    Ok(())
+}
+
+// Salvages what can be read from damaged segments. The store must not be
+// open elsewhere.
+fn repair(args: &[String]) -> Result<(), String> {
+    let dir = match args {
+        [] => PathBuf::from("./data"),
+        [dir] if !dir.starts_with("--") => PathBuf::from(dir),
+        _ => return Err(USAGE.to_string()),
+    };
+    let summary = KvStore::check_and_repair(dir.clone()).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if summary.segments_rewritten.is_empty() {
+        println!(
+            "ok: {} segments checked, {} records, {} live keys, nothing to repair",
+            summary.segments_checked, summary.records_kept, summary.live_keys
+        );
+        return Ok(());
+    }
+    println!(
+        "repaired: rewrote segments {:?}; kept {} records, discarded {} records and {} unreadable bytes; {} live keys",
+        summary.segments_rewritten,
+        summary.records_kept,
+        summary.records_discarded,
+        summary.bytes_discarded,
+        summary.live_keys
+    );
+    Ok(())
+}
+
+fn value_hashes(dir: &Path) -> Result<BTreeMap<String, [u8; 32]>, String> {
+    let store = KvStore::open_read_only(dir.to_path_buf()).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut hashes = BTreeMap::new();
+    for key in store.keys().map_err(|e| e.to_string())? {
+        if let Some(value) = store.get(&key).map_err(|e| format!("{}: {}", key, e))? {
+            hashes.insert(key, Sha256::digest(value.as_bytes()).into());
+        }
+    }
+    Ok(hashes)
+}
+
+fn flag_value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a String, String> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag))
+}
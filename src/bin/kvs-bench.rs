@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::process;
+use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitkv_rs::KvStore;
+
+const USAGE: &str = "Usage:
+    kvs-bench [--threads 1,2,4,...] [--ops N] [--keys N] [--read-ratio R]
+              [--shards N] [--seed N] [--strategies rwlock,mutex,sharded,store]
+
+Prints one CSV row per strategy and thread count.";
+
+// The index operations the store performs under its lock, reduced to what
+// the benchmark needs. Values stand in for `CommandPos`.
+trait Index: Send + Sync {
+    fn get(&self, key: &str) -> Option<u64>;
+    fn set(&self, key: String, value: u64);
+
+    // Called once timing has stopped, before the run's files are removed.
+    fn finish(&self) {}
+}
+
+// The design the store uses today.
+struct RwLockIndex(RwLock<HashMap<String, u64>>);
+
+struct MutexIndex(Mutex<HashMap<String, u64>>);
+
+// Keys hashed onto independent locks, so writers only block readers of the
+// same shard.
+struct ShardedIndex(Vec<RwLock<HashMap<String, u64>>>);
+
+// The real store end to end, so index contention can be weighed against
+// the cost of the log itself.
+struct StoreIndex(KvStore);
+
+impl Index for RwLockIndex {
+    fn get(&self, key: &str) -> Option<u64> {
+        self.0.read().unwrap().get(key).copied()
+    }
+
+    fn set(&self, key: String, value: u64) {
+        self.0.write().unwrap().insert(key, value);
+    }
+}
+
+impl Index for MutexIndex {
+    fn get(&self, key: &str) -> Option<u64> {
+        self.0.lock().unwrap().get(key).copied()
+    }
+
+    fn set(&self, key: String, value: u64) {
+        self.0.lock().unwrap().insert(key, value);
+    }
+}
+
+impl ShardedIndex {
+    fn new(shards: usize) -> ShardedIndex {
+        ShardedIndex((0..shards).map(|_| RwLock::new(HashMap::new())).collect())
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, u64>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.0[hasher.finish() as usize % self.0.len()]
+    }
+}
+
+impl Index for ShardedIndex {
+    fn get(&self, key: &str) -> Option<u64> {
+        self.shard(key).read().unwrap().get(key).copied()
+    }
+
+    fn set(&self, key: String, value: u64) {
+        self.shard(&key).write().unwrap().insert(key, value);
+    }
+}
+
+impl Index for StoreIndex {
+    fn get(&self, key: &str) -> Option<u64> {
+        self.0.get(key).unwrap().and_then(|v| v.parse().ok())
+    }
+
+    fn set(&self, key: String, value: u64) {
+        self.0.clone().set(key, value.to_string()).unwrap();
+    }
+
+    // Background compaction must not outlive the directory it works in.
+    fn finish(&self) {
+        while self.0.stats().map(|stats| stats.compacting).unwrap_or(false) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+struct Config {
+    threads: Vec<usize>,
+    ops: usize,
+    keys: usize,
+    read_ratio: f64,
+    shards: usize,
+    seed: u64,
+    strategies: Vec<String>,
+}
+
+fn main() {
+    let config = match parse_args(&env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("kvs-bench: {}", e);
+            process::exit(2);
+        }
+    };
+
+    println!("strategy,threads,ops,read_ratio,elapsed_ms,ops_per_sec");
+    for strategy in &config.strategies {
+        for &threads in &config.threads {
+            let (index, dir) = match build(strategy, threads, &config) {
+                Ok(built) => built,
+                Err(e) => {
+                    eprintln!("kvs-bench: {}", e);
+                    process::exit(1);
+                }
+            };
+            let elapsed = run(Arc::clone(&index), threads, &config);
+            index.finish();
+            let ops = threads * config.ops;
+            println!(
+                "{},{},{},{},{:.3},{:.0}",
+                strategy,
+                threads,
+                ops,
+                config.read_ratio,
+                elapsed.as_secs_f64() * 1000.0,
+                ops as f64 / elapsed.as_secs_f64()
+            );
+            if let Some(dir) = dir {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        }
+    }
+}
+
+// A fresh, prefilled index per run so earlier runs cannot skew later ones.
+fn build(strategy: &str, threads: usize, config: &Config) -> Result<(Arc<dyn Index>, Option<std::path::PathBuf>), String> {
+    let mut dir = None;
+    let index: Arc<dyn Index> = match strategy {
+        "rwlock" => Arc::new(RwLockIndex(RwLock::new(HashMap::new()))),
+        "mutex" => Arc::new(MutexIndex(Mutex::new(HashMap::new()))),
+        "sharded" => Arc::new(ShardedIndex::new(config.shards)),
+        "store" => {
+            let path = env::temp_dir().join(format!("kvs-bench-{}-{}", process::id(), threads));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).map_err(|e| e.to_string())?;
+            let store = KvStore::open(path.clone()).map_err(|e| e.to_string())?;
+            dir = Some(path);
+            Arc::new(StoreIndex(store))
+        }
+        other => return Err(format!("unknown strategy {}", other)),
+    };
+    for i in 0..config.keys {
+        index.set(key(i), i as u64);
+    }
+    Ok((index, dir))
+}
+
+// Every thread starts at the same barrier and runs a seeded, per-thread
+// sequence of operations, so a run is repeatable for a given config.
+fn run(index: Arc<dyn Index>, threads: usize, config: &Config) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let index = Arc::clone(&index);
+            let barrier = Arc::clone(&barrier);
+            let (ops, keys, read_ratio) = (config.ops, config.keys, config.read_ratio);
+            let mut rng = XorShift::new(config.seed ^ (t as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..ops {
+                    let k = rng.next() as usize % keys;
+                    if rng.next_f64() < read_ratio {
+                        std::hint::black_box(index.get(&key(k)));
+                    } else {
+                        index.set(key(k), rng.next());
+                    }
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().expect("benchmark thread panicked");
+    }
+    start.elapsed()
+}
+
+fn key(i: usize) -> String {
+    format!("key{:08}", i)
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config {
+        threads: vec![1, 2, 4, 8, 16, 32, 64],
+        ops: 100_000,
+        keys: 10_000,
+        read_ratio: 0.9,
+        shards: 16,
+        seed: 42,
+        strategies: vec!["rwlock".to_string(), "mutex".to_string(), "sharded".to_string()],
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threads" => {
+                config.threads = flag_value(&mut args, "--threads")?
+                    .split(',')
+                    .map(|n| n.trim().parse().map_err(|e| format!("invalid --threads: {}", e)))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--ops" => config.ops = parse_flag(&mut args, "--ops")?,
+            "--keys" => config.keys = parse_flag(&mut args, "--keys")?,
+            "--read-ratio" => config.read_ratio = parse_flag(&mut args, "--read-ratio")?,
+            "--shards" => config.shards = parse_flag(&mut args, "--shards")?,
+            "--seed" => config.seed = parse_flag(&mut args, "--seed")?,
+            "--strategies" => {
+                config.strategies = flag_value(&mut args, "--strategies")?
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .collect();
+            }
+            other => return Err(format!("unknown argument {}\n{}", other, USAGE)),
+        }
+    }
+    if config.threads.contains(&0) || config.keys == 0 || config.shards == 0 {
+        return Err("--threads, --keys and --shards must be positive".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.read_ratio) {
+        return Err("--read-ratio must be between 0 and 1".to_string());
+    }
+    Ok(config)
+}
+
+fn parse_flag<'a, T: std::str::FromStr>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    flag_value(args, flag)?
+        .parse()
+        .map_err(|e| format!("invalid {}: {}", flag, e))
+}
+
+fn flag_value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a String, String> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag))
+}
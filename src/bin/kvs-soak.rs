@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitkv_rs::{KvStore, OnDuplicate, Options};
+
+const USAGE: &str = "Usage:
+    kvs-soak [--duration SECS] [--keys N] [--seed N] [--restart-every N]
+             [--compact-every N] [--check-every N] [DATA_DIR]
+
+Runs randomized operations against a store in DATA_DIR (a fresh temporary
+directory by default), checking it against an in-memory model, and prints
+a report. Exits with status 1 if any invariant was violated.";
+
+// Values above this go to blob segments, so both paths get exercised.
+const BLOB_THRESHOLD: usize = 256;
+// Violations reported in full before only being counted.
+const MAX_REPORTED: usize = 20;
+
+struct Config {
+    duration: Duration,
+    keys: usize,
+    seed: u64,
+    restart_every: u64,
+    compact_every: u64,
+    check_every: u64,
+    dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Report {
+    ops: u64,
+    sets: u64,
+    removes: u64,
+    batches: u64,
+    gets: u64,
+    compactions: u64,
+    restarts: u64,
+    checks: u64,
+    violations: Vec<String>,
+    violation_count: u64,
+}
+
+impl Report {
+    fn violation(&mut self, message: String) {
+        self.violation_count += 1;
+        if self.violations.len() < MAX_REPORTED {
+            eprintln!("violation: {}", message);
+            self.violations.push(message);
+        }
+    }
+}
+
+fn main() {
+    let config = match parse_args(&env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("kvs-soak: {}", e);
+            process::exit(2);
+        }
+    };
+    let (dir, _temp) = match &config.dir {
+        Some(dir) => (dir.clone(), None),
+        None => {
+            let dir = env::temp_dir().join(format!("kvs-soak-{}", process::id()));
+            (dir.clone(), Some(TempDir(dir)))
+        }
+    };
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        eprintln!("kvs-soak: {}", e);
+        process::exit(1);
+    }
+
+    let started = Instant::now();
+    let report = match soak(&config, &dir) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("kvs-soak: {}", e);
+            process::exit(1);
+        }
+    };
+    print_report(&config, &report, started.elapsed());
+    if report.violation_count > 0 {
+        // Exiting skips `_temp`, so the data is left for inspection.
+        println!("  data            {}", dir.display());
+        process::exit(1);
+    }
+}
+
+fn soak(config: &Config, dir: &Path) -> bitkv_rs::Result<Report> {
+    let mut report = Report::default();
+    let mut rng = XorShift::new(config.seed);
+    // What every acknowledged write says the store should hold.
+    let mut model: HashMap<String, String> = HashMap::new();
+    let mut store = open(dir)?;
+    // A directory reused from an earlier run starts out as the model.
+    for i in 0..config.keys {
+        if let Some(value) = store.get(&key(i))? {
+            model.insert(key(i), value);
+        }
+    }
+
+    let deadline = Instant::now() + config.duration;
+    while Instant::now() < deadline {
+        report.ops += 1;
+        let roll = rng.next() % 100;
+        let k = key(rng.next() as usize % config.keys);
+        if roll < 45 {
+            report.gets += 1;
+            let got = store.get(&k)?;
+            if got.as_ref() != model.get(&k) {
+                report.violation(format!("get {:?}: store has {:?}, expected {:?}", k, got, model.get(&k)));
+            }
+        } else if roll < 80 {
+            report.sets += 1;
+            let value = value(&mut rng, report.ops);
+            store.set(k.clone(), value.clone())?;
+            model.insert(k, value);
+        } else if roll < 92 {
+            report.removes += 1;
+            store.remove(k.clone())?;
+            model.remove(&k);
+        } else {
+            report.batches += 1;
+            let pairs: Vec<(String, String)> = (0..1 + rng.next() % 8)
+                .map(|_| (key(rng.next() as usize % config.keys), value(&mut rng, report.ops)))
+                .collect();
+            store.import_batch(pairs.clone(), OnDuplicate::Overwrite)?;
+            model.extend(pairs);
+        }
+
+        if report.ops.is_multiple_of(config.compact_every) {
+            report.compactions += 1;
+            store.compact()?;
+        }
+        if report.ops.is_multiple_of(config.restart_every) {
+            report.restarts += 1;
+            wait_for_compaction(&store)?;
+            store.shutdown()?;
+            drop(store);
+            store = open(dir)?;
+        }
+        if report.ops.is_multiple_of(config.check_every) {
+            report.checks += 1;
+            check(&store, &model, config.keys, &mut report)?;
+        }
+    }
+
+    wait_for_compaction(&store)?;
+    report.checks += 1;
+    check(&store, &model, config.keys, &mut report)?;
+    Ok(report)
+}
+
+// Every acknowledged write is readable, and the store holds no key the
+// model does not.
+fn check(store: &KvStore, model: &HashMap<String, String>, keys: usize, report: &mut Report) -> bitkv_rs::Result<()> {
+    for i in 0..keys {
+        let k = key(i);
+        let got = store.get(&k)?;
+        if got.as_ref() != model.get(&k) {
+            report.violation(format!("check {:?}: store has {:?}, expected {:?}", k, got, model.get(&k)));
+        }
+    }
+    let key_count = store.stats()?.key_count;
+    if key_count != model.len() {
+        report.violation(format!("store indexes {} keys, expected {}", key_count, model.len()));
+    }
+    Ok(())
+}
+
+fn open(dir: &Path) -> bitkv_rs::Result<KvStore> {
+    KvStore::open_with(dir.to_path_buf(), Options::new().blob_threshold(BLOB_THRESHOLD as u64))
+}
+
+// Restarting while a compaction still deletes files would race the reopen.
+fn wait_for_compaction(store: &KvStore) -> bitkv_rs::Result<()> {
+    while store.stats()?.compacting {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+fn key(i: usize) -> String {
+    format!("soak/{:06}", i)
+}
+
+// Mostly small values, with the occasional one large enough for a blob.
+// The op number makes every write distinguishable.
+fn value(rng: &mut XorShift, op: u64) -> String {
+    let len = if rng.next().is_multiple_of(10) { BLOB_THRESHOLD * 2 } else { 16 };
+    let mut value = format!("{}:", op);
+    while value.len() < len {
+        value.push((b'a' + (rng.next() % 26) as u8) as char);
+    }
+    value
+}
+
+fn print_report(config: &Config, report: &Report, elapsed: Duration) {
+    println!("kvs-soak report");
+    println!("  seed            {}", config.seed);
+    println!("  elapsed         {:.1}s", elapsed.as_secs_f64());
+    println!("  operations      {}", report.ops);
+    println!("    gets          {}", report.gets);
+    println!("    sets          {}", report.sets);
+    println!("    removes       {}", report.removes);
+    println!("    batches       {}", report.batches);
+    println!("  compactions     {}", report.compactions);
+    println!("  restarts        {}", report.restarts);
+    println!("  checks          {}", report.checks);
+    println!("  violations      {}", report.violation_count);
+    for violation in &report.violations {
+        println!("    {}", violation);
+    }
+    println!("  result          {}", if report.violation_count == 0 { "PASS" } else { "FAIL" });
+}
+
+// Removes the default data directory once the run is over.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config {
+        duration: Duration::from_secs(3600),
+        keys: 10_000,
+        seed: 1,
+        restart_every: 50_000,
+        compact_every: 20_000,
+        check_every: 10_000,
+        dir: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--duration" => config.duration = Duration::from_secs(parse_flag(&mut args, "--duration")?),
+            "--keys" => config.keys = parse_flag(&mut args, "--keys")?,
+            "--seed" => config.seed = parse_flag(&mut args, "--seed")?,
+            "--restart-every" => config.restart_every = parse_flag(&mut args, "--restart-every")?,
+            "--compact-every" => config.compact_every = parse_flag(&mut args, "--compact-every")?,
+            "--check-every" => config.check_every = parse_flag(&mut args, "--check-every")?,
+            other if other.starts_with("--") => return Err(format!("unknown flag {}\n{}", other, USAGE)),
+            other => config.dir = Some(PathBuf::from(other)),
+        }
+    }
+    if config.keys == 0 || config.restart_every == 0 || config.compact_every == 0 || config.check_every == 0 {
+        return Err("--keys and the --*-every intervals must be positive".to_string());
+    }
+    Ok(config)
+}
+
+fn parse_flag<'a, T: std::str::FromStr>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", flag))?
+        .parse()
+        .map_err(|e| format!("invalid {}: {}", flag, e))
+}
@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bitkv_rs::client::Client;
+use bitkv_rs::protocol::Info;
+use bitkv_rs::replication::Role;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const HISTORY_LEN: usize = 120;
+
+struct Dashboard {
+    address: String,
+    info: Option<Info>,
+    error: Option<String>,
+    last_sample: Option<(Instant, u64)>,
+    ops_per_sec: VecDeque<u64>,
+}
+
+impl Dashboard {
+    fn refresh(&mut self, client: &mut Client) {
+        match client.info() {
+            Ok(info) => {
+                let now = Instant::now();
+                if let Some((at, total_ops)) = self.last_sample {
+                    let elapsed = now.duration_since(at).as_secs_f64();
+                    let delta = info.total_ops.saturating_sub(total_ops) as f64;
+                    if elapsed > 0.0 {
+                        self.ops_per_sec.push_back((delta / elapsed).round() as u64);
+                    }
+                    if self.ops_per_sec.len() > HISTORY_LEN {
+                        self.ops_per_sec.pop_front();
+                    }
+                }
+                self.last_sample = Some((now, info.total_ops));
+                self.info = Some(info);
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let address = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:6379".to_string());
+    let mut client = Client::connect(&address)?;
+    let mut dashboard = Dashboard {
+        address,
+        info: None,
+        error: None,
+        last_sample: None,
+        ops_per_sec: VecDeque::new(),
+    };
+
+    let terminal = ratatui::init();
+    let result = run(terminal, &mut client, &mut dashboard);
+    ratatui::restore();
+    result
+}
+
+fn run(mut terminal: DefaultTerminal, client: &mut Client, dashboard: &mut Dashboard) -> io::Result<()> {
+    let mut next_refresh = Instant::now();
+    loop {
+        if Instant::now() >= next_refresh {
+            dashboard.refresh(client);
+            next_refresh = Instant::now() + REFRESH_INTERVAL;
+        }
+        terminal.draw(|frame| render(frame, dashboard))?;
+
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        if event::poll(timeout)?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+            && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+        {
+            return Ok(());
+        }
+    }
+}
+
+fn render(frame: &mut Frame, dashboard: &Dashboard) {
+    let [header, throughput, details, top_keys] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(8),
+        Constraint::Length(8),
+        Constraint::Min(5),
+    ])
+    .areas(frame.area());
+
+    render_header(frame, header, dashboard);
+    render_throughput(frame, throughput, dashboard);
+
+    let [latency, store] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(details);
+    render_latency(frame, latency, dashboard.info.as_ref());
+    render_store(frame, store, dashboard.info.as_ref());
+    render_top_keys(frame, top_keys, dashboard.info.as_ref());
+}
+
+fn render_header(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    let status = match (&dashboard.error, &dashboard.info) {
+        (Some(e), _) => Line::from(format!("error: {}", e)).fg(Color::Red),
+        (None, Some(info)) => Line::from(format!("uptime {}s", info.uptime_secs)).fg(Color::Green),
+        (None, None) => Line::from("connecting..."),
+    };
+    let title = format!(" kvs-top - {} (q to quit) ", dashboard.address);
+    frame.render_widget(Paragraph::new(status).block(Block::bordered().title(title)), area);
+}
+
+fn render_throughput(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    let current = dashboard.ops_per_sec.back().copied().unwrap_or(0);
+    let data: Vec<u64> = dashboard.ops_per_sec.iter().copied().collect();
+    let sparkline = Sparkline::default()
+        .block(Block::bordered().title(format!(" ops/sec: {} ", current)))
+        .data(&data)
+        .style(Style::default().fg(Color::Cyan));
+    frame.render_widget(sparkline, area);
+}
+
+fn render_latency(frame: &mut Frame, area: Rect, info: Option<&Info>) {
+    let lines = match info {
+        Some(info) => vec![
+            Line::from(format!("p50  {:>10} us", info.latency.p50_us)),
+            Line::from(format!("p90  {:>10} us", info.latency.p90_us)),
+            Line::from(format!("p99  {:>10} us", info.latency.p99_us)),
+            Line::from(format!("max  {:>10} us", info.latency.max_us)),
+            Line::from(format!("ops  {:>10}", info.total_ops)),
+        ],
+        None => vec![],
+    };
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" latency ")), area);
+}
+
+fn render_store(frame: &mut Frame, area: Rect, info: Option<&Info>) {
+    let lines = match info {
+        Some(info) => {
+            let compaction = if info.store.compacting {
+                Line::from("compaction  running").fg(Color::Yellow)
+            } else {
+                Line::from("compaction  idle")
+            };
+            let mut lines = vec![
+                Line::from(format!("segments    {}", info.store.segment_count)),
+                Line::from(format!("generation  {}", info.store.current_generation)),
+                Line::from(format!("keys        {}", info.store.key_count)),
+                compaction,
+            ];
+            if let Some(resident) = info.resident_bytes {
+                lines.push(Line::from(format!("resident    {} MiB", resident / (1024 * 1024))));
+            }
+            lines.push(Line::from(format!("store heap  {} MiB", info.memory.total() / (1024 * 1024))));
+            if info.store.blob_segment_count > 0 {
+                lines.push(Line::from(format!(
+                    "blobs       {} live / {} bytes",
+                    info.store.blob_live_bytes, info.store.blob_total_bytes
+                )));
+            }
+            if info.store.low_disk {
+                lines.push(Line::from("disk        LOW, read-only").fg(Color::Red));
+            }
+            if info.unclean_shutdown {
+                lines.push(Line::from("last stop   unclean").fg(Color::Yellow));
+            }
+            if let Role::Replica { primary } = &info.replication.role {
+                let lag = match (info.replication.lag_records, info.replication.lag_millis) {
+                    (Some(records), Some(millis)) => format!("{} records / {} ms", records, millis),
+                    _ => "unknown".to_string(),
+                };
+                let line = Line::from(format!("replica of  {} (lag {})", primary, lag));
+                lines.push(if info.replication.connected { line } else { line.fg(Color::Red) });
+            }
+            lines
+        }
+        None => vec![],
+    };
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" store ")), area);
+}
+
+fn render_top_keys(frame: &mut Frame, area: Rect, info: Option<&Info>) {
+    let rows: Vec<Row> = info
+        .map(|info| {
+            info.top_keys
+                .iter()
+                .map(|(key, count)| Row::new(vec![key.clone(), count.to_string()]))
+                .collect()
+        })
+        .unwrap_or_default();
+    let table = Table::new(rows, [Constraint::Percentage(80), Constraint::Percentage(20)])
+        .header(Row::new(vec!["key", "accesses"]).bold())
+        .block(Block::bordered().title(" top keys "));
+    frame.render_widget(table, area);
+}
@@ -1,74 +1,104 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use bitkv_rs::KvStore;
-use bitkv_rs::protocol::{Request, Response};
 use std::path::PathBuf;
+use std::time::Duration;
 
+use bitkv_rs::config::ServerConfig;
+use bitkv_rs::self_test::{self, SelfTestConfig};
+use bitkv_rs::server::Service;
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let store = KvStore::open(PathBuf::from("./data"))?;
-    let address = "127.0.0.1:6379";
-    println!("BitKV server started on {}", address);
-    let listener = TcpListener::bind(address).await?;
-
-    loop {
-        let (socket, _) = listener.accept().await?;
-        println!("Accepted connection");
-        let store = store.clone();
-        tokio::spawn(async move {
-            if let Err(e) = process_connection(socket, store).await {
-                eprintln!("Connection error: {}", e);
-            }
-        });
+async fn main() -> bitkv_rs::Result<()> {
+    if std::env::args().any(|arg| arg == "--check-config") {
+        check_config();
+    }
+    if std::env::args().any(|arg| arg == "--self-test") {
+        run_self_test();
     }
+    let config = match config_path() {
+        Some(path) => ServerConfig::load(&path)?,
+        None => ServerConfig::default(),
+    };
+    let service = Service::bind(config).await?;
+    service
+        .run(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
 }
 
-async fn process_connection(mut socket: TcpStream, store: KvStore) -> std::io::Result<()> {
-    println!("Processing connection...");
-    let (reader, mut writer) = socket.split();
-    let stream = BufReader::new(reader);
-    let mut lines = stream.lines();
-    while let Ok(Some(line)) = lines.next_line().await {
-            let req: Request = match serde_json::from_str(&line) {
-                Ok(req) => req,
-                Err(e) => {
-                    let resp = Response::Error(format!("Invalid Request: {}", e));
-                    let resp_json = serde_json::to_string(&resp)?;
-                    writer.write_all(resp_json.as_bytes()).await?;
-                    writer.write_all(b"\n").await?;
-                    continue;
-                }
-            };
-        let response = execute_request(req, store.clone()).await;
-
-        let resp_json = serde_json::to_string(&response)?;
-        writer.write_all(resp_json.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
+// Prints what is wrong with the config as JSON and exits, with status 1
+// if anything is.
+fn check_config() -> ! {
+    let issues = match config_path() {
+        Some(path) => match ServerConfig::load_checked(&path) {
+            Ok(config) => config.check(),
+            Err(issues) => issues,
+        },
+        None => ServerConfig::default().check(),
+    };
+    for issue in &issues {
+        eprintln!("{}", issue);
     }
-    Ok(())
+    let report = serde_json::json!({ "ok": issues.is_empty(), "issues": issues });
+    println!("{}", serde_json::to_string_pretty(&report).expect("issues serialize"));
+    std::process::exit(if issues.is_empty() { 0 } else { 1 });
 }
 
-async fn execute_request(req: Request, mut store: KvStore) -> Response {
-    let result = tokio::task::spawn_blocking(move || {
-        match req {
-            Request::Get { key } => match store.get(&key) {
-                Ok(Some(v)) => Response::Value(v),
-                Ok(None) => Response::NotFound,
-                Err(e) => Response::Error(e.to_string()),
-            },
-            Request::Set { key, value } => match store.set(key, value) {
-                Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e.to_string()),
-            },
-            Request::Remove { key } => match store.remove(key) {
-                Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e.to_string()),
-            },
+// Runs a mixed workload against a scratch store in the data directory,
+// with the store options the config gives, and prints how it went as JSON.
+// `--duration SECS` and `--threads N` override the defaults.
+fn run_self_test() -> ! {
+    let result = (|| -> bitkv_rs::Result<self_test::SelfTestReport> {
+        let config = match config_path() {
+            Some(path) => ServerConfig::load(&path)?,
+            None => ServerConfig::default(),
+        };
+        let mut test = SelfTestConfig::default();
+        if let Some(secs) = flag_value("--duration") {
+            test.duration = Duration::from_secs(parse_flag("--duration", &secs)?);
+        }
+        if let Some(threads) = flag_value("--threads") {
+            test.threads = parse_flag("--threads", &threads)?;
         }
-    }).await;
+        let directory = config.data_dir.join(format!("self-test-{}", std::process::id()));
+        eprintln!(
+            "Running a {}s self-test on {} threads in {}",
+            test.duration.as_secs(),
+            test.threads,
+            directory.display()
+        );
+        self_test::run(&directory, config.store_options(), &test)
+    })();
     match result {
-        Ok(response) => response,
-        Err(e) => Response::Error(format!("Internal server error: {}", e)),
+        Ok(report) => {
+            println!("{}", serde_json::to_string_pretty(&report).expect("report serializes"));
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Self-test failed: {}", e);
+            std::process::exit(1);
+        }
     }
-}
\ No newline at end of file
+}
+
+fn config_path() -> Option<PathBuf> {
+    flag_value("--config").map(PathBuf::from)
+}
+
+fn flag_value(flag: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+    }
+    None
+}
+
+fn parse_flag<T: std::str::FromStr>(flag: &str, value: &str) -> std::io::Result<T> {
+    value.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} expects a number, not {:?}", flag, value),
+        )
+    })
+}
@@ -0,0 +1,408 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, KvStore, Result, SharedData, manifest};
+
+// Bytes read from the reader at a time by `append_blob_from`.
+const STREAM_BUFFER: usize = 64 * 1024;
+// Size at which the active blob segment is sealed.
+const BLOB_SEGMENT_LIMIT: u64 = 16 * 1024 * 1024;
+// `gc_blobs` rewrites segments where less than this share is still live.
+const BLOB_GC_LIVE_RATIO: f64 = 0.5;
+
+// Where a separated value lives: a record in `<segment>.blob`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlobRef {
+    pub(crate) segment: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+}
+
+// Blob records carry their key so a dump of a blob segment can be matched
+// back to the log.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BlobRecord {
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+// `live_bytes` counts the records the index still points at.
+pub(crate) struct BlobSegment {
+    reader: Mutex<BufReader<File>>,
+    pub(crate) total_bytes: u64,
+    pub(crate) live_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlobGcStats {
+    pub segments_rewritten: usize,
+    pub values_moved: usize,
+    pub bytes_reclaimed: u64,
+}
+
+impl BlobSegment {
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.reader.lock().capacity()
+    }
+}
+
+pub(crate) fn blob_path(directory: &Path, segment: u64) -> PathBuf {
+    directory.join(format!("{}.blob", segment))
+}
+
+pub(crate) fn open_blob_segments(directory: &Path) -> io::Result<BTreeMap<u64, BlobSegment>> {
+    let mut segments = BTreeMap::new();
+    for dir_entry in fs::read_dir(directory)? {
+        let path = dir_entry?.path();
+        if path.extension() != Some(std::ffi::OsStr::new("blob")) {
+            continue;
+        }
+        let Some(segment) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        let file = fs::OpenOptions::new().read(true).open(path)?;
+        let total_bytes = file.metadata()?.len();
+        segments.insert(
+            segment,
+            BlobSegment {
+                reader: Mutex::new(BufReader::new(file)),
+                total_bytes,
+                live_bytes: 0,
+            },
+        );
+    }
+    Ok(segments)
+}
+
+// Whether the next blob opens a new segment.
+pub(crate) fn blob_writer_full(inner: &mut SharedData) -> io::Result<bool> {
+    match &mut inner.blob_writer {
+        Some((_, writer)) => Ok(writer.stream_position()? >= BLOB_SEGMENT_LIMIT),
+        None => Ok(true),
+    }
+}
+
+// Appends and flushes the value to the active blob segment, opening a new
+// one first if there is none yet or the current one is full.
+pub(crate) fn append_blob(inner: &mut SharedData, key: &str, value: &str) -> io::Result<BlobRef> {
+    append_blob_with(inner, |writer| {
+        let record = BlobRecord {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        serde_json::to_writer(writer, &record)?;
+        Ok(())
+    })
+}
+
+// Like `append_blob`, but the value is the `len` bytes `reader` yields,
+// copied a buffer at a time. Returns the value's checksum too. A reader
+// that fails, ends early or yields anything but UTF-8 leaves the segment
+// as it was.
+pub(crate) fn append_blob_from(
+    inner: &mut SharedData,
+    key: &str,
+    reader: impl Read,
+    len: u64,
+) -> io::Result<(BlobRef, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let blob = append_blob_with(inner, |writer| {
+        // The same JSON `BlobRecord` serializes to, with the value's
+        // string written out as it arrives.
+        write!(writer, "{{\"key\":{},\"value\":\"", serde_json::to_string(key)?)?;
+        let mut reader = reader.take(len);
+        let mut buf = vec![0; STREAM_BUFFER];
+        // Bytes of a character split across reads, carried over.
+        let mut carried = 0;
+        let mut copied = 0;
+        loop {
+            let read = reader.read(&mut buf[carried..])?;
+            if read == 0 {
+                break;
+            }
+            copied += read as u64;
+            let filled = carried + read;
+            let text = match std::str::from_utf8(&buf[..filled]) {
+                Ok(text) => text,
+                // Only the last character is incomplete; the rest waits for more.
+                Err(e) if e.error_len().is_none() => {
+                    std::str::from_utf8(&buf[..e.valid_up_to()]).expect("checked up to here")
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            hasher.update(text.as_bytes());
+            let escaped = serde_json::to_string(text)?;
+            writer.write_all(&escaped.as_bytes()[1..escaped.len() - 1])?;
+            let used = text.len();
+            buf.copy_within(used..filled, 0);
+            carried = filled - used;
+        }
+        if copied < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Reader ended after {} of {} bytes", copied, len),
+            ));
+        }
+        if carried > 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Value ends part way through a character"));
+        }
+        writer.write_all(b"\"}")
+    })?;
+    Ok((blob, hasher.finalize()))
+}
+
+// Opens a blob segment if needed and lets `write` append one record. If it
+// fails, the segment is cut back to where the record started.
+fn append_blob_with(
+    inner: &mut SharedData,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<BlobRef> {
+    if blob_writer_full(inner)? {
+        // `set_from_reader` writes blobs without a blob threshold set.
+        let SharedData { directory, metadata, .. } = &mut *inner;
+        manifest::record_feature(directory, metadata, "blob_segments")?;
+        let segment = inner.blob_segments.keys().last().map_or(1, |last| last + 1);
+        let path = blob_path(&inner.directory, segment);
+        let writer = BufWriter::new(fs::OpenOptions::new().create(true).append(true).open(&path)?);
+        let reader = BufReader::new(fs::OpenOptions::new().read(true).open(&path)?);
+        inner.blob_segments.insert(
+            segment,
+            BlobSegment {
+                reader: Mutex::new(reader),
+                total_bytes: 0,
+                live_bytes: 0,
+            },
+        );
+        inner.blob_writer = Some((segment, writer));
+    }
+
+    let Some((segment, writer)) = &mut inner.blob_writer else {
+        unreachable!("blob writer opened above");
+    };
+    let segment = *segment;
+    let pos = writer.stream_position()?;
+    if let Err(e) = write(writer).and_then(|_| writer.flush()) {
+        // Whatever the buffer still holds is dropped with the tail. The
+        // file is in append mode, so its offset is put back by hand.
+        let buffered = std::mem::replace(writer, BufWriter::new(writer.get_ref().try_clone()?));
+        let (file, _) = buffered.into_parts();
+        file.set_len(pos)?;
+        writer.seek(SeekFrom::Start(pos))?;
+        return Err(e);
+    }
+    let len = writer.stream_position()? - pos;
+    if let Some(stats) = inner.blob_segments.get_mut(&segment) {
+        stats.total_bytes += len;
+    }
+    Ok(BlobRef { segment, pos, len })
+}
+
+pub(crate) fn read_blob(inner: &SharedData, blob: &BlobRef) -> io::Result<String> {
+    let segment = inner.blob_segments.get(&blob.segment).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Blob segment {} not found", blob.segment),
+        )
+    })?;
+    let mut reader = segment.reader.lock();
+    reader.seek(SeekFrom::Start(blob.pos))?;
+    let record: BlobRecord = serde_json::from_reader((&mut *reader).take(blob.len))?;
+    Ok(record.value)
+}
+
+// Writes the blob's value to `out` as it is read, without holding all of
+// it. Returns the bytes written and their checksum.
+pub(crate) fn copy_blob(inner: &SharedData, blob: &BlobRef, out: &mut dyn Write) -> io::Result<(u64, u32)> {
+    let segment = inner.blob_segments.get(&blob.segment).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Blob segment {} not found", blob.segment),
+        )
+    })?;
+    let mut reader = segment.reader.lock();
+    reader.seek(SeekFrom::Start(blob.pos))?;
+    let mut record = (&mut *reader).take(blob.len);
+    // Both writers lay the record out as `BlobRecord` serializes.
+    expect_bytes(&mut record, b"{\"key\":\"")?;
+    copy_json_string(&mut record, &mut io::sink(), &mut crc32fast::Hasher::new())?;
+    expect_bytes(&mut record, b",\"value\":\"")?;
+    let mut hasher = crc32fast::Hasher::new();
+    let copied = copy_json_string(&mut record, out, &mut hasher)?;
+    Ok((copied, hasher.finalize()))
+}
+
+// Copies the rest of a JSON string, its opening quote already read, to
+// `out` with escapes decoded. Runs of plain bytes are written as they are.
+fn copy_json_string(
+    reader: &mut impl BufRead,
+    out: &mut dyn Write,
+    hasher: &mut crc32fast::Hasher,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Blob record ends inside a string"));
+        }
+        let run = buf.iter().position(|&b| b == b'"' || b == b'\\').unwrap_or(buf.len());
+        out.write_all(&buf[..run])?;
+        hasher.update(&buf[..run]);
+        copied += run as u64;
+        let stop = buf.get(run).copied();
+        reader.consume(run + stop.is_some() as usize);
+        match stop {
+            Some(b'"') => return Ok(copied),
+            Some(_) => {
+                let mut utf8 = [0; 4];
+                let decoded = read_escape(reader)?.encode_utf8(&mut utf8).as_bytes();
+                out.write_all(decoded)?;
+                hasher.update(decoded);
+                copied += decoded.len() as u64;
+            }
+            None => {}
+        }
+    }
+}
+
+// The character a JSON escape stands for, its backslash already read.
+fn read_escape(reader: &mut impl Read) -> io::Result<char> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid escape in blob record");
+    let c = match read_byte(reader)? {
+        b'"' => '"',
+        b'\\' => '\\',
+        b'/' => '/',
+        b'b' => '\u{8}',
+        b'f' => '\u{c}',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'u' => {
+            let mut code = read_hex4(reader)?;
+            if (0xD800..0xDC00).contains(&code) {
+                expect_bytes(reader, b"\\u")?;
+                let low = read_hex4(reader)?;
+                code = 0x10000 + ((code - 0xD800) << 10) + low.checked_sub(0xDC00).ok_or_else(invalid)?;
+            }
+            return char::from_u32(code).ok_or_else(invalid);
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(c)
+}
+
+fn read_hex4(reader: &mut impl Read) -> io::Result<u32> {
+    let mut digits = [0; 4];
+    reader.read_exact(&mut digits)?;
+    std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid escape in blob record"))
+}
+
+fn read_byte(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn expect_bytes(reader: &mut impl Read, expected: &[u8]) -> io::Result<()> {
+    let mut found = vec![0; expected.len()];
+    reader.read_exact(&mut found)?;
+    if found != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected blob record layout"));
+    }
+    Ok(())
+}
+
+// For callers without the store's readers, such as the fallback scan.
+pub(crate) fn read_blob_from(directory: &Path, blob: &BlobRef) -> io::Result<String> {
+    let mut file = File::open(blob_path(directory, blob.segment))?;
+    file.seek(SeekFrom::Start(blob.pos))?;
+    let record: BlobRecord = serde_json::from_reader(BufReader::new(file.take(blob.len)))?;
+    Ok(record.value)
+}
+
+impl KvStore {
+    // Rewrites blob segments that are mostly dead: live values move to the
+    // active blob segment, each with a fresh log record pointing at its new
+    // place, and the old segment file is deleted.
+    pub fn gc_blobs(&mut self) -> Result<BlobGcStats> {
+        let mut inner = self.inner.write();
+        crate::check_writable(&inner)?;
+        let candidates: Vec<u64> = inner
+            .blob_segments
+            .iter()
+            .filter(|(_, segment)| {
+                segment.total_bytes > 0
+                    && (segment.live_bytes as f64) < segment.total_bytes as f64 * BLOB_GC_LIVE_RATIO
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut stats = BlobGcStats::default();
+        for segment in candidates {
+            if let Some(old) = inner.blob_segments.get(&segment) {
+                stats.bytes_reclaimed += old.total_bytes.saturating_sub(old.live_bytes);
+            }
+            if inner.blob_writer.as_ref().is_some_and(|(active, _)| *active == segment) {
+                inner.blob_writer = None;
+            }
+            let live: Vec<String> = inner
+                .index
+                .iter()
+                .filter(|(_, pos)| pos.blob.is_some_and(|blob| blob.segment == segment))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in live {
+                let Some(cmd_pos) = inner.index.get(&key).copied() else {
+                    continue;
+                };
+                let Some(old_blob) = cmd_pos.blob else {
+                    continue;
+                };
+                let (timestamp, content_type, checksum, expires_at) = match self.read_command(&inner, &cmd_pos, &key)? {
+                    Some(Command::Set {
+                        timestamp,
+                        content_type,
+                        checksum,
+                        expires_at,
+                        ..
+                    }) => (timestamp, content_type, checksum, expires_at),
+                    _ => (None, None, None, None),
+                };
+                let value = read_blob(&inner, &old_blob)?;
+                let new_blob = append_blob(&mut inner, &key, &value)?;
+                let cmd = Command::Set {
+                    key,
+                    value: String::new(),
+                    timestamp,
+                    blob: Some(new_blob),
+                    content_type,
+                    checksum,
+                    expires_at,
+                };
+                let mut new_pos = self.append_command(&mut inner, &cmd)?;
+                new_pos.blob = Some(new_blob);
+                if let Command::Set { key, .. } = cmd {
+                    inner.index_insert(key, new_pos);
+                }
+                stats.values_moved += 1;
+            }
+
+            inner.blob_segments.remove(&segment);
+            fs::remove_file(blob_path(&inner.directory, segment))?;
+            stats.segments_rewritten += 1;
+        }
+        inner.blob_bytes_reclaimed += stats.bytes_reclaimed;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,19 @@
+use crate::{KvError, KvStore, Result};
+
+// CRC-32 (IEEE) of a value's bytes, as recorded with each write and sent
+// alongside values both ways between clients and the server.
+pub fn value_checksum(value: impl AsRef<[u8]>) -> u32 {
+    crc32fast::hash(value.as_ref())
+}
+
+impl KvStore {
+    // Like `set`, but refuses the write if `value` does not match the
+    // checksum its sender computed.
+    pub fn set_checked(&mut self, key: String, value: String, checksum: u32) -> Result<()> {
+        if value_checksum(&value) != checksum {
+            return Err(KvError::ChecksumMismatch(key));
+        }
+        let mut inner = self.inner.write();
+        self.set_locked(&mut inner, key, value)
+    }
+}
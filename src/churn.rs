@@ -0,0 +1,42 @@
+use std::collections::BTreeSet;
+
+use crate::{KvStore, SharedData};
+
+// At or above this share of its keys under high-churn prefixes, a sealed
+// segment is compacted ahead of the rest.
+const CHURN_SHARE: f64 = 0.5;
+// High-churn segments to collect before compacting them together.
+const CHURN_COMPACT_SEGMENTS: usize = 2;
+
+impl KvStore {
+    // Called as the active segment is about to be sealed; its hint already
+    // lists the keys it holds. Segments sealed before the store was opened
+    // are not classified and wait for a full compaction.
+    pub(crate) fn classify_active_segment(&self, inner: &mut SharedData) {
+        if self.options.churn_prefixes.is_empty() {
+            return;
+        }
+        let (mut keys, mut churning) = (0, 0);
+        for key in inner.active_hint.keys() {
+            keys += 1;
+            if self.options.high_churn(key) {
+                churning += 1;
+            }
+        }
+        if keys > 0 && churning as f64 / keys as f64 >= CHURN_SHARE {
+            inner.churn_segments.insert(inner.current_generation);
+        } else {
+            inner.churn_segments.remove(&inner.current_generation);
+        }
+    }
+
+    // The high-churn segments to compact now, once enough have built up.
+    pub(crate) fn churn_compaction_due(&self, inner: &mut SharedData) -> Option<BTreeSet<u64>> {
+        let SharedData {
+            churn_segments, readers, ..
+        } = inner;
+        // Compactions, partial or full, remove the segments they rewrite.
+        churn_segments.retain(|generation| readers.contains_key(generation));
+        (churn_segments.len() >= CHURN_COMPACT_SEGMENTS).then(|| churn_segments.clone())
+    }
+}
@@ -0,0 +1,1185 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    Aggregate, ContentType, ScanOptions, ScanPage, SetOptions, SetOutcome, SyncBatch, VersionVector, value_checksum,
+};
+use crate::codec::CodecKind;
+use crate::protocol::{ClientInfo, Info, Request, Response, WatchEvent};
+use crate::replication::{ReplicationEvent, SnapshotManifest, Staleness};
+
+const PIPELINE_WINDOW: usize = 256;
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    codec: CodecKind,
+    heartbeat: Option<Duration>,
+    last_response: Instant,
+    bearer_token: Option<String>,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Client {
+            reader,
+            writer: BufWriter::new(stream),
+            codec: CodecKind::Json,
+            heartbeat: None,
+            last_response: Instant::now(),
+            bearer_token: None,
+        })
+    }
+
+    // Connects and switches the connection to `codec`.
+    pub fn connect_with_codec(addr: impl ToSocketAddrs, codec: CodecKind) -> io::Result<Self> {
+        let mut client = Self::connect(addr)?;
+        if codec != CodecKind::Json {
+            match client.request(&Request::Hello { codec })? {
+                Response::Ok => client.codec = codec,
+                other => return Err(unexpected(other)),
+            }
+        }
+        Ok(client)
+    }
+
+    // Once the connection has been idle for `interval`, the next request
+    // is preceded by a ping that must be answered within `interval`. A
+    // link that died while idle then fails fast instead of hanging, and
+    // the connection is closed, since a late pong would be taken for the
+    // next response.
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>) {
+        self.heartbeat = interval;
+    }
+
+    // Sent with every later request but handshakes and pings, for servers
+    // that authenticate each request.
+    pub fn set_bearer_token(&mut self, token: Option<String>) {
+        self.bearer_token = token;
+    }
+
+    // Round-trip time to the server.
+    pub fn ping(&mut self) -> io::Result<Duration> {
+        let started = Instant::now();
+        match self.request(&Request::Ping)? {
+            Response::Pong => Ok(started.elapsed()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn check_link(&mut self) -> io::Result<()> {
+        let Some(interval) = self.heartbeat else {
+            return Ok(());
+        };
+        if self.last_response.elapsed() < interval {
+            return Ok(());
+        }
+        self.reader.get_ref().set_read_timeout(Some(interval))?;
+        let pong = self.ping();
+        self.reader.get_ref().set_read_timeout(None)?;
+        if let Err(e) = pong {
+            let _ = self.writer.get_ref().shutdown(Shutdown::Both);
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("Server did not answer a heartbeat: {}", e),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let value = value_of(self.request(&Request::Get { key: key.into() })?)?;
+        value.map(text).transpose()
+    }
+
+    // Fails on a replica lagging by more than `staleness` allows.
+    pub fn get_bounded(&mut self, key: impl Into<String>, staleness: Staleness) -> io::Result<Option<String>> {
+        let value = value_of(self.request(&Request::GetBounded { key: key.into(), staleness })?)?;
+        value.map(text).transpose()
+    }
+
+    // Sends a window of requests before reading any response. The server
+    // answers a connection's requests in order, so responses line up with
+    // `keys`. Windows keep both socket buffers from filling up at once.
+    pub fn get_pipeline<K: Into<String>>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> io::Result<Vec<Option<String>>> {
+        self.check_link()?;
+        let mut keys = keys.into_iter().peekable();
+        let mut values = Vec::new();
+        let mut first_error = None;
+        while keys.peek().is_some() {
+            let mut sent = 0;
+            for key in keys.by_ref().take(PIPELINE_WINDOW) {
+                self.send(&Request::Get { key: key.into() })?;
+                sent += 1;
+            }
+            self.writer.flush()?;
+
+            for _ in 0..sent {
+                match value_of(self.receive()?).and_then(|value| value.map(text).transpose()) {
+                    Ok(value) => values.push(value),
+                    Err(e) => {
+                        // Keep draining so the connection stays in sync.
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(values),
+        }
+    }
+
+    // The value travels with its checksum, so the server refuses it if it
+    // was corrupted on the way.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<()> {
+        let value = value.into();
+        let req = Request::SetChecked {
+            key: key.into(),
+            checksum: value_checksum(&value),
+            value,
+        };
+        match self.request(&req)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Sends the value as raw bytes rather than a string, so it need not be
+    // UTF-8.
+    pub fn set_bytes(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> io::Result<()> {
+        let value = value.into();
+        let req = Request::Set {
+            key: key.into(),
+            checksum: Some(value_checksum(&value)),
+            value,
+        };
+        match self.request(&req)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: impl Into<String>) -> io::Result<Option<Vec<u8>>> {
+        value_of(self.request(&Request::Get { key: key.into() })?)
+    }
+
+    pub fn set_with_content_type(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        content_type: ContentType,
+    ) -> io::Result<()> {
+        let value = value.into();
+        let req = Request::SetTagged {
+            key: key.into(),
+            checksum: Some(value_checksum(&value)),
+            value,
+            content_type,
+        };
+        match self.request(&req)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn set_opts(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        options: SetOptions,
+    ) -> io::Result<SetOutcome> {
+        let value = value.into();
+        let req = Request::SetOpts {
+            key: key.into(),
+            checksum: Some(value_checksum(&value)),
+            value,
+            options,
+        };
+        match self.request(&req)? {
+            Response::SetOutcome(outcome) => Ok(outcome),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn get_with_content_type(&mut self, key: impl Into<String>) -> io::Result<Option<(String, Option<ContentType>)>> {
+        match self.request(&Request::GetTagged { key: key.into() })? {
+            Response::TaggedValue {
+                value,
+                content_type,
+                checksum,
+            } => Ok(Some((text(verify(value.into_bytes(), checksum)?)?, content_type))),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Typed values; JSON is tagged with its content type. A value that
+    // doesn't decode as `T` is an `InvalidData` error.
+    pub fn set_json<T: Serialize + ?Sized>(&mut self, key: impl Into<String>, value: &T) -> io::Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.set_with_content_type(key, value, ContentType::Json)
+    }
+
+    pub fn get_json<T: DeserializeOwned>(&mut self, key: impl Into<String>) -> io::Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_msgpack<T: Serialize + ?Sized>(&mut self, key: impl Into<String>, value: &T) -> io::Result<()> {
+        let bytes = rmp_serde::to_vec_named(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.set_bytes(key, bytes)
+    }
+
+    pub fn get_msgpack<T: DeserializeOwned>(&mut self, key: impl Into<String>) -> io::Result<Option<T>> {
+        let Some(bytes) = self.get_bytes(key)? else {
+            return Ok(None);
+        };
+        let value = rmp_serde::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(value))
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> io::Result<()> {
+        match self.request(&Request::Remove { key: key.into() })? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Sends `request` under `token`. Resending the same token under the
+    // same bearer token subject, even on a new connection, gets back the
+    // first response instead of applying the request again, for as long as
+    // the server keeps it.
+    pub fn request_idempotent(&mut self, token: impl Into<String>, request: Request) -> io::Result<Response> {
+        let req = Request::Idempotent {
+            token: token.into(),
+            request: Box::new(request),
+        };
+        match self.request(&req)? {
+            error @ Response::Error(_) => Err(unexpected(error)),
+            response => Ok(response),
+        }
+    }
+
+    // Removes the key, returning the value it had.
+    pub fn get_and_remove(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let value = value_of(self.request(&Request::GetDel { key: key.into() })?)?;
+        value.map(text).transpose()
+    }
+
+    // Sets the key, returning the value it had.
+    pub fn get_and_set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<Option<String>> {
+        let value = value.into();
+        let req = Request::GetSet {
+            key: key.into(),
+            checksum: Some(value_checksum(&value)),
+            value,
+        };
+        value_of(self.request(&req)?)?.map(text).transpose()
+    }
+
+    // Returns the value's new length in bytes.
+    pub fn append(&mut self, key: impl Into<String>, suffix: impl Into<String>) -> io::Result<u64> {
+        let suffix = suffix.into();
+        let req = Request::Append {
+            key: key.into(),
+            checksum: Some(value_checksum(&suffix)),
+            suffix,
+        };
+        match self.request(&req)? {
+            Response::Length(len) => Ok(len),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // The values of `keys`, in order, as of a single point in time.
+    pub fn get_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<Vec<Option<String>>> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        match self.request(&Request::MGet { keys })? {
+            Response::Values(values) => Ok(values),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // One page of the entries matching `options`; the filters run on the
+    // server. Pass the page's `next` as `start_after` to fetch the rest.
+    pub fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        match self.request(&Request::Scan { options })? {
+            Response::Scan(page) => Ok(page),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn remove_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<()> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        match self.request(&Request::MRemove { keys })? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Fails with `ErrorKind::NotFound` if `from` does not exist.
+    pub fn rename(&mut self, from: impl Into<String>, to: impl Into<String>, overwrite: bool) -> io::Result<()> {
+        let req = Request::Rename {
+            from: from.into(),
+            to: to.into(),
+            overwrite,
+        };
+        match self.request(&req)? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(io::Error::new(io::ErrorKind::NotFound, "Key not found")),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Fails with `ErrorKind::NotFound` if `from` does not exist.
+    pub fn copy(&mut self, from: impl Into<String>, to: impl Into<String>, overwrite: bool) -> io::Result<()> {
+        let req = Request::Copy {
+            from: from.into(),
+            to: to.into(),
+            overwrite,
+        };
+        match self.request(&req)? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(io::Error::new(io::ErrorKind::NotFound, "Key not found")),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn eval(
+        &mut self,
+        script: impl Into<String>,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let req = Request::Eval {
+            script: script.into(),
+            keys,
+            args,
+        };
+        value_of(self.request(&req)?)?.map(text).transpose()
+    }
+
+    pub fn info(&mut self) -> io::Result<Info> {
+        match self.request(&Request::Info)? {
+            Response::Info(info) => Ok(*info),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn compact(&mut self) -> io::Result<()> {
+        match self.request(&Request::Compact)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Asks the server to flush the store and exit.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self.request(&Request::Shutdown)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn cancel_compaction(&mut self) -> io::Result<()> {
+        match self.request(&Request::CancelCompaction)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // `None` if the server has no aggregate registered for `prefix`.
+    pub fn aggregate(&mut self, prefix: impl Into<String>) -> io::Result<Option<Aggregate>> {
+        match self.request(&Request::Aggregate { prefix: prefix.into() })? {
+            Response::Aggregate(aggregate) => Ok(Some(aggregate)),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn client_list(&mut self) -> io::Result<Vec<ClientInfo>> {
+        match self.request(&Request::ClientList)? {
+            Response::Clients(clients) => Ok(clients),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Returns whether a connection with that id was open.
+    pub fn client_kill(&mut self, id: u64) -> io::Result<bool> {
+        match self.request(&Request::ClientKill { id })? {
+            Response::Ok => Ok(true),
+            Response::NotFound => Ok(false),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Turns this connection into a stream of changes to keys starting with
+    // `prefix`; it can no longer be used for requests.
+    pub fn watch(mut self, prefix: impl Into<String>) -> io::Result<Watch> {
+        match self.request(&Request::Watch { prefix: prefix.into() })? {
+            Response::Ok => Ok(Watch { client: self }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Turns this connection into the primary's stream of changes after
+    // `after_seq` of run `run_id`; a `run_id` of 0 starts at the head.
+    pub fn replicate(mut self, run_id: u64, after_seq: u64) -> io::Result<Replication> {
+        match self.request(&Request::Replicate { run_id, after_seq })? {
+            Response::Ok => Ok(Replication { client: self }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // The server's latest record of every synced key past `seen`; see
+    // `KvStore::sync`, which takes a client as its peer.
+    pub fn sync_changes(&mut self, seen: VersionVector) -> io::Result<SyncBatch> {
+        match self.request(&Request::SyncChanges { seen })? {
+            Response::Sync(batch) => Ok(batch),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Returns how many of the records changed the server's store.
+    pub fn sync_merge(&mut self, batch: SyncBatch) -> io::Result<usize> {
+        match self.request(&Request::SyncMerge { batch })? {
+            Response::Length(changed) => Ok(changed as usize),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn snapshot_manifest(&mut self) -> io::Result<SnapshotManifest> {
+        match self.request(&Request::SnapshotManifest)? {
+            Response::SnapshotManifest(manifest) => Ok(manifest),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // The server caps `len` at `replication::SNAPSHOT_CHUNK`.
+    pub fn snapshot_chunk(&mut self, name: impl Into<String>, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let req = Request::SnapshotChunk {
+            name: name.into(),
+            offset,
+            len,
+        };
+        match self.request(&req)? {
+            Response::Chunk(chunk) => Ok(chunk),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // The next message of a streaming connection, `None` once it is closed.
+    fn next_streamed(&mut self) -> Option<io::Result<Response>> {
+        match self.codec.read_frame(&mut self.reader) {
+            Ok(Some(frame)) => Some(self.codec.codec().decode_response(&frame)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn request(&mut self, req: &Request) -> io::Result<Response> {
+        if !matches!(req, Request::Ping) {
+            self.check_link()?;
+        }
+        self.send(req)?;
+        self.writer.flush()?;
+        self.receive()
+    }
+
+    fn send(&mut self, req: &Request) -> io::Result<()> {
+        let frame = match &self.bearer_token {
+            Some(token) if !matches!(req, Request::Hello { .. } | Request::Ping) => {
+                self.codec.codec().encode_request(&Request::Authenticated {
+                    token: token.clone(),
+                    request: Box::new(req.clone()),
+                })?
+            }
+            _ => self.codec.codec().encode_request(req)?,
+        };
+        self.codec.write_frame(&mut self.writer, &frame)
+    }
+
+    fn receive(&mut self) -> io::Result<Response> {
+        match self.codec.read_frame(&mut self.reader)? {
+            Some(frame) => {
+                self.last_response = Instant::now();
+                self.codec.codec().decode_response(&frame)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Server closed the connection",
+            )),
+        }
+    }
+}
+
+pub struct Watch {
+    client: Client,
+}
+
+impl Watch {
+    // A handle that can end the stream from another thread by shutting the
+    // socket down.
+    pub fn stream(&self) -> io::Result<TcpStream> {
+        self.client.writer.get_ref().try_clone()
+    }
+}
+
+// Ends when the server closes the connection.
+impl Iterator for Watch {
+    type Item = io::Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.client.next_streamed()? {
+            Ok(Response::Event(event)) => Ok(event),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+pub struct Replication {
+    client: Client,
+}
+
+// Ends when the primary closes the connection.
+impl Iterator for Replication {
+    type Item = io::Result<ReplicationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.client.next_streamed()? {
+            Ok(Response::Replication(event)) => Ok(event),
+            Ok(other) => Err(unexpected(other)),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+// A client that serves repeated gets from memory. Entries expire after
+// `ttl` and are dropped as soon as a second connection watching the whole
+// keyspace reports a change. If that connection is lost, reads go straight
+// to the server from then on.
+pub struct CachedClient {
+    client: Client,
+    ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+    watch_stream: TcpStream,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, (Option<String>, Instant)>,
+    // Bumped by every invalidation, so a get that raced one does not cache
+    // what it read.
+    epoch: u64,
+    watching: bool,
+}
+
+impl Cache {
+    fn invalidate(&mut self, key: &str) {
+        self.entries.remove(key);
+        self.epoch += 1;
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.epoch += 1;
+    }
+}
+
+impl CachedClient {
+    pub fn connect(addr: impl ToSocketAddrs, ttl: Duration) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let client = Client::connect(&addrs[..])?;
+        let watch = Client::connect(&addrs[..])?.watch("")?;
+        let watch_stream = watch.stream()?;
+        let cache = Arc::new(Mutex::new(Cache {
+            watching: true,
+            ..Cache::default()
+        }));
+
+        let watched = Arc::clone(&cache);
+        thread::spawn(move || {
+            for event in watch {
+                let Ok(mut cache) = watched.lock() else {
+                    return;
+                };
+                match event {
+                    Ok(WatchEvent::Changed { key }) => cache.invalidate(&key),
+                    Ok(WatchEvent::Lagged) => cache.clear(),
+                    Err(_) => break,
+                }
+            }
+            if let Ok(mut cache) = watched.lock() {
+                cache.watching = false;
+                cache.clear();
+            }
+        });
+
+        Ok(CachedClient {
+            client,
+            ttl,
+            cache,
+            watch_stream,
+        })
+    }
+
+    pub fn get(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let key = key.into();
+        let epoch = {
+            let mut cache = self.lock_cache()?;
+            if !cache.watching {
+                drop(cache);
+                return self.client.get(key);
+            }
+            match cache.entries.get(&key) {
+                Some((value, expires)) if *expires > Instant::now() => return Ok(value.clone()),
+                Some(_) => {
+                    cache.entries.remove(&key);
+                }
+                None => {}
+            }
+            cache.epoch
+        };
+
+        let value = self.client.get(key.as_str())?;
+        let mut cache = self.lock_cache()?;
+        if cache.watching && cache.epoch == epoch {
+            cache.entries.insert(key, (value.clone(), Instant::now() + self.ttl));
+        }
+        Ok(value)
+    }
+
+    // Writes invalidate the local entry straight away rather than waiting
+    // for the server's event.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<()> {
+        let key = key.into();
+        self.lock_cache()?.invalidate(&key);
+        self.client.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> io::Result<()> {
+        let key = key.into();
+        self.lock_cache()?.invalidate(&key);
+        self.client.remove(key)
+    }
+
+    pub fn remove_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<()> {
+        let keys: Vec<String> = keys.into_iter().map(Into::into).collect();
+        {
+            let mut cache = self.lock_cache()?;
+            for key in &keys {
+                cache.invalidate(key);
+            }
+        }
+        self.client.remove_many(keys)
+    }
+
+    pub fn invalidate_all(&self) -> io::Result<()> {
+        self.lock_cache()?.clear();
+        Ok(())
+    }
+
+    // The uncached connection, for requests the cache does not wrap.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    fn lock_cache(&self) -> io::Result<std::sync::MutexGuard<'_, Cache>> {
+        self.cache.lock().map_err(|_| io::Error::other("Mutex poisoned"))
+    }
+}
+
+impl Drop for CachedClient {
+    fn drop(&mut self) {
+        let _ = self.watch_stream.shutdown(Shutdown::Both);
+    }
+}
+
+// Where `ReplicaSetClient` sends reads. Writes always go to the primary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadPreference {
+    #[default]
+    Primary,
+    // Replicas in the order given, then the primary.
+    ReplicaPreferred,
+    // Every endpoint by the round trip measured when connecting.
+    Nearest,
+}
+
+// Routes requests across a primary and its replicas. Connections are made
+// lazily and dropped on error; a read that fails on one endpoint is retried
+// on the next one its preference allows.
+pub struct ReplicaSetClient {
+    endpoints: Vec<Endpoint>,
+    preference: ReadPreference,
+}
+
+// `endpoints[0]` is always the primary.
+struct Endpoint {
+    addr: String,
+    client: Option<Client>,
+    rtt: Option<Duration>,
+}
+
+impl Endpoint {
+    fn client(&mut self) -> io::Result<&mut Client> {
+        if self.client.is_none() {
+            self.client = Some(Client::connect(self.addr.as_str())?);
+        }
+        Ok(self.client.as_mut().expect("connected above"))
+    }
+
+    fn call<T>(&mut self, f: impl FnOnce(&mut Client) -> io::Result<T>) -> io::Result<T> {
+        let result = self.client().and_then(f);
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+}
+
+impl ReplicaSetClient {
+    // Endpoints that cannot be reached yet are tried again on later reads.
+    pub fn connect<S: Into<String>>(
+        primary: impl Into<String>,
+        replicas: impl IntoIterator<Item = S>,
+        preference: ReadPreference,
+    ) -> io::Result<Self> {
+        let mut endpoints: Vec<Endpoint> = std::iter::once(primary.into())
+            .chain(replicas.into_iter().map(Into::into))
+            .map(|addr| Endpoint {
+                addr,
+                client: None,
+                rtt: None,
+            })
+            .collect();
+        if preference == ReadPreference::Nearest {
+            for endpoint in &mut endpoints {
+                let started = Instant::now();
+                if endpoint.call(|client| client.info()).is_ok() {
+                    endpoint.rtt = Some(started.elapsed());
+                }
+            }
+        }
+        Ok(ReplicaSetClient { endpoints, preference })
+    }
+
+    pub fn get(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let key = key.into();
+        self.read(|client| client.get(key.as_str()))
+    }
+
+    // Replicas lagging by more than `staleness` allows refuse the read, so
+    // it falls through to the next endpoint.
+    pub fn get_bounded(&mut self, key: impl Into<String>, staleness: Staleness) -> io::Result<Option<String>> {
+        let key = key.into();
+        self.read(|client| client.get_bounded(key.as_str(), staleness))
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<()> {
+        self.endpoints[0].call(|client| client.set(key, value))
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> io::Result<()> {
+        self.endpoints[0].call(|client| client.remove(key))
+    }
+
+    pub fn remove_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<()> {
+        self.endpoints[0].call(|client| client.remove_many(keys))
+    }
+
+    // The primary's connection, for requests that are not routed.
+    pub fn primary(&mut self) -> io::Result<&mut Client> {
+        self.endpoints[0].client()
+    }
+
+    fn read<T>(&mut self, mut f: impl FnMut(&mut Client) -> io::Result<T>) -> io::Result<T> {
+        let mut last_error = None;
+        for i in self.read_order() {
+            match self.endpoints[i].call(&mut f) {
+                Ok(value) => return Ok(value),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("the primary is always a candidate"))
+    }
+
+    fn read_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        match self.preference {
+            ReadPreference::Primary => order.truncate(1),
+            ReadPreference::ReplicaPreferred => order.rotate_left(1),
+            // Unmeasured endpoints sort last.
+            ReadPreference::Nearest => order.sort_by_key(|&i| self.endpoints[i].rtt.unwrap_or(Duration::MAX)),
+        }
+        order
+    }
+}
+
+// The data calls every client here answers, for code that shouldn't care
+// which one it is given, or whether it is a `MockClient` in tests. Keys
+// are borrowed so the trait can be used as `dyn KvClient`.
+pub trait KvClient {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>>;
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()>;
+    fn remove(&mut self, key: &str) -> io::Result<()>;
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage>;
+    // The values of `keys`, in order, as of a single point in time.
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>>;
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()>;
+}
+
+impl KvClient for Client {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        Client::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        Client::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        Client::remove(self, key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        Client::scan(self, options)
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        Client::get_many(self, keys.iter().copied())
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        Client::remove_many(self, keys.iter().copied())
+    }
+}
+
+// Only `get` is cached; the rest go straight to the server.
+impl KvClient for CachedClient {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        CachedClient::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        CachedClient::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        CachedClient::remove(self, key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        self.client.scan(options)
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        self.client.get_many(keys.iter().copied())
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        CachedClient::remove_many(self, keys.iter().copied())
+    }
+}
+
+// Reads follow the read preference, writes go to the primary.
+impl KvClient for ReplicaSetClient {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        ReplicaSetClient::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        ReplicaSetClient::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        ReplicaSetClient::remove(self, key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        self.read(|client| client.scan(options.clone()))
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        self.read(|client| client.get_many(keys.iter().copied()))
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        ReplicaSetClient::remove_many(self, keys.iter().copied())
+    }
+}
+
+impl KvClient for MockClient {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        MockClient::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        MockClient::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        MockClient::remove(self, key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        MockClient::scan(self, options)
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        MockClient::get_many(self, keys.iter().copied())
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        MockClient::remove_many(self, keys.iter().copied())
+    }
+}
+
+impl<C: KvClient + ?Sized> KvClient for &mut C {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        (**self).remove(key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        (**self).scan(options)
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        (**self).get_many(keys)
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        (**self).remove_many(keys)
+    }
+}
+
+impl<C: KvClient + ?Sized> KvClient for Box<C> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        (**self).remove(key)
+    }
+
+    fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        (**self).scan(options)
+    }
+
+    fn get_many(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        (**self).get_many(keys)
+    }
+
+    fn remove_many(&mut self, keys: &[&str]) -> io::Result<()> {
+        (**self).remove_many(keys)
+    }
+}
+
+// Stands in for `Client` in unit tests of code that talks to a server:
+// the same calls, answered from an in-memory map. Clones share the map and
+// settings, so a test can keep one to inject failures and inspect the data
+// while the code under test uses another. Hand it over as a `KvClient`.
+#[derive(Clone, Default)]
+pub struct MockClient {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    data: BTreeMap<String, String>,
+    latency: Duration,
+    // Returned by the next calls, one each, before any of them run.
+    failures: VecDeque<(io::ErrorKind, String)>,
+    // Returned by every call while set, as from a server that is down.
+    failing: Option<io::ErrorKind>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        MockClient::default()
+    }
+
+    // Each call sleeps this long before it is answered.
+    pub fn set_latency(&self, latency: Duration) -> io::Result<()> {
+        self.lock_state()?.latency = latency;
+        Ok(())
+    }
+
+    // Fails the next call that isn't already due to fail.
+    pub fn fail_next(&self, kind: io::ErrorKind, message: impl Into<String>) -> io::Result<()> {
+        self.lock_state()?.failures.push_back((kind, message.into()));
+        Ok(())
+    }
+
+    // Fails every call with `kind` until called again with `None`.
+    pub fn set_failing(&self, kind: Option<io::ErrorKind>) -> io::Result<()> {
+        self.lock_state()?.failing = kind;
+        Ok(())
+    }
+
+    // Everything stored, in key order.
+    pub fn data(&self) -> io::Result<BTreeMap<String, String>> {
+        Ok(self.lock_state()?.data.clone())
+    }
+
+    pub fn get(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let key = key.into();
+        self.call(|data| Ok(data.get(&key).cloned()))
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.call(|data| {
+            data.insert(key, value);
+            Ok(())
+        })
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> io::Result<()> {
+        let key = key.into();
+        self.call(|data| {
+            data.remove(&key);
+            Ok(())
+        })
+    }
+
+    pub fn get_and_remove(&mut self, key: impl Into<String>) -> io::Result<Option<String>> {
+        let key = key.into();
+        self.call(|data| Ok(data.remove(&key)))
+    }
+
+    pub fn get_and_set(&mut self, key: impl Into<String>, value: impl Into<String>) -> io::Result<Option<String>> {
+        let (key, value) = (key.into(), value.into());
+        self.call(|data| Ok(data.insert(key, value)))
+    }
+
+    pub fn append(&mut self, key: impl Into<String>, suffix: impl Into<String>) -> io::Result<u64> {
+        let (key, suffix) = (key.into(), suffix.into());
+        self.call(|data| {
+            let value = data.entry(key).or_default();
+            value.push_str(&suffix);
+            Ok(value.len() as u64)
+        })
+    }
+
+    pub fn get_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<Vec<Option<String>>> {
+        let keys: Vec<String> = keys.into_iter().map(Into::into).collect();
+        self.call(|data| Ok(keys.iter().map(|key| data.get(key).cloned()).collect()))
+    }
+
+    pub fn remove_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> io::Result<()> {
+        let keys: Vec<String> = keys.into_iter().map(Into::into).collect();
+        self.call(|data| {
+            for key in &keys {
+                data.remove(key);
+            }
+            Ok(())
+        })
+    }
+
+    // Filters and pages like the server's scan.
+    pub fn scan(&mut self, options: ScanOptions) -> io::Result<ScanPage> {
+        let regex = options.value_regex()?;
+        self.call(|data| {
+            let mut page = ScanPage::default();
+            let entries = data
+                .range(options.key_prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&options.key_prefix))
+                .filter(|(key, _)| options.start_after.as_ref().is_none_or(|after| *key > after));
+            for (key, value) in entries {
+                if options.limit > 0 && page.entries.len() == options.limit {
+                    page.next = page.entries.last().map(|(key, _)| key.clone());
+                    break;
+                }
+                if options.value_matches(regex.as_ref(), value) {
+                    page.entries.push((key.clone(), value.clone()));
+                }
+            }
+            Ok(page)
+        })
+    }
+
+    // Applies the latency and any failure due, then runs `f` on the data.
+    fn call<T>(&self, f: impl FnOnce(&mut BTreeMap<String, String>) -> io::Result<T>) -> io::Result<T> {
+        let latency = self.lock_state()?.latency;
+        if !latency.is_zero() {
+            thread::sleep(latency);
+        }
+        let mut state = self.lock_state()?;
+        if let Some((kind, message)) = state.failures.pop_front() {
+            return Err(io::Error::new(kind, message));
+        }
+        if let Some(kind) = state.failing {
+            return Err(io::Error::new(kind, "MockClient set to fail"));
+        }
+        f(&mut state.data)
+    }
+
+    fn lock_state(&self) -> io::Result<std::sync::MutexGuard<'_, MockState>> {
+        self.state.lock().map_err(|_| io::Error::other("Mutex poisoned"))
+    }
+}
+
+// The value a read was answered with, if any, checked against the checksum
+// it came with.
+fn value_of(resp: Response) -> io::Result<Option<Vec<u8>>> {
+    match resp {
+        Response::CheckedValue { value, checksum } => verify(value, Some(checksum)).map(Some),
+        Response::Value(value) => Ok(Some(value)),
+        Response::NotFound => Ok(None),
+        other => Err(unexpected(other)),
+    }
+}
+
+fn verify(value: Vec<u8>, checksum: Option<u32>) -> io::Result<Vec<u8>> {
+    match checksum {
+        Some(checksum) if value_checksum(&value) != checksum => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Value checksum mismatch: corrupted in transit",
+        )),
+        _ => Ok(value),
+    }
+}
+
+fn text(value: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn unexpected(resp: Response) -> io::Error {
+    match resp {
+        Response::Error(msg) => io::Error::other(msg),
+        other => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected response: {:?}", other),
+        ),
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::{self, BufRead, Write};
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::{Request, Response};
+
+// Frames larger than this are rejected rather than allocated.
+const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+// Every connection starts out speaking JSON; a `Hello` request switches both
+// ends to another codec from the next message on.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    MessagePack,
+    Bincode,
+}
+
+pub trait Codec: Send + Sync {
+    fn encode_request(&self, req: &Request) -> io::Result<Vec<u8>>;
+    fn decode_request(&self, bytes: &[u8]) -> io::Result<Request>;
+    fn encode_response(&self, resp: &Response) -> io::Result<Vec<u8>>;
+    fn decode_response(&self, bytes: &[u8]) -> io::Result<Response>;
+}
+
+// A serde format; every format is a codec for both message types.
+trait Format: Send + Sync {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T>;
+}
+
+impl<F: Format> Codec for F {
+    fn encode_request(&self, req: &Request) -> io::Result<Vec<u8>> {
+        self.encode(req)
+    }
+
+    fn decode_request(&self, bytes: &[u8]) -> io::Result<Request> {
+        self.decode(bytes)
+    }
+
+    fn encode_response(&self, resp: &Response) -> io::Result<Vec<u8>> {
+        self.encode(resp)
+    }
+
+    fn decode_response(&self, bytes: &[u8]) -> io::Result<Response> {
+        self.decode(bytes)
+    }
+}
+
+struct JsonCodec;
+struct MessagePackCodec;
+struct BincodeCodec;
+
+impl Format for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+impl Format for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>> {
+        rmp_serde::to_vec(value).map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        rmp_serde::from_slice(bytes).map_err(invalid_data)
+    }
+}
+
+impl Format for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard()).map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<T> {
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map(|(value, _)| value)
+            .map_err(invalid_data)
+    }
+}
+
+impl CodecKind {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            CodecKind::Json => &JsonCodec,
+            CodecKind::MessagePack => &MessagePackCodec,
+            CodecKind::Bincode => &BincodeCodec,
+        }
+    }
+
+    // JSON stays newline-delimited so it can be typed by hand; binary
+    // codecs use a 4-byte big-endian length prefix.
+    fn length_prefixed(self) -> bool {
+        self != CodecKind::Json
+    }
+
+    // `None` on a clean end of stream.
+    pub fn read_frame(self, reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+        if self.length_prefixed() {
+            let mut len = [0; 4];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let mut frame = vec![0; frame_len(len)?];
+            reader.read_exact(&mut frame)?;
+            Ok(Some(frame))
+        } else {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(None);
+            }
+            trim_newline(&mut line);
+            Ok(Some(line))
+        }
+    }
+
+    pub fn write_frame(self, writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+        if self.length_prefixed() {
+            writer.write_all(&frame_header(frame)?)?;
+            writer.write_all(frame)
+        } else {
+            writer.write_all(frame)?;
+            writer.write_all(b"\n")
+        }
+    }
+
+    pub async fn read_frame_async<R: AsyncBufRead + Unpin>(self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        if self.length_prefixed() {
+            let mut len = [0; 4];
+            match reader.read_exact(&mut len).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let mut frame = vec![0; frame_len(len)?];
+            reader.read_exact(&mut frame).await?;
+            Ok(Some(frame))
+        } else {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(None);
+            }
+            trim_newline(&mut line);
+            Ok(Some(line))
+        }
+    }
+
+    pub async fn write_frame_async<W: AsyncWrite + Unpin>(self, writer: &mut W, frame: &[u8]) -> io::Result<()> {
+        if self.length_prefixed() {
+            writer.write_all(&frame_header(frame)?).await?;
+            writer.write_all(frame).await
+        } else {
+            writer.write_all(frame).await?;
+            writer.write_all(b"\n").await
+        }
+    }
+}
+
+fn frame_len(header: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {} bytes exceeds the limit", len)));
+    }
+    Ok(len)
+}
+
+fn frame_header(frame: &[u8]) -> io::Result<[u8; 4]> {
+    if frame.len() > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {} bytes exceeds the limit", frame.len())));
+    }
+    Ok((frame.len() as u32).to_be_bytes())
+}
+
+fn trim_newline(line: &mut Vec<u8>) {
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::TokenValidator;
+use crate::{DiskWatchdog, JsonValidator, MergeMode, Options, RecordFormat, Result, SyncPolicy};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    // Extra listeners that only serve reads, for networks that should
+    // not be able to change anything.
+    pub read_only_addresses: Vec<String>,
+    pub data_dir: PathBuf,
+    pub validators: Vec<ValidatorConfig>,
+    // Prefixes to maintain count/sum aggregates for.
+    pub aggregates: Vec<String>,
+    // Prefixes whose keys peers can sync with, the later write winning.
+    pub sync_prefixes: Vec<String>,
+    // Tamper-evident log of mutating requests, off unless set.
+    pub audit_log: Option<PathBuf>,
+    pub disk_watchdog: Option<DiskWatchdogConfig>,
+    // Address of the primary to follow; the server then refuses writes
+    // from clients.
+    pub replica_of: Option<String>,
+    // Bearer token presented to a primary that authenticates requests.
+    pub primary_token: Option<String>,
+    // Requires a bearer token on every request; off unless set.
+    pub auth: Option<AuthConfig>,
+    // Gets, sets and removes that cannot start within this long of
+    // arriving are answered with an error instead.
+    pub request_timeout_ms: Option<u64>,
+    // Connections that send nothing, not even a ping, for this long are
+    // closed. Watch and replication streams are exempt.
+    pub idle_timeout_secs: Option<u64>,
+    // A connection whose unread responses pass this many bytes is closed.
+    pub max_pending_write_bytes: u64,
+    // Format for new segments; compaction rewrites older ones in it.
+    pub record_format: RecordFormat,
+    pub sync: SyncConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct DiskWatchdogConfig {
+    pub soft_bytes: u64,
+    pub hard_bytes: u64,
+}
+
+// `SyncPolicy`, e.g. `{"mode": "every", "interval_ms": 100}`.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SyncConfig {
+    Always,
+    Every {
+        interval_ms: u64,
+    },
+    #[default]
+    Never,
+}
+
+// HS256 tokens checked against `secret`, or the symmetric keys of the JWKS
+// document at `jwks_path`, whichever is set.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub secret: Option<String>,
+    pub jwks_path: Option<PathBuf>,
+    // Tokens must name these in `iss` and `aud`, when set.
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ValidatorConfig {
+    pub prefix: String,
+    #[serde(flatten)]
+    pub kind: ValidatorKind,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValidatorKind {
+    Json {
+        #[serde(default)]
+        required_fields: Vec<String>,
+    },
+}
+
+// Something `ServerConfig::check` found wrong with a setting.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    // The setting, as named in the config file.
+    pub field: String,
+    #[serde(flatten)]
+    pub problem: ConfigProblem,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConfigProblem {
+    Unparseable { reason: String },
+    BadAddress { address: String, reason: String },
+    DuplicateAddress { address: String },
+    NotWritable { path: PathBuf, reason: String },
+    OutOfRange { value: u64, reason: String },
+    Invalid { reason: String },
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            ConfigProblem::Unparseable { reason } => write!(f, "config does not parse: {}", reason),
+            ConfigProblem::BadAddress { address, reason } => {
+                write!(f, "{}: bad address {:?}: {}", self.field, address, reason)
+            }
+            ConfigProblem::DuplicateAddress { address } => {
+                write!(f, "{}: {} is already used by another listener", self.field, address)
+            }
+            ConfigProblem::NotWritable { path, reason } => {
+                write!(f, "{}: {} is not writable: {}", self.field, path.display(), reason)
+            }
+            ConfigProblem::OutOfRange { value, reason } => write!(f, "{}: {} {}", self.field, value, reason),
+            ConfigProblem::Invalid { reason } => write!(f, "{}: {}", self.field, reason),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1:6379".to_string(),
+            read_only_addresses: Vec::new(),
+            data_dir: PathBuf::from("./data"),
+            validators: Vec::new(),
+            aggregates: Vec::new(),
+            sync_prefixes: Vec::new(),
+            audit_log: None,
+            disk_watchdog: None,
+            replica_of: None,
+            primary_token: None,
+            auth: None,
+            request_timeout_ms: None,
+            idle_timeout_secs: None,
+            max_pending_write_bytes: 64 * 1024 * 1024,
+            record_format: RecordFormat::Json,
+            sync: SyncConfig::Never,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    // Like `load`, but reports a config that doesn't parse as an issue.
+    pub fn load_checked(path: &Path) -> std::result::Result<Self, Vec<ConfigIssue>> {
+        let unparseable = |reason: String| {
+            vec![ConfigIssue {
+                field: String::new(),
+                problem: ConfigProblem::Unparseable { reason },
+            }]
+        };
+        let contents = fs::read_to_string(path).map_err(|e| unparseable(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&contents).map_err(|e| unparseable(e.to_string()))
+    }
+
+    // Validates what parsing can't: that addresses resolve, paths are
+    // writable and limits are sane. Nothing is bound or left behind.
+    pub fn check(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue = |field: &str, problem| {
+            issues.push(ConfigIssue {
+                field: field.to_string(),
+                problem,
+            })
+        };
+
+        let mut listeners = Vec::new();
+        let addresses = std::iter::once(("address", &self.address))
+            .chain(self.read_only_addresses.iter().map(|a| ("read_only_addresses", a)));
+        for (field, address) in addresses {
+            if let Err(reason) = resolve(address) {
+                issue(field, ConfigProblem::BadAddress {
+                    address: address.clone(),
+                    reason,
+                });
+            } else if listeners.contains(&address) {
+                issue(field, ConfigProblem::DuplicateAddress {
+                    address: address.clone(),
+                });
+            }
+            listeners.push(address);
+        }
+        if let Some(primary) = &self.replica_of {
+            if let Err(reason) = resolve(primary) {
+                issue("replica_of", ConfigProblem::BadAddress {
+                    address: primary.clone(),
+                    reason,
+                });
+            } else if *primary == self.address {
+                issue("replica_of", ConfigProblem::BadAddress {
+                    address: primary.clone(),
+                    reason: "a server can't replicate from itself".to_string(),
+                });
+            }
+        }
+
+        if let Err(reason) = check_writable_dir(&self.data_dir) {
+            issue("data_dir", ConfigProblem::NotWritable {
+                path: self.data_dir.clone(),
+                reason,
+            });
+        }
+        if let Some(path) = &self.audit_log {
+            let writable = match fs::metadata(path) {
+                Ok(metadata) if metadata.is_dir() => Err("is a directory".to_string()),
+                Ok(_) => fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+                Err(_) => check_writable_dir(path.parent().unwrap_or(Path::new("."))),
+            };
+            if let Err(reason) = writable {
+                issue("audit_log", ConfigProblem::NotWritable {
+                    path: path.clone(),
+                    reason,
+                });
+            }
+        }
+
+        if let Err(reason) = self.token_validator() {
+            issue("auth", ConfigProblem::Invalid {
+                reason: reason.to_string(),
+            });
+        }
+
+        if let Some(watchdog) = self.disk_watchdog
+            && watchdog.hard_bytes > watchdog.soft_bytes
+        {
+            issue("disk_watchdog.hard_bytes", ConfigProblem::OutOfRange {
+                value: watchdog.hard_bytes,
+                reason: format!("is above soft_bytes ({})", watchdog.soft_bytes),
+            });
+        }
+        let sync_interval = match self.sync {
+            SyncConfig::Every { interval_ms } => Some(interval_ms),
+            _ => None,
+        };
+        let positive = [
+            ("sync.interval_ms", sync_interval),
+            ("request_timeout_ms", self.request_timeout_ms),
+            ("idle_timeout_secs", self.idle_timeout_secs),
+            ("max_pending_write_bytes", Some(self.max_pending_write_bytes)),
+        ];
+        for (field, value) in positive {
+            if value == Some(0) {
+                issue(field, ConfigProblem::OutOfRange {
+                    value: 0,
+                    reason: "must be positive".to_string(),
+                });
+            }
+        }
+        issues
+    }
+
+    pub fn token_validator(&self) -> Result<Option<TokenValidator>> {
+        let Some(auth) = &self.auth else {
+            return Ok(None);
+        };
+        let validator = match (&auth.secret, &auth.jwks_path) {
+            (Some(secret), None) => TokenValidator::with_secret(secret.as_bytes()),
+            (None, Some(path)) => TokenValidator::from_jwks(&fs::read_to_string(path)?)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "set exactly one of auth.secret and auth.jwks_path",
+                )
+                .into());
+            }
+        };
+        let validator = match &auth.issuer {
+            Some(issuer) => validator.issuer(issuer.clone()),
+            None => validator,
+        };
+        Ok(Some(match &auth.audience {
+            Some(audience) => validator.audience(audience.clone()),
+            None => validator,
+        }))
+    }
+
+    pub fn store_options(&self) -> Options {
+        let sync = match self.sync {
+            SyncConfig::Always => SyncPolicy::Always,
+            SyncConfig::Every { interval_ms } => SyncPolicy::Every(Duration::from_millis(interval_ms)),
+            SyncConfig::Never => SyncPolicy::Never,
+        };
+        let mut options = Options::default()
+            .record_format(self.record_format)
+            .sync_policy(sync);
+        for validator in &self.validators {
+            options = match &validator.kind {
+                ValidatorKind::Json { required_fields } => options.validator(
+                    validator.prefix.clone(),
+                    JsonValidator::new().require_fields(required_fields.iter().cloned()),
+                ),
+            };
+        }
+        for prefix in &self.aggregates {
+            options = options.aggregate(prefix.clone());
+        }
+        for prefix in &self.sync_prefixes {
+            options = options.sync_prefix(prefix.clone(), MergeMode::LastWriteWins);
+        }
+        if let Some(watchdog) = self.disk_watchdog {
+            options = options.disk_watchdog(DiskWatchdog::new(watchdog.soft_bytes, watchdog.hard_bytes));
+        }
+        options
+    }
+}
+
+fn resolve(address: &str) -> std::result::Result<(), String> {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) => addrs
+            .next()
+            .map(|_| ())
+            .ok_or_else(|| "resolves to no addresses".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// A directory that doesn't exist yet only needs its nearest existing
+// ancestor to be writable, since the server creates it.
+fn check_writable_dir(path: &Path) -> std::result::Result<(), String> {
+    let mut dir = path;
+    while !dir.exists() {
+        dir = match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let probe = dir.join(format!(".bitkv-check-{}", std::process::id()));
+    fs::File::create(&probe).map_err(|e| e.to_string())?;
+    fs::remove_file(&probe).map_err(|e| e.to_string())
+}
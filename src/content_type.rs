@@ -0,0 +1,92 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, Result, now_millis};
+
+// How a value's bytes are meant to be interpreted. Stored alongside the
+// value and kept through compaction; the store never looks inside.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Text,
+    Json,
+    // Values are strings, so binary values are kept base64-encoded.
+    Bytes,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContentType::Text => "text",
+            ContentType::Json => "json",
+            ContentType::Bytes => "bytes",
+            ContentType::MessagePack => "msgpack",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ContentType::Text),
+            "json" => Ok(ContentType::Json),
+            "bytes" => Ok(ContentType::Bytes),
+            "msgpack" => Ok(ContentType::MessagePack),
+            other => Err(format!("unknown content type {:?}", other)),
+        }
+    }
+}
+
+impl KvStore {
+    pub fn set_with_content_type(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        content_type: ContentType,
+    ) -> Result<()> {
+        let mut inner = self.inner.write();
+        self.set_at_locked(&mut inner, key.into(), value.into(), now_millis(), Some(content_type), None)
+    }
+
+    // Like `get`, along with the tag the value was written with, if any.
+    pub fn get_with_content_type(&self, key: &str) -> Result<Option<(String, Option<ContentType>)>> {
+        let inner = self.inner.read();
+        self.read_tagged_locked(&inner, key)
+    }
+
+    // UTF-8 is stored as it is; anything else is tagged `Bytes` and
+    // encoded, so `get_bytes` can hand back exactly what was set.
+    pub fn set_bytes(&mut self, key: impl Into<String>, value: Vec<u8>) -> Result<()> {
+        match String::from_utf8(value) {
+            Ok(value) => self.set(key.into(), value),
+            Err(e) => self.set_with_content_type(key, STANDARD.encode(e.into_bytes()), ContentType::Bytes),
+        }
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let inner = self.inner.read();
+        self.read_tagged_locked(&inner, key)?
+            .map(|(value, content_type)| value_bytes(key, value, content_type))
+            .transpose()
+    }
+}
+
+// The bytes a value read with its tag stands for.
+pub(crate) fn value_bytes(key: &str, value: String, content_type: Option<ContentType>) -> Result<Vec<u8>> {
+    match content_type {
+        Some(ContentType::Bytes) => STANDARD.decode(value).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Bytes value for {:?} is not base64: {}", key, e)).into()
+        }),
+        _ => Ok(value.into_bytes()),
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Instant;
+
+use parking_lot::{RwLockReadGuard, RwLockWriteGuard};
+
+use crate::content_type::value_bytes;
+use crate::{KvError, KvStore, Result, SharedData};
+
+// Variants of `get`, `set` and `remove` that give up with
+// `KvError::DeadlineExceeded` rather than wait past `deadline`. A write
+// either starts before the deadline and completes, or is not made at all;
+// a read that overruns it is discarded.
+impl KvStore {
+    pub fn get_with_deadline(&self, key: &str, deadline: Instant) -> Result<Option<String>> {
+        let inner = self.read_before(deadline)?;
+        let value = self.read_locked(&inner, key)?;
+        check_deadline(deadline)?;
+        Ok(value)
+    }
+
+    // Like `get_bytes`.
+    pub fn get_bytes_with_deadline(&self, key: &str, deadline: Instant) -> Result<Option<Vec<u8>>> {
+        let inner = self.read_before(deadline)?;
+        let value = self.read_tagged_locked(&inner, key)?;
+        check_deadline(deadline)?;
+        value
+            .map(|(value, content_type)| value_bytes(key, value, content_type))
+            .transpose()
+    }
+
+    // Only the wait for the write lock is bounded. Once the lock is held
+    // the write runs to completion, even past the deadline, as failing a
+    // write that has already reached the log would misreport it.
+    pub fn set_with_deadline(&mut self, key: String, value: String, deadline: Instant) -> Result<()> {
+        let mut inner = self.write_before(deadline)?;
+        self.set_locked(&mut inner, key, value)
+    }
+
+    // Bounded like `set_with_deadline`.
+    pub fn remove_with_deadline(&mut self, key: impl Into<String>, deadline: Instant) -> Result<()> {
+        let mut inner = self.write_before(deadline)?;
+        self.remove_locked(&mut inner, key.into())
+    }
+
+    fn read_before(&self, deadline: Instant) -> Result<RwLockReadGuard<'_, SharedData>> {
+        let guard = self.inner.try_read_until(deadline).ok_or(KvError::DeadlineExceeded)?;
+        check_deadline(deadline)?;
+        Ok(guard)
+    }
+
+    fn write_before(&self, deadline: Instant) -> Result<RwLockWriteGuard<'_, SharedData>> {
+        let guard = self.inner.try_write_until(deadline).ok_or(KvError::DeadlineExceeded)?;
+        check_deadline(deadline)?;
+        Ok(guard)
+    }
+}
+
+fn check_deadline(deadline: Instant) -> Result<()> {
+    if Instant::now() >= deadline {
+        return Err(KvError::DeadlineExceeded);
+    }
+    Ok(())
+}
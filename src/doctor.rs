@@ -0,0 +1,212 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::MANIFEST_FILE;
+use crate::{Command, KvStore, StoreMetadata, segment};
+
+// At or above this share of tombstones among a log's records,
+// compaction would reclaim most of the space.
+const TOMBSTONE_RATIO_LIMIT: f64 = 0.5;
+// Logs smaller than this are not worth flagging for their shape.
+const MIN_RECORDS_FOR_RATIOS: u64 = 1000;
+// Bytes on disk per live byte above which compaction is overdue.
+const WRITE_AMPLIFICATION_LIMIT: f64 = 4.0;
+// Free space below this, or below the size of the log, since compaction
+// needs room for a full copy of the live data.
+const MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub problem: String,
+    pub remedy: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, problem: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Finding {
+            severity,
+            problem: problem.into(),
+            remedy: remedy.into(),
+        }
+    }
+}
+
+// Inspects a data directory without writing to it and lists what looks
+// wrong, each with a suggested fix. An empty list means a clean bill of
+// health.
+pub fn examine(directory: &Path) -> io::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    if !directory.is_dir() {
+        findings.push(Finding::new(
+            Severity::Error,
+            format!("{} is not a directory", directory.display()),
+            "check the data directory path",
+        ));
+        return Ok(findings);
+    }
+
+    let metadata = match fs::read(directory.join(MANIFEST_FILE)) {
+        Ok(bytes) => match serde_json::from_slice::<StoreMetadata>(&bytes) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    format!("manifest is unreadable: {}", e),
+                    "restore MANIFEST from a backup; the store refuses to open without a readable one",
+                ));
+                None
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "manifest is missing",
+                "open the store once with a current build, which writes one",
+            ));
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut segments = Vec::new();
+    let mut blob_segments = 0;
+    let mut log_bytes = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let generation = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        match (extension, generation) {
+            (Some("db"), Some(generation)) => {
+                log_bytes += entry.metadata()?.len();
+                segments.push(generation);
+            }
+            (Some("blob"), Some(_)) => blob_segments += 1,
+            (Some("db" | "blob"), None) => findings.push(Finding::new(
+                Severity::Warning,
+                format!("orphan segment {}: the name is not a generation number", name),
+                "move it out of the data directory; the store ignores it",
+            )),
+            (Some("tmp"), _) => findings.push(Finding::new(
+                Severity::Warning,
+                format!("orphan temporary file {} left by an interrupted write", name),
+                "delete it while the store is stopped",
+            )),
+            _ => {}
+        }
+    }
+    segments.sort_unstable();
+
+    if let Some(limit) = open_files_limit() {
+        let needed = (segments.len() + blob_segments) as u64;
+        if needed * 2 > limit {
+            findings.push(Finding::new(
+                Severity::Warning,
+                format!("{} segments against an open files limit of {}", needed, limit),
+                "raise the limit (ulimit -n) or compact to reduce the segment count",
+            ));
+        }
+    }
+
+    let available = fs4::available_space(directory)?;
+    if available < MIN_FREE_BYTES.max(log_bytes) {
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!("{} bytes free for a {} byte log", available, log_bytes),
+            "free up disk space; compaction needs room for a copy of the live data",
+        ));
+    }
+
+    let (mut records, mut tombstones) = (0u64, 0u64);
+    for generation in &segments {
+        let format = metadata
+            .as_ref()
+            .map(|metadata| metadata.segment_format(*generation))
+            .unwrap_or_default();
+        let reader = match segment::open_records(&directory.join(format!("{}.db", generation)), format) {
+            Ok((reader, _)) => reader,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    format!("segment {}.db has a bad header: {}", generation, e),
+                    "move it out of the data directory if it is not a bitkv segment; a newer one needs a newer build",
+                ));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for record in format.records(reader) {
+            let command = match record {
+                Ok((command, _)) => command,
+                Err(e) => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        format!("segment {}.db has an unreadable record: {}", generation, e),
+                        "restore the segment from a backup or truncate it at the damaged record",
+                    ));
+                    break;
+                }
+            };
+            for op in command.into_ops() {
+                records += 1;
+                if matches!(op, Command::Remove { .. }) {
+                    tombstones += 1;
+                }
+            }
+        }
+    }
+    if records >= MIN_RECORDS_FOR_RATIOS && tombstones as f64 / records as f64 >= TOMBSTONE_RATIO_LIMIT {
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!("{} of {} records are tombstones", tombstones, records),
+            "run a compaction to drop them",
+        ));
+    }
+
+    if metadata.is_some() {
+        match KvStore::open_read_only(directory.to_path_buf()).and_then(|store| store.stats()) {
+            Ok(stats) if records >= MIN_RECORDS_FOR_RATIOS && stats.live_bytes > 0 => {
+                let amplification = log_bytes as f64 / stats.live_bytes as f64;
+                if amplification > WRITE_AMPLIFICATION_LIMIT {
+                    findings.push(Finding::new(
+                        Severity::Warning,
+                        format!(
+                            "write amplification is {:.1}: {} bytes on disk for {} live",
+                            amplification, log_bytes, stats.live_bytes
+                        ),
+                        "run a compaction, or rotate segments more often so compaction triggers sooner",
+                    ));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => findings.push(Finding::new(
+                Severity::Error,
+                format!("store does not open: {}", e),
+                "see the error; an incompatible store needs a newer build",
+            )),
+        }
+    }
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    Ok(findings)
+}
+
+// The soft limit on open descriptors, where the platform reports it.
+fn open_files_limit() -> Option<u64> {
+    let limits = fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|line| line.starts_with("Max open files"))?;
+    line.split_whitespace().nth(3)?.parse().ok()
+}
@@ -0,0 +1,70 @@
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crate::{KvStore, Result, SharedData, SyncPolicy};
+
+impl KvStore {
+    // Runs `SyncPolicy::Every` on a background thread that exits once
+    // every handle to the store has been dropped.
+    pub(crate) fn start_periodic_sync(&self) -> Result<()> {
+        let interval = match self.options.sync {
+            SyncPolicy::Every(interval) if !self.options.read_only => interval,
+            _ => return Ok(()),
+        };
+        let inner = Arc::downgrade(&self.inner);
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let inner = inner.read();
+                if !inner.unsynced.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                if let Err(e) = inner.sync_writers() {
+                    inner.unsynced.store(true, Ordering::Relaxed);
+                    eprintln!("Periodic fsync failed: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Called once a record is appended and flushed.
+    pub(crate) fn after_append(&self, inner: &SharedData) -> io::Result<()> {
+        match self.options.sync {
+            SyncPolicy::Always => inner.sync_writers(),
+            SyncPolicy::Every(_) => {
+                inner.unsynced.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            SyncPolicy::Never => Ok(()),
+        }
+    }
+
+    // Called before the active segment is replaced, since nothing syncs
+    // it once it is sealed.
+    pub(crate) fn before_seal(&self, inner: &SharedData) -> io::Result<()> {
+        match self.options.sync {
+            SyncPolicy::Never => Ok(()),
+            _ => inner.sync_writers(),
+        }
+    }
+}
+
+impl SharedData {
+    // The active blob segment first, since log records point into it.
+    pub(crate) fn sync_writers(&self) -> io::Result<()> {
+        if let Some((_, writer)) = &self.blob_writer {
+            writer.get_ref().sync_data()?;
+        }
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock();
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fmt, io};
+
+use crate::{CorruptRecord, CorruptionKind};
+
+#[derive(Debug)]
+pub enum KvError {
+    Io(io::Error),
+    Serde(serde_json::Error),
+    InvalidValue {
+        key: String,
+        prefix: String,
+        reason: String,
+    },
+    Script(String),
+    ReadOnly(ReadOnlyReason),
+    DuplicateKey(String),
+    KeyNotFound(String),
+    Incompatible(Incompatibility),
+    DeadlineExceeded,
+    ChecksumMismatch(String),
+    CorruptRecord(CorruptRecord),
+}
+
+// Why a store's manifest rules out opening it with this build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    FormatVersion { found: u32, supported: u32 },
+    UnknownFeature(String),
+    Unreadable(String),
+    SegmentVersion { segment: String, found: u16, supported: u16 },
+}
+
+// Why a writable store is temporarily refusing writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOnlyReason {
+    LowDisk,
+}
+
+pub type Result<T> = std::result::Result<T, KvError>;
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::Io(e) => write!(f, "{}", e),
+            KvError::Serde(e) => write!(f, "Serialization error: {}", e),
+            KvError::InvalidValue {
+                key,
+                prefix,
+                reason,
+            } => write!(
+                f,
+                "Invalid value for key {:?} (validator for prefix {:?}): {}",
+                key, prefix, reason
+            ),
+            KvError::Script(msg) => write!(f, "Script error: {}", msg),
+            KvError::DuplicateKey(key) => write!(f, "Key {:?} already exists", key),
+            KvError::KeyNotFound(key) => write!(f, "Key {:?} not found", key),
+            KvError::DeadlineExceeded => write!(f, "Deadline exceeded"),
+            KvError::ChecksumMismatch(key) => write!(f, "Checksum mismatch for key {:?}", key),
+            KvError::CorruptRecord(record) => {
+                let kind = match record.kind {
+                    CorruptionKind::Unreadable => "Unreadable record",
+                    CorruptionKind::ChecksumMismatch => "Checksum mismatch",
+                };
+                write!(
+                    f,
+                    "{} at offset {} of generation {}: {}",
+                    kind, record.offset, record.generation, record.detail
+                )
+            }
+            KvError::Incompatible(Incompatibility::FormatVersion { found, supported }) => write!(
+                f,
+                "Store format version {} is newer than supported version {}",
+                found, supported
+            ),
+            KvError::Incompatible(Incompatibility::UnknownFeature(feature)) => {
+                write!(f, "Store uses unsupported feature {:?}", feature)
+            }
+            KvError::Incompatible(Incompatibility::Unreadable(reason)) => {
+                write!(f, "Store manifest is unreadable: {}", reason)
+            }
+            KvError::Incompatible(Incompatibility::SegmentVersion {
+                segment,
+                found,
+                supported,
+            }) => write!(
+                f,
+                "Segment {} has version {}, newer than supported version {}",
+                segment, found, supported
+            ),
+            KvError::ReadOnly(ReadOnlyReason::LowDisk) => {
+                write!(f, "Store is read-only: free disk space below the hard threshold")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::Io(e) => Some(e),
+            KvError::Serde(e) => Some(e),
+            KvError::InvalidValue { .. }
+            | KvError::Script(_)
+            | KvError::ReadOnly(_)
+            | KvError::DuplicateKey(_)
+            | KvError::KeyNotFound(_)
+            | KvError::Incompatible(_)
+            | KvError::DeadlineExceeded
+            | KvError::ChecksumMismatch(_)
+            | KvError::CorruptRecord(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvError {
+    fn from(e: io::Error) -> Self {
+        KvError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KvError {
+    fn from(e: serde_json::Error) -> Self {
+        KvError::Serde(e)
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{Command, CommandPos, EvictionPolicy, KvStore, Result, SharedData};
+
+impl KvStore {
+    // Removes keys, `keep` aside, until the store is back within its
+    // bounds. Each eviction is an ordinary remove, so it is logged and
+    // survives a restart.
+    pub(crate) fn evict_locked(&self, inner: &mut SharedData, keep: &str) -> Result<()> {
+        if !self.over_bounds(inner) {
+            return Ok(());
+        }
+        for key in self.eviction_order(inner, keep)? {
+            self.remove_locked(inner, key)?;
+            inner.evicted_keys += 1;
+            if !self.over_bounds(inner) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn over_bounds(&self, inner: &SharedData) -> bool {
+        self.options.max_live_keys.is_some_and(|max| inner.index.len() > max)
+            || self.options.max_live_bytes.is_some_and(|max| inner.live_bytes > max)
+    }
+
+    // Every live key but `keep`, first to go first. Keys without access
+    // stats, such as those last touched before a restart, rank as never
+    // accessed.
+    fn eviction_order(&self, inner: &SharedData, keep: &str) -> Result<Vec<String>> {
+        let access = inner.access.lock();
+        let mut ranked = Vec::with_capacity(inner.index.len());
+        for (key, cmd_pos) in inner.index.iter() {
+            if key == keep {
+                continue;
+            }
+            let stats = access.get(key);
+            let last_access = stats.map_or(0, |stats| stats.last_access_ms);
+            let count = stats.map_or(0, |stats| stats.access_count);
+            let rank = match self.options.eviction {
+                EvictionPolicy::Lru => (0, last_access, 0),
+                EvictionPolicy::Lfu => (0, count, last_access),
+                EvictionPolicy::TtlFirst => match self.expiry(inner, cmd_pos, key)? {
+                    Some(expiry) => (0, expiry, 0),
+                    None => (1, last_access, 0),
+                },
+            };
+            ranked.push((rank, key.clone()));
+        }
+        ranked.sort_unstable();
+        Ok(ranked.into_iter().map(|(_, key)| key).collect())
+    }
+
+    // The earlier of the value's own expiry and its retention deadline.
+    fn expiry(&self, inner: &SharedData, cmd_pos: &CommandPos, key: &str) -> Result<Option<u64>> {
+        let Some(Command::Set {
+            timestamp, expires_at, ..
+        }) = self.read_command(inner, cmd_pos, key)?
+        else {
+            return Ok(None);
+        };
+        let retained_until = self
+            .options
+            .retention_for(key)
+            .map(|max_age| timestamp.unwrap_or(0).saturating_add(max_age.as_millis() as u64));
+        Ok(match (expires_at, retained_until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        })
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use crate::{Command, CommandPos, KvStore, Result, SharedData, now_millis};
+
+impl KvStore {
+    // Removes every key whose value has expired, by TTL or retention, and
+    // returns how many. Reads already treat such keys as missing; the
+    // tombstones let compaction drop their records too.
+    pub fn sweep_expired(&mut self) -> Result<usize> {
+        // Found under the read lock, so the scan doesn't hold up writers,
+        // and checked again under the write lock in case a key was
+        // written since.
+        let candidates = {
+            let inner = self.inner.read();
+            let now = now_millis();
+            let mut candidates = Vec::new();
+            for (key, cmd_pos) in inner.index.iter() {
+                if self.expired(&inner, cmd_pos, key, now)? {
+                    candidates.push(key.clone());
+                }
+            }
+            candidates
+        };
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+
+        let mut inner = self.inner.write();
+        let now = now_millis();
+        let mut swept = 0;
+        for key in candidates {
+            let Some(cmd_pos) = inner.index.get(&key).copied() else {
+                continue;
+            };
+            if self.expired(&inner, &cmd_pos, &key, now)? {
+                self.remove_locked(&mut inner, key)?;
+                swept += 1;
+            }
+        }
+        inner.expired_keys += swept as u64;
+        Ok(swept)
+    }
+
+    // Sweeps every `Options::expiration_sweep` on a background thread that
+    // exits once every handle to the store has been dropped.
+    pub(crate) fn start_expiration_sweep(&self) {
+        let interval = match self.options.expiration_sweep {
+            Some(interval) if !self.options.read_only => interval,
+            _ => return,
+        };
+        let inner = Arc::downgrade(&self.inner);
+        let options = self.options.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let mut store = KvStore {
+                    inner,
+                    options: options.clone(),
+                };
+                if let Err(e) = store.sweep_expired() {
+                    eprintln!("Expiration sweep failed: {}", e);
+                }
+            }
+        });
+    }
+
+    // Whether reads would treat the key's value as expired at `now`.
+    pub(crate) fn expired(&self, inner: &SharedData, cmd_pos: &CommandPos, key: &str, now: u64) -> Result<bool> {
+        match self.read_command(inner, cmd_pos, key)? {
+            Some(Command::Set {
+                timestamp, expires_at, ..
+            }) => self.value_expired(inner, cmd_pos, key, timestamp, expires_at, now),
+            _ => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::MANIFEST_FILE;
+use crate::{KvStore, Result, blob, hint, rotate_locked};
+
+impl KvStore {
+    // Creates a store in `directory` holding the same data, without copying
+    // it, and opens it with the same options. The fork starts a fresh
+    // active segment of its own, so either store can diverge freely.
+    pub fn fork(&self, directory: PathBuf) -> Result<KvStore> {
+        self.link_into(&directory)?;
+        KvStore::open_with(directory, (*self.options).clone())
+    }
+
+    // Writes a consistent, durable copy of the store to `directory` for
+    // backup tooling to archive. Takes milliseconds whatever the data size,
+    // since segments are linked rather than copied.
+    pub fn checkpoint(&self, directory: PathBuf) -> Result<()> {
+        self.link_into(&directory)?;
+        fs::File::open(&directory)?.sync_all()?;
+        Ok(())
+    }
+
+    // Seals the active segment, then hard links every segment into
+    // `directory` and copies the manifest, all under the write lock so the
+    // result is a point-in-time view. Sealed segments are never modified
+    // in place, and compaction only unlinks them, so sharing them is safe.
+    // Falls back to copying when `directory` is on another file system.
+    fn link_into(&self, directory: &Path) -> Result<()> {
+        if directory.exists() && fs::read_dir(directory)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is not empty", directory.display()),
+            )
+            .into());
+        }
+        fs::create_dir_all(directory)?;
+        let mut inner = self.inner.write();
+        if inner.writer.is_some() {
+            self.before_seal(&inner)?;
+            rotate_locked(&mut inner, &self.options)?;
+        }
+        // Later blobs go to a new segment rather than one now shared.
+        inner.blob_writer = None;
+
+        let source = inner.directory.clone();
+        for generation in inner.readers.keys() {
+            if *generation == inner.current_generation && inner.writer.is_some() {
+                continue;
+            }
+            let name = format!("{}.db", generation);
+            link_or_copy(&source.join(&name), &directory.join(&name))?;
+            let hint = hint::hint_path(&source, *generation);
+            if hint.exists() {
+                link_or_copy(&hint, &directory.join(format!("{}.hint", generation)))?;
+            }
+        }
+        for segment in inner.blob_segments.keys() {
+            let path = blob::blob_path(&source, *segment);
+            if let Some(name) = path.file_name() {
+                link_or_copy(&path, &directory.join(name))?;
+            }
+        }
+        // Small, and each store keeps its own.
+        if source.join(MANIFEST_FILE).exists() {
+            fs::copy(source.join(MANIFEST_FILE), directory.join(MANIFEST_FILE))?;
+            fs::File::open(directory.join(MANIFEST_FILE))?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// Syncs the file either way: a link shares the segment's data, which was
+// only flushed when it was written.
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    fs::File::open(to)?.sync_all()
+}
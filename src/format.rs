@@ -0,0 +1,127 @@
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Command;
+
+// Starts every binary record, so padding before it can be told apart.
+pub(crate) const BINARY_MARKER: u8 = 0xB1;
+// The marker, then the payload's length and CRC32 as little-endian u32s.
+const BINARY_HEADER_LEN: usize = 9;
+
+// How a segment's records are encoded. Each segment has exactly one; the
+// manifest lists the segments that are not `Json`, so a directory can mix
+// formats and compaction rewrites older segments in the store's current
+// one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    // serde_json values back to back, optionally separated by whitespace.
+    #[default]
+    Json,
+    // A length-prefixed header with a CRC32 of the payload, which is the
+    // command as MessagePack with named fields so optional fields can
+    // still be skipped or added. Spaces between records are padding.
+    Binary,
+}
+
+impl RecordFormat {
+    pub(crate) fn encode(self, cmd: &Command) -> io::Result<Vec<u8>> {
+        match self {
+            RecordFormat::Json => Ok(serde_json::to_vec(cmd)?),
+            RecordFormat::Binary => {
+                let payload = rmp_serde::to_vec_named(cmd).map_err(io::Error::other)?;
+                let len = u32::try_from(payload.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+                let mut record = Vec::with_capacity(BINARY_HEADER_LEN + payload.len());
+                record.push(BINARY_MARKER);
+                record.extend_from_slice(&len.to_le_bytes());
+                record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+                record.extend_from_slice(&payload);
+                Ok(record)
+            }
+        }
+    }
+
+    // Decodes the single record `reader` holds.
+    pub(crate) fn decode(self, mut reader: impl Read) -> io::Result<Command> {
+        match self {
+            RecordFormat::Json => Ok(serde_json::from_reader(reader)?),
+            RecordFormat::Binary => match read_binary(&mut reader)? {
+                Some((cmd, _)) => Ok(cmd),
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing record")),
+            },
+        }
+    }
+
+    // Every record in a segment, each with the offset just past it,
+    // relative to where `reader` started.
+    pub(crate) fn records<'r>(
+        self,
+        reader: impl Read + 'r,
+    ) -> Box<dyn Iterator<Item = io::Result<(Command, u64)>> + 'r> {
+        match self {
+            RecordFormat::Json => {
+                let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+                Box::new(std::iter::from_fn(move || {
+                    let record = stream.next()?;
+                    Some(record.map(|cmd| (cmd, stream.byte_offset() as u64)).map_err(io::Error::from))
+                }))
+            }
+            RecordFormat::Binary => {
+                let mut reader = reader;
+                let mut offset = 0;
+                let mut failed = false;
+                Box::new(std::iter::from_fn(move || {
+                    if failed {
+                        return None;
+                    }
+                    match read_binary(&mut reader) {
+                        Ok(Some((cmd, len))) => {
+                            offset += len;
+                            Some(Ok((cmd, offset)))
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            failed = true;
+                            Some(Err(e))
+                        }
+                    }
+                }))
+            }
+        }
+    }
+}
+
+// The next binary record and how many bytes it took, padding included,
+// or `None` at a clean end of the segment.
+fn read_binary(reader: &mut impl Read) -> io::Result<Option<(Command, u64)>> {
+    let mut header = [0u8; BINARY_HEADER_LEN];
+    let mut padding = 0;
+    loop {
+        match reader.read(&mut header[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) if header[0] == b' ' => padding += 1,
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if header[0] != BINARY_MARKER {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a binary record marker"));
+    }
+    reader.read_exact(&mut header[1..])?;
+    let len = u32::from_le_bytes(header[1..5].try_into().expect("4 bytes"));
+    let crc = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes"));
+    // Read through `take` so a corrupt length can't allocate gigabytes.
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record ends early"));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "record checksum mismatch"));
+    }
+    let cmd = rmp_serde::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some((cmd, padding + (BINARY_HEADER_LEN + payload.len()) as u64)))
+}
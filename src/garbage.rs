@@ -0,0 +1,49 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, Result, SharedData};
+
+// A segment's size on disk and how many of those bytes hold records the
+// index has since moved past.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub generation: u64,
+    pub bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl KvStore {
+    // Records become dead as their keys are overwritten or removed, during
+    // replay too; ones an index snapshot skipped on open are not counted.
+    pub fn segment_stats(&self) -> Result<Vec<SegmentStats>> {
+        let inner = self.inner.read();
+        Ok(inner
+            .readers
+            .keys()
+            .map(|&generation| SegmentStats {
+                generation,
+                bytes: segment_bytes(&inner, generation),
+                dead_bytes: inner.dead_bytes.get(&generation).copied().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    // Whether dead records make up enough of the segments, the active one
+    // included, for `Options::compact_at_garbage_ratio`.
+    pub(crate) fn garbage_compaction_due(&self, inner: &SharedData) -> bool {
+        let Some(ratio) = self.options.garbage_ratio else {
+            return false;
+        };
+        let dead: u64 = inner.dead_bytes.values().sum();
+        if inner.compacting || dead == 0 {
+            return false;
+        }
+        let total: u64 = inner.readers.keys().map(|&generation| segment_bytes(inner, generation)).sum();
+        total > 0 && dead as f64 / total as f64 >= ratio
+    }
+}
+
+fn segment_bytes(inner: &SharedData, generation: u64) -> u64 {
+    fs::metadata(inner.directory.join(format!("{}.db", generation))).map_or(0, |metadata| metadata.len())
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, CommandPos, SharedData};
+
+// A sealed segment's index updates, written next to it as
+// `{generation}.hint` so open can apply them without decoding the
+// segment. The file is a CRC32 of the rest, then the hint as MessagePack.
+// Only trusted while the segment is still `segment_len` bytes.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Hint {
+    generation: u64,
+    segment_len: u64,
+    // The last update to each key in the segment; `None` is a removal.
+    entries: HashMap<String, Option<CommandPos>>,
+}
+
+impl Hint {
+    // Mirrors what replay does with the record at `pos`: every op in a
+    // batch points at the whole batch.
+    pub(crate) fn record(&mut self, cmd: &Command, pos: CommandPos) {
+        match cmd {
+            Command::Set { key, blob, .. } => {
+                let cmd_pos = CommandPos { blob: *blob, ..pos };
+                self.entries.insert(key.clone(), Some(cmd_pos));
+            }
+            Command::Remove { key, .. } => {
+                self.entries.insert(key.clone(), None);
+            }
+            Command::Batch { commands } => {
+                for cmd in commands {
+                    self.record(cmd, pos);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: String, cmd_pos: Option<CommandPos>) {
+        self.entries.insert(key, cmd_pos);
+    }
+
+    pub(crate) fn heap_bytes(&self) -> u64 {
+        crate::memory::table_bytes(&self.entries)
+    }
+
+    // The hint for `generation`, or `None` if there is none or it can't be
+    // trusted, in which case the segment is replayed instead.
+    pub(crate) fn load(directory: &Path, generation: u64) -> Option<Hint> {
+        let bytes = fs::read(hint_path(directory, generation)).ok()?;
+        let segment_len = fs::metadata(directory.join(format!("{}.db", generation))).ok()?.len();
+        match Hint::decode(&bytes).and_then(|hint| hint.check(generation, segment_len).map(|_| hint)) {
+            Ok(hint) => Some(hint),
+            Err(reason) => {
+                eprintln!("Ignoring the hint for generation {}: {}", generation, reason);
+                None
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Hint, String> {
+        let (crc, payload) = bytes.split_first_chunk::<4>().ok_or("file is truncated")?;
+        if crc32fast::hash(payload) != u32::from_le_bytes(*crc) {
+            return Err("checksum mismatch".to_string());
+        }
+        rmp_serde::from_slice(payload).map_err(|e| e.to_string())
+    }
+
+    // Whether the hint covers exactly the segment as it is now.
+    fn check(&self, generation: u64, segment_len: u64) -> Result<(), String> {
+        if self.generation != generation {
+            return Err(format!("written for generation {}", self.generation));
+        }
+        if self.segment_len != segment_len {
+            return Err(format!("covers {} bytes of a {}-byte segment", self.segment_len, segment_len));
+        }
+        let outside = self.entries.iter().find(|(_, cmd_pos)| {
+            cmd_pos.is_some_and(|cmd_pos| cmd_pos.generation != generation || cmd_pos.pos + cmd_pos.len > segment_len)
+        });
+        match outside {
+            Some((key, _)) => Err(format!("{:?} points outside the segment", key)),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub(crate) fn into_entries(self) -> impl Iterator<Item = (String, Option<CommandPos>)> {
+        self.entries.into_iter()
+    }
+
+    // Not synced: a hint lost or torn in a crash only costs a replay.
+    pub(crate) fn write(mut self, directory: &Path, generation: u64) -> io::Result<()> {
+        self.generation = generation;
+        self.segment_len = fs::metadata(directory.join(format!("{}.db", generation)))?.len();
+        let payload = rmp_serde::to_vec(&self).map_err(io::Error::other)?;
+        let path = hint_path(directory, generation);
+        let tmp_path = path.with_extension("hint.tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.write_all(&payload)?;
+        fs::rename(&tmp_path, &path)
+    }
+}
+
+impl SharedData {
+    // Called with the active segment flushed, just before it is sealed.
+    pub(crate) fn write_active_hint(&mut self) {
+        let hint = std::mem::take(&mut self.active_hint);
+        if let Err(e) = hint.write(&self.directory, self.writer_generation) {
+            eprintln!("Failed to write hint for generation {}: {}", self.writer_generation, e);
+        }
+    }
+}
+
+pub(crate) fn hint_path(directory: &Path, generation: u64) -> PathBuf {
+    directory.join(format!("{}.hint", generation))
+}
+
+// Hints are only an optimisation, so one that is already gone is fine.
+pub(crate) fn remove_hint(directory: &Path, generation: u64) -> io::Result<()> {
+    match fs::remove_file(hint_path(directory, generation)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use crate::now_millis;
+
+// A hybrid logical clock reading: wall-clock milliseconds, a counter that
+// orders readings within the same millisecond, and the node that took it,
+// so readings from different nodes never compare equal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HlcTimestamp {
+    pub physical_ms: u64,
+    pub logical: u32,
+    pub node: u64,
+}
+
+// Hands out readings that only go forward, even if the wall clock steps
+// back, and that follow every reading it has observed from other nodes.
+#[derive(Debug, Clone)]
+pub struct Hlc {
+    last: HlcTimestamp,
+}
+
+impl Hlc {
+    pub fn new(node: u64) -> Self {
+        Hlc {
+            last: HlcTimestamp {
+                node,
+                ..HlcTimestamp::default()
+            },
+        }
+    }
+
+    pub fn now(&mut self) -> HlcTimestamp {
+        let wall = now_millis();
+        if wall > self.last.physical_ms {
+            self.last.physical_ms = wall;
+            self.last.logical = 0;
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    // Takes in a reading from another node, such as a replicated write's,
+    // and returns one that comes after it and everything before.
+    pub fn observe(&mut self, remote: HlcTimestamp) -> HlcTimestamp {
+        let wall = now_millis();
+        let physical_ms = wall.max(self.last.physical_ms).max(remote.physical_ms);
+        let local = (physical_ms == self.last.physical_ms).then_some(self.last.logical);
+        let theirs = (physical_ms == remote.physical_ms).then_some(remote.logical);
+        self.last.logical = match local.max(theirs) {
+            Some(logical) => logical + 1,
+            None => 0,
+        };
+        self.last.physical_ms = physical_ms;
+        self.last
+    }
+}
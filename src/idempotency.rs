@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::now_millis;
+
+// Kept next to the store's files rather than in it, so no client can read
+// the responses and nothing counts or replicates them.
+const TABLE_FILE: &str = "IDEMPOTENCY";
+// How long responses to idempotent requests are kept for retries.
+const WINDOW_MILLIS: u64 = 10 * 60 * 1000;
+// Responses kept at most; past it the oldest go first, whatever their age.
+const CAPACITY: usize = 10_000;
+
+// An idempotency token, with the subject of the bearer token it came under.
+pub(crate) type IdempotencyKey = (Option<String>, String);
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    kept_at: u64,
+    subject: Option<String>,
+    token: String,
+    response: String,
+}
+
+// First responses to idempotent requests. Each one is appended to the
+// table file as it is kept, and the file is rewritten with only the live
+// entries at open and whenever it holds twice `CAPACITY` lines.
+pub(crate) struct IdempotencyTable {
+    // Encoded as JSON, as `Response` can't be cloned.
+    done: HashMap<IdempotencyKey, String>,
+    // When each response in `done` was kept, oldest first.
+    kept: VecDeque<(u64, IdempotencyKey)>,
+    // First attempts that have not finished yet.
+    pub(crate) in_flight: HashSet<IdempotencyKey>,
+    path: PathBuf,
+    file: BufWriter<File>,
+    lines: usize,
+}
+
+impl IdempotencyTable {
+    // Entries past the window are dropped as the file is read. A torn last
+    // line, left by a crash mid-append, is skipped.
+    pub(crate) fn open(directory: &Path) -> io::Result<IdempotencyTable> {
+        let path = directory.join(TABLE_FILE);
+        let cutoff = now_millis().saturating_sub(WINDOW_MILLIS);
+        let mut done = HashMap::new();
+        let mut kept = VecDeque::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                        continue;
+                    };
+                    if entry.kept_at <= cutoff {
+                        continue;
+                    }
+                    let key = (entry.subject, entry.token);
+                    if done.insert(key.clone(), entry.response).is_some() {
+                        kept.retain(|(_, kept_key)| *kept_key != key);
+                    }
+                    kept.push_back((entry.kept_at, key));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        while kept.len() > CAPACITY {
+            if let Some((_, key)) = kept.pop_front() {
+                done.remove(&key);
+            }
+        }
+        let file = write_table(&path, &kept, &done)?;
+        Ok(IdempotencyTable {
+            lines: kept.len(),
+            done,
+            kept,
+            in_flight: HashSet::new(),
+            path,
+            file,
+        })
+    }
+
+    pub(crate) fn response(&mut self, key: &IdempotencyKey) -> Option<&str> {
+        self.expire();
+        self.done.get(key).map(String::as_str)
+    }
+
+    pub(crate) fn record(&mut self, key: IdempotencyKey, response: String) -> io::Result<()> {
+        self.expire();
+        let entry = Entry {
+            kept_at: now_millis(),
+            subject: key.0.clone(),
+            token: key.1.clone(),
+            response,
+        };
+        self.kept.push_back((entry.kept_at, key.clone()));
+        self.done.insert(key, entry.response.clone());
+        while self.kept.len() > CAPACITY {
+            self.forget_oldest();
+        }
+        if self.lines >= 2 * CAPACITY {
+            self.file = write_table(&self.path, &self.kept, &self.done)?;
+            self.lines = self.kept.len();
+            return Ok(());
+        }
+        serde_json::to_writer(&mut self.file, &entry)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let cutoff = now_millis().saturating_sub(WINDOW_MILLIS);
+        while self.kept.front().is_some_and(|(kept_at, _)| *kept_at <= cutoff) {
+            self.forget_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((_, key)) = self.kept.pop_front() {
+            self.done.remove(&key);
+        }
+    }
+}
+
+// Writes the live entries to a new table file, replacing the old one by a
+// rename so a crash leaves one or the other, and opens it for appending.
+fn write_table(
+    path: &Path,
+    kept: &VecDeque<(u64, IdempotencyKey)>,
+    done: &HashMap<IdempotencyKey, String>,
+) -> io::Result<BufWriter<File>> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for (kept_at, key) in kept {
+        let Some(response) = done.get(key) else {
+            continue;
+        };
+        let entry = Entry {
+            kept_at: *kept_at,
+            subject: key.0.clone(),
+            token: key.1.clone(),
+            response: response.clone(),
+        };
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(BufWriter::new(OpenOptions::new().append(true).open(path)?))
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use crate::{
+    Command, CommandPos, KvError, KvStore, Result, check_writable, now_millis, update_aggregates, value_checksum,
+};
+
+// What `import_batch` does with a key that already exists, either in the
+// store or earlier in the same batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDuplicate {
+    Overwrite,
+    Skip,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub inserted: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+}
+
+impl KvStore {
+    // Writes every accepted pair as one batch record, under one lock and
+    // one flush. Validation and `OnDuplicate::Error` are checked for the
+    // whole batch before anything is written.
+    pub fn import_batch<K: Into<String>, V: Into<String>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        on_duplicate: OnDuplicate,
+    ) -> Result<ImportSummary> {
+        let mut inner = self.inner.write();
+
+        let mut summary = ImportSummary::default();
+        let mut accepted: Vec<(String, String)> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (key, value) in pairs {
+            let (key, value) = (key.into(), value.into());
+            self.validate(&key, &value)?;
+            let earlier = positions.get(&key).copied();
+            if earlier.is_none() && !inner.index.contains_key(&key) {
+                positions.insert(key.clone(), accepted.len());
+                accepted.push((key, value));
+                summary.inserted += 1;
+                continue;
+            }
+            match on_duplicate {
+                OnDuplicate::Error => return Err(KvError::DuplicateKey(key)),
+                OnDuplicate::Skip => summary.skipped += 1,
+                OnDuplicate::Overwrite => {
+                    summary.overwritten += 1;
+                    match earlier {
+                        Some(i) => accepted[i].1 = value,
+                        None => {
+                            positions.insert(key.clone(), accepted.len());
+                            accepted.push((key, value));
+                        }
+                    }
+                }
+            }
+        }
+        if accepted.is_empty() {
+            return Ok(summary);
+        }
+        check_writable(&inner)?;
+
+        let mut olds = HashMap::new();
+        let mut news = HashMap::new();
+        let mut synced = HashMap::new();
+        for (key, value) in &accepted {
+            if let Some(old) = self.aggregated_value(&inner, key)? {
+                olds.insert(key.clone(), old);
+            }
+            if self.options.aggregated(key) {
+                news.insert(key.clone(), value.clone());
+            }
+            if self.options.synced(key) {
+                synced.insert(key.clone(), value.clone());
+            }
+        }
+
+        let timestamp = Some(now_millis());
+        let mut commands = Vec::with_capacity(accepted.len());
+        for (key, value) in accepted {
+            let checksum = Some(value_checksum(&value));
+            let (value, blob) = self.separate_value(&mut inner, &key, value)?;
+            commands.push(Command::Set {
+                key,
+                value,
+                timestamp,
+                blob,
+                content_type: None,
+                checksum,
+                expires_at: None,
+            });
+        }
+        let cmd = Command::Batch { commands };
+        let cmd_pos = self.append_command(&mut inner, &cmd)?;
+        for op in cmd.into_ops() {
+            if let Command::Set { key, blob, .. } = op {
+                update_aggregates(
+                    &mut inner,
+                    &key,
+                    olds.get(&key).map(String::as_str),
+                    news.get(&key).map(String::as_str),
+                );
+                inner.index_insert(key.clone(), CommandPos { blob, ..cmd_pos });
+                if let Some(value) = synced.remove(&key) {
+                    self.record_sync_write(&mut inner, &key, Some(value))?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+
+use crate::{Command, CorruptionPolicy, IntegrityCheck, KvError, KvStore, Result, TruncatedTail, value_checksum};
+
+// What the integrity check run at open found.
+#[derive(Debug, Clone, Default)]
+pub struct OpenReport {
+    pub records_checked: u64,
+    pub problems: Vec<IntegrityProblem>,
+    // Records replay skipped; only with `CorruptionPolicy::Skip`.
+    pub corrupt_records: Vec<CorruptRecord>,
+    // A record left half written by a crash, cut off the newest segment.
+    pub truncated_tail: Option<TruncatedTail>,
+    // The store was last closed without `KvStore::shutdown`: by a crash,
+    // a kill or a dropped handle.
+    pub unclean_shutdown: bool,
+}
+
+// A record replay found damaged, by where it starts in its segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRecord {
+    pub generation: u64,
+    pub offset: u64,
+    pub kind: CorruptionKind,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    // The record doesn't decode. Replay can't find the next record
+    // boundary, so the rest of the segment is skipped with it.
+    Unreadable,
+    // A value doesn't match its checksum. Only this record is skipped.
+    ChecksumMismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegrityProblem {
+    pub key: String,
+    pub error: String,
+}
+
+impl KvStore {
+    pub fn open_report(&self) -> Result<OpenReport> {
+        let inner = self.inner.read();
+        Ok(inner.open_report.clone())
+    }
+
+    // Reads back the live record of every key, or of a random share of
+    // them, verifying checksums along the way. Problems are reported rather
+    // than failing the open.
+    // Aborts the open or notes the record, as `verify_on_load` says.
+    pub(crate) fn corrupt_record(&self, record: CorruptRecord, found: &mut Vec<CorruptRecord>) -> Result<()> {
+        match self.options.verify_on_load {
+            Some(CorruptionPolicy::Skip) => {
+                eprintln!(
+                    "Skipping corrupt record at offset {} of generation {}: {}",
+                    record.offset, record.generation, record.detail
+                );
+                found.push(record);
+                Ok(())
+            }
+            _ => Err(KvError::CorruptRecord(record)),
+        }
+    }
+
+    pub(crate) fn check_integrity(&self) -> io::Result<()> {
+        let check = match self.options.integrity_check {
+            IntegrityCheck::None if self.inner.read().open_report.unclean_shutdown => {
+                self.options.unclean_shutdown_check
+            }
+            check => check,
+        };
+        let share = match check {
+            IntegrityCheck::None => return Ok(()),
+            IntegrityCheck::Sample(share) => share.clamp(0.0, 1.0),
+            IntegrityCheck::Full => 1.0,
+        };
+        let keys: Vec<String> = {
+            let inner = self.inner.read();
+            // A fresh random seed picks a different sample every open.
+            let hasher = RandomState::new();
+            inner
+                .index
+                .keys()
+                .filter(|key| share >= 1.0 || (hasher.hash_one(key) as f64 / u64::MAX as f64) < share)
+                .cloned()
+                .collect()
+        };
+
+        let mut records_checked = 0;
+        let mut problems = Vec::new();
+        for key in keys {
+            let inner = self.inner.read();
+            if !inner.index.contains_key(&key) {
+                continue;
+            }
+            records_checked += 1;
+            if let Err(e) = self.get_tagged_locked(&inner, &key) {
+                problems.push(IntegrityProblem {
+                    key,
+                    error: e.to_string(),
+                });
+            }
+        }
+        if !problems.is_empty() {
+            eprintln!(
+                "Integrity check found {} bad records out of {} checked",
+                problems.len(),
+                records_checked
+            );
+        }
+        let mut inner = self.inner.write();
+        inner.open_report.records_checked = records_checked;
+        inner.open_report.problems = problems;
+        Ok(())
+    }
+}
+
+// The key of the first inline value in `cmd` that doesn't match its
+// checksum. Separated values are checked when read.
+pub(crate) fn checksum_mismatch(cmd: &Command) -> Option<&str> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            blob: None,
+            checksum: Some(checksum),
+            ..
+        } if *checksum != value_checksum(value) => Some(key),
+        Command::Batch { commands } => commands.iter().find_map(checksum_mismatch),
+        _ => None,
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{Command, CommandPos, KvError, KvStore, Result, now_millis, update_aggregates, value_checksum};
+
+impl KvStore {
+    // Moves the value of `from` to `to` as one batch record holding both
+    // the tombstone and the write, so neither readers nor a crash can see
+    // one without the other. The value keeps its expiry. Fails if `from`
+    // is missing, or if `to` exists and `overwrite` is false.
+    pub fn rename(&mut self, from: impl Into<String>, to: impl Into<String>, overwrite: bool) -> Result<()> {
+        let (from, to) = (from.into(), to.into());
+        let mut inner = self.inner.write();
+        let Some((value, content_type)) = self.get_tagged_locked(&inner, &from)? else {
+            return Err(KvError::KeyNotFound(from));
+        };
+        if from == to {
+            return Ok(());
+        }
+        let replaced = self.get_locked(&inner, &to)?;
+        if replaced.is_some() && !overwrite {
+            return Err(KvError::DuplicateKey(to));
+        }
+        self.validate(&to, &value)?;
+        let expires_at = self.expires_at_locked(&inner, &from)?;
+
+        let old = self.options.aggregated(&from).then(|| value.clone());
+        let replaced = replaced.filter(|_| self.options.aggregated(&to));
+        let new = self.options.aggregated(&to).then(|| value.clone());
+        let mut synced = self.options.synced(&to).then(|| value.clone());
+        let checksum = Some(value_checksum(&value));
+        let (value, blob) = self.separate_value(&mut inner, &to, value)?;
+        let cmd = Command::Batch {
+            commands: vec![
+                Command::Remove {
+                    key: from,
+                    timestamp: Some(now_millis()),
+                },
+                Command::Set {
+                    key: to,
+                    value,
+                    timestamp: Some(now_millis()),
+                    blob,
+                    content_type,
+                    checksum,
+                    expires_at,
+                },
+            ],
+        };
+        let cmd_pos = self.append_command(&mut inner, &cmd)?;
+        for op in cmd.into_ops() {
+            match op {
+                Command::Remove { key, .. } => {
+                    update_aggregates(&mut inner, &key, old.as_deref(), None);
+                    inner.index_remove(&key);
+                    if self.options.synced(&key) {
+                        self.record_sync_write(&mut inner, &key, None)?;
+                    }
+                }
+                Command::Set { key, blob, .. } => {
+                    update_aggregates(&mut inner, &key, replaced.as_deref(), new.as_deref());
+                    self.record_access(&inner, &key);
+                    inner.index_insert(key.clone(), CommandPos { blob, ..cmd_pos });
+                    if let Some(value) = synced.take() {
+                        self.record_sync_write(&mut inner, &key, Some(value))?;
+                    }
+                    self.evict_locked(&mut inner, &key)?;
+                }
+                Command::Batch { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Duplicates the value of `from` under `to` without it leaving the
+    // server. The copy keeps the original's write time, expiry and content
+    // type, so it expires no later than the original would.
+    // Fails like `rename`.
+    pub fn copy(&mut self, from: impl Into<String>, to: impl Into<String>, overwrite: bool) -> Result<()> {
+        let (from, to) = (from.into(), to.into());
+        let mut inner = self.inner.write();
+        let Some(value) = self.get_locked(&inner, &from)? else {
+            return Err(KvError::KeyNotFound(from));
+        };
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && self.get_locked(&inner, &to)?.is_some() {
+            return Err(KvError::DuplicateKey(to));
+        }
+        let (written, content_type, expires_at) = match inner.index.get(&from).copied() {
+            Some(cmd_pos) => match self.read_command(&inner, &cmd_pos, &from)? {
+                Some(Command::Set {
+                    timestamp,
+                    content_type,
+                    expires_at,
+                    ..
+                }) => (timestamp, content_type, expires_at),
+                _ => (None, None, None),
+            },
+            None => (None, None, None),
+        };
+        let written = written.unwrap_or_else(now_millis);
+        self.set_at_locked(&mut inner, to, value, written, content_type, expires_at)
+    }
+
+    // Returns the value `key` had, removing it. A missing key writes nothing.
+    pub fn get_and_remove(&mut self, key: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        let mut inner = self.inner.write();
+        let old = self.get_locked(&inner, &key)?;
+        if old.is_some() {
+            self.remove_locked(&mut inner, key)?;
+        }
+        Ok(old)
+    }
+
+    // Returns the value `key` had before `value` replaced it.
+    pub fn get_and_set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<Option<String>> {
+        let key = key.into();
+        let mut inner = self.inner.write();
+        let old = self.get_locked(&inner, &key)?;
+        self.set_locked(&mut inner, key, value.into())?;
+        Ok(old)
+    }
+
+    // Appends `suffix` to the key's value, treating a missing key as empty,
+    // and returns the new length in bytes. The value keeps its content type
+    // and expiry.
+    pub fn append(&mut self, key: impl Into<String>, suffix: &str) -> Result<usize> {
+        let key = key.into();
+        let mut inner = self.inner.write();
+        let (mut value, content_type) = self.get_tagged_locked(&inner, &key)?.unwrap_or_default();
+        value.push_str(suffix);
+        let len = value.len();
+        let expires_at = self.expires_at_locked(&inner, &key)?;
+        self.set_at_locked(&mut inner, key, value, now_millis(), content_type, expires_at)?;
+        Ok(len)
+    }
+}
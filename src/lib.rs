@@ -1,48 +1,387 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs::{self, File},
-    io::{self, BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
+    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
 };
 
+// parking_lot locks do not poison: a panic while one is held leaves the
+// store usable by every other clone.
+use parking_lot::{Mutex, MutexGuard, RwLock};
+
+mod access;
+mod aggregate;
+pub mod audit;
+pub mod auth;
+mod blob;
+mod checksum;
+mod churn;
+pub mod client;
+pub mod codec;
+pub mod config;
+mod content_type;
+mod deadline;
+mod durability;
+pub mod doctor;
+mod error;
+mod eviction;
+mod expiration;
+mod fork;
+mod format;
+mod garbage;
+mod hint;
+mod hlc;
+mod idempotency;
+mod import;
+mod integrity;
+mod keys;
+mod manifest;
+mod memory;
+mod migrate;
+mod open;
+mod options;
 pub mod protocol;
+mod read_repair;
+mod recovery;
+mod repair;
+mod rotation;
+pub mod replication;
+mod scan;
+mod scrub;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+mod segment;
+pub mod self_test;
+pub mod server;
+mod set_options;
+mod snapshot;
+mod streaming;
+pub mod stats;
+mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod value_metadata;
+mod watchdog;
+
+pub use access::KeyStats;
+pub use aggregate::Aggregate;
+pub use blob::BlobGcStats;
+pub use checksum::value_checksum;
+pub use content_type::ContentType;
+pub use format::RecordFormat;
+pub use garbage::SegmentStats;
+pub use hlc::{Hlc, HlcTimestamp};
+pub use error::{Incompatibility, KvError, ReadOnlyReason, Result};
+pub use import::{ImportSummary, OnDuplicate};
+pub use integrity::{CorruptRecord, CorruptionKind, IntegrityProblem, OpenReport};
+pub use manifest::StoreMetadata;
+pub use memory::MemoryUsage;
+pub use migrate::MigrationSummary;
+pub use open::OpenHandle;
+
+pub use options::{
+    CompactionEvent, CompactionListener, CorruptionPolicy, DiskWatchdog, EvictionPolicy, IntegrityCheck, JsonValidator,
+    Options, RotationPolicy, Scrubber, SyncPolicy, Validator,
+};
+pub use recovery::TruncatedTail;
+pub use repair::RepairSummary;
+pub use scan::{ScanOptions, ScanPage};
+pub use scrub::ScrubReport;
+pub use set_options::{SetOptions, SetOutcome};
+pub use sync::{Merge, MergeMode, SyncBatch, SyncPeer, SyncRecord, SyncSummary, VersionVector};
+pub use value_metadata::ValueMetadata;
 
 use serde::{Deserialize, Serialize};
 
-const SPLIT_LIMIT: u64 = 1 * 1024; // 1 KB
-const COMPACT_LIMIT: u64 = 5;
+use blob::{BlobRef, BlobSegment};
+use hint::Hint;
+use segment::SegmentReader;
+
+const SPLIT_LIMIT: u64 = 1024; // 1 KB, default RotationPolicy size
+const COMPACT_LIMIT: u64 = 5; // default Options::compact_after_segments
+const READ_BUFFER: usize = 8 * 1024; // default Options::read_buffer
+const LOAD_BATCH_SIZE: usize = 1024;
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        // Milliseconds since the Unix epoch; absent in logs written before
+        // timestamps were recorded.
+        #[serde(default)]
+        timestamp: Option<u64>,
+        // Set for values separated into a blob segment; `value` is then empty.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        blob: Option<BlobRef>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<ContentType>,
+        // `value_checksum` of the full value, checked on every read; absent
+        // in logs written before checksums were recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<u32>,
+        // Milliseconds since the Unix epoch after which the value reads as
+        // missing; compaction drops it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+        // As for `Set`; absent in logs written before removes carried one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    // Applied atomically: replay sees either all of it or, if torn, none.
+    Batch { commands: Vec<Command> },
+}
+
+impl Command {
+    // Flattens batches into their individual sets and removes, in order.
+    fn into_ops(self) -> Vec<Command> {
+        match self {
+            Command::Batch { commands } => commands.into_iter().flat_map(Command::into_ops).collect(),
+            cmd => vec![cmd],
+        }
+    }
+
+    fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key, .. } => Some(key),
+            Command::Batch { .. } => None,
+        }
+    }
+
+    // How many sets and removes the record holds, batches flattened.
+    fn op_counts(&self) -> (u64, u64) {
+        match self {
+            Command::Set { .. } => (1, 0),
+            Command::Remove { .. } => (0, 1),
+            Command::Batch { commands } => commands
+                .iter()
+                .map(Command::op_counts)
+                .fold((0, 0), |(sets, removes), (s, r)| (sets + s, removes + r)),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 struct CommandPos {
     pos: u64,
     len: u64,
     generation: u64,
+    #[serde(default)]
+    blob: Option<BlobRef>,
+}
+
+impl CommandPos {
+    fn live_bytes(&self) -> u64 {
+        self.len + self.blob.map_or(0, |blob| blob.len)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StoreStats {
+    pub segment_count: usize,
+    // Segments whose file has been opened for reading so far.
+    pub open_segment_count: usize,
+    pub key_count: usize,
+    pub current_generation: u64,
+    pub compacting: bool,
+    pub fallback_hits: u64,
+    pub low_disk: bool,
+    pub blob_segment_count: usize,
+    pub blob_live_bytes: u64,
+    pub blob_total_bytes: u64,
+    pub blob_bytes_reclaimed: u64,
+    // Bytes of the records, and blobs, the index points at.
+    pub live_bytes: u64,
+    // Bytes of log records the index no longer points at, which the next
+    // compaction reclaims; see `KvStore::segment_stats`.
+    pub dead_bytes: u64,
+    pub evicted_keys: u64,
+    // Keys removed by the expiration sweep.
+    pub expired_keys: u64,
+    // Totals since the store was opened: sets and removes appended,
+    // batches counted by their parts, and what compaction did with them.
+    pub records_written: u64,
+    pub tombstones_written: u64,
+    pub records_rewritten: u64,
+    pub compaction_bytes_reclaimed: u64,
+    // Reads that found the index pointing at a compacted-away segment.
+    pub read_repairs: u64,
 }
 
 #[derive(Clone)]
 pub struct KvStore {
     inner: Arc<RwLock<SharedData>>,
+    options: Arc<Options>,
 }
 
 struct SharedData {
     index: HashMap<String, CommandPos>,
     directory: PathBuf,
-    readers: std::collections::BTreeMap<u64, Mutex<BufReader<fs::File>>>,
+    readers: std::collections::BTreeMap<u64, SegmentReader>,
     current_generation: u64,
+    generation_started: SystemTime,
     compacting: bool,
-    writer: Mutex<BufWriter<fs::File>>,
+    compaction_cancel: Arc<AtomicBool>,
+    compaction_thread: Option<std::thread::JoinHandle<()>>,
+    fallback_hits: AtomicU64,
+    writer: Option<Mutex<BufWriter<fs::File>>>,
+    aggregates: HashMap<String, Aggregate>,
+    // Set by the disk watchdog below the hard free-space threshold.
+    low_disk: AtomicBool,
+    blob_segments: std::collections::BTreeMap<u64, BlobSegment>,
+    // Opened on the first separated value.
+    blob_writer: Option<(u64, BufWriter<fs::File>)>,
+    blob_bytes_reclaimed: u64,
+    live_bytes: u64,
+    // By generation; see `KvStore::segment_stats`.
+    dead_bytes: std::collections::BTreeMap<u64, u64>,
+    evicted_keys: u64,
+    expired_keys: u64,
+    records_written: u64,
+    tombstones_written: u64,
+    records_rewritten: u64,
+    compaction_bytes_reclaimed: u64,
+    metadata: StoreMetadata,
+    open_report: OpenReport,
+    // Set while the clean shutdown marker is on disk.
+    clean_marker: AtomicBool,
+    // The generation `writer` appends to, set together with it. Every
+    // clone shares this state, so once any of them rotates or compacts,
+    // all of them write to the new generation.
+    writer_generation: u64,
+    // Only filled in with `Options::track_access`.
+    access: Mutex<HashMap<String, KeyStats>>,
+    // Output generations of the last compaction; every older generation
+    // without a reader was folded into them.
+    compacted_into: Option<std::ops::Range<u64>>,
+    repairs: Mutex<Vec<read_repair::Repair>>,
+    read_repairs: AtomicU64,
+    // Appends since the last periodic fsync.
+    unsynced: AtomicBool,
+    // Written out as the active generation's hint when it is sealed.
+    active_hint: Hint,
+    // Sealed segments mostly holding keys under `Options::churn_prefix`.
+    churn_segments: BTreeSet<u64>,
+    scrub_report: ScrubReport,
+    // Only with `Options::sync_prefix`.
+    sync: Option<sync::SyncState>,
+}
+
+impl SharedData {
+    fn writer(&self) -> io::Result<MutexGuard<'_, BufWriter<fs::File>>> {
+        self.check_generation();
+        Ok(self.writer.as_ref().ok_or_else(read_only_error)?.lock())
+    }
+
+    // Debug builds treat a writer left behind by a rotation or compaction
+    // as a hard error rather than letting writes land in a stale segment.
+    fn check_generation(&self) {
+        debug_assert_eq!(
+            self.writer_generation, self.current_generation,
+            "writer appends to generation {} but the active generation is {}",
+            self.writer_generation, self.current_generation
+        );
+        debug_assert_eq!(
+            self.readers.keys().last(),
+            Some(&self.current_generation),
+            "the active generation must be the newest segment"
+        );
+    }
+
+    // Index updates go through these two so blob liveness and the live
+    // byte count stay in step.
+    fn index_insert(&mut self, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+        self.track_blob(cmd_pos.blob, true);
+        self.live_bytes += cmd_pos.live_bytes();
+        let old = self.index.insert(key, cmd_pos);
+        self.untrack(old);
+        old
+    }
+
+    fn index_remove(&mut self, key: &str) -> Option<CommandPos> {
+        self.access.get_mut().remove(key);
+        let old = self.index.remove(key);
+        self.untrack(old);
+        old
+    }
+
+    fn untrack(&mut self, old: Option<CommandPos>) {
+        if let Some(old) = old {
+            self.track_blob(old.blob, false);
+            self.live_bytes = self.live_bytes.saturating_sub(old.live_bytes());
+            if self.readers.contains_key(&old.generation) {
+                *self.dead_bytes.entry(old.generation).or_default() += old.len;
+            }
+        }
+    }
+
+    // Segments are only ever written in the format they were created with.
+    fn segment_format(&self, generation: u64) -> RecordFormat {
+        self.readers
+            .get(&generation)
+            .map(|reader| reader.format())
+            .unwrap_or_default()
+    }
+
+    fn track_blob(&mut self, blob: Option<BlobRef>, live: bool) {
+        if let Some(blob) = blob
+            && let Some(segment) = self.blob_segments.get_mut(&blob.segment)
+        {
+            if live {
+                segment.live_bytes += blob.len;
+            } else {
+                segment.live_bytes = segment.live_bytes.saturating_sub(blob.len);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrefixStats {
+    pub prefix: String,
+    pub depth: usize,
+    pub keys: u64,
+    pub bytes: u64,
 }
 
 impl KvStore {
-    pub fn open(directory: PathBuf) -> io::Result<Self> {
-        fs::create_dir_all(&directory)?;
+    pub fn open(directory: PathBuf) -> Result<Self> {
+        Self::open_with(directory, Options::default())
+    }
+
+    // Opens an existing store without creating a new generation; writes and
+    // compaction fail with `PermissionDenied`.
+    pub fn open_read_only(directory: PathBuf) -> Result<Self> {
+        Self::open_with(directory, Options::default().read_only(true))
+    }
+
+    pub fn open_with(directory: PathBuf, options: Options) -> Result<Self> {
+        let store = Self::create(directory, options)?;
+        store.load()?;
+        store.start_access_stats()?;
+        store.start_disk_watchdog()?;
+        store.start_scrubber();
+        store.start_expiration_sweep();
+        store.start_periodic_sync()?;
+        store.start_rotation_timer();
+        Ok(store)
+    }
+
+    // Sets up readers and the active generation with an empty index.
+    fn create(directory: PathBuf, options: Options) -> Result<Self> {
+        let read_only = options.read_only;
+        if !read_only {
+            fs::create_dir_all(&directory)?;
+        }
+        let mut metadata = manifest::load_or_init(&directory, &options)?;
         let generation_files = fs::read_dir(&directory)?;
         let mut readers = std::collections::BTreeMap::new();
         for dir_entry in generation_files {
@@ -58,314 +397,1276 @@ impl KvStore {
                 Some(g) => g,
                 None => continue,
             };
-            let file = fs::OpenOptions::new().read(true).open(path)?;
-            readers.insert(generation, Mutex::new(BufReader::new(file)));
+            let format = metadata.segment_format(generation);
+            segment::records_start(&mut fs::File::open(&path)?, &path, format)?;
+            readers.insert(generation, SegmentReader::new(path, format, options.read_buffer_bytes()));
         }
-        // We always create a new generation on start up
-        let current_generation = readers.keys().last().copied().unwrap_or(0) + 1;
-        let (writer, reader) = new_log_file(&directory, current_generation)?;
-        readers.insert(current_generation, reader);
+        let unclean_shutdown = recovery::take_clean_marker(&directory, !readers.is_empty(), read_only)?;
+        let last_generation = readers.keys().last().copied().unwrap_or(0);
+        let (current_generation, writer) = if read_only {
+            (last_generation, None)
+        } else {
+            // We always create a new generation on start up
+            let current_generation = last_generation + 1;
+            manifest::record_segment_formats(&directory, &mut metadata, [current_generation], options.record_format)?;
+            let (writer, reader) =
+                new_log_file(&directory, current_generation, options.record_format, options.read_buffer_bytes())?;
+            readers.insert(current_generation, reader);
+            (current_generation, Some(Mutex::new(writer)))
+        };
 
+        let blob_segments = blob::open_blob_segments(&directory)?;
+        let sync = sync::open_sync_state(&directory, &options)?;
         let index = HashMap::new();
+        let aggregates = options
+            .aggregates
+            .iter()
+            .map(|prefix| (prefix.clone(), Aggregate::default()))
+            .collect();
         let data = SharedData {
             index,
             directory,
             readers,
             current_generation,
+            generation_started: SystemTime::now(),
             compacting: false,
-            writer: Mutex::new(writer),
+            compaction_cancel: Arc::new(AtomicBool::new(false)),
+            compaction_thread: None,
+            fallback_hits: AtomicU64::new(0),
+            writer,
+            aggregates,
+            low_disk: AtomicBool::new(false),
+            blob_segments,
+            blob_writer: None,
+            blob_bytes_reclaimed: 0,
+            live_bytes: 0,
+            dead_bytes: std::collections::BTreeMap::new(),
+            evicted_keys: 0,
+            expired_keys: 0,
+            records_written: 0,
+            tombstones_written: 0,
+            records_rewritten: 0,
+            compaction_bytes_reclaimed: 0,
+            metadata,
+            open_report: OpenReport {
+                unclean_shutdown,
+                ..OpenReport::default()
+            },
+            clean_marker: AtomicBool::new(false),
+            writer_generation: current_generation,
+            access: Mutex::new(HashMap::new()),
+            compacted_into: None,
+            repairs: Mutex::new(Vec::new()),
+            read_repairs: AtomicU64::new(0),
+            unsynced: AtomicBool::new(false),
+            active_hint: Hint::default(),
+            churn_segments: BTreeSet::new(),
+            scrub_report: ScrubReport::default(),
+            sync,
         };
-        let mut store = KvStore {
+        Ok(KvStore {
             inner: Arc::new(RwLock::new(data)),
-        };
-        store.load()?;
-        Ok(store)
+            options: Arc::new(options),
+        })
     }
 
-    fn load(&mut self) -> io::Result<()> {
-        let mut inner_guard = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
-        let SharedData {
-            ref mut readers,
-            ref mut index,
-            ..
-        } = *inner_guard;
-
-        for generation in readers.keys() {
-            if let Some(reader) = readers.get(&generation) {
-                let mut reader_guard = reader
-                    .lock()
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-                let mut pos = reader_guard.seek(SeekFrom::Start(0))?;
-                let mut stream = serde_json::Deserializer::from_reader(&mut *reader_guard)
-                    .into_iter::<Command>();
-
-                while let Some(command) = stream.next() {
-                    let c = command?;
-                    let new_pos = stream.byte_offset() as u64;
-                    let len = new_pos - pos;
-                    match c {
-                        Command::Set { key, .. } => {
+    // Replays generations oldest first from private file handles, applying
+    // index updates in batches so readers only wait for one batch at a time.
+    // Segments covered by an index snapshot only replay what came after it,
+    // and sealed segments with a hint are not replayed at all unless records
+    // are being verified. Replaying a sealed segment writes its hint.
+    fn load(&self) -> Result<()> {
+        let (directory, generations, current_generation) = {
+            let inner = self.inner.read();
+            let generations: Vec<(u64, RecordFormat)> = inner
+                .readers
+                .iter()
+                .map(|(generation, reader)| (*generation, reader.format()))
+                .collect();
+            (inner.directory.clone(), generations, inner.current_generation)
+        };
+        let snapshot_offsets = self.load_snapshot(&directory)?;
+        let writer_generation = (!self.options.read_only).then_some(current_generation);
+        let tail_generation = recovery::tail_generation(&directory, &generations, writer_generation)?;
+        let mut truncated_tail = None;
+        let mut corrupt = Vec::new();
+        let load_started = now_millis();
+
+        for (generation, format) in generations {
+            if !snapshot_offsets.contains_key(&generation)
+                && self.options.verify_on_load.is_none()
+                && let Some(hint) = Hint::load(&directory, generation)
+            {
+                let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+                for entry in hint.into_entries() {
+                    batch.push(entry);
+                    if batch.len() >= LOAD_BATCH_SIZE {
+                        self.apply_load_batch(&mut batch)?;
+                    }
+                }
+                self.apply_load_batch(&mut batch)?;
+                continue;
+            }
+            let sealed = !self.options.read_only && generation < current_generation;
+            let mut hint = (!snapshot_offsets.contains_key(&generation) && sealed).then(Hint::default);
+            let corrupt_before = corrupt.len();
+            let path = directory.join(format!("{}.db", generation));
+            let (mut file, records_start) = segment::open_records(&path, format)?;
+            let start = snapshot_offsets.get(&generation).copied().unwrap_or(records_start);
+            file.seek(SeekFrom::Start(start))?;
+            let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+            let mut pos = start;
+
+            for record in format.records(file) {
+                let (c, end) = match record {
+                    Ok(record) => record,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && Some(generation) == tail_generation => {
+                        truncated_tail = Some(self.truncate_torn_tail(&path, generation, pos)?);
+                        break;
+                    }
+                    Err(e) if self.options.verify_on_load.is_some() => {
+                        let record = CorruptRecord {
+                            generation,
+                            offset: pos,
+                            kind: CorruptionKind::Unreadable,
+                            detail: e.to_string(),
+                        };
+                        self.corrupt_record(record, &mut corrupt)?;
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let new_pos = start + end;
+                let len = new_pos - pos;
+                if self.options.verify_on_load.is_some()
+                    && let Some(key) = integrity::checksum_mismatch(&c)
+                {
+                    let record = CorruptRecord {
+                        generation,
+                        offset: pos,
+                        kind: CorruptionKind::ChecksumMismatch,
+                        detail: format!("value of key {:?}", key),
+                    };
+                    self.corrupt_record(record, &mut corrupt)?;
+                    pos = new_pos;
+                    continue;
+                }
+                if let Some(hint) = &mut hint {
+                    let cmd_pos = CommandPos {
+                        pos,
+                        len,
+                        generation,
+                        blob: None,
+                    };
+                    hint.record(&c, cmd_pos);
+                }
+                for op in c.into_ops() {
+                    match op {
+                        // Expired values read as missing, so they are not
+                        // indexed, nor listed in the hint written below.
+                        Command::Set {
+                            key,
+                            expires_at: Some(expires_at),
+                            ..
+                        } if expires_at <= load_started => {
+                            if let Some(hint) = &mut hint {
+                                hint.insert(key.clone(), None);
+                            }
+                            batch.push((key, None));
+                        }
+                        Command::Set { key, blob, .. } => {
                             let cmd_pos = CommandPos {
                                 pos,
                                 len,
-                                generation: *generation,
+                                generation,
+                                blob,
                             };
-                            index.insert(key, cmd_pos);
-                        }
-                        Command::Remove { key } => {
-                            index.remove(&key);
+                            batch.push((key, Some(cmd_pos)));
                         }
+                        Command::Remove { key, .. } => batch.push((key, None)),
+                        Command::Batch { .. } => {}
                     }
-                    pos = new_pos;
+                }
+                pos = new_pos;
+                if batch.len() >= LOAD_BATCH_SIZE {
+                    self.apply_load_batch(&mut batch)?;
                 }
             }
+            self.apply_load_batch(&mut batch)?;
+            if let Some(hint) = hint
+                && corrupt.len() == corrupt_before
+                && let Err(e) = hint.write(&directory, generation)
+            {
+                eprintln!("Failed to write hint for generation {}: {}", generation, e);
+            }
+        }
+
+        if !self.options.aggregates.is_empty() {
+            let mut inner = self.inner.write();
+            inner.aggregates = self
+                .recompute_aggregates(&inner, &HashMap::new())
+                .map_err(io::Error::other)?;
+        }
+        {
+            let mut inner = self.inner.write();
+            inner.open_report.corrupt_records = corrupt;
+            inner.open_report.truncated_tail = truncated_tail;
+        }
+        Ok(self.check_integrity()?)
+    }
+
+    fn apply_load_batch(&self, batch: &mut Vec<(String, Option<CommandPos>)>) -> io::Result<()> {
+        let mut inner = self.inner.write();
+        for (key, cmd_pos) in batch.drain(..) {
+            match cmd_pos {
+                Some(cmd_pos) => inner.index_insert(key, cmd_pos),
+                None => inner.index_remove(&key),
+            };
         }
         Ok(())
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let mut inner = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
-
-        let mut writer_guard = inner
-            .writer
-            .lock()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-        let mut pos = writer_guard.stream_position()?;
-        println!("pos is {}", pos);
-        
-        if pos > SPLIT_LIMIT {
-            drop(writer_guard);
-            println!("number of readers is {}", inner.readers.len());
-            if inner.readers.len() as u64 > COMPACT_LIMIT {
-                self.compact_locked(&mut inner)?;
-                writer_guard = inner
-                    .writer
-                    .lock()
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-                pos = writer_guard.stream_position()?;
-            } else {
-                let new_generation = inner.current_generation + 1;
-                let (writer, reader) = new_log_file(&inner.directory, new_generation)?;
-                inner.readers.insert(new_generation, reader);
-                inner.current_generation = new_generation;
-                inner.writer = Mutex::new(writer);
-                writer_guard = inner
-                    .writer
-                    .lock()
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-                pos = writer_guard.stream_position()?;
+        let mut inner = self.inner.write();
+        self.set_locked(&mut inner, key, value)
+    }
+
+    fn set_locked(&self, inner: &mut SharedData, key: String, value: String) -> Result<()> {
+        self.set_at_locked(inner, key, value, now_millis(), None, None)
+    }
+
+    // `timestamp` is the write time retention is measured from.
+    fn set_at_locked(
+        &self,
+        inner: &mut SharedData,
+        key: String,
+        value: String,
+        timestamp: u64,
+        content_type: Option<ContentType>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.validate(&key, &value)?;
+        let old = self.aggregated_value(inner, &key)?;
+        let new = self.options.aggregated(&key).then(|| value.clone());
+        let synced = self.options.synced(&key).then(|| value.clone());
+        let checksum = Some(value_checksum(&value));
+        let (value, blob) = self.separate_value(inner, &key, value)?;
+        let cmd = Command::Set {
+            key,
+            value,
+            timestamp: Some(timestamp),
+            blob,
+            content_type,
+            checksum,
+            expires_at,
+        };
+        let mut cmd_pos = self.append_command(inner, &cmd)?;
+        cmd_pos.blob = blob;
+        if let Command::Set { key, .. } = cmd {
+            update_aggregates(inner, &key, old.as_deref(), new.as_deref());
+            self.record_access(inner, &key);
+            inner.index_insert(key.clone(), cmd_pos);
+            if synced.is_some() {
+                self.record_sync_write(inner, &key, synced)?;
             }
+            self.evict_locked(inner, &key)?;
         }
-        serde_json::to_writer(&mut *writer_guard, &cmd)?;
-        writer_guard.flush()?;
-        let ending_position = writer_guard.stream_position()?;
-        let len = ending_position - pos;
-        let generation = inner.current_generation;
+        Ok(())
+    }
+
+    // Moves values over the blob threshold into a blob segment, leaving the
+    // log record an empty value and the pointer.
+    fn separate_value(
+        &self,
+        inner: &mut SharedData,
+        key: &str,
+        value: String,
+    ) -> Result<(String, Option<BlobRef>)> {
+        match self.options.blob_threshold {
+            Some(threshold) if value.len() as u64 >= threshold => {
+                check_writable(inner)?;
+                if blob::blob_writer_full(inner)? {
+                    self.before_seal(inner)?;
+                }
+                // The blob is durable before the record that points at it.
+                let blob = blob::append_blob(inner, key, &value)?;
+                Ok((String::new(), Some(blob)))
+            }
+            _ => Ok((value, None)),
+        }
+    }
 
-        drop(writer_guard); // Release writer lock
+    // The key's current value if it falls under an aggregate, so a write can
+    // back out its old contribution.
+    fn aggregated_value(&self, inner: &SharedData, key: &str) -> Result<Option<String>> {
+        if !self.options.aggregated(key) {
+            return Ok(None);
+        }
+        self.get_locked(inner, key)
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            inner.index.insert(
-                key,
-                CommandPos {
-                    pos,
-                    len,
-                    generation,
-                },
-            );
+    // Seals the active generation, by compacting instead when enough sealed
+    // segments or dead bytes have built up.
+    pub(crate) fn seal_active_locked(&self, inner: &mut SharedData) -> Result<()> {
+        self.classify_active_segment(inner);
+        if let Some(churning) = self.churn_compaction_due(inner) {
+            self.compact_segments_locked(inner, Some(churning))?;
+        } else if inner.readers.len() as u64 > self.options.compaction_segments()
+            || self.garbage_compaction_due(inner)
+        {
+            self.compact_locked(inner)?;
+        } else {
+            self.before_seal(inner)?;
+            rotate_locked(inner, &self.options)?;
+        }
+        Ok(())
+    }
+
+    // Seals the active generation first if the rotation policy says so, then
+    // appends and flushes `cmd`.
+    fn append_command(&self, inner: &mut SharedData, cmd: &Command) -> Result<CommandPos> {
+        check_writable(inner)?;
+        recovery::clear_clean_marker(inner)?;
+        inner.apply_repairs();
+        let pos = inner.writer()?.stream_position()?;
+        if self.options.rotation.should_rotate(pos, inner.generation_started) {
+            self.seal_active_locked(inner)?;
+        }
+
+        let format = inner.segment_format(inner.current_generation);
+        let mut writer_guard = inner.writer()?;
+        let (pos, len) = write_record(&mut writer_guard, format, cmd, self.options.record_alignment)?;
+        writer_guard.flush()?;
+        drop(writer_guard);
+        let (sets, removes) = cmd.op_counts();
+        inner.records_written += sets + removes;
+        inner.tombstones_written += removes;
+        self.after_append(inner)?;
+        let cmd_pos = CommandPos {
+            pos,
+            len,
+            generation: inner.current_generation,
+            blob: None,
+        };
+        inner.active_hint.record(cmd, cmd_pos);
+        Ok(cmd_pos)
+    }
+
+    fn validate(&self, key: &str, value: &str) -> Result<()> {
+        for (prefix, validator) in &self.options.validators {
+            if key.starts_with(prefix.as_str()) {
+                validator
+                    .validate(key, value)
+                    .map_err(|reason| KvError::InvalidValue {
+                        key: key.to_string(),
+                        prefix: prefix.clone(),
+                        reason,
+                    })?;
+            }
         }
         Ok(())
     }
 
     pub fn get(&self, key: &str) -> Result<Option<String>> {
-        let inner = self
-            .inner
-            .read()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
+        let inner = self.inner.read();
+        self.read_locked(&inner, key)
+    }
+
+    // Reads every key under one read lock. Batches, renames and
+    // transactions hold the write lock for their whole update, so the
+    // values come from a single point in time: either all of a concurrent
+    // batch or none of it.
+    pub fn get_many<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Result<Vec<Option<String>>> {
+        let inner = self.inner.read();
+        keys.into_iter()
+            .map(|key| self.read_locked(&inner, key.as_ref()))
+            .collect()
+    }
+
+    // A read on a caller's behalf, counted in the key's access stats.
+    fn read_locked(&self, inner: &SharedData, key: &str) -> Result<Option<String>> {
+        Ok(self.read_tagged_locked(inner, key)?.map(|(value, _)| value))
+    }
+
+    fn read_tagged_locked(&self, inner: &SharedData, key: &str) -> Result<Option<(String, Option<ContentType>)>> {
+        Ok(self
+            .read_entry_locked(inner, key)?
+            .map(|(value, metadata)| (value, metadata.content_type)))
+    }
+
+    fn read_entry_locked(&self, inner: &SharedData, key: &str) -> Result<Option<(String, ValueMetadata)>> {
+        let entry = self.get_entry_locked(inner, key)?;
+        if entry.is_some() {
+            self.record_access(inner, key);
+        }
+        Ok(entry)
+    }
+
+    fn get_locked(&self, inner: &SharedData, key: &str) -> Result<Option<String>> {
+        Ok(self.get_tagged_locked(inner, key)?.map(|(value, _)| value))
+    }
+
+    fn get_tagged_locked(&self, inner: &SharedData, key: &str) -> Result<Option<(String, Option<ContentType>)>> {
+        Ok(self
+            .get_entry_locked(inner, key)?
+            .map(|(value, metadata)| (value, metadata.content_type)))
+    }
+
+    // Values recovered by the fallback scan come back without metadata.
+    fn get_entry_locked(&self, inner: &SharedData, key: &str) -> Result<Option<(String, ValueMetadata)>> {
         let cmd_pos = match inner.index.get(key) {
             Some(value) => *value,
-            None => return Ok(None),
+            None => return Ok(self.fallback_scan(inner, key)?.map(|value| (value, ValueMetadata::default()))),
         };
-        if let Some(reader) = inner.readers.get(&cmd_pos.generation) {
-            let mut reader_guard = reader
-                .lock()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-            reader_guard.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let reader_guard = (&mut *reader_guard).take(cmd_pos.len);
-            let cmd = serde_json::from_reader(reader_guard)?;
-            match cmd {
-                Command::Set { value, .. } => Ok(Some(value)),
-                _ => Ok(None),
+        match self.read_command(inner, &cmd_pos, key)? {
+            Some(Command::Set {
+                value,
+                timestamp,
+                blob,
+                content_type,
+                checksum,
+                expires_at,
+                ..
+            }) => {
+                if self.value_expired(inner, &cmd_pos, key, timestamp, expires_at, now_millis())? {
+                    return Ok(None);
+                }
+                let value = match blob {
+                    Some(blob) => blob::read_blob(inner, &blob)?,
+                    None => value,
+                };
+                if checksum.is_some_and(|checksum| checksum != value_checksum(&value)) {
+                    return Err(KvError::ChecksumMismatch(key.to_string()));
+                }
+                let metadata = ValueMetadata {
+                    written_at: timestamp,
+                    content_type,
+                    expires_at,
+                };
+                Ok(Some((value, metadata)))
             }
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Log file for generation {} not found", cmd_pos.generation),
-            ))
+            _ => Ok(None),
         }
     }
 
-    pub fn remove(&mut self, key: impl Into<String>) -> Result<()> {
-        let cmd = Command::Remove { key: key.into() };
-        let mut inner = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
-
-        let mut writer_guard = inner
-            .writer
-            .lock()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
-        let pos = writer_guard.stream_position()?;
-
-        if pos > SPLIT_LIMIT {
-            drop(writer_guard);
-            if inner.readers.len() as u64 > COMPACT_LIMIT {
-                self.compact_locked(&mut inner)?;
-            } else {
-                let new_generation = inner.current_generation + 1;
-                let (writer, reader) = new_log_file(&inner.directory, new_generation)?;
+    // Whether the value written at `timestamp` reads as missing at `now`,
+    // by its own expiry or the key's retention.
+    fn value_expired(
+        &self,
+        inner: &SharedData,
+        cmd_pos: &CommandPos,
+        key: &str,
+        timestamp: Option<u64>,
+        expires_at: Option<u64>,
+        now: u64,
+    ) -> Result<bool> {
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Ok(true);
+        }
+        if self.options.retention_for(key).is_none() {
+            return Ok(false);
+        }
+        let written = match timestamp {
+            Some(ts) => ts,
+            None => segment_mtime_millis(&inner.directory, cmd_pos.generation)?,
+        };
+        Ok(self.options.retention_expired(key, written))
+    }
 
-                inner.writer = Mutex::new(writer);
-                inner.readers.insert(new_generation, reader);
-                inner.current_generation = new_generation;
+    // The record at `cmd_pos`, narrowed to the last op on `key` if it is a
+    // batch.
+    fn read_command(&self, inner: &SharedData, cmd_pos: &CommandPos, key: &str) -> Result<Option<Command>> {
+        let Some(reader) = inner.readers.get(&cmd_pos.generation) else {
+            return match self.relocate(inner, cmd_pos, key)? {
+                Some(fresh) => self.read_command(inner, &fresh, key),
+                None => Ok(None),
+            };
+        };
+        let mut reader_guard = reader.lock()?;
+        reader_guard.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd = reader.format().decode((&mut *reader_guard).take(cmd_pos.len))?;
+        Ok(cmd.into_ops().into_iter().rev().find(|op| op.key() == Some(key)))
+    }
+
+    // Diagnostic path for index misses: looks for the key's last record in the
+    // newest `fallback_scan_segments` segments, newest first.
+    fn fallback_scan(&self, inner: &SharedData, key: &str) -> Result<Option<String>> {
+        let limit = match self.options.fallback_scan_segments {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        for (generation, reader) in inner.readers.iter().rev().take(limit) {
+            match find_in_generation(&inner.directory, *generation, reader.format(), key)? {
+                Some(Some(value)) => {
+                    eprintln!(
+                        "Index miss for key {:?} recovered from generation {}",
+                        key, generation
+                    );
+                    inner.fallback_hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Some(value));
+                }
+                Some(None) => return Ok(None),
+                None => continue,
             }
-            writer_guard = inner
-                .writer
-                .lock()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Mutex poisoned"))?;
         }
-        
-        serde_json::to_writer(&mut *writer_guard, &cmd)?;
-        writer_guard.flush()?;
-        drop(writer_guard); // Release writer lock
+        Ok(None)
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> Result<()> {
+        let mut inner = self.inner.write();
+        self.remove_locked(&mut inner, key.into())
+    }
 
-        if let Command::Remove { key } = cmd {
-            inner.index.remove(&key);
+    fn remove_locked(&self, inner: &mut SharedData, key: String) -> Result<()> {
+        let old = self.aggregated_value(inner, &key)?;
+        let cmd = Command::Remove {
+            key,
+            timestamp: Some(now_millis()),
+        };
+        self.append_command(inner, &cmd)?;
+        if let Command::Remove { key, .. } = cmd {
+            update_aggregates(inner, &key, old.as_deref(), None);
+            inner.index_remove(&key);
+            if self.options.synced(&key) {
+                self.record_sync_write(inner, &key, None)?;
+            }
         };
         Ok(())
     }
 
+    // Runs `f` while holding the store's write lock, so no other reader or
+    // writer interleaves with its operations. Writes are applied as they are
+    // made; an error part way through does not roll back earlier ones.
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut Transaction<'_>) -> Result<T>) -> Result<T> {
+        let mut inner = self.inner.write();
+        let mut txn = Transaction {
+            store: self,
+            inner: &mut inner,
+        };
+        f(&mut txn)
+    }
+
+    // Tombstones every key in one batch record under a single lock
+    // acquisition. Returns how many of the keys were present.
+    pub fn remove_many<K: Into<String>>(&mut self, keys: impl IntoIterator<Item = K>) -> Result<usize> {
+        let commands: Vec<Command> = keys
+            .into_iter()
+            .map(|key| Command::Remove {
+                key: key.into(),
+                timestamp: Some(now_millis()),
+            })
+            .collect();
+        if commands.is_empty() {
+            return Ok(0);
+        }
+        let mut inner = self.inner.write();
+
+        let mut olds = HashMap::new();
+        for op in &commands {
+            if let Some(key) = op.key()
+                && let Some(old) = self.aggregated_value(&inner, key)?
+            {
+                olds.insert(key.to_string(), old);
+            }
+        }
+
+        let cmd = Command::Batch { commands };
+        self.append_command(&mut inner, &cmd)?;
+        let mut removed = 0;
+        for op in cmd.into_ops() {
+            if let Command::Remove { key, .. } = op {
+                if let Some(old) = olds.remove(&key) {
+                    update_aggregates(&mut inner, &key, Some(&old), None);
+                }
+                if inner.index_remove(&key).is_some() {
+                    removed += 1;
+                }
+                if self.options.synced(&key) {
+                    self.record_sync_write(&mut inner, &key, None)?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn metadata(&self) -> Result<StoreMetadata> {
+        let inner = self.inner.read();
+        Ok(inner.metadata.clone())
+    }
+
+    // Count and numeric sum of the live keys under `prefix`, or `None` if
+    // no aggregate was registered for it in `Options`.
+    pub fn aggregate(&self, prefix: &str) -> Result<Option<Aggregate>> {
+        let inner = self.inner.read();
+        Ok(inner.aggregates.get(prefix).copied())
+    }
+
+    // Rebuilds every aggregate from the index. `known` supplies values that
+    // are already in memory so they need not be read back from disk.
+    fn recompute_aggregates(
+        &self,
+        inner: &SharedData,
+        known: &HashMap<String, String>,
+    ) -> Result<HashMap<String, Aggregate>> {
+        let mut aggregates: HashMap<String, Aggregate> = self
+            .options
+            .aggregates
+            .iter()
+            .map(|prefix| (prefix.clone(), Aggregate::default()))
+            .collect();
+        for key in inner.index.keys() {
+            if !self.options.aggregated(key) {
+                continue;
+            }
+            let value = match known.get(key) {
+                Some(value) => Some(value.clone()),
+                None => self.get_locked(inner, key)?,
+            };
+            if let Some(value) = value {
+                for (prefix, aggregate) in aggregates.iter_mut() {
+                    if key.starts_with(prefix.as_str()) {
+                        aggregate.add(&value);
+                    }
+                }
+            }
+        }
+        Ok(aggregates)
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let inner = self.inner.read();
+        Ok(StoreStats {
+            segment_count: inner.readers.len(),
+            open_segment_count: inner.readers.values().filter(|reader| reader.is_open()).count(),
+            key_count: inner.index.len(),
+            current_generation: inner.current_generation,
+            compacting: inner.compacting,
+            fallback_hits: inner.fallback_hits.load(Ordering::Relaxed),
+            low_disk: inner.low_disk.load(Ordering::Relaxed),
+            blob_segment_count: inner.blob_segments.len(),
+            blob_live_bytes: inner.blob_segments.values().map(|s| s.live_bytes).sum(),
+            blob_total_bytes: inner.blob_segments.values().map(|s| s.total_bytes).sum(),
+            blob_bytes_reclaimed: inner.blob_bytes_reclaimed,
+            live_bytes: inner.live_bytes,
+            dead_bytes: inner.dead_bytes.values().sum(),
+            evicted_keys: inner.evicted_keys,
+            expired_keys: inner.expired_keys,
+            records_written: inner.records_written,
+            tombstones_written: inner.tombstones_written,
+            records_rewritten: inner.records_rewritten,
+            compaction_bytes_reclaimed: inner.compaction_bytes_reclaimed,
+            read_repairs: inner.read_repairs.load(Ordering::Relaxed),
+        })
+    }
+
+    // Aggregates live keys by their first `depth` separator-delimited
+    // segments. The empty prefix at depth 0 holds the totals; `bytes`
+    // counts blob-separated values as well as log records. Keys that reads
+    // treat as expired, by TTL or retention, are left out.
+    pub fn keyspace_stats(&self, depth: usize, separator: char) -> Result<Vec<PrefixStats>> {
+        let inner = self.inner.read();
+        let now = now_millis();
+        let mut prefixes: std::collections::BTreeMap<String, PrefixStats> =
+            std::collections::BTreeMap::new();
+        for (key, cmd_pos) in inner.index.iter() {
+            if self.expired(&inner, cmd_pos, key, now)? {
+                continue;
+            }
+            let mut prefix = String::new();
+            let segments = key.split(separator).collect::<Vec<_>>();
+            let levels = depth.min(segments.len() - 1);
+            for level in 0..=levels {
+                if level > 0 {
+                    prefix.push_str(segments[level - 1]);
+                    prefix.push(separator);
+                }
+                let entry = prefixes.entry(prefix.clone()).or_insert_with(|| PrefixStats {
+                    prefix: prefix.clone(),
+                    depth: level,
+                    keys: 0,
+                    bytes: 0,
+                });
+                entry.keys += 1;
+                entry.bytes += cmd_pos.live_bytes();
+            }
+        }
+        Ok(prefixes.into_values().collect())
+    }
+
+    // Every indexed key, sorted. Keys past their retention are included
+    // until compaction drops them.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let inner = self.inner.read();
+        let mut keys: Vec<String> = inner.index.keys().cloned().collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
     pub fn compact(&mut self) -> Result<()> {
-        let mut inner = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
+        let mut inner = self.inner.write();
+        if inner.writer.is_none() {
+            return Err(read_only_error().into());
+        }
         self.compact_locked(&mut inner)
     }
 
-    fn compact_locked(&self, inner: &mut RwLockWriteGuard<SharedData>) -> Result<()> {
+    fn compact_locked(&self, inner: &mut SharedData) -> Result<()> {
+        self.compact_segments_locked(inner, None)
+    }
+
+    // With `only`, compacts just those of the sealed segments. Older
+    // segments left in place may still hold a key, so tombstones are kept,
+    // and only records the index still points at are carried over: any
+    // other was overwritten in a segment that is not being rewritten.
+    fn compact_segments_locked(&self, inner: &mut SharedData, only: Option<BTreeSet<u64>>) -> Result<()> {
         if inner.compacting {
             return Ok(());
         }
+        recovery::clear_clean_marker(inner)?;
         inner.compacting = true;
+        let selected = |generation: &u64| only.as_ref().is_none_or(|only| only.contains(generation));
 
+        // Output is split into segments of the rotation size, so generations
+        // are reserved for as many as the inputs could fill. Output is never
+        // larger than its inputs by more than the odd added timestamp, which
+        // the last segment absorbs.
+        let input_bytes: u64 = inner
+            .readers
+            .keys()
+            .filter(|generation| selected(generation))
+            .map(|generation| {
+                fs::metadata(inner.directory.join(format!("{}.db", generation)))
+                    .map(|m| m.len())
+                    .unwrap_or(0)
+            })
+            .sum();
+        let output_segments = match self.options.rotation.max_bytes {
+            Some(max_bytes) if max_bytes > 0 => input_bytes / max_bytes + 1,
+            _ => 1,
+        };
+        self.before_seal(inner)?;
         let compaction_generation = inner.current_generation + 1;
-        inner.current_generation += 2;
-        let (writer, reader) = new_log_file(&inner.directory, inner.current_generation)?;
-        inner.writer = Mutex::new(writer);
+        inner.current_generation += output_segments + 1;
+        let output_generations = compaction_generation..inner.current_generation;
+        let comp_format = self.options.record_format;
+        manifest::record_segment_formats(
+            &inner.directory,
+            &mut inner.metadata,
+            compaction_generation..=inner.current_generation,
+            comp_format,
+        )?;
+        inner.write_active_hint();
+        let read_buffer = self.options.read_buffer_bytes();
+        let (writer, reader) = new_log_file(&inner.directory, inner.current_generation, comp_format, read_buffer)?;
+        inner.writer = Some(Mutex::new(writer));
+        inner.writer_generation = inner.current_generation;
+        inner.generation_started = SystemTime::now();
         let current_generation = inner.current_generation;
         inner.readers.insert(current_generation, reader);
 
-        let (mut comp_writer, comp_reader) = new_log_file(&inner.directory, compaction_generation)?;
-        let compaction_generations: Vec<u64> = inner
+        // Output is always written in the current format, which is how
+        // segments in older formats get upgraded without an offline pass.
+        let (comp_writer, comp_reader) = new_log_file(&inner.directory, compaction_generation, comp_format, read_buffer)?;
+        let compaction_inputs: Vec<(u64, RecordFormat)> = inner
             .readers
-            .keys()
-            .copied()
-            .filter(|g| g < &compaction_generation)
+            .range(..compaction_generation)
+            .filter(|(generation, _)| selected(generation))
+            .map(|(generation, reader)| (*generation, reader.format()))
             .collect();
+        let partial = only.is_some();
+        let compaction_generations: Vec<u64> = compaction_inputs.iter().map(|(generation, _)| *generation).collect();
         println!("Spawning compaction for generations: {:?}", compaction_generations);
+        let store = self.clone();
         let thread_inner = self.inner.clone();
+        let options = self.options.clone();
         let directory = inner.directory.clone();
-        std::thread::spawn(move || {
-            let try_compact = || -> std::io::Result<()> {
-                let mut compacted_map: HashMap<String, String> = HashMap::new();
-                for gen_id in &compaction_generations {
-                    let path = directory.join(format!("{}.db", gen_id));
-                    let reader = BufReader::new(fs::OpenOptions::new().read(true).open(&path)?);
-                    let mut stream =
-                        serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-
-                    while let Some(command) = stream.next() {
-                        match command? {
-                            Command::Set { key, value } => {
-                                compacted_map.insert(key, value);
-                            }
-                            Command::Remove { key } => {
-                                compacted_map.remove(&key);
-                            }
+        let cancel = Arc::new(AtomicBool::new(false));
+        inner.compaction_cancel = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            let mut comp_writer = comp_writer;
+            let mut outputs = vec![(compaction_generation, comp_reader)];
+            let try_compact = || -> std::io::Result<Vec<u64>> {
+                let mut sealed = Vec::new();
+                let mut compacted_map = scan_generations(&directory, &compaction_inputs, &cancel, partial)?;
+                if partial {
+                    let inner = thread_inner.read();
+                    compacted_map.retain(|key, cmd| match inner.index.get(key) {
+                        Some(pos) => {
+                            matches!(cmd, Command::Set { .. }) && compaction_generations.contains(&pos.generation)
                         }
-                    }
+                        None => matches!(cmd, Command::Remove { .. }),
+                    });
                 }
                 let mut new_pos_map = HashMap::new();
-                for (key, value) in compacted_map {
-                    let pos = comp_writer.stream_position()?;
-                    let cmd = Command::Set { key, value };
-                    serde_json::to_writer(&mut comp_writer, &cmd)?;
-                    let len = comp_writer.stream_position()? - pos;
-                    if let Command::Set { key, .. } = cmd {
-                        new_pos_map.insert(
-                            key,
-                            CommandPos {
-                                pos,
-                                len,
-                                generation: compaction_generation,
-                            },
-                        );
+                let mut tombstones = Vec::new();
+                let mut aggregated_values = HashMap::new();
+                let mut rewritten = 0;
+                for (key, cmd) in compacted_map {
+                    check_cancelled(&cancel)?;
+                    if let Command::Set {
+                        timestamp: Some(ts),
+                        expires_at,
+                        ..
+                    } = cmd
+                        && (options.retention_expired(&key, ts)
+                            || expires_at.is_some_and(|expires_at| expires_at <= now_millis()))
+                    {
+                        continue;
+                    }
+                    let mut output_generation = outputs.last().expect("at least one output").0;
+                    if options
+                        .rotation
+                        .max_bytes
+                        .is_some_and(|max| comp_writer.stream_position().is_ok_and(|len| len > max))
+                        && output_generations.contains(&(output_generation + 1))
+                    {
+                        output_generation += 1;
+                        let (writer, reader) =
+                            new_log_file(&directory, output_generation, comp_format, options.read_buffer_bytes())?;
+                        sealed.push(std::mem::replace(&mut comp_writer, writer));
+                        outputs.push((output_generation, reader));
+                    }
+                    let (pos, len) = write_record(&mut comp_writer, comp_format, &cmd, options.record_alignment)?;
+                    rewritten += 1;
+                    match cmd {
+                        Command::Set { key, value, blob, .. } => {
+                            // Separated values are read back if needed.
+                            if options.aggregated(&key) && blob.is_none() {
+                                aggregated_values.insert(key.clone(), value);
+                            }
+                            new_pos_map.insert(
+                                key,
+                                CommandPos {
+                                    pos,
+                                    len,
+                                    generation: output_generation,
+                                    blob,
+                                },
+                            );
+                        }
+                        Command::Remove { key, .. } => tombstones.push((key, output_generation)),
+                        Command::Batch { .. } => {}
                     }
                 }
-                comp_writer.flush()?;
-                let mut inner_guard = thread_inner
-                    .write()
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "RwLock poisoned"))?;
+                sealed.push(comp_writer);
+                sync_segments(sealed)?;
+                let mut hints: HashMap<u64, Hint> = HashMap::new();
+                for (key, cmd_pos) in &new_pos_map {
+                    hints.entry(cmd_pos.generation).or_default().insert(key.clone(), Some(*cmd_pos));
+                }
+                for (key, generation) in tombstones {
+                    hints.entry(generation).or_default().insert(key, None);
+                }
+                let mut output_bytes = 0;
+                for (generation, _) in &outputs {
+                    hints.remove(generation).unwrap_or_default().write(&directory, *generation)?;
+                    output_bytes += fs::metadata(directory.join(format!("{}.db", generation)))?.len();
+                }
+                let mut inner_guard = thread_inner.write();
+                // Last chance to back out: past this point the swap is visible.
+                check_cancelled(&cancel)?;
+                inner_guard.records_rewritten += rewritten;
+                inner_guard.compaction_bytes_reclaimed += input_bytes.saturating_sub(output_bytes);
                 for gen_id in &compaction_generations {
-                    inner_guard.readers.remove(&gen_id);
+                    inner_guard.readers.remove(gen_id);
                 }
-                inner_guard
-                    .readers
-                    .insert(compaction_generation, comp_reader);
+                let added: Vec<u64> = outputs.iter().map(|(generation, _)| *generation).collect();
+                inner_guard.readers.extend(outputs.drain(..));
+                inner_guard.compacted_into = Some(output_generations.clone());
                 for (k, new_pos) in new_pos_map {
-                    if let Some(current_pos) = inner_guard.index.get(&k) {
-                        if compaction_generations.contains(&current_pos.generation) {
-                            inner_guard.index.insert(k, new_pos);
+                    if let Some(current_pos) = inner_guard.index.get(&k)
+                        && compaction_generations.contains(&current_pos.generation)
+                    {
+                        inner_guard.index_insert(k, new_pos);
+                    }
+                }
+                // Whatever still points into the compacted generations was
+                // dropped by compaction (e.g. past retention).
+                let dropped: Vec<String> = inner_guard
+                    .index
+                    .iter()
+                    .filter(|(_, pos)| compaction_generations.contains(&pos.generation))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in dropped {
+                    inner_guard.index_remove(&key);
+                }
+                let SharedData { readers, dead_bytes, .. } = &mut *inner_guard;
+                dead_bytes.retain(|generation, _| readers.contains_key(generation));
+                if !options.aggregates.is_empty() {
+                    // Values still pointing into the compaction output are the
+                    // ones just written; anything newer is read back.
+                    let known: HashMap<String, String> = aggregated_values
+                        .into_iter()
+                        .filter(|(key, _)| {
+                            inner_guard
+                                .index
+                                .get(key)
+                                .is_some_and(|pos| output_generations.contains(&pos.generation))
+                        })
+                        .collect();
+                    let recomputed = store
+                        .recompute_aggregates(&inner_guard, &known)
+                        .map_err(io::Error::other)?;
+                    for (prefix, aggregate) in &recomputed {
+                        if inner_guard
+                            .aggregates
+                            .get(prefix)
+                            .is_some_and(|current| !current.matches(aggregate))
+                        {
+                            eprintln!("Aggregate for prefix {:?} drifted; corrected by compaction", prefix);
                         }
                     }
+                    inner_guard.aggregates = recomputed;
                 }
                 inner_guard.compacting = false;
                 for gen_id in &compaction_generations {
                     fs::remove_file(directory.join(format!("{}.db", gen_id)))?;
+                    hint::remove_hint(&directory, *gen_id)?;
                 }
-                Ok(())
+                // Also forgets reserved output generations that went unused.
+                let SharedData { readers, metadata, .. } = &mut *inner_guard;
+                manifest::forget_segments(&directory, metadata, |generation| readers.contains_key(&generation))?;
+                Ok(added)
             };
-            if let Err(e) = try_compact() {
-                eprintln!("Compaction failed: {}", e);
-                let _ = thread_inner.write().map(|mut inner| inner.compacting = false);
+            match try_compact() {
+                Ok(added) => {
+                    let event = CompactionEvent {
+                        removed: compaction_generations,
+                        added,
+                    };
+                    for listener in &options.compaction_listeners {
+                        listener.on_compaction_complete(&event);
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        println!("Compaction of generations {:?} cancelled", compaction_generations);
+                    } else {
+                        eprintln!("Compaction failed: {}", e);
+                    }
+                    for (generation, _) in outputs {
+                        let comp_path = directory.join(format!("{}.db", generation));
+                        if let Err(e) = fs::remove_file(&comp_path) {
+                            eprintln!("Failed to remove {}: {}", comp_path.display(), e);
+                        }
+                        if let Err(e) = hint::remove_hint(&directory, generation) {
+                            eprintln!("Failed to remove the hint for generation {}: {}", generation, e);
+                        }
+                    }
+                    thread_inner.write().compacting = false;
+                }
             }
         });
+        inner.compaction_thread = Some(handle);
         Ok(())
     }
+
+    // Asks a running compaction to stop at the next record boundary and waits
+    // for it to clean up. Returns whether a compaction was running.
+    pub fn cancel_compaction(&self) -> Result<bool> {
+        let handle = {
+            let mut inner = self.inner.write();
+            if !inner.compacting {
+                return Ok(false);
+            }
+            inner.compaction_cancel.store(true, Ordering::Relaxed);
+            inner.compaction_thread.take()
+        };
+        if let Some(handle) = handle {
+            handle
+                .join()
+                .map_err(|_| io::Error::other("Compaction thread panicked"))?;
+        }
+        Ok(true)
+    }
+}
+
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    inner: &'a mut SharedData,
+}
+
+impl Transaction<'_> {
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.read_locked(self.inner, key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set_locked(self.inner, key, value)
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> Result<()> {
+        self.store.remove_locked(self.inner, key.into())
+    }
+}
+
+fn update_aggregates(inner: &mut SharedData, key: &str, old: Option<&str>, new: Option<&str>) {
+    for (prefix, aggregate) in inner.aggregates.iter_mut() {
+        if !key.starts_with(prefix.as_str()) {
+            continue;
+        }
+        if let Some(old) = old {
+            aggregate.subtract(old);
+        }
+        if let Some(new) = new {
+            aggregate.add(new);
+        }
+    }
+}
+
+// Writes `cmd` at the end of a segment and returns where it starts and
+// its length. With an alignment, a record that would straddle a boundary
+// is first pushed to the next one with spaces, which readers skip, so a
+// torn block write damages as few records as possible.
+fn write_record(
+    writer: &mut BufWriter<File>,
+    format: RecordFormat,
+    cmd: &Command,
+    alignment: Option<u64>,
+) -> io::Result<(u64, u64)> {
+    let record = format.encode(cmd)?;
+    let mut pos = writer.stream_position()?;
+    if let Some(block) = alignment.filter(|block| *block > 0) {
+        let offset = pos % block;
+        if offset > 0 && offset + record.len() as u64 > block {
+            let padding = block - offset;
+            io::copy(&mut io::repeat(b' ').take(padding), writer)?;
+            pos += padding;
+        }
+    }
+    writer.write_all(&record)?;
+    Ok((pos, record.len() as u64))
+}
+
+fn rotate_locked(inner: &mut SharedData, options: &Options) -> io::Result<()> {
+    let new_generation = inner.current_generation + 1;
+    let format = options.record_format;
+    manifest::record_segment_formats(&inner.directory, &mut inner.metadata, [new_generation], format)?;
+    inner.write_active_hint();
+    let (writer, reader) = new_log_file(&inner.directory, new_generation, format, options.read_buffer_bytes())?;
+    inner.readers.insert(new_generation, reader);
+    inner.current_generation = new_generation;
+    inner.writer = Some(Mutex::new(writer));
+    inner.writer_generation = new_generation;
+    inner.generation_started = SystemTime::now();
+    Ok(())
+}
+
+// Scans segments on up to `available_parallelism` threads, then folds the
+// per-segment results oldest first so later writes win. Sorted, so the same
+// live records always compact to the same bytes. Removed keys are dropped
+// unless `keep_tombstones`.
+fn scan_generations(
+    directory: &Path,
+    generations: &[(u64, RecordFormat)],
+    cancel: &AtomicBool,
+    keep_tombstones: bool,
+) -> io::Result<BTreeMap<String, Command>> {
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(generations.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<io::Result<SegmentScan>>>> =
+        generations.iter().map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= generations.len() {
+                        break;
+                    }
+                    let (generation, format) = generations[i];
+                    let scan = scan_generation(directory, generation, format, cancel);
+                    *results[i].lock() = Some(scan);
+                }
+            });
+        }
+    });
+
+    let mut compacted_map = BTreeMap::new();
+    for result in results {
+        let scan = result
+            .into_inner()
+            .ok_or_else(|| io::Error::other("Segment scan did not complete"))??;
+        for (key, cmd) in scan {
+            match cmd {
+                Command::Set { .. } => compacted_map.insert(key, cmd),
+                Command::Remove { .. } if keep_tombstones => compacted_map.insert(key, cmd),
+                Command::Remove { .. } | Command::Batch { .. } => compacted_map.remove(&key),
+            };
+        }
+    }
+    Ok(compacted_map)
+}
+
+// Flushes and fsyncs finished segments on up to `available_parallelism`
+// threads, since on high-latency storage each sync mostly waits.
+fn sync_segments(writers: Vec<BufWriter<File>>) -> io::Result<()> {
+    let files = writers
+        .into_iter()
+        .map(|writer| writer.into_inner().map_err(|e| e.into_error()))
+        .collect::<io::Result<Vec<File>>>()?;
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(files.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<io::Result<()>>> = files.iter().map(|_| Mutex::new(Ok(()))).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= files.len() {
+                        break;
+                    }
+                    *results[i].lock() = files[i].sync_all();
+                }
+            });
+        }
+    });
+    results.into_iter().try_for_each(Mutex::into_inner)
+}
+
+// Last command for each key within one segment.
+type SegmentScan = HashMap<String, Command>;
+
+fn scan_generation(
+    directory: &Path,
+    generation: u64,
+    format: RecordFormat,
+    cancel: &AtomicBool,
+) -> io::Result<SegmentScan> {
+    let path = directory.join(format!("{}.db", generation));
+    let (reader, _) = segment::open_records(&path, format)?;
+
+    // Records from before timestamps existed inherit the segment's mtime, an
+    // upper bound on their write time, so their age survives compaction.
+    let segment_mtime = segment_mtime_millis(directory, generation)?;
+    let mut scan = HashMap::new();
+    for record in format.records(reader) {
+        check_cancelled(cancel)?;
+        for op in record?.0.into_ops() {
+            match op {
+                Command::Set {
+                    key,
+                    value,
+                    timestamp,
+                    blob,
+                    content_type,
+                    checksum,
+                    expires_at,
+                } => {
+                    let cmd = Command::Set {
+                        key: key.clone(),
+                        value,
+                        timestamp: timestamp.or(Some(segment_mtime)),
+                        blob,
+                        content_type,
+                        checksum,
+                        expires_at,
+                    };
+                    scan.insert(key, cmd)
+                }
+                Command::Remove { key, timestamp } => scan.insert(key.clone(), Command::Remove { key, timestamp }),
+                Command::Batch { .. } => None,
+            };
+        }
+    }
+    Ok(scan)
+}
+
+// `Some(None)` when the key's last record in the segment is a tombstone.
+fn find_in_generation(
+    directory: &Path,
+    generation: u64,
+    format: RecordFormat,
+    key: &str,
+) -> io::Result<Option<Option<String>>> {
+    let path = directory.join(format!("{}.db", generation));
+    let (reader, _) = segment::open_records(&path, format)?;
+
+    let mut found = None;
+    for record in format.records(reader) {
+        for op in record?.0.into_ops() {
+            match op {
+                Command::Set {
+                    key: k, value, blob, ..
+                } if k == key => {
+                    found = Some(Some(match blob {
+                        Some(blob) => blob::read_blob_from(directory, &blob)?,
+                        None => value,
+                    }))
+                }
+                Command::Remove { key: k, .. } if k == key => found = Some(None),
+                _ => {}
+            }
+        }
+    }
+    Ok(found)
 }
 
+fn segment_mtime_millis(directory: &Path, generation: u64) -> io::Result<u64> {
+    let modified = fs::metadata(directory.join(format!("{}.db", generation)))?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> io::Result<()> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "Compaction cancelled"));
+    }
+    Ok(())
+}
+
+fn check_writable(inner: &SharedData) -> Result<()> {
+    if inner.writer.is_none() {
+        return Err(read_only_error().into());
+    }
+    if inner.low_disk.load(Ordering::Relaxed) {
+        return Err(KvError::ReadOnly(ReadOnlyReason::LowDisk));
+    }
+    Ok(())
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Store is opened read-only")
+}
+
+// New segments start with a header naming their format version.
 fn new_log_file(
     dir: &Path,
     generation: u64,
-) -> io::Result<(BufWriter<File>, Mutex<BufReader<File>>)> {
+    format: RecordFormat,
+    read_buffer: usize,
+) -> io::Result<(BufWriter<File>, SegmentReader)> {
     let path = dir.join(format!("{}.db", generation));
-    let writer = BufWriter::new(
+    let mut writer = BufWriter::new(
         fs::OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
             .open(&path)?,
     );
-    let reader = BufReader::new(fs::OpenOptions::new().read(true).open(&path)?);
-    Ok((writer, Mutex::new(reader)))
+    if writer.get_ref().metadata()?.len() == 0 {
+        writer.write_all(&segment::header(format))?;
+        writer.flush()?;
+    }
+    Ok((writer, SegmentReader::new(path, format, read_buffer)))
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Incompatibility, KvError, Options, RecordFormat, Result, now_millis};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+// Bumped whenever older builds could misread what newer ones write.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+// On-disk features this build understands.
+const KNOWN_FEATURES: &[&str] = &["blob_segments", "binary_records", "segment_headers"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoreMetadata {
+    pub format_version: u32,
+    // Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub features: BTreeSet<String>,
+    // Segments whose records are not JSON. Compaction output may be in a
+    // newer format than the segments it replaces, so one directory can
+    // hold both.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub segment_formats: BTreeMap<u64, RecordFormat>,
+}
+
+impl StoreMetadata {
+    pub fn segment_format(&self, generation: u64) -> RecordFormat {
+        self.segment_formats.get(&generation).copied().unwrap_or_default()
+    }
+}
+
+// Reads and checks the manifest, writing one for new or pre-manifest
+// stores and recording any feature these options start using.
+pub(crate) fn load_or_init(directory: &Path, options: &Options) -> Result<StoreMetadata> {
+    let path = directory.join(MANIFEST_FILE);
+    let existing = match fs::read(&path) {
+        Ok(bytes) => Some(
+            serde_json::from_slice::<StoreMetadata>(&bytes)
+                .map_err(|e| KvError::Incompatible(Incompatibility::Unreadable(e.to_string())))?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut metadata = match existing {
+        Some(metadata) => {
+            check_compatible(&metadata)?;
+            metadata
+        }
+        None => StoreMetadata {
+            format_version: FORMAT_VERSION,
+            created_at: now_millis(),
+            features: BTreeSet::new(),
+            segment_formats: BTreeMap::new(),
+        },
+    };
+    let before = metadata.clone();
+    if options.blob_threshold.is_some() {
+        metadata.features.insert("blob_segments".to_string());
+    }
+    if options.record_format == RecordFormat::Binary {
+        metadata.features.insert("binary_records".to_string());
+    }
+    // Every segment this build creates has a header older builds can't read.
+    if !options.read_only {
+        metadata.features.insert("segment_headers".to_string());
+    }
+    if !options.read_only && (metadata != before || !path.exists()) {
+        write_manifest(directory, &metadata)?;
+    }
+    Ok(metadata)
+}
+
+fn check_compatible(metadata: &StoreMetadata) -> Result<()> {
+    if metadata.format_version > FORMAT_VERSION {
+        return Err(KvError::Incompatible(Incompatibility::FormatVersion {
+            found: metadata.format_version,
+            supported: FORMAT_VERSION,
+        }));
+    }
+    if let Some(feature) = metadata
+        .features
+        .iter()
+        .find(|feature| !KNOWN_FEATURES.contains(&feature.as_str()))
+    {
+        return Err(KvError::Incompatible(Incompatibility::UnknownFeature(feature.clone())));
+    }
+    Ok(())
+}
+
+// Records a feature a store starts using without options asking for it,
+// before anything that needs it is written.
+pub(crate) fn record_feature(directory: &Path, metadata: &mut StoreMetadata, feature: &str) -> io::Result<()> {
+    if metadata.features.contains(feature) {
+        return Ok(());
+    }
+    metadata.features.insert(feature.to_string());
+    write_manifest(directory, metadata)
+}
+
+// Lists new segments that are not JSON. Called before anything is written
+// to them, so a segment is never read in the wrong format.
+pub(crate) fn record_segment_formats(
+    directory: &Path,
+    metadata: &mut StoreMetadata,
+    generations: impl IntoIterator<Item = u64>,
+    format: RecordFormat,
+) -> io::Result<()> {
+    if format == RecordFormat::Json {
+        return Ok(());
+    }
+    metadata
+        .segment_formats
+        .extend(generations.into_iter().map(|generation| (generation, format)));
+    write_manifest(directory, metadata)
+}
+
+// Drops the entries of segments that no longer exist. Called after they
+// are deleted, so a crash in between only leaves stale entries behind.
+pub(crate) fn forget_segments(
+    directory: &Path,
+    metadata: &mut StoreMetadata,
+    exists: impl Fn(u64) -> bool,
+) -> io::Result<()> {
+    let before = metadata.segment_formats.len();
+    metadata.segment_formats.retain(|generation, _| exists(*generation));
+    if metadata.segment_formats.len() == before {
+        return Ok(());
+    }
+    write_manifest(directory, metadata)
+}
+
+// Written to a temporary file and renamed so a crash never leaves a torn
+// manifest behind.
+fn write_manifest(directory: &Path, metadata: &StoreMetadata) -> io::Result<()> {
+    let tmp_path = directory.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = fs::File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&mut file, metadata)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(tmp_path, directory.join(MANIFEST_FILE))
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CommandPos, KeyStats, KvStore, Result};
+
+// Heap bytes a store holds, by what holds them. Estimates: hash tables are
+// counted at capacity with their keys, not with allocator overhead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub index_bytes: u64,
+    // Buffers of the segment and blob files opened for reading so far.
+    pub reader_buffer_bytes: u64,
+    // Per-key tables kept beside the index: the active segment's hint and,
+    // with `Options::track_access`, access stats.
+    pub cache_bytes: u64,
+    // Buffers of the active segment and blob segment writers.
+    pub write_buffer_bytes: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.index_bytes + self.reader_buffer_bytes + self.cache_bytes + self.write_buffer_bytes
+    }
+}
+
+impl KvStore {
+    // Walks every key, so it costs about as much as `keys()`.
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        let inner = self.inner.read();
+        let reader_buffer_bytes = inner
+            .readers
+            .values()
+            .map(|reader| reader.buffer_capacity())
+            .chain(inner.blob_segments.values().map(|segment| segment.buffer_capacity()))
+            .sum::<usize>();
+        let write_buffer_bytes = inner.writer.as_ref().map_or(0, |writer| writer.lock().capacity())
+            + inner.blob_writer.as_ref().map_or(0, |(_, writer)| writer.capacity());
+        Ok(MemoryUsage {
+            index_bytes: table_bytes::<CommandPos>(&inner.index),
+            reader_buffer_bytes: reader_buffer_bytes as u64,
+            cache_bytes: inner.active_hint.heap_bytes() + table_bytes::<KeyStats>(&inner.access.lock()),
+            write_buffer_bytes: write_buffer_bytes as u64,
+        })
+    }
+}
+
+// A slot per unit of capacity plus a control byte, and each key's own
+// allocation.
+pub(crate) fn table_bytes<V>(table: &HashMap<String, V>) -> u64 {
+    let slots = table.capacity() * (size_of::<String>() + size_of::<V>() + 1);
+    let keys: usize = table.keys().map(String::capacity).sum();
+    (slots + keys) as u64
+}
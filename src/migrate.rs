@@ -0,0 +1,65 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CompactionEvent, KvStore, Options, RecordFormat, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationSummary {
+    pub segments_before: usize,
+    pub segments_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl KvStore {
+    // Rewrites every segment of the store at `directory` in `format`, with
+    // a current header, by running one full compaction to the end. As with
+    // any compaction, a crash part way leaves the old segments in charge.
+    // The store must not be open anywhere else; blob segments are kept.
+    pub fn migrate(directory: PathBuf, format: RecordFormat) -> Result<MigrationSummary> {
+        let (segments_before, bytes_before) = segment_usage(&directory)?;
+        let (done, completed) = mpsc::channel();
+        let options = Options::new()
+            .record_format(format)
+            .on_compaction_complete(move |_: &CompactionEvent| {
+                let _ = done.send(());
+            });
+        let mut store = KvStore::open_with(directory.clone(), options)?;
+        store.compact()?;
+        let handle = store.inner.write().compaction_thread.take();
+        if let Some(handle) = handle {
+            handle
+                .join()
+                .map_err(|_| io::Error::other("Compaction thread panicked"))?;
+        }
+        if completed.try_recv().is_err() {
+            return Err(io::Error::other("Compaction failed; the store was left in its old format").into());
+        }
+        store.shutdown()?;
+        drop(store);
+
+        let (segments_after, bytes_after) = segment_usage(&directory)?;
+        Ok(MigrationSummary {
+            segments_before,
+            segments_after,
+            bytes_before,
+            bytes_after,
+        })
+    }
+}
+
+fn segment_usage(directory: &Path) -> io::Result<(usize, u64)> {
+    let mut usage = (0, 0);
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.path().extension().is_some_and(|ext| ext == "db") {
+            usage.0 += 1;
+            usage.1 += entry.metadata()?.len();
+        }
+    }
+    Ok(usage)
+}
@@ -0,0 +1,115 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{KvStore, Options, Result};
+
+#[derive(Default)]
+struct OpenState {
+    partial: Option<KvStore>,
+    result: Option<Result<KvStore>>,
+}
+
+// A store being opened on a background thread.
+pub struct OpenHandle {
+    state: Arc<(Mutex<OpenState>, Condvar)>,
+}
+
+impl KvStore {
+    // Returns immediately; the index is rebuilt on a background thread.
+    pub fn open_background(directory: PathBuf, options: Options) -> OpenHandle {
+        let state = Arc::new((Mutex::new(OpenState::default()), Condvar::new()));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let result = KvStore::create(directory, options).and_then(|store| {
+                if let Ok(mut state) = thread_state.0.lock() {
+                    state.partial = Some(store.clone());
+                }
+                store.load()?;
+                store.start_disk_watchdog()?;
+                store.start_periodic_sync()?;
+                Ok(store)
+            });
+            if let Ok(mut state) = thread_state.0.lock() {
+                state.partial = None;
+                state.result = Some(result);
+            }
+            thread_state.1.notify_all();
+        });
+        OpenHandle { state }
+    }
+
+    // Gives up with `TimedOut` if replay takes longer than `timeout`. The
+    // replay itself keeps running in the background until it finishes.
+    pub fn open_with_timeout(directory: PathBuf, options: Options, timeout: Duration) -> Result<Self> {
+        let handle = Self::open_background(directory, options);
+        if !handle.wait_timeout(timeout)? {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Store did not open within {:?}", timeout),
+            )
+            .into());
+        }
+        handle.wait()
+    }
+}
+
+impl OpenHandle {
+    pub fn is_ready(&self) -> bool {
+        self.state
+            .0
+            .lock()
+            .map(|state| state.result.is_some())
+            .unwrap_or(true)
+    }
+
+    // Returns whether the store finished opening within `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().map_err(|_| io::Error::other("Mutex poisoned"))?;
+        while state.result.is_none() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            state = ready
+                .wait_timeout(state, remaining)
+                .map_err(|_| io::Error::other("Mutex poisoned"))?
+                .0;
+        }
+        Ok(true)
+    }
+
+    pub fn wait(self) -> Result<KvStore> {
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().map_err(|_| io::Error::other("Mutex poisoned"))?;
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = ready
+                .wait(state)
+                .map_err(|_| io::Error::other("Mutex poisoned"))?;
+        }
+    }
+
+    // Reads whatever has been replayed so far, in append order, so a key may
+    // return an older value (or none) until replay reaches its latest record.
+    pub fn get_partial(&self, key: &str) -> Result<Option<String>> {
+        let store = {
+            let state = self
+                .state
+                .0
+                .lock()
+                .map_err(|_| io::Error::other("Mutex poisoned"))?;
+            match (&state.result, &state.partial) {
+                (Some(Ok(store)), _) | (None, Some(store)) => store.clone(),
+                (Some(Err(_)), _) => return Err(io::Error::other("Store failed to open").into()),
+                (None, None) => return Ok(None),
+            }
+        };
+        store.get(key)
+    }
+}
@@ -0,0 +1,519 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{COMPACT_LIMIT, MergeMode, READ_BUFFER, RecordFormat, SPLIT_LIMIT};
+
+pub trait Validator: Send + Sync {
+    fn validate(&self, key: &str, value: &str) -> Result<(), String>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&str, &str) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, key: &str, value: &str) -> Result<(), String> {
+        self(key, value)
+    }
+}
+
+// Told which segments a finished compaction replaced, so indexes derived
+// from segment contents only need to resync those.
+pub trait CompactionListener: Send + Sync {
+    fn on_compaction_complete(&self, event: &CompactionEvent);
+}
+
+impl<F> CompactionListener for F
+where
+    F: Fn(&CompactionEvent) + Send + Sync,
+{
+    fn on_compaction_complete(&self, event: &CompactionEvent) {
+        self(event)
+    }
+}
+
+// Segments by generation, the number in `{generation}.db`. Output is split
+// at the rotation size, so one compaction can add several.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionEvent {
+    pub removed: Vec<u64>,
+    pub added: Vec<u64>,
+}
+
+// Requires values to be JSON; with required fields, a JSON object holding
+// at least those top-level fields.
+#[derive(Debug, Clone, Default)]
+pub struct JsonValidator {
+    required_fields: Vec<String>,
+}
+
+impl JsonValidator {
+    pub fn new() -> Self {
+        JsonValidator::default()
+    }
+
+    pub fn require_fields<S: Into<String>>(mut self, fields: impl IntoIterator<Item = S>) -> Self {
+        self.required_fields.extend(fields.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Validator for JsonValidator {
+    fn validate(&self, _key: &str, value: &str) -> Result<(), String> {
+        let parsed: serde_json::Value =
+            serde_json::from_str(value).map_err(|e| format!("not valid JSON: {}", e))?;
+        if self.required_fields.is_empty() {
+            return Ok(());
+        }
+        let object = parsed
+            .as_object()
+            .ok_or_else(|| "expected a JSON object".to_string())?;
+        for field in &self.required_fields {
+            if !object.contains_key(field) {
+                return Err(format!("missing required field {:?}", field));
+            }
+        }
+        Ok(())
+    }
+}
+
+// When to seal the active segment and start a new one. With both limits
+// set, whichever is hit first wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy::size(SPLIT_LIMIT)
+    }
+}
+
+impl RotationPolicy {
+    pub fn size(max_bytes: u64) -> Self {
+        RotationPolicy {
+            max_bytes: Some(max_bytes),
+            max_age: None,
+        }
+    }
+
+    pub fn interval(max_age: Duration) -> Self {
+        RotationPolicy {
+            max_bytes: None,
+            max_age: Some(max_age),
+        }
+    }
+
+    pub fn hourly() -> Self {
+        RotationPolicy::interval(Duration::from_secs(60 * 60))
+    }
+
+    pub fn daily() -> Self {
+        RotationPolicy::interval(Duration::from_secs(24 * 60 * 60))
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // An empty segment is never sealed for age alone.
+    pub(crate) fn should_rotate(&self, segment_len: u64, started: SystemTime) -> bool {
+        if self.max_bytes.is_some_and(|max| segment_len > max) {
+            return true;
+        }
+        segment_len > 0
+            && self
+                .max_age
+                .is_some_and(|max| started.elapsed().is_ok_and(|age| age >= max))
+    }
+}
+
+// Free-space thresholds for the data directory, checked every `interval`.
+// Below `soft_bytes` the store warns and compacts; below `hard_bytes` it
+// refuses writes until space recovers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskWatchdog {
+    pub soft_bytes: u64,
+    pub hard_bytes: u64,
+    pub interval: Duration,
+}
+
+impl DiskWatchdog {
+    pub fn new(soft_bytes: u64, hard_bytes: u64) -> Self {
+        DiskWatchdog {
+            soft_bytes,
+            hard_bytes,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+// Re-reads sealed segments in the background at no more than
+// `bytes_per_sec`, checking every record, and starts another pass
+// `interval` after finishing one. A damaged segment is replaced with the
+// same generation from `repair_from`, a directory of copies such as an
+// archived checkpoint, if that copy checks out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scrubber {
+    pub bytes_per_sec: u64,
+    pub interval: Duration,
+    pub repair_from: Option<PathBuf>,
+}
+
+impl Scrubber {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Scrubber {
+            bytes_per_sec,
+            interval: Duration::from_secs(24 * 60 * 60),
+            repair_from: None,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn repair_from(mut self, directory: PathBuf) -> Self {
+        self.repair_from = Some(directory);
+        self
+    }
+}
+
+// How much of the store to read back and verify when it is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IntegrityCheck {
+    #[default]
+    None,
+    // Each live record is checked with this probability, from 0.0 to 1.0.
+    Sample(f64),
+    Full,
+}
+
+// Which keys go first when a bounded store is over its limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    // Least recently read or written.
+    #[default]
+    Lru,
+    // Least often read or written, least recently among equals.
+    Lfu,
+    // Keys with a TTL or under a retention rule, soonest to expire first,
+    // then the rest as with `Lru`.
+    TtlFirst,
+}
+
+// What replay does with a record that doesn't decode or whose value fails
+// its checksum, once `Options::verify_on_load` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    // Fail the open with `KvError::CorruptRecord`.
+    #[default]
+    Abort,
+    // Leave the record out of the index and list it in the open report.
+    Skip,
+}
+
+// When appended records are forced to stable storage. Every write is
+// flushed to the OS, which is enough to survive the process dying but not
+// a power failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    // fsync before each write returns.
+    Always,
+    // fsync from a background thread at most this often, so a power
+    // failure loses at most about one interval of writes.
+    Every(Duration),
+    // Leave it to the OS.
+    #[default]
+    Never,
+}
+
+#[derive(Clone, Default)]
+pub struct Options {
+    pub(crate) read_only: bool,
+    pub(crate) rotation: RotationPolicy,
+    pub(crate) validators: Vec<(String, Arc<dyn Validator>)>,
+    pub(crate) fallback_scan_segments: Option<usize>,
+    pub(crate) retention: Vec<(String, Duration)>,
+    pub(crate) aggregates: Vec<String>,
+    pub(crate) churn_prefixes: Vec<String>,
+    pub(crate) disk_watchdog: Option<DiskWatchdog>,
+    pub(crate) scrubber: Option<Scrubber>,
+    pub(crate) blob_threshold: Option<u64>,
+    pub(crate) integrity_check: IntegrityCheck,
+    pub(crate) unclean_shutdown_check: IntegrityCheck,
+    pub(crate) access_stats: Option<Duration>,
+    pub(crate) expiration_sweep: Option<Duration>,
+    pub(crate) max_live_keys: Option<usize>,
+    pub(crate) max_live_bytes: Option<u64>,
+    pub(crate) eviction: EvictionPolicy,
+    pub(crate) record_alignment: Option<u64>,
+    pub(crate) record_format: RecordFormat,
+    pub(crate) verify_on_load: Option<CorruptionPolicy>,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) compact_after_segments: Option<u64>,
+    pub(crate) read_buffer: Option<usize>,
+    pub(crate) garbage_ratio: Option<f64>,
+    pub(crate) sync_prefixes: Vec<(String, MergeMode)>,
+    pub(crate) compaction_listeners: Vec<Arc<dyn CompactionListener>>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn rotation(mut self, policy: RotationPolicy) -> Self {
+        self.rotation = policy;
+        self
+    }
+
+    // Diagnostic mode: a `get` that misses the index scans up to `segments`
+    // of the newest segments for the key before reporting it missing.
+    pub fn fallback_scan(mut self, segments: usize) -> Self {
+        self.fallback_scan_segments = Some(segments);
+        self
+    }
+
+    // Values under `prefix` older than `max_age` read as missing and are
+    // dropped by compaction. The longest matching prefix wins.
+    pub fn retention(mut self, prefix: impl Into<String>, max_age: Duration) -> Self {
+        self.retention.push((prefix.into(), max_age));
+        self
+    }
+
+    // Maintains a count and numeric sum over the keys under `prefix`, read
+    // back with `KvStore::aggregate`.
+    pub fn aggregate(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        if !self.aggregates.contains(&prefix) {
+            self.aggregates.push(prefix);
+        }
+        self
+    }
+
+    // Marks keys under `prefix` as rewritten often, like sessions. Sealed
+    // segments mostly holding such keys are compacted on their own, ahead
+    // of full compactions, so their dead records are reclaimed without
+    // rewriting colder segments.
+    pub fn churn_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        if !self.churn_prefixes.contains(&prefix) {
+            self.churn_prefixes.push(prefix);
+        }
+        self
+    }
+
+    pub fn disk_watchdog(mut self, watchdog: DiskWatchdog) -> Self {
+        self.disk_watchdog = Some(watchdog);
+        self
+    }
+
+    pub fn scrubber(mut self, scrubber: Scrubber) -> Self {
+        self.scrubber = Some(scrubber);
+        self
+    }
+
+    // Values of at least `bytes` go to separate blob segments and the log
+    // only keeps a pointer, so compaction never copies them.
+    pub fn blob_threshold(mut self, bytes: u64) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    // Verifies records on open; see `KvStore::open_report`.
+    pub fn integrity_check(mut self, check: IntegrityCheck) -> Self {
+        self.integrity_check = check;
+        self
+    }
+
+    // The check to run instead of none when the store was not shut down
+    // cleanly last time; a torn tail is cut off either way.
+    pub fn check_after_unclean_shutdown(mut self, check: IntegrityCheck) -> Self {
+        self.unclean_shutdown_check = check;
+        self
+    }
+
+    // Tracks when each key was last read or written and how often, read
+    // back with `KvStore::key_stats`. Kept in memory and saved to the data
+    // directory every `persist_every`.
+    pub fn track_access(mut self, persist_every: Duration) -> Self {
+        self.access_stats = Some(persist_every);
+        self
+    }
+
+    // Removes keys whose value has expired, by TTL or retention, every
+    // `interval`, so keys nobody reads again still give their space back
+    // at the next compaction.
+    pub fn expiration_sweep(mut self, interval: Duration) -> Self {
+        self.expiration_sweep = Some(interval);
+        self
+    }
+
+    // Bounds the store to `keys` live keys. Writes past the bound evict
+    // other keys according to the eviction policy.
+    pub fn max_live_keys(mut self, keys: usize) -> Self {
+        self.max_live_keys = Some(keys);
+        self
+    }
+
+    // Bounds the live records, blobs included, to `bytes`.
+    pub fn max_live_bytes(mut self, bytes: u64) -> Self {
+        self.max_live_bytes = Some(bytes);
+        self
+    }
+
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    // Starts any record that would straddle a multiple of `block_bytes`
+    // on the next one instead, so a torn write of one block damages at
+    // most the records inside it. Set it to the device's sector or page
+    // size; records larger than a block still start on a boundary.
+    pub fn align_records(mut self, block_bytes: u64) -> Self {
+        self.record_alignment = Some(block_bytes);
+        self
+    }
+
+    // Format for new segments, compaction output included, so compacting
+    // a store gradually rewrites it in this format. Older segments stay
+    // readable in theirs. Stores that have used `RecordFormat::Binary`
+    // can't be opened by builds without it.
+    pub fn record_format(mut self, format: RecordFormat) -> Self {
+        self.record_format = format;
+        self
+    }
+
+    // Checks every replayed record against its checksum. Without this,
+    // replay trusts the log and a bad value only shows up when read.
+    pub fn verify_on_load(mut self, policy: CorruptionPolicy) -> Self {
+        self.verify_on_load = Some(policy);
+        self
+    }
+
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    // Tracks writes to keys under `prefix` for `KvStore::sync`, which
+    // reconciles them with other nodes' writes by `mode`. The longest
+    // matching prefix wins.
+    pub fn sync_prefix(mut self, prefix: impl Into<String>, mode: MergeMode) -> Self {
+        self.sync_prefixes.push((prefix.into(), mode));
+        self
+    }
+
+    // Compacts when sealing a segment would leave more than `segments`
+    // segments, instead of the default five.
+    pub fn compact_after_segments(mut self, segments: u64) -> Self {
+        self.compact_after_segments = Some(segments);
+        self
+    }
+
+    // Also compacts when sealing a segment finds at least `ratio`, from
+    // 0.0 to 1.0, of the segments' bytes are records since overwritten or
+    // removed.
+    pub fn compact_at_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.garbage_ratio = Some(ratio);
+        self
+    }
+
+    // Size of the buffer each segment's read handle gets once opened, 8 KB
+    // by default. Larger buffers help scans and compaction of big records
+    // at the cost of memory per open segment.
+    pub fn read_buffer(mut self, bytes: usize) -> Self {
+        self.read_buffer = Some(bytes);
+        self
+    }
+
+    pub(crate) fn compaction_segments(&self) -> u64 {
+        self.compact_after_segments.unwrap_or(COMPACT_LIMIT)
+    }
+
+    pub(crate) fn read_buffer_bytes(&self) -> usize {
+        self.read_buffer.unwrap_or(READ_BUFFER)
+    }
+
+    pub(crate) fn bounded(&self) -> bool {
+        self.max_live_keys.is_some() || self.max_live_bytes.is_some()
+    }
+
+    // Eviction ranks keys by their access stats, so a bound turns tracking
+    // on even when they are not persisted.
+    pub(crate) fn tracks_access(&self) -> bool {
+        self.access_stats.is_some() || self.bounded()
+    }
+
+    pub(crate) fn aggregated(&self, key: &str) -> bool {
+        self.aggregates.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn high_churn(&self, key: &str) -> bool {
+        self.churn_prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn synced(&self, key: &str) -> bool {
+        self.sync_prefixes.iter().any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn merge_mode(&self, key: &str) -> Option<&MergeMode> {
+        self.sync_prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, mode)| mode)
+    }
+
+    pub(crate) fn retention_for(&self, key: &str) -> Option<Duration> {
+        self.retention
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, max_age)| *max_age)
+    }
+
+    pub(crate) fn retention_expired(&self, key: &str, written_at_ms: u64) -> bool {
+        let Some(max_age) = self.retention_for(key) else {
+            return false;
+        };
+        let written_at = UNIX_EPOCH + Duration::from_millis(written_at_ms);
+        SystemTime::now()
+            .duration_since(written_at)
+            .is_ok_and(|age| age > max_age)
+    }
+
+    // Every validator whose prefix matches the key runs on `set`.
+    pub fn validator(mut self, prefix: impl Into<String>, validator: impl Validator + 'static) -> Self {
+        self.validators.push((prefix.into(), Arc::new(validator)));
+        self
+    }
+
+    // Runs on the compaction thread once the output is live and the inputs
+    // are deleted, without the store's lock held.
+    pub fn on_compaction_complete(mut self, listener: impl CompactionListener + 'static) -> Self {
+        self.compaction_listeners.push(Arc::new(listener));
+        self
+    }
+}
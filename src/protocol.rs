@@ -1,16 +1,338 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::codec::CodecKind;
+use crate::replication::{ReplicationEvent, ReplicationInfo, SnapshotManifest, Staleness};
+use crate::{
+    Aggregate, ContentType, MemoryUsage, ScanOptions, ScanPage, SetOptions, SetOutcome, StoreStats, SyncBatch,
+    VersionVector, value_checksum,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String }
+    // Any bytes; values that are not UTF-8 are stored tagged `Bytes`, and
+    // `Get` answers with the same bytes. Every request carrying a value is
+    // refused if it also carries a `checksum` that is not the value's
+    // `value_checksum`.
+    Set {
+        key: String,
+        #[serde(with = "value_bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    SetChecked { key: String, value: String, checksum: u32 },
+    // `GetTagged` is answered with `TaggedValue`.
+    SetTagged {
+        key: String,
+        value: String,
+        content_type: ContentType,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    GetTagged { key: String },
+    // Answered with `SetOutcome`.
+    SetOpts {
+        key: String,
+        value: String,
+        options: SetOptions,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    Remove { key: String },
+    // Both answer with the previous value, or `NotFound`.
+    GetDel { key: String },
+    GetSet {
+        key: String,
+        value: String,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    // Answered with the value's new length. `checksum` covers `suffix`.
+    Append {
+        key: String,
+        suffix: String,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    // Answered with `Values`, one per key, all read at the same point in
+    // time.
+    MGet { keys: Vec<String> },
+    MRemove { keys: Vec<String> },
+    // `NotFound` if `from` does not exist.
+    Rename { from: String, to: String, overwrite: bool },
+    Copy { from: String, to: String, overwrite: bool },
+    Eval { script: String, keys: Vec<String>, args: Vec<String> },
+    Info,
+    Compact,
+    CancelCompaction,
+    // Answered with `Ok` before the server shuts down.
+    Shutdown,
+    Aggregate { prefix: String },
+    // Answered with `Scan`, one page of the entries that pass every filter.
+    Scan { options: ScanOptions },
+    ClientList,
+    ClientKill { id: u64 },
+    // Answered in the current codec; both sides switch right after.
+    Hello { codec: CodecKind },
+    // Answered with `Pong`; keeps an idle connection open.
+    Ping,
+    // Answered with `Ok`, after which the connection only carries
+    // `Response::Event`s for keys starting with `prefix`.
+    Watch { prefix: String },
+    // Like `Get`, but a replica refuses it when it lags by more than
+    // `staleness` allows.
+    GetBounded { key: String, staleness: Staleness },
+    // Sent by a replica: answered with `Ok`, then the connection carries
+    // `Response::Replication` events for changes after `after_seq`.
+    Replicate { run_id: u64, after_seq: u64 },
+    // Used by a new replica to copy the store before it starts tailing.
+    SnapshotManifest,
+    SnapshotChunk { name: String, offset: u64, len: u64 },
+    // The two halves of `KvStore::sync` with this server's store as the
+    // peer: `SyncChanges` is answered with `Sync`, `SyncMerge` with the
+    // number of records that changed the store as a `Length`.
+    SyncChanges { seen: VersionVector },
+    SyncMerge { batch: SyncBatch },
+    // Runs `request` once per `token`: a retry within the server's window
+    // is answered with the first attempt's response.
+    Idempotent { token: String, request: Box<Request> },
+    // Carries a bearer token for servers that authenticate each request;
+    // answered as `request` would be.
+    Authenticated { token: String, request: Box<Request> },
+}
+
+impl Request {
+    // Requests that can change stored data.
+    pub fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::SetChecked { .. }
+                | Request::SetTagged { .. }
+                | Request::SetOpts { .. }
+                | Request::Remove { .. }
+                | Request::GetDel { .. }
+                | Request::GetSet { .. }
+                | Request::Append { .. }
+                | Request::MRemove { .. }
+                | Request::Rename { .. }
+                | Request::Copy { .. }
+                | Request::Eval { .. }
+                | Request::SyncMerge { .. }
+        ) || matches!(
+            self,
+            Request::Idempotent { request, .. } | Request::Authenticated { request, .. } if request.is_mutating()
+        )
+    }
+
+    // Requests a read-only listener accepts: reads and the handshake,
+    // nothing that changes data, administers the server or copies its
+    // files.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::GetBounded { .. }
+                | Request::GetTagged { .. }
+                | Request::MGet { .. }
+                | Request::Aggregate { .. }
+                | Request::Scan { .. }
+                | Request::Info
+                | Request::Hello { .. }
+                | Request::Ping
+                | Request::Watch { .. }
+                | Request::SyncChanges { .. }
+        ) || matches!(self, Request::Authenticated { request, .. } if request.is_read_only())
+    }
+
+    // Requests the server runs on its admin lane, apart from client
+    // traffic.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Request::Info
+                | Request::Compact
+                | Request::CancelCompaction
+                | Request::Shutdown
+                | Request::ClientList
+                | Request::ClientKill { .. }
+        )
+    }
+
+    // False when the request carries a checksum that its value does not
+    // match.
+    pub fn checksum_matches(&self) -> bool {
+        let (value, checksum) = match self {
+            Request::Set { value, checksum, .. } => (value.as_slice(), *checksum),
+            Request::SetChecked { value, checksum, .. } => (value.as_bytes(), Some(*checksum)),
+            Request::SetTagged { value, checksum, .. }
+            | Request::SetOpts { value, checksum, .. }
+            | Request::GetSet { value, checksum, .. } => (value.as_bytes(), *checksum),
+            Request::Append { suffix, checksum, .. } => (suffix.as_bytes(), *checksum),
+            Request::Idempotent { request, .. } | Request::Authenticated { request, .. } => {
+                return request.checksum_matches();
+            }
+            _ => return true,
+        };
+        checksum.is_none_or(|checksum| value_checksum(value) == checksum)
+    }
+
+    // Keys a successful request may have changed. Scripts are trusted to
+    // declare every key they touch.
+    pub fn written_keys(&self) -> Vec<&str> {
+        match self {
+            Request::Set { key, .. }
+            | Request::SetChecked { key, .. }
+            | Request::SetTagged { key, .. }
+            | Request::SetOpts { key, .. }
+            | Request::Remove { key }
+            | Request::GetDel { key }
+            | Request::GetSet { key, .. }
+            | Request::Append { key, .. } => vec![key],
+            Request::Rename { from, to, .. } => vec![from, to],
+            Request::Copy { to, .. } => vec![to],
+            Request::MRemove { keys } | Request::Eval { keys, .. } => keys.iter().map(String::as_str).collect(),
+            Request::SyncMerge { batch } => batch.records.iter().map(|record| record.key.as_str()).collect(),
+            Request::Idempotent { request, .. } | Request::Authenticated { request, .. } => request.written_keys(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Ok,
-    Value(String),
+    Pong,
+    // Sent by servers that predate checksums; `CheckedValue` otherwise.
+    Value(#[serde(with = "value_bytes")] Vec<u8>),
+    // `checksum` is the `value_checksum` of `value`, for the client to check.
+    CheckedValue {
+        #[serde(with = "value_bytes")]
+        value: Vec<u8>,
+        checksum: u32,
+    },
+    TaggedValue {
+        value: String,
+        content_type: Option<ContentType>,
+        #[serde(default)]
+        checksum: Option<u32>,
+    },
+    Values(Vec<Option<String>>),
+    Scan(ScanPage),
+    SetOutcome(SetOutcome),
+    Length(u64),
     NotFound,
-    Error(String)
+    Error(String),
+    Info(Box<Info>),
+    Aggregate(Aggregate),
+    Clients(Vec<ClientInfo>),
+    Event(WatchEvent),
+    Replication(ReplicationEvent),
+    SnapshotManifest(SnapshotManifest),
+    Chunk(Vec<u8>),
+    Sync(SyncBatch),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Changed { key: String },
+    // The watcher fell behind and events were dropped; anything derived
+    // from watched keys should be treated as stale.
+    Lagged,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Info {
+    pub uptime_secs: u64,
+    pub total_ops: u64,
+    pub latency: LatencySummary,
+    pub top_keys: Vec<(String, u64)>,
+    pub store: StoreStats,
+    #[serde(default)]
+    pub replication: ReplicationInfo,
+    // `None` where the platform does not report it.
+    #[serde(default)]
+    pub resident_bytes: Option<u64>,
+    // The store's own estimate of the heap it holds.
+    #[serde(default)]
+    pub memory: MemoryUsage,
+    // The store was opened after it last stopped without a clean shutdown.
+    #[serde(default)]
+    pub unclean_shutdown: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct LatencySummary {
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub age_secs: u64,
+    pub idle_secs: u64,
+    pub commands: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Responses the client has not read yet.
+    pub pending_write_bytes: u64,
+}
+
+// Values travel as raw bytes in the binary codecs. JSON keeps UTF-8 values
+// as strings so it can still be typed by hand, and falls back to an array
+// of bytes for anything else.
+mod value_bytes {
+    use std::fmt;
+
+    use serde::{Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(value) {
+            Ok(text) if serializer.is_human_readable() => serializer.serialize_str(text),
+            _ => serializer.serialize_bytes(value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> de::Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<u8>, E> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, value: String) -> Result<Vec<u8>, E> {
+            Ok(value.into_bytes())
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(value.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(value)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
 }
@@ -0,0 +1,110 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::{Command, CommandPos, KvStore, RecordFormat, Result, SharedData, segment};
+
+// An index entry that a reader found pointing at a compacted-away segment,
+// and where the key lives now (`None` if compaction dropped it).
+pub(crate) struct Repair {
+    key: String,
+    stale: CommandPos,
+    fresh: Option<CommandPos>,
+}
+
+impl KvStore {
+    // Finds `key` in the last compaction's output when `cmd_pos` points at
+    // a segment it replaced. Readers only hold the read lock, so the index
+    // fix is queued and applied by the next write. Finding the key means
+    // reading the output segments through, with the read lock held; reads
+    // of it before that write take the queued position instead.
+    pub(crate) fn relocate(&self, inner: &SharedData, cmd_pos: &CommandPos, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(queued) = inner.repairs.lock().iter().find(|repair| repair.fixes(key, cmd_pos)) {
+            return Ok(queued.fresh);
+        }
+        let outputs = match &inner.compacted_into {
+            Some(outputs) if cmd_pos.generation < outputs.start => outputs.clone(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Log file for generation {} not found", cmd_pos.generation),
+                )
+                .into());
+            }
+        };
+        let mut fresh = None;
+        for (generation, reader) in inner.readers.range(outputs).rev() {
+            fresh = locate_in_generation(&inner.directory, *generation, reader.format(), key)?;
+            if fresh.is_some() {
+                break;
+            }
+        }
+        inner.read_repairs.fetch_add(1, Ordering::Relaxed);
+        inner.repairs.lock().push(Repair {
+            key: key.to_string(),
+            stale: *cmd_pos,
+            fresh,
+        });
+        Ok(fresh)
+    }
+}
+
+impl Repair {
+    fn fixes(&self, key: &str, cmd_pos: &CommandPos) -> bool {
+        self.key == key && self.stale.generation == cmd_pos.generation && self.stale.pos == cmd_pos.pos
+    }
+}
+
+impl SharedData {
+    // Skips keys written again since the repair was queued.
+    pub(crate) fn apply_repairs(&mut self) {
+        let repairs = std::mem::take(self.repairs.get_mut());
+        for repair in repairs {
+            let still_stale = self
+                .index
+                .get(&repair.key)
+                .is_some_and(|current| repair.fixes(&repair.key, current));
+            if !still_stale {
+                continue;
+            }
+            match repair.fresh {
+                Some(fresh) => self.index_insert(repair.key, fresh),
+                None => self.index_remove(&repair.key),
+            };
+        }
+    }
+}
+
+// Where the key's last record in the segment starts, if it is a set.
+fn locate_in_generation(
+    directory: &Path,
+    generation: u64,
+    format: RecordFormat,
+    key: &str,
+) -> io::Result<Option<CommandPos>> {
+    let path = directory.join(format!("{}.db", generation));
+    let (reader, start) = segment::open_records(&path, format)?;
+
+    let mut found = None;
+    let mut pos = start;
+    for record in format.records(reader) {
+        let (command, end) = record?;
+        let end = start + end;
+        for op in command.into_ops() {
+            match op {
+                Command::Set { key: k, blob, .. } if k == key => {
+                    found = Some(CommandPos {
+                        pos,
+                        len: end - pos,
+                        generation,
+                        blob,
+                    })
+                }
+                Command::Remove { key: k, .. } if k == key => found = None,
+                _ => {}
+            }
+        }
+        pos = end;
+    }
+    Ok(found)
+}
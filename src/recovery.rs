@@ -0,0 +1,99 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::{KvStore, RecordFormat, SharedData, segment};
+
+// Present only while the store is closed cleanly: `shutdown` writes it,
+// and opens and the first change after a shutdown take it away, so a
+// store that stops any other way is found without it.
+const CLEAN_SHUTDOWN_MARKER: &str = "CLEAN_SHUTDOWN";
+
+// A partial record a crash left at the end of the newest segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TruncatedTail {
+    pub generation: u64,
+    // Where the partial record started, and the segment's length since.
+    pub offset: u64,
+    pub dropped_bytes: u64,
+}
+
+impl KvStore {
+    // Cuts the segment back to `offset`, where replay found a record that
+    // ends early. Read-only opens leave the file alone and only skip it.
+    pub(crate) fn truncate_torn_tail(&self, path: &Path, generation: u64, offset: u64) -> io::Result<TruncatedTail> {
+        let len = fs::metadata(path)?.len();
+        let tail = TruncatedTail {
+            generation,
+            offset,
+            dropped_bytes: len - offset,
+        };
+        if self.options.read_only {
+            eprintln!(
+                "Ignoring a partial record in the last {} bytes of generation {}",
+                tail.dropped_bytes, generation
+            );
+            return Ok(tail);
+        }
+        eprintln!(
+            "Truncating a partial record in the last {} bytes of generation {}",
+            tail.dropped_bytes, generation
+        );
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        file.set_len(offset)?;
+        file.sync_data()?;
+        Ok(tail)
+    }
+}
+
+// Whether the store was last closed without a `shutdown`. Stores with no
+// segments yet never were. Writable opens remove the marker.
+pub(crate) fn take_clean_marker(directory: &Path, has_segments: bool, read_only: bool) -> io::Result<bool> {
+    let path = directory.join(CLEAN_SHUTDOWN_MARKER);
+    let clean = path.exists();
+    if clean && !read_only {
+        fs::remove_file(&path)?;
+    }
+    let unclean = has_segments && !clean;
+    if unclean {
+        eprintln!("The store was not shut down cleanly last time");
+    }
+    Ok(unclean)
+}
+
+pub(crate) fn write_clean_marker(inner: &SharedData) -> io::Result<()> {
+    fs::File::create(inner.directory.join(CLEAN_SHUTDOWN_MARKER))?.sync_all()?;
+    inner.clean_marker.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Called before anything changes the segments.
+pub(crate) fn clear_clean_marker(inner: &SharedData) -> io::Result<()> {
+    if inner.clean_marker.load(Ordering::Relaxed) {
+        fs::remove_file(inner.directory.join(CLEAN_SHUTDOWN_MARKER))?;
+        inner.clean_marker.store(false, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+// The newest segment holding any records, other than the active one a
+// writable open just created: the only one a crash can have left a record
+// half written in.
+pub(crate) fn tail_generation(
+    directory: &Path,
+    generations: &[(u64, RecordFormat)],
+    writer_generation: Option<u64>,
+) -> io::Result<Option<u64>> {
+    for (generation, format) in generations.iter().rev() {
+        if Some(*generation) == writer_generation {
+            continue;
+        }
+        let path = directory.join(format!("{}.db", generation));
+        let (file, records_start) = segment::open_records(&path, *format)?;
+        if file.get_ref().metadata()?.len() > records_start {
+            return Ok(Some(*generation));
+        }
+    }
+    Ok(None)
+}
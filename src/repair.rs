@@ -0,0 +1,163 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::BINARY_MARKER;
+use crate::snapshot::SNAPSHOT_FILE;
+use crate::{Command, KvStore, Options, RecordFormat, Result, hint, integrity, manifest, segment};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairSummary {
+    pub segments_checked: usize,
+    // Segments that were rewritten without their damaged parts.
+    pub segments_rewritten: Vec<u64>,
+    pub records_kept: u64,
+    // Records that decoded but failed their checksum.
+    pub records_discarded: u64,
+    // Bytes that did not decode as any record and were skipped.
+    pub bytes_discarded: u64,
+    // Keys the rebuilt index holds.
+    pub live_keys: usize,
+}
+
+// What could be read back from one segment.
+#[derive(Default)]
+struct Salvage {
+    kept: Vec<Command>,
+    records_discarded: u64,
+    bytes_discarded: u64,
+}
+
+impl Salvage {
+    fn damaged(&self) -> bool {
+        self.records_discarded > 0 || self.bytes_discarded > 0
+    }
+}
+
+impl KvStore {
+    // Reads every segment of the store at `directory`, skipping past
+    // anything that does not decode to the next record that does and
+    // dropping records that fail their checksum. Damaged segments are
+    // rewritten with only what survived, then the store is opened once to
+    // rebuild the index. The store must not be open anywhere else.
+    pub fn check_and_repair(directory: PathBuf) -> Result<RepairSummary> {
+        let metadata = manifest::load_or_init(&directory, &Options::new().read_only(true))?;
+        let mut summary = RepairSummary::default();
+        for generation in segment_generations(&directory)? {
+            let format = metadata.segment_format(generation);
+            let path = directory.join(format!("{}.db", generation));
+            let (mut reader, _) = segment::open_records(&path, format)?;
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            drop(reader);
+
+            let salvage = salvage(format, &bytes);
+            summary.segments_checked += 1;
+            summary.records_kept += salvage.kept.len() as u64;
+            summary.records_discarded += salvage.records_discarded;
+            summary.bytes_discarded += salvage.bytes_discarded;
+            if salvage.damaged() {
+                eprintln!(
+                    "Rewriting generation {} without {} bad records and {} unreadable bytes",
+                    generation, salvage.records_discarded, salvage.bytes_discarded
+                );
+                rewrite_segment(&directory, generation, format, &salvage.kept)?;
+                summary.segments_rewritten.push(generation);
+            }
+        }
+        if !summary.segments_rewritten.is_empty() {
+            // Offsets in the snapshot no longer match the rewritten segments.
+            remove_if_present(&directory.join(SNAPSHOT_FILE))?;
+        }
+
+        let store = KvStore::open(directory)?;
+        summary.live_keys = store.stats()?.key_count;
+        store.shutdown()?;
+        Ok(summary)
+    }
+}
+
+fn salvage(format: RecordFormat, bytes: &[u8]) -> Salvage {
+    let mut salvage = Salvage::default();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        // Offsets from `records` are relative to where this pass started.
+        let start = pos;
+        let mut failed = false;
+        for record in format.records(&bytes[start..]) {
+            match record {
+                Ok((cmd, end)) => {
+                    if integrity::checksum_mismatch(&cmd).is_some() {
+                        salvage.records_discarded += 1;
+                    } else {
+                        salvage.kept.push(cmd);
+                    }
+                    pos = start + end as usize;
+                }
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if !failed {
+            break;
+        }
+        let next = (pos + 1..bytes.len())
+            .find(|&p| starts_record(format, &bytes[p..]))
+            .unwrap_or(bytes.len());
+        salvage.bytes_discarded += (next - pos) as u64;
+        pos = next;
+    }
+    salvage
+}
+
+// Whether a whole record decodes from the start of `bytes`.
+fn starts_record(format: RecordFormat, bytes: &[u8]) -> bool {
+    let first = match format {
+        RecordFormat::Json => b'{',
+        RecordFormat::Binary => BINARY_MARKER,
+    };
+    bytes[0] == first && matches!(format.records(bytes).next(), Some(Ok(_)))
+}
+
+// Writes the surviving records to a temporary file and renames it over the
+// segment, so a crash part way leaves the damaged one in place. The
+// segment's hint describes the old layout and goes too.
+fn rewrite_segment(directory: &Path, generation: u64, format: RecordFormat, records: &[Command]) -> io::Result<()> {
+    let tmp_path = directory.join(format!("{}.db.tmp", generation));
+    let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    file.write_all(&segment::header(format))?;
+    for cmd in records {
+        file.write_all(&format.encode(cmd)?)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    remove_if_present(&hint::hint_path(directory, generation))?;
+    fs::rename(&tmp_path, directory.join(format!("{}.db", generation)))
+}
+
+fn segment_generations(directory: &Path) -> io::Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "db")
+            && let Some(generation) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            generations.push(generation);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::client::Client;
+use crate::codec::CodecKind;
+use crate::manifest::MANIFEST_FILE;
+use crate::snapshot::SNAPSHOT_FILE;
+use crate::{Hlc, HlcTimestamp, KvStore, Result, ValueMetadata, now_millis};
+
+// Largest piece of a file sent per request during snapshot transfer.
+pub const SNAPSHOT_CHUNK: u64 = 1024 * 1024;
+// Present in a replica's data directory until a bootstrap has completed.
+const BOOTSTRAP_MARKER: &str = "BOOTSTRAP";
+// Where a bootstrap keeps finished files, so a retry only fetches the rest.
+const STAGING_DIR: &str = "bootstrap";
+// The run and sequence number of the last change a replica applied, so it
+// resumes from there after a restart.
+const POSITION_FILE: &str = "REPLICA_POSITION";
+
+// A write as the primary numbered it. `value` is the key's value right after
+// the write, `None` once it was removed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub seq: u64,
+    // Milliseconds since the Unix epoch, on the primary's clock.
+    pub timestamp: u64,
+    pub key: String,
+    pub value: Option<String>,
+    // Orders the write against writes taken on other nodes. Zero from
+    // servers that predate it.
+    #[serde(default)]
+    pub hlc: HlcTimestamp,
+    // The write time, tag and expiry `value` was stored with, so a replica
+    // stores it the same way. Empty from servers that predate it.
+    #[serde(default)]
+    pub metadata: ValueMetadata,
+}
+
+// What to do with a replicated write to a key that has a recent write of
+// its own, possibly a concurrent one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    KeepCurrent,
+    TakeIncoming,
+    // Store this instead, `None` to remove the key.
+    Value(Option<String>),
+}
+
+// Decides between a key's most recent write a replica knows of and one
+// arriving from its primary. Set with `Service::set_conflict_resolver`.
+pub trait ConflictResolver: Send + Sync {
+    fn resolve(&self, key: &str, current: &Change, incoming: &Change) -> Resolution;
+}
+
+// The write with the later HLC stamp wins. Stamps from different nodes
+// never tie, so every replica picks the same one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastWriteWins;
+
+impl ConflictResolver for LastWriteWins {
+    fn resolve(&self, _key: &str, current: &Change, incoming: &Change) -> Resolution {
+        if incoming.hlc > current.hlc {
+            Resolution::TakeIncoming
+        } else {
+            Resolution::KeepCurrent
+        }
+    }
+}
+
+// What a primary streams to a replica after `Request::Replicate`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationEvent {
+    Change(Change),
+    // Sent first and then whenever the stream is idle, so a replica can
+    // tell how far behind it is without any writes.
+    Heartbeat { run_id: u64, seq: u64 },
+    // The changes the replica asked for are gone; streaming continues from
+    // `seq` and the replica's data can no longer be trusted.
+    Resync { run_id: u64, seq: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum Role {
+    #[default]
+    Primary,
+    Replica { primary: String },
+}
+
+// Lags are `None` while they are unknown: before a replica first hears from
+// its primary, and after it missed changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReplicationInfo {
+    pub role: Role,
+    // The last change this server numbered (primary) or applied (replica).
+    pub seq: u64,
+    pub lag_records: Option<u64>,
+    pub lag_millis: Option<u64>,
+    pub connected: bool,
+}
+
+// The store's files as of change `seq` of run `run_id`. Each file is
+// described by a prefix: files are append-only, so the prefix stays valid
+// until compaction deletes the file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    pub run_id: u64,
+    pub seq: u64,
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotFile {
+    pub name: String,
+    pub len: u64,
+    // Hex SHA-256 of the first `len` bytes.
+    pub sha256: String,
+}
+
+// How far behind a replica may be and still answer a bounded read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Staleness {
+    pub max_records: Option<u64>,
+    pub max_millis: Option<u64>,
+}
+
+// The primary's numbering of changes, with the most recent ones kept so a
+// replica that reconnects can pick up where it left off.
+pub struct ReplicationLog {
+    // Distinguishes this run's sequence numbers from a previous run's.
+    run_id: u64,
+    seq: u64,
+    backlog: VecDeque<Change>,
+    capacity: usize,
+    // Stamps every change; this run's id is its node id.
+    clock: Hlc,
+}
+
+impl ReplicationLog {
+    pub fn new(capacity: usize) -> Self {
+        let run_id = now_millis().max(1);
+        ReplicationLog {
+            run_id,
+            seq: 0,
+            backlog: VecDeque::new(),
+            capacity,
+            clock: Hlc::new(run_id),
+        }
+    }
+
+    pub fn run_id(&self) -> u64 {
+        self.run_id
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn info(&self) -> ReplicationInfo {
+        ReplicationInfo {
+            role: Role::Primary,
+            seq: self.seq,
+            lag_records: Some(0),
+            lag_millis: Some(0),
+            connected: true,
+        }
+    }
+
+    pub fn append(&mut self, key: String, value: Option<String>) -> Change {
+        self.append_with_metadata(key, value, ValueMetadata::default())
+    }
+
+    pub fn append_with_metadata(&mut self, key: String, value: Option<String>, metadata: ValueMetadata) -> Change {
+        let hlc = self.clock.now();
+        self.push(key, value, metadata, hlc)
+    }
+
+    // Numbers a change applied from another node. It keeps that node's
+    // stamp, and this log's clock moves past it.
+    pub fn relay(&mut self, change: &Change, value: Option<String>, metadata: ValueMetadata) -> Change {
+        self.clock.observe(change.hlc);
+        self.push(change.key.clone(), value, metadata, change.hlc)
+    }
+
+    // The key's latest change still in the backlog. Older writes are taken
+    // to be settled, as nothing arriving now can be concurrent with them.
+    pub fn latest(&self, key: &str) -> Option<&Change> {
+        self.backlog.iter().rev().find(|change| change.key == key)
+    }
+
+    fn push(&mut self, key: String, value: Option<String>, metadata: ValueMetadata, hlc: HlcTimestamp) -> Change {
+        self.seq += 1;
+        let change = Change {
+            seq: self.seq,
+            timestamp: now_millis(),
+            key,
+            value,
+            hlc,
+            metadata,
+        };
+        if self.backlog.len() == self.capacity {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(change.clone());
+        change
+    }
+
+    // The changes after `seq` of run `run_id`, or `None` if some of them are
+    // no longer in the backlog. A `run_id` of 0 asks to start at the head.
+    pub fn since(&self, run_id: u64, seq: u64) -> Option<Vec<Change>> {
+        if run_id == 0 {
+            return Some(Vec::new());
+        }
+        if run_id != self.run_id || seq > self.seq {
+            return None;
+        }
+        let oldest = self.backlog.front().map_or(self.seq + 1, |change| change.seq);
+        if seq + 1 < oldest {
+            return None;
+        }
+        Some(self.backlog.iter().filter(|change| change.seq > seq).cloned().collect())
+    }
+}
+
+// A replica's view of its position in the primary's stream.
+pub struct ReplicaState {
+    primary: String,
+    run_id: u64,
+    applied_seq: u64,
+    primary_seq: u64,
+    // When the replica last knew it had applied everything the primary had.
+    caught_up_at: Option<Instant>,
+    diverged: bool,
+    connected: bool,
+    resolver: Arc<dyn ConflictResolver>,
+}
+
+impl ReplicaState {
+    pub fn new(primary: impl Into<String>) -> Self {
+        ReplicaState {
+            primary: primary.into(),
+            run_id: 0,
+            applied_seq: 0,
+            primary_seq: 0,
+            caught_up_at: None,
+            diverged: false,
+            connected: false,
+            resolver: Arc::new(LastWriteWins),
+        }
+    }
+
+    pub fn set_resolver(&mut self, resolver: Arc<dyn ConflictResolver>) {
+        self.resolver = resolver;
+    }
+
+    pub fn resolver(&self) -> Arc<dyn ConflictResolver> {
+        self.resolver.clone()
+    }
+
+    // For a replica bootstrapped from a snapshot taken at `seq`.
+    pub fn resume_from(&mut self, run_id: u64, seq: u64) {
+        self.run_id = run_id;
+        self.applied_seq = seq;
+        self.primary_seq = seq;
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    // Where to resume the stream from.
+    pub fn position(&self) -> (u64, u64) {
+        (self.run_id, self.applied_seq)
+    }
+
+    // The position to resume from after a restart, `None` once the data
+    // can no longer be trusted.
+    pub fn checkpoint(&self) -> Option<(u64, u64)> {
+        (self.run_id != 0 && !self.diverged).then_some((self.run_id, self.applied_seq))
+    }
+
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+    }
+
+    // Call once the event has been applied.
+    pub fn record(&mut self, event: &ReplicationEvent) {
+        match event {
+            ReplicationEvent::Change(change) => {
+                self.applied_seq = change.seq;
+                self.primary_seq = self.primary_seq.max(change.seq);
+            }
+            ReplicationEvent::Heartbeat { run_id, seq } => {
+                if self.run_id == 0 {
+                    // Nothing says which changes the data already has.
+                    self.applied_seq = *seq;
+                    self.diverged = true;
+                }
+                self.run_id = *run_id;
+                self.primary_seq = *seq;
+            }
+            ReplicationEvent::Resync { run_id, seq } => {
+                self.run_id = *run_id;
+                self.applied_seq = *seq;
+                self.primary_seq = *seq;
+                self.diverged = true;
+            }
+        }
+        if self.applied_seq >= self.primary_seq {
+            self.caught_up_at = Some(Instant::now());
+        }
+    }
+
+    pub fn lag(&self) -> (Option<u64>, Option<u64>) {
+        if self.diverged {
+            return (None, None);
+        }
+        match self.caught_up_at {
+            Some(at) => (
+                Some(self.primary_seq.saturating_sub(self.applied_seq)),
+                Some(at.elapsed().as_millis().min(u64::MAX as u128) as u64),
+            ),
+            None => (None, None),
+        }
+    }
+
+    pub fn info(&self) -> ReplicationInfo {
+        let (lag_records, lag_millis) = self.lag();
+        ReplicationInfo {
+            role: Role::Replica {
+                primary: self.primary.clone(),
+            },
+            seq: self.applied_seq,
+            lag_records,
+            lag_millis,
+            connected: self.connected,
+        }
+    }
+
+    // Describes the violated bound, if any.
+    pub fn check(&self, bound: &Staleness) -> std::result::Result<(), String> {
+        let (records, millis) = self.lag();
+        if let Some(max) = bound.max_records {
+            match records {
+                Some(records) if records <= max => {}
+                Some(records) => return Err(format!("replica is {} records behind (max {})", records, max)),
+                None => return Err("replica lag is unknown".to_string()),
+            }
+        }
+        if let Some(max) = bound.max_millis {
+            match millis {
+                Some(millis) if millis <= max => {}
+                Some(millis) => return Err(format!("replica is {} ms behind (max {})", millis, max)),
+                None => return Err("replica lag is unknown".to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvStore {
+    // The directory and the files that make up the store, with their
+    // current lengths. Holding the read lock keeps writes and compaction
+    // swaps out, so every length ends on a record boundary.
+    fn snapshot_files(&self) -> Result<(PathBuf, Vec<(String, u64)>)> {
+        let inner = self.inner.read();
+        let names = inner
+            .readers
+            .keys()
+            .map(|generation| format!("{}.db", generation))
+            .chain(inner.blob_segments.keys().map(|segment| format!("{}.blob", segment)))
+            .chain(std::iter::once(MANIFEST_FILE.to_string()));
+        let mut files = Vec::new();
+        for name in names {
+            match fs::metadata(inner.directory.join(&name)) {
+                Ok(metadata) => files.push((name, metadata.len())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && name == MANIFEST_FILE => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok((inner.directory.clone(), files))
+    }
+}
+
+// Describes the store as of the log's current position. File lengths are
+// taken with the log locked, so every change numbered so far is included;
+// checksums are computed after letting go of both locks.
+pub fn snapshot_manifest(store: &KvStore, log: &Mutex<ReplicationLog>) -> Result<SnapshotManifest> {
+    let (run_id, seq, (directory, files)) = {
+        let log = log.lock().map_err(|_| io::Error::other("Mutex poisoned"))?;
+        (log.run_id(), log.seq(), store.snapshot_files()?)
+    };
+    let files = files
+        .into_iter()
+        .map(|(name, len)| {
+            let sha256 = file_checksum(&directory.join(&name), len)?;
+            Ok(SnapshotFile { name, len, sha256 })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(SnapshotManifest { run_id, seq, files })
+}
+
+// Up to `SNAPSHOT_CHUNK` bytes of a snapshot file from `offset`.
+pub fn read_snapshot_chunk(store: &KvStore, name: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+    if !is_snapshot_file(name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a store file", name)).into());
+    }
+    let directory = store.inner.read().directory.clone();
+    let mut file = File::open(directory.join(name))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = Vec::new();
+    file.take(len.min(SNAPSHOT_CHUNK)).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn is_snapshot_file(name: &str) -> bool {
+    if name == MANIFEST_FILE {
+        return true;
+    }
+    match name.rsplit_once('.') {
+        Some((stem, "db" | "blob" | "hint")) => !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit()),
+        _ => false,
+    }
+}
+
+fn file_checksum(path: &Path, len: u64) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?.take(len));
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    let mut read = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    if read != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than expected", path.display())));
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// The position a replica last saved in `directory`. One that cannot be
+// read is as good as none.
+pub fn saved_position(directory: &Path) -> io::Result<Option<(u64, u64)>> {
+    let contents = match fs::read_to_string(directory.join(POSITION_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut fields = contents.split_whitespace().map(str::parse::<u64>);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(run_id)), Some(Ok(seq)), None) => Ok(Some((run_id, seq))),
+        _ => {
+            eprintln!("Ignoring unreadable {}", POSITION_FILE);
+            Ok(None)
+        }
+    }
+}
+
+// Saves a replica's position after the changes up to it were applied, or
+// forgets it when `position` is `None`. Replaced by a rename, so a crash
+// leaves either the old position or the new one.
+pub fn save_position(directory: &Path, position: Option<(u64, u64)>) -> io::Result<()> {
+    let path = directory.join(POSITION_FILE);
+    match position {
+        Some((run_id, seq)) => {
+            let tmp_path = directory.join(format!("{}.tmp", POSITION_FILE));
+            fs::write(&tmp_path, format!("{} {}\n", run_id, seq))?;
+            fs::rename(&tmp_path, &path)
+        }
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+// A replica needs a bootstrap unless it saved the position its data is
+// current to and no bootstrap is half done. Data without a position may be
+// missing any number of changes, so it is replaced.
+pub fn needs_bootstrap(directory: &Path) -> io::Result<bool> {
+    if directory.join(BOOTSTRAP_MARKER).exists() {
+        return Ok(true);
+    }
+    Ok(saved_position(directory)?.is_none())
+}
+
+// Copies the primary's store into `directory` and returns the run and
+// sequence number to tail the primary from. Files are staged and verified
+// one by one; a retry after a failure skips the ones already verified.
+// `token` is the bearer token for a primary that authenticates requests.
+pub fn bootstrap(primary: &str, directory: &Path, token: Option<String>) -> Result<(u64, u64)> {
+    fs::create_dir_all(directory)?;
+    File::create(directory.join(BOOTSTRAP_MARKER))?.sync_all()?;
+    save_position(directory, None)?;
+    let staging = directory.join(STAGING_DIR);
+    fs::create_dir_all(&staging)?;
+
+    let mut client = Client::connect_with_codec(primary, CodecKind::Bincode)?;
+    client.set_bearer_token(token);
+    let manifest = client.snapshot_manifest()?;
+    for file in &manifest.files {
+        let path = staging.join(&file.name);
+        if path.exists() && file_checksum(&path, file.len).is_ok_and(|sha256| sha256 == file.sha256) {
+            continue;
+        }
+        let tmp_path = staging.join(format!("{}.tmp", file.name));
+        let mut out = File::create(&tmp_path)?;
+        let mut offset = 0;
+        while offset < file.len {
+            let chunk = client.snapshot_chunk(file.name.as_str(), offset, file.len - offset)?;
+            if chunk.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} ended early on the primary", file.name),
+                )
+                .into());
+            }
+            out.write_all(&chunk)?;
+            offset += chunk.len() as u64;
+        }
+        out.sync_all()?;
+        if file_checksum(&tmp_path, file.len)? != file.sha256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("checksum mismatch for {}", file.name)).into());
+        }
+        fs::rename(&tmp_path, &path)?;
+    }
+
+    // Anything already in the data directory is from an older attempt or
+    // data that can't be trusted, and so is an index snapshot of it.
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str());
+        if path.is_file() && name.is_some_and(|name| is_snapshot_file(name) || name == SNAPSHOT_FILE) {
+            fs::remove_file(path)?;
+        }
+    }
+    for file in &manifest.files {
+        fs::rename(staging.join(&file.name), directory.join(&file.name))?;
+    }
+    fs::remove_dir_all(&staging)?;
+    save_position(directory, Some((manifest.run_id, manifest.seq)))?;
+    fs::remove_file(directory.join(BOOTSTRAP_MARKER))?;
+    Ok((manifest.run_id, manifest.seq))
+}
@@ -0,0 +1,59 @@
+use std::io::Seek;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{KvStore, Result, SharedData, check_writable, segment};
+
+impl KvStore {
+    // With a `max_age` in the rotation policy, checks the active generation
+    // on a background thread and seals it once it is due, so the last
+    // writes before a quiet spell still reach a sealed segment on time. The
+    // thread exits once every handle to the store has been dropped.
+    pub(crate) fn start_rotation_timer(&self) {
+        let max_age = match self.options.rotation.max_age {
+            Some(max_age) if !self.options.read_only => max_age,
+            _ => return,
+        };
+        let tick = (max_age / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let inner = Arc::downgrade(&self.inner);
+        let options = self.options.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(tick);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let store = KvStore {
+                    inner,
+                    options: options.clone(),
+                };
+                if let Err(e) = store.seal_if_due() {
+                    eprintln!("Timed segment rotation failed: {}", e);
+                }
+            }
+        });
+    }
+
+    // Checked under the read lock first, so an idle tick doesn't hold up
+    // readers, then again under the write lock.
+    fn seal_if_due(&self) -> Result<()> {
+        if !self.rotation_due(&self.inner.read())? {
+            return Ok(());
+        }
+        let mut inner = self.inner.write();
+        if self.rotation_due(&inner)? {
+            self.seal_active_locked(&mut inner)?;
+        }
+        Ok(())
+    }
+
+    // The header alone doesn't count, so an empty segment is never sealed.
+    fn rotation_due(&self, inner: &SharedData) -> Result<bool> {
+        if check_writable(inner).is_err() {
+            return Ok(false);
+        }
+        let len = inner.writer()?.stream_position()?;
+        let records = len.saturating_sub(segment::HEADER_LEN as u64);
+        Ok(self.options.rotation.should_rotate(records, inner.generation_started))
+    }
+}
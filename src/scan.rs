@@ -0,0 +1,82 @@
+use std::io;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, Result};
+
+// Which live entries `KvStore::scan` returns, in key order. Every filter
+// is evaluated by the store, so a remote scan only transfers matches.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub key_prefix: String,
+    // Resume after this key, the `next` of the previous page.
+    pub start_after: Option<String>,
+    // Matching entries per page; 0 means no limit.
+    pub limit: usize,
+    // Bounds on the value's length in bytes, inclusive.
+    pub min_value_len: Option<usize>,
+    pub max_value_len: Option<usize>,
+    pub value_prefix: Option<String>,
+    pub value_regex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(String, String)>,
+    // Where the next page starts, or `None` once the scan is complete.
+    pub next: Option<String>,
+}
+
+impl ScanOptions {
+    pub(crate) fn value_regex(&self) -> io::Result<Option<Regex>> {
+        match &self.value_regex {
+            Some(pattern) => Ok(Some(Regex::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)),
+            None => Ok(None),
+        }
+    }
+
+    // Whether the key's value passes the value filters; `regex` is the
+    // compiled `value_regex`.
+    pub(crate) fn value_matches(&self, regex: Option<&Regex>, value: &str) -> bool {
+        self.min_value_len.is_none_or(|min| value.len() >= min)
+            && self.max_value_len.is_none_or(|max| value.len() <= max)
+            && self.value_prefix.as_ref().is_none_or(|prefix| value.starts_with(prefix))
+            && regex.is_none_or(|regex| regex.is_match(value))
+    }
+}
+
+impl KvStore {
+    // Values are read one key at a time, so a long scan never holds the
+    // store's lock for long. Keys written after the scan started may be
+    // missed.
+    pub fn scan(&self, options: &ScanOptions) -> Result<ScanPage> {
+        let regex = options.value_regex()?;
+        let mut keys: Vec<String> = {
+            let inner = self.inner.read();
+            inner
+                .index
+                .keys()
+                .filter(|key| key.starts_with(&options.key_prefix))
+                .filter(|key| options.start_after.as_ref().is_none_or(|after| *key > after))
+                .cloned()
+                .collect()
+        };
+        keys.sort_unstable();
+
+        let mut page = ScanPage::default();
+        for key in keys {
+            if options.limit > 0 && page.entries.len() == options.limit {
+                page.next = page.entries.last().map(|(key, _)| key.clone());
+                break;
+            }
+            let Some(value) = self.get(&key)? else {
+                continue;
+            };
+            if options.value_matches(regex.as_ref(), &value) {
+                page.entries.push((key, value));
+            }
+        }
+        Ok(page)
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::{Cell, RefCell};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Value, VmState};
+
+use crate::{KvError, KvStore, Result};
+
+// The hook fires every HOOK_INTERVAL instructions; a script gets
+// INSTRUCTION_BUDGET instructions before it is aborted, since it runs while
+// holding the store's write lock.
+const HOOK_INTERVAL: u32 = 1_000;
+const INSTRUCTION_BUDGET: u32 = 10_000_000;
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+// Runs a Lua script atomically against the store. The script sees `KEYS` and
+// `ARGV` tables and a `kv` table with `get`, `set` and `remove`. A nil result
+// maps to `None`; strings, numbers and booleans are returned as strings.
+pub fn eval(store: &mut KvStore, script: &str, keys: Vec<String>, args: Vec<String>) -> Result<Option<String>> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )
+    .map_err(script_error)?;
+    let remaining = Cell::new(INSTRUCTION_BUDGET / HOOK_INTERVAL);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| {
+            if remaining.get() == 0 {
+                return Err(mlua::Error::runtime("script exceeded its instruction budget"));
+            }
+            remaining.set(remaining.get() - 1);
+            Ok(VmState::Continue)
+        },
+    )
+    .map_err(script_error)?;
+    lua.set_memory_limit(MEMORY_LIMIT).map_err(script_error)?;
+    let globals = lua.globals();
+    globals.set("KEYS", keys).map_err(script_error)?;
+    globals.set("ARGV", args).map_err(script_error)?;
+
+    store.transaction(|txn| {
+        let txn = RefCell::new(txn);
+        let result = lua
+            .scope(|scope| {
+                let kv = lua.create_table()?;
+                kv.set(
+                    "get",
+                    scope.create_function(|_, key: String| {
+                        txn.borrow().get(&key).map_err(mlua::Error::external)
+                    })?,
+                )?;
+                kv.set(
+                    "set",
+                    scope.create_function(|_, (key, value): (String, String)| {
+                        txn.borrow_mut().set(key, value).map_err(mlua::Error::external)
+                    })?,
+                )?;
+                kv.set(
+                    "remove",
+                    scope.create_function(|_, key: String| {
+                        txn.borrow_mut().remove(key).map_err(mlua::Error::external)
+                    })?,
+                )?;
+                lua.globals().set("kv", kv)?;
+                lua.load(script).set_name("script").eval::<Value>()
+            })
+            .map_err(script_error)?;
+        reply(result)
+    })
+}
+
+fn reply(value: Value) -> Result<Option<String>> {
+    match value {
+        Value::Nil => Ok(None),
+        Value::Boolean(b) => Ok(Some(b.to_string())),
+        Value::Integer(i) => Ok(Some(i.to_string())),
+        Value::Number(n) => Ok(Some(n.to_string())),
+        Value::String(s) => Ok(Some(s.to_str().map_err(script_error)?.to_string())),
+        other => Err(KvError::Script(format!(
+            "unsupported return type {}",
+            other.type_name()
+        ))),
+    }
+}
+
+fn script_error(e: mlua::Error) -> KvError {
+    KvError::Script(e.to_string())
+}
@@ -0,0 +1,221 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+
+use crate::segment::{self, SegmentReader};
+use crate::{CorruptRecord, CorruptionKind, KvStore, RecordFormat, Scrubber, SharedData, integrity};
+
+// What the background scrubber has found since the store was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    // Full passes over the sealed segments.
+    pub passes: u64,
+    pub segments_scrubbed: u64,
+    pub bytes_scrubbed: u64,
+    // Damage found, oldest first, whether or not it was repaired since.
+    pub damaged: Vec<CorruptRecord>,
+    // Generations replaced with a good copy from `Scrubber::repair_from`.
+    pub repaired: Vec<u64>,
+}
+
+impl KvStore {
+    pub fn scrub_report(&self) -> ScrubReport {
+        self.inner.read().scrub_report.clone()
+    }
+
+    // Scrubs on a thread of its own that exits once every handle to the
+    // store has been dropped.
+    pub(crate) fn start_scrubber(&self) {
+        let Some(scrubber) = self.options.scrubber.clone() else {
+            return;
+        };
+        let inner = Arc::downgrade(&self.inner);
+        let read_only = self.options.read_only;
+        thread::spawn(move || {
+            loop {
+                if scrub_pass(&inner, &scrubber, read_only).is_none() {
+                    return;
+                }
+                thread::sleep(scrubber.interval);
+            }
+        });
+    }
+}
+
+// One pass over the segments sealed when it starts. `None` once the store
+// is gone.
+fn scrub_pass(inner: &Weak<RwLock<SharedData>>, scrubber: &Scrubber, read_only: bool) -> Option<()> {
+    let (directory, segments) = {
+        let inner = inner.upgrade()?;
+        let inner = inner.read();
+        let active = inner.writer.as_ref().map(|_| inner.current_generation);
+        let segments: Vec<(u64, RecordFormat)> = inner
+            .readers
+            .iter()
+            .filter(|(generation, _)| Some(**generation) != active)
+            .map(|(generation, reader)| (*generation, reader.format()))
+            .collect();
+        (inner.directory.clone(), segments)
+    };
+
+    for (generation, format) in segments {
+        let path = directory.join(format!("{}.db", generation));
+        let mut throttle = Throttle::new(scrubber.bytes_per_sec);
+        let result = scrub_segment(&path, generation, format, &mut |bytes| {
+            throttle.wait(bytes);
+            inner.strong_count() > 0
+        });
+        let inner = inner.upgrade()?;
+        let (bytes, damaged) = match result {
+            Ok(Some(found)) => found,
+            Ok(None) => return None,
+            // Compacted away since the pass started.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                eprintln!("Scrubber failed to read generation {}: {}", generation, e);
+                continue;
+            }
+        };
+        let repaired = match (&scrubber.repair_from, damaged.is_empty() || read_only) {
+            (Some(backup), false) => restore_segment(&inner, backup, &path, generation, format),
+            _ => false,
+        };
+
+        let mut inner = inner.write();
+        let report = &mut inner.scrub_report;
+        report.segments_scrubbed += 1;
+        report.bytes_scrubbed += bytes;
+        for record in damaged {
+            eprintln!(
+                "Scrubber found a damaged record at offset {} of generation {}: {}",
+                record.offset, record.generation, record.detail
+            );
+            report.damaged.push(record);
+        }
+        if repaired {
+            eprintln!("Scrubber restored generation {} from its copy", generation);
+            report.repaired.push(generation);
+        }
+    }
+    inner.upgrade()?.write().scrub_report.passes += 1;
+    Some(())
+}
+
+// Reads every record of the segment, calling `progress` with the bytes
+// read so far after each one; it returns false to give up, and the scrub
+// then returns `None`. Returns the bytes read and the damage found. Damage
+// that doesn't decode ends the scrub of the segment, as there is no telling
+// where the next record starts.
+fn scrub_segment(
+    path: &Path,
+    generation: u64,
+    format: RecordFormat,
+    progress: &mut dyn FnMut(u64) -> bool,
+) -> io::Result<Option<(u64, Vec<CorruptRecord>)>> {
+    let (reader, start) = segment::open_records(path, format)?;
+    let mut damaged = Vec::new();
+    let mut pos = start;
+    for record in format.records(reader) {
+        match record {
+            Ok((cmd, end)) => {
+                if let Some(key) = integrity::checksum_mismatch(&cmd) {
+                    damaged.push(CorruptRecord {
+                        generation,
+                        offset: pos,
+                        kind: CorruptionKind::ChecksumMismatch,
+                        detail: format!("Value of {} does not match its checksum", key),
+                    });
+                }
+                pos = start + end;
+            }
+            Err(e) => {
+                damaged.push(CorruptRecord {
+                    generation,
+                    offset: pos,
+                    kind: CorruptionKind::Unreadable,
+                    detail: e.to_string(),
+                });
+                break;
+            }
+        }
+        if !progress(pos - start) {
+            return Ok(None);
+        }
+    }
+    Ok(Some((pos - start, damaged)))
+}
+
+// Copies the generation over from `backup` if the copy scrubs clean and is
+// the same size; a sealed segment never changes, so it then holds what the
+// damaged one did. The copy is renamed into place and the segment's reader
+// reopened under the write lock, so reads never see a half copied file.
+fn restore_segment(
+    inner: &RwLock<SharedData>,
+    backup: &Path,
+    path: &Path,
+    generation: u64,
+    format: RecordFormat,
+) -> bool {
+    let copy = backup.join(format!("{}.db", generation));
+    let tmp_path = path.with_extension("db.scrub");
+    let restore = || -> io::Result<bool> {
+        if fs::metadata(&copy)?.len() != fs::metadata(path)?.len() {
+            return Ok(false);
+        }
+        match scrub_segment(&copy, generation, format, &mut |_| true)? {
+            Some((_, damaged)) if damaged.is_empty() => {}
+            _ => return Ok(false),
+        }
+        fs::copy(&copy, &tmp_path)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        let mut inner = inner.write();
+        if !inner.readers.contains_key(&generation) {
+            fs::remove_file(&tmp_path)?;
+            return Ok(false);
+        }
+        let buffer = inner.readers[&generation].buffer_size();
+        fs::rename(&tmp_path, path)?;
+        inner.readers.insert(generation, SegmentReader::new(path.to_path_buf(), format, buffer));
+        Ok(true)
+    };
+    match restore() {
+        Ok(restored) => restored,
+        Err(e) => {
+            eprintln!(
+                "Scrubber could not restore generation {} from {}: {}",
+                generation,
+                backup.display(),
+                e
+            );
+            let _ = fs::remove_file(&tmp_path);
+            false
+        }
+    }
+}
+
+// Spaces reads out so a segment is read at no more than `bytes_per_sec`.
+struct Throttle {
+    bytes_per_sec: u64,
+    started: Instant,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            started: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self, bytes: u64) {
+        let due = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::{Incompatibility, KvError, RecordFormat, Result};
+
+// Starts every segment written since headers were added; older segments
+// start straight with their first record, which can't begin with `B` in
+// either format. Kept ASCII so JSON segments stay valid UTF-8.
+const MAGIC: [u8; 4] = *b"BKV\0";
+// Bumped whenever older builds could misread a segment's layout.
+pub(crate) const SEGMENT_VERSION: u16 = 1;
+// The magic, then the version and flags as little-endian u16s.
+pub(crate) const HEADER_LEN: usize = 8;
+const FLAG_BINARY_RECORDS: u16 = 1;
+
+// A generation's read handle. The file is only opened on the first read,
+// so a store with many cold segments opens quickly and holds a descriptor
+// for just the segments it has actually served.
+pub(crate) struct SegmentReader {
+    path: PathBuf,
+    format: RecordFormat,
+    buffer: usize,
+    reader: Mutex<Option<BufReader<File>>>,
+}
+
+impl SegmentReader {
+    pub(crate) fn new(path: PathBuf, format: RecordFormat, buffer: usize) -> Self {
+        SegmentReader {
+            path,
+            format,
+            buffer,
+            reader: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> io::Result<MappedMutexGuard<'_, BufReader<File>>> {
+        let mut reader = self.reader.lock();
+        if reader.is_none() {
+            *reader = Some(BufReader::with_capacity(self.buffer, File::open(&self.path)?));
+        }
+        Ok(MutexGuard::map(reader, |reader| reader.as_mut().expect("opened above")))
+    }
+
+    pub(crate) fn format(&self) -> RecordFormat {
+        self.format
+    }
+
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.buffer
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.reader.lock().is_some()
+    }
+
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.reader.lock().as_ref().map_or(0, BufReader::capacity)
+    }
+}
+
+pub(crate) fn header(format: RecordFormat) -> [u8; HEADER_LEN] {
+    let flags = match format {
+        RecordFormat::Json => 0,
+        RecordFormat::Binary => FLAG_BINARY_RECORDS,
+    };
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&SEGMENT_VERSION.to_le_bytes());
+    header[6..].copy_from_slice(&flags.to_le_bytes());
+    header
+}
+
+// Checks the segment's header against the format the manifest lists for
+// it and returns where its first record starts.
+pub(crate) fn records_start(file: &mut File, path: &Path, format: RecordFormat) -> Result<u64> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match file.read(&mut header[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let invalid = |reason: String| KvError::from(io::Error::new(io::ErrorKind::InvalidData, reason));
+    if read == 0 {
+        return Ok(0);
+    }
+    if header[..read.min(MAGIC.len())] != MAGIC[..read.min(MAGIC.len())] {
+        // Headerless segments from older builds start with a record.
+        return match header[0] {
+            b'{' | 0xB1 => Ok(0),
+            b if b.is_ascii_whitespace() => Ok(0),
+            _ => Err(invalid(format!("{} is not a bitkv segment", path.display()))),
+        };
+    }
+    if read < HEADER_LEN {
+        // Torn while the segment was created, before any record.
+        return Ok(read as u64);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > SEGMENT_VERSION {
+        return Err(KvError::Incompatible(Incompatibility::SegmentVersion {
+            segment: path.display().to_string(),
+            found: version,
+            supported: SEGMENT_VERSION,
+        }));
+    }
+    let flags = u16::from_le_bytes([header[6], header[7]]);
+    if flags & !FLAG_BINARY_RECORDS != 0 {
+        return Err(invalid(format!("{} has unknown flags {:#06x}", path.display(), flags)));
+    }
+    if (flags & FLAG_BINARY_RECORDS != 0) != (format == RecordFormat::Binary) {
+        return Err(invalid(format!(
+            "{} holds records in another format than the manifest lists",
+            path.display()
+        )));
+    }
+    Ok(HEADER_LEN as u64)
+}
+
+// Opens a segment for a sequential read from its first record, which
+// starts at the returned offset.
+pub(crate) fn open_records(path: &Path, format: RecordFormat) -> io::Result<(BufReader<File>, u64)> {
+    let mut file = File::open(path)?;
+    let start = records_start(&mut file, path, format).map_err(|e| match e {
+        KvError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    })?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok((BufReader::new(file), start))
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::LatencySummary;
+use crate::stats::LatencyHistogram;
+use crate::{KvStore, Options, Result};
+
+// Of the writes, the share that are removes rather than sets.
+const REMOVE_RATIO: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct SelfTestConfig {
+    pub duration: Duration,
+    pub threads: usize,
+    pub keys: usize,
+    pub value_size: usize,
+    pub read_ratio: f64,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        SelfTestConfig {
+            duration: Duration::from_secs(10),
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            keys: 10_000,
+            value_size: 256,
+            read_ratio: 0.8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SelfTestReport {
+    pub threads: usize,
+    pub elapsed_ms: u64,
+    pub gets: u64,
+    pub sets: u64,
+    pub removes: u64,
+    pub ops_per_sec: f64,
+    pub get_latency: LatencySummary,
+    pub write_latency: LatencySummary,
+}
+
+// One worker's share of the run.
+#[derive(Default)]
+struct WorkerTally {
+    gets: u64,
+    sets: u64,
+    removes: u64,
+    get_latency: LatencyHistogram,
+    write_latency: LatencyHistogram,
+}
+
+// Runs a mixed workload of gets, sets and removes on `config.threads`
+// threads for `config.duration`, against a scratch store created in
+// `directory` with `options`, and removes the store afterwards. Meant to
+// run on the disk the real store will use, with its options, so the
+// numbers say something about the hardware.
+pub fn run(directory: &Path, options: Options, config: &SelfTestConfig) -> Result<SelfTestReport> {
+    fs::create_dir_all(directory)?;
+    let result = run_in(directory, options, config);
+    fs::remove_dir_all(directory)?;
+    result
+}
+
+fn run_in(directory: &Path, options: Options, config: &SelfTestConfig) -> Result<SelfTestReport> {
+    let mut store = KvStore::open_with(directory.to_path_buf(), options)?;
+    let value = "x".repeat(config.value_size);
+    for i in 0..config.keys {
+        store.set(key(i), value.clone())?;
+    }
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let tallies = thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.threads.max(1))
+            .map(|t| {
+                let mut store = store.clone();
+                let value = &value;
+                let mut rng = XorShift::new(seed ^ (t as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+                scope.spawn(move || -> Result<WorkerTally> {
+                    let mut tally = WorkerTally::default();
+                    while Instant::now() < deadline {
+                        let key = key(rng.next() as usize % config.keys.max(1));
+                        let began = Instant::now();
+                        if rng.next_f64() < config.read_ratio {
+                            store.get(&key)?;
+                            tally.get_latency.record(began.elapsed());
+                            tally.gets += 1;
+                        } else if rng.next_f64() < REMOVE_RATIO {
+                            store.remove(key)?;
+                            tally.write_latency.record(began.elapsed());
+                            tally.removes += 1;
+                        } else {
+                            store.set(key, value.clone())?;
+                            tally.write_latency.record(began.elapsed());
+                            tally.sets += 1;
+                        }
+                    }
+                    Ok(tally)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("self-test worker panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    // Compaction the writes started must not outlive the directory.
+    while store.stats()?.compacting {
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.shutdown()?;
+
+    let mut total = WorkerTally::default();
+    for tally in &tallies {
+        total.gets += tally.gets;
+        total.sets += tally.sets;
+        total.removes += tally.removes;
+        total.get_latency.merge(&tally.get_latency);
+        total.write_latency.merge(&tally.write_latency);
+    }
+    let ops = total.gets + total.sets + total.removes;
+    Ok(SelfTestReport {
+        threads: tallies.len(),
+        elapsed_ms: elapsed.as_millis() as u64,
+        gets: total.gets,
+        sets: total.sets,
+        removes: total.removes,
+        ops_per_sec: ops as f64 / elapsed.as_secs_f64(),
+        get_latency: total.get_latency.summary(),
+        write_latency: total.write_latency.summary(),
+    })
+}
+
+fn key(i: usize) -> String {
+    format!("self-test/{:08}", i)
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
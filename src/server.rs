@@ -0,0 +1,1074 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::WriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, broadcast};
+
+use crate::audit::AuditLog;
+use crate::auth::TokenValidator;
+use crate::client::Client;
+use crate::codec::CodecKind;
+use crate::config::ServerConfig;
+use crate::idempotency::{IdempotencyKey, IdempotencyTable};
+use crate::protocol::{Info, Request, Response, WatchEvent};
+use crate::replication::{self, Change, ConflictResolver, ReplicaState, ReplicationEvent, ReplicationLog, Resolution};
+use crate::stats::{ConnectionTable, ServerStats};
+use crate::{KvError, KvStore, ValueMetadata, value_checksum};
+
+const INFO_TOP_KEYS: usize = 10;
+// Changes buffered per watcher or replica before it is told it lagged.
+const WATCH_BUFFER: usize = 1024;
+// Recent changes kept for replicas that reconnect.
+const REPLICATION_BACKLOG: usize = 10_000;
+const REPLICATION_HEARTBEAT: Duration = Duration::from_secs(1);
+const REPLICA_RETRY: Duration = Duration::from_secs(1);
+
+// Server-wide state shared by every connection.
+struct Server {
+    store: KvStore,
+    stats: Mutex<ServerStats>,
+    connections: Mutex<ConnectionTable>,
+    audit: Option<Mutex<AuditLog>>,
+    // Numbers every change made through this server. Appending and
+    // broadcasting happen under its lock, so receivers see changes in order.
+    replication: Mutex<ReplicationLog>,
+    changes: broadcast::Sender<Change>,
+    // Set when following a primary.
+    replica: Option<Mutex<ReplicaState>>,
+    primary_token: Option<String>,
+    // Where a replica saves its position.
+    data_dir: PathBuf,
+    // Tells the replication thread to stop before the store shuts down.
+    stopping: AtomicBool,
+    // Set when every request must carry a bearer token.
+    auth: Option<TokenValidator>,
+    request_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_pending_write_bytes: usize,
+    admin: AdminLane,
+    // Signalled by `Request::Shutdown`.
+    shutdown: Notify,
+    idempotency: Mutex<IdempotencyTable>,
+}
+
+type AdminJob = Box<dyn FnOnce() + Send>;
+
+// A dedicated thread for the blocking part of admin requests, so Info,
+// Compact and the like never queue behind a flood of client requests in
+// the shared blocking pool.
+struct AdminLane {
+    jobs: mpsc::Sender<AdminJob>,
+}
+
+impl AdminLane {
+    fn start() -> std::io::Result<AdminLane> {
+        let (jobs, queue) = mpsc::channel::<AdminJob>();
+        std::thread::Builder::new()
+            .name("admin-lane".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job();
+                }
+            })?;
+        Ok(AdminLane { jobs })
+    }
+
+    async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> std::io::Result<T> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.jobs
+            .send(Box::new(move || {
+                let _ = tx.send(job());
+            }))
+            .map_err(|_| std::io::Error::other("Admin lane stopped"))?;
+        rx.await.map_err(|_| std::io::Error::other("Admin job failed"))
+    }
+}
+
+// A server with its store open and its addresses bound, not accepting
+// connections yet.
+pub struct Service {
+    server: Arc<Server>,
+    listener: TcpListener,
+    read_only: Vec<TcpListener>,
+}
+
+impl Service {
+    // Copies the primary first if this is a replica with no saved position,
+    // and otherwise resumes from that position.
+    pub async fn bind(config: ServerConfig) -> crate::Result<Service> {
+        let mut replica = config.replica_of.clone().map(ReplicaState::new);
+        if let Some(state) = &mut replica {
+            let position = if replication::needs_bootstrap(&config.data_dir)? {
+                let primary = state.primary().to_string();
+                Some(bootstrap_replica(primary, config.data_dir.clone(), config.primary_token.clone()).await)
+            } else {
+                replication::saved_position(&config.data_dir)?
+            };
+            if let Some((run_id, seq)) = position {
+                state.resume_from(run_id, seq);
+            }
+        }
+        let store = KvStore::open_with(config.data_dir.clone(), config.store_options())?;
+        let audit = match &config.audit_log {
+            Some(path) => Some(Mutex::new(AuditLog::open(path)?)),
+            None => None,
+        };
+        let server = Arc::new(Server {
+            store,
+            stats: Mutex::new(ServerStats::default()),
+            connections: Mutex::new(ConnectionTable::default()),
+            audit,
+            replication: Mutex::new(ReplicationLog::new(REPLICATION_BACKLOG)),
+            changes: broadcast::channel(WATCH_BUFFER).0,
+            replica: replica.map(Mutex::new),
+            primary_token: config.primary_token.clone(),
+            data_dir: config.data_dir.clone(),
+            stopping: AtomicBool::new(false),
+            auth: config.token_validator()?,
+            request_timeout: config.request_timeout_ms.map(Duration::from_millis),
+            idle_timeout: config.idle_timeout_secs.map(Duration::from_secs),
+            max_pending_write_bytes: config.max_pending_write_bytes as usize,
+            admin: AdminLane::start()?,
+            shutdown: Notify::new(),
+            idempotency: Mutex::new(IdempotencyTable::open(&config.data_dir)?),
+        });
+        let listener = TcpListener::bind(&config.address).await?;
+        println!("BitKV server started on {}", listener.local_addr()?);
+        let mut read_only = Vec::new();
+        for address in &config.read_only_addresses {
+            read_only.push(TcpListener::bind(address).await?);
+            println!("Serving reads only on {}", address);
+        }
+        Ok(Service {
+            server,
+            listener,
+            read_only,
+        })
+    }
+
+    // Decides between conflicting writes while following a primary, in
+    // place of `LastWriteWins`. Does nothing on a primary.
+    pub fn set_conflict_resolver(&self, resolver: Arc<dyn ConflictResolver>) -> std::io::Result<()> {
+        if let Some(replica) = &self.server.replica {
+            replica
+                .lock()
+                .map_err(|_| std::io::Error::other("Mutex poisoned"))?
+                .set_resolver(resolver);
+        }
+        Ok(())
+    }
+
+    // The bound address, for configs that ask for port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serves until a client sends `Request::Shutdown` or `signal`
+    // completes, then shuts the store down.
+    pub async fn run(self, signal: impl Future<Output = ()>) -> crate::Result<()> {
+        let Service {
+            server,
+            listener,
+            read_only,
+        } = self;
+        let mut follower = server.replica.is_some().then(|| {
+            let server = server.clone();
+            std::thread::spawn(move || follow_primary(&server))
+        });
+        for listener in read_only {
+            tokio::spawn(accept_read_only(listener, server.clone()));
+        }
+
+        let mut signal = std::pin::pin!(signal);
+        loop {
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = server.shutdown.notified() => {
+                    println!("Shutting down on request");
+                    stop_following(&server, follower.take()).await?;
+                    let store = server.store.clone();
+                    server.admin.run(move || store.shutdown()).await??;
+                    return Ok(());
+                }
+                _ = &mut signal => {
+                    println!("Shutting down");
+                    stop_following(&server, follower.take()).await?;
+                    let store = server.store.clone();
+                    tokio::task::spawn_blocking(move || store.shutdown())
+                        .await
+                        .map_err(std::io::Error::other)??;
+                    return Ok(());
+                }
+            };
+            spawn_connection(socket, addr, server.clone(), false);
+        }
+    }
+}
+
+async fn accept_read_only(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => spawn_connection(socket, addr, server.clone(), true),
+            Err(e) => eprintln!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+fn spawn_connection(socket: TcpStream, addr: SocketAddr, server: Arc<Server>, read_only: bool) {
+    println!("Accepted connection");
+    if let Err(e) = socket.set_nodelay(true) {
+        eprintln!("Failed to set TCP_NODELAY: {}", e);
+    }
+    tokio::spawn(async move {
+        if let Err(e) = process_connection(socket, addr, server, read_only).await {
+            eprintln!("Connection error: {}", e);
+        }
+    });
+}
+
+// Waits for the replication thread, which notices within a heartbeat, so
+// it applies nothing once the store has shut down.
+async fn stop_following(server: &Server, follower: Option<JoinHandle<()>>) -> std::io::Result<()> {
+    server.stopping.store(true, Ordering::Relaxed);
+    if let Some(follower) = follower {
+        tokio::task::spawn_blocking(move || follower.join())
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(|_| std::io::Error::other("Replication thread panicked"))?;
+    }
+    Ok(())
+}
+
+// Retries until the copy succeeds; files verified by a failed attempt are
+// kept.
+async fn bootstrap_replica(primary: String, data_dir: PathBuf, token: Option<String>) -> (u64, u64) {
+    loop {
+        println!("Copying the store from {}", primary);
+        let task = {
+            let (primary, data_dir, token) = (primary.clone(), data_dir.clone(), token.clone());
+            tokio::task::spawn_blocking(move || replication::bootstrap(&primary, &data_dir, token))
+        };
+        match task.await {
+            Ok(Ok(position)) => return position,
+            Ok(Err(e)) => eprintln!("Bootstrap failed: {}", e),
+            Err(e) => eprintln!("Bootstrap failed: {}", e),
+        }
+        tokio::time::sleep(REPLICA_RETRY).await;
+    }
+}
+
+async fn process_connection(
+    socket: TcpStream,
+    addr: SocketAddr,
+    server: Arc<Server>,
+    read_only: bool,
+) -> std::io::Result<()> {
+    let (id, kill) = server
+        .connections
+        .lock()
+        .map_err(|_| std::io::Error::other("Mutex poisoned"))?
+        .register(addr);
+    let result = serve_connection(socket, addr, id, kill, &server, read_only).await;
+    if let Ok(mut connections) = server.connections.lock() {
+        connections.unregister(id);
+    }
+    result
+}
+
+async fn serve_connection(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    id: u64,
+    kill: Arc<Notify>,
+    server: &Arc<Server>,
+    read_only: bool,
+) -> std::io::Result<()> {
+    println!("Processing connection {}...", id);
+    let (reader, mut writer) = socket.split();
+    let mut reader = BufReader::new(reader);
+    let mut codec = CodecKind::Json;
+    let mut outbox = Outbox::default();
+    loop {
+        // Waits for the start of the next request while earlier responses
+        // drain. Reading the rest of it is left out of the select, since
+        // a frame read is not safe to cancel part way.
+        let eof = tokio::select! {
+            ready = reader.fill_buf() => match ready {
+                Ok(buf) => buf.is_empty(),
+                Err(_) => break,
+            },
+            ready = writer.writable(), if !outbox.is_empty() => {
+                ready?;
+                outbox.flush(&writer)?;
+                if let Ok(mut connections) = server.connections.lock() {
+                    connections.set_pending(id, outbox.pending as u64);
+                }
+                continue;
+            }
+            _ = kill.notified() => {
+                println!("Connection {} killed", id);
+                break;
+            }
+            _ = idle(server.idle_timeout) => {
+                println!("Connection {} idle, closing", id);
+                break;
+            }
+        };
+        if eof {
+            outbox.drain(&mut writer).await?;
+            break;
+        }
+        let frame = match codec.read_frame_async(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            _ => break,
+        };
+        let mut next_codec = codec;
+        let mut subject = None;
+        let request = codec
+            .codec()
+            .decode_request(&frame)
+            .map_err(|e| format!("Invalid Request: {}", e))
+            .and_then(|req| authorize(req, server))
+            .map(|(req, claimed)| {
+                subject = claimed;
+                req
+            });
+        let response = match request {
+            Ok(req) if read_only && !req.is_read_only() => {
+                Response::Error("This listener only serves reads".to_string())
+            }
+            Ok(Request::Hello { codec: requested }) => {
+                next_codec = requested;
+                Response::Ok
+            }
+            Ok(Request::Ping) => Response::Pong,
+            Ok(Request::Shutdown) => {
+                outbox.drain(&mut writer).await?;
+                let encoded = codec.codec().encode_response(&Response::Ok)?;
+                codec.write_frame_async(&mut writer, &encoded).await?;
+                server.shutdown.notify_one();
+                return Ok(());
+            }
+            Ok(req) if req.is_admin() => execute_admin(req, server).await,
+            Ok(Request::Watch { prefix }) => {
+                // Subscribe before acknowledging so no later write is missed.
+                let changes = server.changes.subscribe();
+                outbox.drain(&mut writer).await?;
+                let encoded = codec.codec().encode_response(&Response::Ok)?;
+                codec.write_frame_async(&mut writer, &encoded).await?;
+                return stream_watch_events(&mut reader, &mut writer, codec, &prefix, changes, &kill).await;
+            }
+            Ok(Request::SnapshotManifest) => {
+                let server = server.clone();
+                let manifest = tokio::task::spawn_blocking(move || {
+                    replication::snapshot_manifest(&server.store, &server.replication)
+                })
+                .await;
+                match manifest {
+                    Ok(Ok(manifest)) => Response::SnapshotManifest(manifest),
+                    Ok(Err(e)) => Response::Error(e.to_string()),
+                    Err(e) => Response::Error(format!("Internal server error: {}", e)),
+                }
+            }
+            Ok(Request::Replicate { run_id, after_seq }) => {
+                outbox.drain(&mut writer).await?;
+                let encoded = codec.codec().encode_response(&Response::Ok)?;
+                codec.write_frame_async(&mut writer, &encoded).await?;
+                return stream_replication(&mut reader, &mut writer, codec, run_id, after_seq, server, &kill).await;
+            }
+            Ok(req) if req.is_mutating() && server.replica.is_some() => {
+                Response::Error("Writes must go to the primary; this server is a replica".to_string())
+            }
+            Ok(Request::GetBounded { staleness, .. })
+                if let Some(Err(reason)) = server
+                    .replica
+                    .as_ref()
+                    .map(|replica| replica.lock().map_err(|_| "Mutex poisoned".to_string())?.check(&staleness)) =>
+            {
+                Response::Error(format!("Stale read refused: {}", reason))
+            }
+            Ok(Request::Idempotent { token, request }) => {
+                let client = format!("{}#{}", addr, id);
+                execute_idempotent((subject, token), *request, server, &client).await?
+            }
+            Ok(req) => execute_client_request(req, server, &format!("{}#{}", addr, id)).await?,
+            Err(reason) => Response::Error(reason),
+        };
+
+        let encoded = codec.codec().encode_response(&response)?;
+        outbox.push(codec, &encoded)?;
+        outbox.flush(&writer)?;
+        if let Ok(mut connections) = server.connections.lock() {
+            connections.record(id, frame.len() as u64, encoded.len() as u64);
+            connections.set_pending(id, outbox.pending as u64);
+        }
+        if outbox.pending > server.max_pending_write_bytes {
+            println!(
+                "Connection {} has {} bytes of unread responses, closing",
+                id, outbox.pending
+            );
+            break;
+        }
+        codec = next_codec;
+    }
+    Ok(())
+}
+
+// Unwraps `Request::Authenticated`, along with the token's subject. When
+// the server authenticates requests, everything but the handshake and
+// pings needs a valid token.
+fn authorize(req: Request, server: &Server) -> Result<(Request, Option<String>), String> {
+    match (req, &server.auth) {
+        (Request::Authenticated { request, .. }, None) => Ok((*request, None)),
+        (Request::Authenticated { token, request }, Some(validator)) => match validator.validate(&token) {
+            Ok(claims) => Ok((*request, claims.sub)),
+            Err(e) => Err(format!("Authentication failed: {}", e)),
+        },
+        (req @ (Request::Hello { .. } | Request::Ping), _) | (req, None) => Ok((req, None)),
+        (_, Some(_)) => Err("Authentication required".to_string()),
+    }
+}
+
+// Framed responses the socket has not taken yet. Requests keep being read
+// while they drain, so a client can pipeline freely, but one that stops
+// reading is cut off at the high-water mark instead of growing this
+// without bound.
+#[derive(Default)]
+struct Outbox {
+    frames: VecDeque<Vec<u8>>,
+    // How much of the front frame has been written.
+    written: usize,
+    pending: usize,
+}
+
+impl Outbox {
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    fn push(&mut self, codec: CodecKind, encoded: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(encoded.len() + 4);
+        codec.write_frame(&mut frame, encoded)?;
+        self.pending += frame.len();
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    // Writes as much as the socket takes without waiting.
+    fn flush(&mut self, writer: &WriteHalf<'_>) -> std::io::Result<()> {
+        while let Some(frame) = self.frames.front() {
+            match writer.try_write(&frame[self.written..]) {
+                Ok(n) => {
+                    self.written += n;
+                    self.pending -= n;
+                    if self.written == frame.len() {
+                        self.frames.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes everything, waiting for the client to read it.
+    async fn drain(&mut self, writer: &mut WriteHalf<'_>) -> std::io::Result<()> {
+        while let Some(frame) = self.frames.pop_front() {
+            writer.write_all(&frame[self.written..]).await?;
+            self.written = 0;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+// Resolves once a connection has waited `timeout` for its next request;
+// never without one.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Runs a data request, recording its latency, publishing the keys it
+// changed and auditing it.
+async fn execute_client_request(req: Request, server: &Arc<Server>, client: &str) -> std::io::Result<Response> {
+    let key = request_key(&req).map(str::to_string);
+    let written: Vec<String> = req.written_keys().into_iter().map(str::to_string).collect();
+    // Audited as JSON whatever codec the client speaks.
+    let audited = (req.is_mutating() && server.audit.is_some())
+        .then(|| serde_json::to_string(&req))
+        .transpose()?;
+    let started = Instant::now();
+    let deadline = server.request_timeout.map(|timeout| started + timeout);
+    let response = execute_request(req, server.store.clone(), deadline).await;
+    if let Ok(mut stats) = server.stats.lock() {
+        stats.record(key.as_deref(), started.elapsed());
+    }
+    if !matches!(response, Response::Error(_)) && !written.is_empty() {
+        publish_changes(server, written).await;
+    }
+    if let (Some(request), Some(audit)) = (audited, &server.audit) {
+        let ok = !matches!(response, Response::Error(_));
+        let recorded = audit
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))
+            .and_then(|mut audit| audit.record(client, &request, ok));
+        if let Err(e) = recorded {
+            eprintln!("Failed to write audit entry: {}", e);
+        }
+    }
+    Ok(response)
+}
+
+// The first response for a token is kept in the idempotency table, and
+// only a retry under the same subject gets it back. Failed attempts are
+// not kept and may be retried.
+async fn execute_idempotent(
+    key: IdempotencyKey,
+    req: Request,
+    server: &Arc<Server>,
+    client: &str,
+) -> std::io::Result<Response> {
+    if matches!(req, Request::Idempotent { .. }) {
+        return Ok(Response::Error("Idempotent requests cannot be nested".to_string()));
+    }
+    {
+        let mut table = server
+            .idempotency
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+        if let Some(stored) = table.response(&key) {
+            return Ok(serde_json::from_str(stored)?);
+        }
+        if !table.in_flight.insert(key.clone()) {
+            return Ok(Response::Error(format!("Request {:?} is already in progress", key.1)));
+        }
+    }
+
+    let response = execute_client_request(req, server, client).await;
+    let encoded = match &response {
+        Ok(response) if !matches!(response, Response::Error(_)) => Some(serde_json::to_string(response)),
+        _ => None,
+    };
+    if let Ok(mut table) = server.idempotency.lock() {
+        table.in_flight.remove(&key);
+        if let Some(Ok(encoded)) = encoded
+            && let Err(e) = table.record(key, encoded)
+        {
+            eprintln!("Failed to keep an idempotent response: {}", e);
+        }
+    }
+    response
+}
+
+// Numbers and broadcasts the current value of each written key, with its
+// metadata so replicas store it the same way. Reading
+// the value under the log's lock keeps concurrent writes to one key from
+// being published out of order.
+async fn publish_changes(server: &Arc<Server>, keys: Vec<String>) {
+    let server = server.clone();
+    let published = tokio::task::spawn_blocking(move || -> crate::Result<()> {
+        let mut log = server
+            .replication
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+        for key in keys {
+            let (value, metadata) = match server.store.get_with_metadata(&key)? {
+                Some((value, metadata)) => (Some(value), metadata),
+                None => (None, ValueMetadata::default()),
+            };
+            // Sending fails only when nobody is listening.
+            let _ = server.changes.send(log.append_with_metadata(key, value, metadata));
+        }
+        Ok(())
+    })
+    .await;
+    match published {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("Failed to publish changes: {}", e),
+        Err(e) => eprintln!("Failed to publish changes: {}", e),
+    }
+}
+
+// Runs until the watcher disconnects or is killed. Anything the client
+// sends in the meantime is ignored.
+async fn stream_watch_events<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    codec: CodecKind,
+    prefix: &str,
+    mut changes: broadcast::Receiver<Change>,
+    kill: &Notify,
+) -> std::io::Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        let event = tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) if change.key.starts_with(prefix) => WatchEvent::Changed { key: change.key },
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => WatchEvent::Lagged,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            frame = codec.read_frame_async(reader) => match frame {
+                Ok(Some(_)) => continue,
+                _ => return Ok(()),
+            },
+            _ = kill.notified() => return Ok(()),
+        };
+        let encoded = codec.codec().encode_response(&Response::Event(event))?;
+        codec.write_frame_async(writer, &encoded).await?;
+    }
+}
+
+// Streams to a replica: where it stands first, then any backlog it missed,
+// then live changes, with heartbeats while idle. Runs until the replica
+// disconnects or is killed.
+async fn stream_replication<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    codec: CodecKind,
+    run_id: u64,
+    after_seq: u64,
+    server: &Server,
+    kill: &Notify,
+) -> std::io::Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let (mut changes, mut pending, mut last_sent) = {
+        let log = server
+            .replication
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+        let changes = server.changes.subscribe();
+        let (pending, last_sent) = catch_up(&log, run_id, after_seq);
+        (changes, pending, last_sent)
+    };
+    let mut heartbeat = tokio::time::interval_at(
+        tokio::time::Instant::now() + REPLICATION_HEARTBEAT,
+        REPLICATION_HEARTBEAT,
+    );
+    loop {
+        for event in pending.drain(..) {
+            let encoded = codec.codec().encode_response(&Response::Replication(event))?;
+            codec.write_frame_async(writer, &encoded).await?;
+        }
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) if change.seq > last_sent => {
+                    last_sent = change.seq;
+                    pending.push(ReplicationEvent::Change(change));
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let log = server
+                        .replication
+                        .lock()
+                        .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+                    (pending, last_sent) = catch_up(&log, log.run_id(), last_sent);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = heartbeat.tick() => {
+                let log = server
+                    .replication
+                    .lock()
+                    .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+                pending.push(ReplicationEvent::Heartbeat { run_id: log.run_id(), seq: log.seq() });
+            }
+            frame = codec.read_frame_async(reader) => match frame {
+                Ok(Some(_)) => {}
+                _ => return Ok(()),
+            },
+            _ = kill.notified() => return Ok(()),
+        }
+    }
+}
+
+// The events that bring a replica at `after_seq` of `run_id` up to the
+// head of `log`, and the last sequence number they cover.
+fn catch_up(log: &ReplicationLog, run_id: u64, after_seq: u64) -> (Vec<ReplicationEvent>, u64) {
+    let (run_id_now, head) = (log.run_id(), log.seq());
+    match log.since(run_id, after_seq) {
+        Some(backlog) => {
+            let mut events = vec![ReplicationEvent::Heartbeat { run_id: run_id_now, seq: head }];
+            events.extend(backlog.into_iter().map(ReplicationEvent::Change));
+            (events, head)
+        }
+        None => (vec![ReplicationEvent::Resync { run_id: run_id_now, seq: head }], head),
+    }
+}
+
+// Follows the primary until the server stops, reconnecting after errors
+// and resuming from the last applied change.
+fn follow_primary(server: &Server) {
+    let Some(replica) = &server.replica else {
+        return;
+    };
+    while !server.stopping.load(Ordering::Relaxed) {
+        if let Err(e) = replicate_from_primary(server, replica) {
+            eprintln!("Replication error: {}", e);
+        }
+        if let Ok(mut state) = replica.lock() {
+            state.set_connected(false);
+        }
+        std::thread::sleep(REPLICA_RETRY);
+    }
+}
+
+fn replicate_from_primary(server: &Server, replica: &Mutex<ReplicaState>) -> crate::Result<()> {
+    let (primary, (run_id, after_seq), mut saved) = {
+        let state = replica.lock().map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+        (state.primary().to_string(), state.position(), state.checkpoint())
+    };
+    let mut client = Client::connect(primary.as_str())?;
+    client.set_bearer_token(server.primary_token.clone());
+    let stream = client.replicate(run_id, after_seq)?;
+    replica
+        .lock()
+        .map_err(|_| std::io::Error::other("Mutex poisoned"))?
+        .set_connected(true);
+    for event in stream {
+        if server.stopping.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let event = event?;
+        match &event {
+            ReplicationEvent::Change(change) => {
+                let resolver = replica
+                    .lock()
+                    .map_err(|_| std::io::Error::other("Mutex poisoned"))?
+                    .resolver();
+                // Held while applying, so no local write to the key slips in
+                // between resolving and applying.
+                let mut log = server
+                    .replication
+                    .lock()
+                    .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+                let resolution = match log.latest(&change.key) {
+                    Some(current) => resolver.resolve(&change.key, current, change),
+                    None => Resolution::TakeIncoming,
+                };
+                // A resolver's own value is written now, untagged.
+                let entry = match resolution {
+                    Resolution::KeepCurrent => None,
+                    Resolution::TakeIncoming => Some((change.value.clone(), change.metadata)),
+                    Resolution::Value(value) => Some((value, ValueMetadata::default())),
+                };
+                if let Some((value, metadata)) = entry {
+                    let mut store = server.store.clone();
+                    match &value {
+                        Some(value) => store.set_with_metadata(change.key.clone(), value.clone(), metadata)?,
+                        None => store.remove(change.key.clone())?,
+                    }
+                    // Watchers of the replica see the change as a local one.
+                    let _ = server.changes.send(log.relay(change, value, metadata));
+                }
+            }
+            ReplicationEvent::Resync { .. } => {
+                eprintln!("Replica missed changes from {}; it copies the primary's store again on restart", primary);
+            }
+            ReplicationEvent::Heartbeat { .. } => {}
+        }
+        let checkpoint = {
+            let mut state = replica.lock().map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+            state.record(&event);
+            state.checkpoint()
+        };
+        // Saved only after the change is in the store, so a crash at worst
+        // applies it again.
+        if checkpoint != saved {
+            replication::save_position(&server.data_dir, checkpoint)?;
+            saved = checkpoint;
+        }
+    }
+    Ok(())
+}
+
+fn request_key(req: &Request) -> Option<&str> {
+    match req {
+        Request::Get { key }
+        | Request::GetBounded { key, .. }
+        | Request::Set { key, .. }
+        | Request::SetChecked { key, .. }
+        | Request::SetTagged { key, .. }
+        | Request::SetOpts { key, .. }
+        | Request::GetTagged { key }
+        | Request::Remove { key }
+        | Request::GetDel { key }
+        | Request::GetSet { key, .. }
+        | Request::Append { key, .. }
+        | Request::Rename { from: key, .. }
+        | Request::Copy { from: key, .. } => Some(key),
+        Request::MGet { .. }
+        | Request::MRemove { .. }
+        | Request::Eval { .. }
+        | Request::Info
+        | Request::Compact
+        | Request::CancelCompaction
+        | Request::Shutdown
+        | Request::Aggregate { .. }
+        | Request::Scan { .. }
+        | Request::ClientList
+        | Request::ClientKill { .. }
+        | Request::Hello { .. }
+        | Request::Ping
+        | Request::Watch { .. }
+        | Request::Replicate { .. }
+        | Request::SnapshotManifest
+        | Request::SnapshotChunk { .. }
+        | Request::SyncChanges { .. }
+        | Request::SyncMerge { .. } => None,
+        Request::Idempotent { request, .. } | Request::Authenticated { request, .. } => request_key(request),
+    }
+}
+
+async fn execute_admin(req: Request, server: &Server) -> Response {
+    match req {
+        Request::Info => execute_info(server).await,
+        Request::ClientList => match server.connections.lock() {
+            Ok(connections) => Response::Clients(connections.list()),
+            Err(_) => Response::Error("Connection table lock poisoned".to_string()),
+        },
+        Request::ClientKill { id } => match server.connections.lock().map(|mut c| c.kill(id)) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(_) => Response::Error("Connection table lock poisoned".to_string()),
+        },
+        Request::Compact => {
+            let mut store = server.store.clone();
+            match server.admin.run(move || store.compact()).await {
+                Ok(Ok(())) => Response::Ok,
+                Ok(Err(e)) => Response::Error(e.to_string()),
+                Err(e) => Response::Error(format!("Internal server error: {}", e)),
+            }
+        }
+        Request::CancelCompaction => {
+            let store = server.store.clone();
+            match server.admin.run(move || store.cancel_compaction()).await {
+                Ok(Ok(_)) => Response::Ok,
+                Ok(Err(e)) => Response::Error(e.to_string()),
+                Err(e) => Response::Error(format!("Internal server error: {}", e)),
+            }
+        }
+        _ => Response::Error("Not an admin request".to_string()),
+    }
+}
+
+async fn execute_info(server: &Server) -> Response {
+    let store = server.store.clone();
+    let stats_and_memory = move || -> crate::Result<_> {
+        Ok((store.stats()?, store.memory_usage()?, store.open_report()?.unclean_shutdown))
+    };
+    let (store_stats, memory, unclean_shutdown) = match server.admin.run(stats_and_memory).await {
+        Ok(Ok(stats)) => stats,
+        Ok(Err(e)) => return Response::Error(e.to_string()),
+        Err(e) => return Response::Error(format!("Internal server error: {}", e)),
+    };
+    let replication = match &server.replica {
+        Some(replica) => replica.lock().ok().map(|state| state.info()),
+        None => server.replication.lock().ok().map(|log| log.info()),
+    };
+    let Some(replication) = replication else {
+        return Response::Error("Replication lock poisoned".to_string());
+    };
+    let stats = match server.stats.lock() {
+        Ok(stats) => stats,
+        Err(_) => return Response::Error("Stats lock poisoned".to_string()),
+    };
+    Response::Info(Box::new(Info {
+        uptime_secs: stats.uptime().as_secs(),
+        total_ops: stats.total_ops(),
+        latency: stats.latency(),
+        top_keys: stats.top_keys(INFO_TOP_KEYS),
+        store: store_stats,
+        replication,
+        resident_bytes: crate::stats::resident_bytes(),
+        memory,
+        unclean_shutdown,
+    }))
+}
+
+// Only gets, sets and removes are bounded by `deadline`.
+async fn execute_request(req: Request, mut store: KvStore, deadline: Option<Instant>) -> Response {
+    let result = tokio::task::spawn_blocking(move || {
+        match req {
+            Request::Get { key } | Request::GetBounded { key, .. } => {
+                let value = match deadline {
+                    Some(deadline) => store.get_bytes_with_deadline(&key, deadline),
+                    None => store.get_bytes(&key),
+                };
+                match value {
+                    Ok(Some(v)) => checked_value(v),
+                    Ok(None) => Response::NotFound,
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            req if !req.checksum_matches() => {
+                let key = request_key(&req).unwrap_or_default().to_string();
+                Response::Error(KvError::ChecksumMismatch(key).to_string())
+            }
+            Request::Set { key, value, .. } => match String::from_utf8(value) {
+                Ok(value) => set(&mut store, key, value, deadline),
+                Err(e) => match store.set_bytes(key, e.into_bytes()) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(e.to_string()),
+                },
+            },
+            Request::SetChecked { key, value, .. } => set(&mut store, key, value, deadline),
+            Request::SetTagged { key, value, content_type, .. } => {
+                match store.set_with_content_type(key, value, content_type) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Request::SetOpts { key, value, options, .. } => match store.set_opts(key, value, options) {
+                Ok(outcome) => Response::SetOutcome(outcome),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::GetTagged { key } => match store.get_with_content_type(&key) {
+                Ok(Some((value, content_type))) => Response::TaggedValue {
+                    checksum: Some(value_checksum(&value)),
+                    value,
+                    content_type,
+                },
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Remove { key } => {
+                let removed = match deadline {
+                    Some(deadline) => store.remove_with_deadline(key, deadline),
+                    None => store.remove(key),
+                };
+                match removed {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Request::GetDel { key } => match store.get_and_remove(key) {
+                Ok(Some(v)) => checked_value(v.into_bytes()),
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::GetSet { key, value, .. } => match store.get_and_set(key, value) {
+                Ok(Some(v)) => checked_value(v.into_bytes()),
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Append { key, suffix, .. } => match store.append(key, &suffix) {
+                Ok(len) => Response::Length(len as u64),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::MGet { keys } => match store.get_many(&keys) {
+                Ok(values) => Response::Values(values),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Scan { options } => match store.scan(&options) {
+                Ok(page) => Response::Scan(page),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::MRemove { keys } => match store.remove_many(keys) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Rename { from, to, overwrite } => match store.rename(from, to, overwrite) {
+                Ok(()) => Response::Ok,
+                Err(KvError::KeyNotFound(_)) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Copy { from, to, overwrite } => match store.copy(from, to, overwrite) {
+                Ok(()) => Response::Ok,
+                Err(KvError::KeyNotFound(_)) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Eval { script, keys, args } => eval(&mut store, &script, keys, args),
+            Request::SnapshotChunk { name, offset, len } => {
+                match replication::read_snapshot_chunk(&store, &name, offset, len) {
+                    Ok(chunk) => Response::Chunk(chunk),
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Request::Aggregate { prefix } => match store.aggregate(&prefix) {
+                Ok(Some(aggregate)) => Response::Aggregate(aggregate),
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::SyncChanges { seen } => match store.changes_since(&seen) {
+                Ok(batch) => Response::Sync(batch),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::SyncMerge { batch } => match store.merge_changes(batch) {
+                Ok(changed) => Response::Length(changed as u64),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Info
+            | Request::Compact
+            | Request::CancelCompaction
+            | Request::Shutdown
+            | Request::ClientList
+            | Request::ClientKill { .. }
+            | Request::Hello { .. }
+            | Request::Ping
+            | Request::Watch { .. }
+            | Request::Replicate { .. }
+            | Request::SnapshotManifest
+            | Request::Idempotent { .. }
+            | Request::Authenticated { .. } => {
+                Response::Error("Handled by the server".to_string())
+            }
+        }
+    }).await;
+    match result {
+        Ok(response) => response,
+        Err(e) => Response::Error(format!("Internal server error: {}", e)),
+    }
+}
+
+fn set(store: &mut KvStore, key: String, value: String, deadline: Option<Instant>) -> Response {
+    let set = match deadline {
+        Some(deadline) => store.set_with_deadline(key, value, deadline),
+        None => store.set(key, value),
+    };
+    match set {
+        Ok(_) => Response::Ok,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+// Values go back with their checksum, so the client can tell if one was
+// corrupted on the way.
+fn checked_value(value: Vec<u8>) -> Response {
+    Response::CheckedValue {
+        checksum: value_checksum(&value),
+        value,
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn eval(store: &mut KvStore, script: &str, keys: Vec<String>, args: Vec<String>) -> Response {
+    match crate::scripting::eval(store, script, keys, args) {
+        Ok(Some(v)) => checked_value(v.into_bytes()),
+        Ok(None) => Response::NotFound,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn eval(_store: &mut KvStore, _script: &str, _keys: Vec<String>, _args: Vec<String>) -> Response {
+    Response::Error("Scripting support is not enabled on this server".to_string())
+}
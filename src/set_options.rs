@@ -0,0 +1,84 @@
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, KvStore, Result, SharedData, now_millis};
+
+// Conditions and side effects for `KvStore::set_opts`, after Redis's SET.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    // Only write if the key is missing.
+    pub nx: bool,
+    // Only write if the key exists.
+    pub xx: bool,
+    // The value reads as missing once `ttl` has passed.
+    pub ttl: Option<Duration>,
+    // Keep the current value's expiry instead of clearing it.
+    pub keep_ttl: bool,
+    // Report the value the key had before.
+    pub get_old: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetOutcome {
+    // False when `nx` or `xx` ruled the write out.
+    pub written: bool,
+    // The previous value, if `get_old` was set and there was one.
+    pub old: Option<String>,
+}
+
+impl KvStore {
+    // Checks the conditions and writes under one write lock, so nothing
+    // can change the key in between. A plain write clears any expiry the
+    // key had, unless `keep_ttl` is set.
+    pub fn set_opts(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        options: SetOptions,
+    ) -> Result<SetOutcome> {
+        if options.nx && options.xx {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "nx and xx are mutually exclusive").into());
+        }
+        if options.ttl.is_some() && options.keep_ttl {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ttl and keep_ttl are mutually exclusive").into());
+        }
+        let key = key.into();
+        let mut inner = self.inner.write();
+        let old = self.get_locked(&inner, &key)?;
+        let written = !(options.nx && old.is_some() || options.xx && old.is_none());
+        if written {
+            let now = now_millis();
+            let expires_at = match options.ttl {
+                Some(ttl) => Some(now.saturating_add(ttl.as_millis() as u64)),
+                None if options.keep_ttl => self.expires_at_locked(&inner, &key)?,
+                None => None,
+            };
+            self.set_at_locked(&mut inner, key, value.into(), now, None, expires_at)?;
+        }
+        Ok(SetOutcome {
+            written,
+            old: old.filter(|_| options.get_old),
+        })
+    }
+
+    // Writes a value that reads as missing once `ttl` has passed.
+    pub fn set_with_ttl(&mut self, key: impl Into<String>, value: impl Into<String>, ttl: Duration) -> Result<()> {
+        let mut inner = self.inner.write();
+        let now = now_millis();
+        let expires_at = now.saturating_add(ttl.as_millis() as u64);
+        self.set_at_locked(&mut inner, key.into(), value.into(), now, None, Some(expires_at))
+    }
+
+    // When the key's current value expires, if it does and is still live.
+    pub(crate) fn expires_at_locked(&self, inner: &SharedData, key: &str) -> Result<Option<u64>> {
+        let Some(cmd_pos) = inner.index.get(key).copied() else {
+            return Ok(None);
+        };
+        match self.read_command(inner, &cmd_pos, key)? {
+            Some(Command::Set { expires_at, .. }) => Ok(expires_at),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CommandPos, KvStore, Result, recovery};
+
+pub(crate) const SNAPSHOT_FILE: &str = "index.snapshot";
+
+// The index as of a clean shutdown, plus how long each segment was then so
+// anything appended afterwards can still be replayed.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot<Index> {
+    segments: BTreeMap<u64, u64>,
+    index: Index,
+}
+
+impl KvStore {
+    // Stops any compaction and writes an index snapshot so the next open can
+    // skip replaying the log, and marks the store as cleanly shut down. The
+    // store stays usable afterwards.
+    pub fn shutdown(&self) -> Result<()> {
+        self.cancel_compaction()?;
+        let inner = self.inner.read();
+        if inner.writer.is_none() {
+            return Ok(());
+        }
+        if inner.compacting {
+            // A write kicked off another compaction meanwhile; the snapshot
+            // would be stale as soon as it finished.
+            return Ok(());
+        }
+        {
+            let mut writer = inner.writer()?;
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+
+        let mut segments = BTreeMap::new();
+        for generation in inner.readers.keys() {
+            let len = fs::metadata(inner.directory.join(format!("{}.db", generation)))?.len();
+            segments.insert(*generation, len);
+        }
+        let snapshot = IndexSnapshot {
+            segments,
+            index: &inner.index,
+        };
+        let tmp_path = inner.directory.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, inner.directory.join(SNAPSHOT_FILE))?;
+        recovery::write_clean_marker(&inner)?;
+        Ok(())
+    }
+
+    // Installs the snapshot's index, if there is a usable one, and returns
+    // the offset to resume replay from in each segment it covers. The
+    // snapshot is consumed so a later crash never resurrects it.
+    pub(crate) fn load_snapshot(&self, directory: &Path) -> io::Result<HashMap<u64, u64>> {
+        let path = directory.join(SNAPSHOT_FILE);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let parsed: serde_json::Result<IndexSnapshot<HashMap<String, CommandPos>>> =
+            serde_json::from_reader(BufReader::new(fs::File::open(&path)?));
+        if !self.options.read_only {
+            fs::remove_file(&path)?;
+        }
+        let snapshot = match parsed {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("Ignoring unreadable index snapshot: {}", e);
+                return Ok(HashMap::new());
+            }
+        };
+
+        // Every segment must still hold at least what it held at shutdown;
+        // anything else (compaction, truncation) means a full replay.
+        for (generation, len) in &snapshot.segments {
+            let current = fs::metadata(directory.join(format!("{}.db", generation))).map(|m| m.len());
+            if !current.is_ok_and(|current| current >= *len) {
+                eprintln!("Index snapshot is stale (generation {}); replaying the log", generation);
+                return Ok(HashMap::new());
+            }
+        }
+
+        let mut inner = self.inner.write();
+        for (key, cmd_pos) in snapshot.index {
+            inner.index_insert(key, cmd_pos);
+        }
+        Ok(snapshot.segments.into_iter().collect())
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::protocol::{ClientInfo, LatencySummary};
+
+const LATENCY_BUCKETS: usize = 40;
+const TOP_KEYS_CAPACITY: usize = 1024;
+
+// Bucket `i` counts requests that took less than 2^i microseconds.
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    max_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            max_us: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += n;
+        }
+        self.count += other.count;
+        self.max_us = self.max_us.max(other.max_us);
+    }
+
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = ((self.count as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return (1u64 << i).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            p50_us: self.percentile(0.50),
+            p90_us: self.percentile(0.90),
+            p99_us: self.percentile(0.99),
+            max_us: self.max_us,
+        }
+    }
+}
+
+// The process's resident set size, where the platform reports it. Worth
+// watching next to latency, since allocator pressure shows up in both.
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Space-saving top-k: when full, the least accessed key is replaced and the
+// newcomer inherits its count, so heavy hitters are never undercounted.
+pub struct TopKeys {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl TopKeys {
+    pub fn new(capacity: usize) -> Self {
+        TopKeys {
+            capacity,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() >= self.capacity {
+            let min_key = self
+                .counts
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(k, _)| k.clone());
+            if let Some(min_key) = min_key {
+                count += self.counts.remove(&min_key).unwrap_or(0);
+            }
+        }
+        self.counts.insert(key.to_string(), count);
+    }
+
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut keys: Vec<(String, u64)> =
+            self.counts.iter().map(|(k, c)| (k.clone(), *c)).collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(n);
+        keys
+    }
+}
+
+pub struct ServerStats {
+    started: Instant,
+    total_ops: u64,
+    latency: LatencyHistogram,
+    top_keys: TopKeys,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats {
+            started: Instant::now(),
+            total_ops: 0,
+            latency: LatencyHistogram::default(),
+            top_keys: TopKeys::new(TOP_KEYS_CAPACITY),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn record(&mut self, key: Option<&str>, latency: Duration) {
+        self.total_ops += 1;
+        self.latency.record(latency);
+        if let Some(key) = key {
+            self.top_keys.record(key);
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn total_ops(&self) -> u64 {
+        self.total_ops
+    }
+
+    pub fn latency(&self) -> LatencySummary {
+        self.latency.summary()
+    }
+
+    pub fn top_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.top_keys.top(n)
+    }
+}
+
+struct Connection {
+    addr: SocketAddr,
+    connected: Instant,
+    last_active: Instant,
+    commands: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    pending_write_bytes: u64,
+    kill: Arc<Notify>,
+}
+
+// Live connections by id. Each connection waits on its `Notify` so a kill
+// from another connection can interrupt a blocked read.
+#[derive(Default)]
+pub struct ConnectionTable {
+    next_id: u64,
+    connections: HashMap<u64, Connection>,
+}
+
+impl ConnectionTable {
+    pub fn register(&mut self, addr: SocketAddr) -> (u64, Arc<Notify>) {
+        self.next_id += 1;
+        let kill = Arc::new(Notify::new());
+        let now = Instant::now();
+        self.connections.insert(
+            self.next_id,
+            Connection {
+                addr,
+                connected: now,
+                last_active: now,
+                commands: 0,
+                bytes_read: 0,
+                bytes_written: 0,
+                pending_write_bytes: 0,
+                kill: kill.clone(),
+            },
+        );
+        (self.next_id, kill)
+    }
+
+    pub fn unregister(&mut self, id: u64) {
+        self.connections.remove(&id);
+    }
+
+    pub fn record(&mut self, id: u64, bytes_read: u64, bytes_written: u64) {
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.commands += 1;
+            conn.bytes_read += bytes_read;
+            conn.bytes_written += bytes_written;
+            conn.last_active = Instant::now();
+        }
+    }
+
+    pub fn set_pending(&mut self, id: u64, pending_write_bytes: u64) {
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.pending_write_bytes = pending_write_bytes;
+        }
+    }
+
+    // Returns whether a connection with that id was open.
+    pub fn kill(&mut self, id: u64) -> bool {
+        match self.connections.remove(&id) {
+            Some(conn) => {
+                conn.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self
+            .connections
+            .iter()
+            .map(|(id, conn)| ClientInfo {
+                id: *id,
+                addr: conn.addr.to_string(),
+                age_secs: conn.connected.elapsed().as_secs(),
+                idle_secs: conn.last_active.elapsed().as_secs(),
+                commands: conn.commands,
+                bytes_read: conn.bytes_read,
+                bytes_written: conn.bytes_written,
+                pending_write_bytes: conn.pending_write_bytes,
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+
+use crate::{Command, KvError, KvStore, Result, blob, check_writable, now_millis};
+
+impl KvStore {
+    // Stores the `len` bytes `reader` yields as the key's value without
+    // holding them in memory: they are copied straight into a blob segment,
+    // whatever the blob threshold, and the log gets the pointer once the
+    // whole value is down. The value must be UTF-8 like any other. The
+    // write lock is held throughout, so other reads and writes wait for
+    // the copy. Keys under a validator, an aggregate or a sync prefix need
+    // the whole value and are read into memory first.
+    pub fn set_from_reader(&mut self, key: impl Into<String>, mut reader: impl Read, len: u64) -> Result<()> {
+        let key = key.into();
+        let mut inner = self.inner.write();
+        let needs_value = self.options.aggregated(&key)
+            || self.options.synced(&key)
+            || self
+                .options
+                .validators
+                .iter()
+                .any(|(prefix, _)| key.starts_with(prefix.as_str()));
+        if needs_value {
+            let mut value = String::new();
+            (&mut reader).take(len).read_to_string(&mut value)?;
+            if (value.len() as u64) < len {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Reader ended early").into());
+            }
+            return self.set_at_locked(&mut inner, key, value, now_millis(), None, None);
+        }
+
+        check_writable(&inner)?;
+        if blob::blob_writer_full(&mut inner)? {
+            self.before_seal(&inner)?;
+        }
+        let (blob, checksum) = blob::append_blob_from(&mut inner, &key, reader, len)?;
+        let cmd = Command::Set {
+            key,
+            value: String::new(),
+            timestamp: Some(now_millis()),
+            blob: Some(blob),
+            content_type: None,
+            checksum: Some(checksum),
+            expires_at: None,
+        };
+        let mut cmd_pos = self.append_command(&mut inner, &cmd)?;
+        cmd_pos.blob = Some(blob);
+        if let Command::Set { key, .. } = cmd {
+            self.record_access(&inner, &key);
+            inner.index_insert(key.clone(), cmd_pos);
+            self.evict_locked(&mut inner, &key)?;
+        }
+        Ok(())
+    }
+
+    // Writes the key's value to `writer` and returns its length, or `None`
+    // if the key is missing. Values in blob segments, as `set_from_reader`
+    // and the blob threshold leave them, are decoded and written out as
+    // they are read rather than held whole; others are small enough to
+    // read first. A streamed value's checksum can only be checked at the
+    // end, so on a mismatch `writer` already has the damaged value and
+    // the error says not to trust it.
+    pub fn get_to_writer(&self, key: &str, mut writer: impl Write) -> Result<Option<u64>> {
+        let inner = self.inner.read();
+        let blob = inner.index.get(key).copied().and_then(|cmd_pos| cmd_pos.blob.map(|blob| (cmd_pos, blob)));
+        let Some((cmd_pos, blob)) = blob else {
+            let Some(value) = self.read_locked(&inner, key)? else {
+                return Ok(None);
+            };
+            writer.write_all(value.as_bytes())?;
+            return Ok(Some(value.len() as u64));
+        };
+        let Some(Command::Set {
+            timestamp,
+            checksum,
+            expires_at,
+            ..
+        }) = self.read_command(&inner, &cmd_pos, key)?
+        else {
+            return Ok(None);
+        };
+        if self.value_expired(&inner, &cmd_pos, key, timestamp, expires_at, now_millis())? {
+            return Ok(None);
+        }
+        let (copied, copied_checksum) = blob::copy_blob(&inner, &blob, &mut writer)?;
+        if checksum.is_some_and(|checksum| checksum != copied_checksum) {
+            return Err(KvError::ChecksumMismatch(key.to_string()));
+        }
+        self.record_access(&inner, key);
+        Ok(Some(copied))
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::Client;
+use crate::{Hlc, HlcTimestamp, KvStore, Options, Result, SharedData, now_millis};
+
+const SYNC_LOG_FILE: &str = "sync.log";
+
+// For each node, the highest of its write numbers a store has taken in.
+pub type VersionVector = BTreeMap<u64, u64>;
+
+// A write to a synced key, numbered and stamped by the node that made it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncRecord {
+    pub key: String,
+    // `None` for a remove.
+    pub value: Option<String>,
+    pub hlc: HlcTimestamp,
+    pub origin: u64,
+    pub seq: u64,
+}
+
+// The latest record of every synced key the other side has not seen,
+// and the sender's version vector, which the receiver catches up to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SyncBatch {
+    pub records: Vec<SyncRecord>,
+    pub seen: VersionVector,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncSummary {
+    // Records from the peer that changed this store.
+    pub received: usize,
+    // Records sent to the peer.
+    pub sent: usize,
+}
+
+// Combines a key's value with one written concurrently on another node;
+// `None` is a removed key, and returning it removes the key. Nodes only
+// converge if the result is the same whatever order values arrive in and
+// however often each one does, as with a union of sets or a max.
+pub trait Merge: Send + Sync {
+    fn merge(&self, key: &str, local: Option<&str>, remote: Option<&str>) -> Option<String>;
+}
+
+// How concurrent writes to keys under a prefix set with
+// `Options::sync_prefix` are reconciled.
+#[derive(Clone)]
+pub enum MergeMode {
+    // The write with the later hybrid logical clock stamp wins.
+    LastWriteWins,
+    Custom(Arc<dyn Merge>),
+}
+
+// The other end of `KvStore::sync`: a store in this process, or a server
+// through a `Client`.
+pub trait SyncPeer {
+    fn changes_since(&mut self, seen: &VersionVector) -> Result<SyncBatch>;
+    // Returns how many records changed the peer.
+    fn merge_changes(&mut self, batch: SyncBatch) -> Result<usize>;
+}
+
+// One line of the sync log. The node id comes first; a `Seen` line
+// records the vector after a merge, as records that lost or were since
+// overwritten don't show how far it got.
+#[derive(Serialize, Deserialize)]
+enum SyncEntry {
+    Node(u64),
+    Seen(VersionVector),
+    Record(SyncRecord),
+}
+
+pub(crate) struct SyncState {
+    node: u64,
+    clock: Hlc,
+    seen: VersionVector,
+    latest: HashMap<String, SyncRecord>,
+    // `None` for read-only opens.
+    log: Option<File>,
+    // Set while a merge applies a peer's record, so the write it makes is
+    // logged as that record rather than stamped as a new local one.
+    adopting: Option<SyncRecord>,
+}
+
+impl SyncState {
+    fn stamp(&mut self, key: &str, value: Option<String>) -> SyncRecord {
+        let seq = self.seen.get(&self.node).copied().unwrap_or(0) + 1;
+        SyncRecord {
+            key: key.to_string(),
+            value,
+            hlc: self.clock.now(),
+            origin: self.node,
+            seq,
+        }
+    }
+
+    fn append(&mut self, entry: &SyncEntry) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            log.write_all(&line)?;
+        }
+        Ok(())
+    }
+
+    fn take_in(&mut self, record: SyncRecord) {
+        self.take_in_seen(&record);
+        self.latest.insert(record.key.clone(), record);
+    }
+
+    // Counts a record as seen without it becoming the key's latest.
+    fn take_in_seen(&mut self, record: &SyncRecord) {
+        let seen = self.seen.entry(record.origin).or_insert(0);
+        *seen = (*seen).max(record.seq);
+    }
+}
+
+// Reads the sync log, giving a new store a node id. A log where most
+// records have been overwritten is rewritten with only the latest ones.
+pub(crate) fn open_sync_state(directory: &Path, options: &Options) -> io::Result<Option<SyncState>> {
+    if options.sync_prefixes.is_empty() {
+        return Ok(None);
+    }
+    let path = directory.join(SYNC_LOG_FILE);
+    let mut node = None;
+    let mut seen = VersionVector::new();
+    let mut latest: HashMap<String, SyncRecord> = HashMap::new();
+    let mut records = 0;
+    match File::open(&path) {
+        Ok(file) => {
+            let mut lines = BufReader::new(file).lines().peekable();
+            while let Some(line) = lines.next() {
+                let entry = match serde_json::from_str::<SyncEntry>(&line?) {
+                    Ok(entry) => entry,
+                    // A crash can leave the last line half written.
+                    Err(_) if lines.peek().is_none() => break,
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                };
+                match entry {
+                    SyncEntry::Node(id) => node = Some(id),
+                    SyncEntry::Seen(vector) => merge_vectors(&mut seen, &vector),
+                    SyncEntry::Record(record) => {
+                        records += 1;
+                        let seq = seen.entry(record.origin).or_insert(0);
+                        *seq = (*seq).max(record.seq);
+                        latest.insert(record.key.clone(), record);
+                    }
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let node = node.unwrap_or_else(new_node_id);
+    let mut clock = Hlc::new(node);
+    if let Some(newest) = latest.values().map(|record| record.hlc).max() {
+        clock.observe(newest);
+    }
+    let log = if options.read_only {
+        None
+    } else if !path.exists() || records > 2 * latest.len() {
+        Some(rewrite_log(directory, node, &seen, &latest)?)
+    } else {
+        Some(fs::OpenOptions::new().append(true).open(&path)?)
+    };
+    Ok(Some(SyncState {
+        node,
+        clock,
+        seen,
+        latest,
+        log,
+        adopting: None,
+    }))
+}
+
+fn rewrite_log(
+    directory: &Path,
+    node: u64,
+    seen: &VersionVector,
+    latest: &HashMap<String, SyncRecord>,
+) -> io::Result<File> {
+    let path = directory.join(SYNC_LOG_FILE);
+    let tmp_path = directory.join(format!("{}.tmp", SYNC_LOG_FILE));
+    let mut file = io::BufWriter::new(File::create(&tmp_path)?);
+    let mut records: Vec<&SyncRecord> = latest.values().collect();
+    records.sort_by_key(|record| (record.origin, record.seq));
+    let entries = [SyncEntry::Node(node), SyncEntry::Seen(seen.clone())]
+        .into_iter()
+        .chain(records.into_iter().cloned().map(SyncEntry::Record));
+    for entry in entries {
+        serde_json::to_writer(&mut file, &entry)?;
+        file.write_all(b"\n")?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    fs::OpenOptions::new().append(true).open(&path)
+}
+
+fn new_node_id() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    (nanos ^ (u64::from(std::process::id()) << 32)).max(1)
+}
+
+fn merge_vectors(into: &mut VersionVector, from: &VersionVector) {
+    for (node, seq) in from {
+        let seen = into.entry(*node).or_insert(0);
+        *seen = (*seen).max(*seq);
+    }
+}
+
+impl KvStore {
+    // Exchanges changes to synced keys with `peer`, both ways: first its
+    // records this store hasn't seen, then this store's, merged as each
+    // side's `Options::sync_prefix` says. Either side can write while
+    // apart from the other; once they have synced with each other, or
+    // through other nodes, they hold the same values.
+    pub fn sync(&mut self, peer: &mut impl SyncPeer) -> Result<SyncSummary> {
+        let seen = self.sync_vector()?;
+        let pulled = peer.changes_since(&seen)?;
+        let peer_seen = pulled.seen.clone();
+        let received = self.merge_changes(pulled)?;
+        let pushed = self.changes_since(&peer_seen)?;
+        let sent = pushed.records.len();
+        if sent > 0 {
+            peer.merge_changes(pushed)?;
+        }
+        Ok(SyncSummary { received, sent })
+    }
+
+    // Empty unless some prefix is synced.
+    pub fn sync_vector(&self) -> Result<VersionVector> {
+        let inner = self.inner.read();
+        Ok(inner.sync.as_ref().map(|sync| sync.seen.clone()).unwrap_or_default())
+    }
+
+    pub fn changes_since(&self, seen: &VersionVector) -> Result<SyncBatch> {
+        let inner = self.inner.read();
+        let Some(sync) = &inner.sync else {
+            return Ok(SyncBatch::default());
+        };
+        let mut records: Vec<SyncRecord> = sync
+            .latest
+            .values()
+            .filter(|record| record.seq > seen.get(&record.origin).copied().unwrap_or(0))
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| (record.origin, record.seq));
+        Ok(SyncBatch {
+            records,
+            seen: sync.seen.clone(),
+        })
+    }
+
+    // Applies a peer's records to keys this store syncs and ignores the
+    // rest. Returns how many changed the store.
+    pub fn merge_changes(&mut self, batch: SyncBatch) -> Result<usize> {
+        let mut inner = self.inner.write();
+        if inner.sync.is_none() {
+            return Ok(0);
+        }
+        let mut changed = 0;
+        for record in batch.records {
+            let Some(mode) = self.options.merge_mode(&record.key).cloned() else {
+                continue;
+            };
+            let sync = inner.sync.as_mut().expect("checked above");
+            if record.seq <= sync.seen.get(&record.origin).copied().unwrap_or(0) {
+                continue;
+            }
+            sync.clock.observe(record.hlc);
+            let local = sync.latest.get(&record.key).cloned();
+            let merged = match (&mode, &local) {
+                (_, None) => Some(record.value.clone()),
+                (MergeMode::LastWriteWins, Some(local)) => (record.hlc > local.hlc).then(|| record.value.clone()),
+                (MergeMode::Custom(merge), Some(local)) => {
+                    let merged = merge.merge(&record.key, local.value.as_deref(), record.value.as_deref());
+                    (merged != local.value).then_some(merged)
+                }
+            };
+            let Some(value) = merged else {
+                sync.take_in_seen(&record);
+                continue;
+            };
+            // A value of the peer's own is logged as its record, so it
+            // isn't sent back as a new write.
+            let adopted = value == record.value;
+            if adopted {
+                sync.adopting = Some(record.clone());
+            } else {
+                sync.take_in_seen(&record);
+            }
+            let key = record.key;
+            let written = match value {
+                Some(value) => self.set_at_locked(&mut inner, key, value, now_millis(), None, None),
+                None => self.remove_locked(&mut inner, key),
+            };
+            if let Some(sync) = inner.sync.as_mut() {
+                sync.adopting = None;
+            }
+            written?;
+            changed += 1;
+        }
+        let sync = inner.sync.as_mut().expect("checked above");
+        merge_vectors(&mut sync.seen, &batch.seen);
+        let seen = SyncEntry::Seen(sync.seen.clone());
+        sync.append(&seen)?;
+        Ok(changed)
+    }
+
+    // Logs a write to a synced key, stamped as this node's next one unless
+    // a merge is adopting a peer's record.
+    pub(crate) fn record_sync_write(&self, inner: &mut SharedData, key: &str, value: Option<String>) -> Result<()> {
+        let Some(sync) = inner.sync.as_mut() else {
+            return Ok(());
+        };
+        let record = match sync.adopting.take() {
+            Some(record) => record,
+            None => sync.stamp(key, value),
+        };
+        sync.append(&SyncEntry::Record(record.clone()))?;
+        sync.take_in(record);
+        Ok(())
+    }
+}
+
+impl SyncPeer for KvStore {
+    fn changes_since(&mut self, seen: &VersionVector) -> Result<SyncBatch> {
+        KvStore::changes_since(self, seen)
+    }
+
+    fn merge_changes(&mut self, batch: SyncBatch) -> Result<usize> {
+        KvStore::merge_changes(self, batch)
+    }
+}
+
+impl SyncPeer for Client {
+    fn changes_since(&mut self, seen: &VersionVector) -> Result<SyncBatch> {
+        Ok(Client::sync_changes(self, seen.clone())?)
+    }
+
+    fn merge_changes(&mut self, batch: SyncBatch) -> Result<usize> {
+        Ok(Client::sync_merge(self, batch)?)
+    }
+}
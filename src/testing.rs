@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+use tokio::sync::oneshot;
+
+use crate::client::Client;
+use crate::config::ServerConfig;
+use crate::server::Service;
+use crate::{CommandPos, KvStore};
+
+// Kept small, so a test can tie up every blocking thread.
+pub const BLOCKING_THREADS: usize = 16;
+
+// A server on an ephemeral local port, over a store in a temporary
+// directory. Dropping it shuts the server down and deletes the store.
+pub struct TestServer {
+    addr: SocketAddr,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<crate::Result<()>>>,
+    data_dir: PathBuf,
+    // `None` when the caller owns the data directory.
+    dir: Option<tempfile::TempDir>,
+}
+
+// Starts a server with the default config and connects a client to it.
+pub fn spawn_server() -> crate::Result<(Client, TestServer)> {
+    spawn_server_with(ServerConfig::default())
+}
+
+// Like `spawn_server`, but `config`'s address and data directory are
+// replaced.
+pub fn spawn_server_with(config: ServerConfig) -> crate::Result<(Client, TestServer)> {
+    let dir = tempfile::tempdir()?;
+    let config = ServerConfig {
+        data_dir: dir.path().to_path_buf(),
+        ..config
+    };
+    let (client, mut server) = spawn_server_in(config)?;
+    server.dir = Some(dir);
+    Ok((client, server))
+}
+
+// Like `spawn_server_with`, but keeps `config`'s data directory, which
+// outlives the server so a test can start another one over it.
+pub fn spawn_server_in(config: ServerConfig) -> crate::Result<(Client, TestServer)> {
+    let config = ServerConfig {
+        address: "127.0.0.1:0".to_string(),
+        ..config
+    };
+    let data_dir = config.data_dir.clone();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(BLOCKING_THREADS)
+        .enable_all()
+        .build()?;
+    let service = runtime.block_on(Service::bind(config))?;
+    let addr = service.local_addr()?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let thread = std::thread::Builder::new()
+        .name("test-server".to_string())
+        .spawn(move || {
+            runtime.block_on(service.run(async {
+                let _ = stopped.await;
+            }))
+        })?;
+    let server = TestServer {
+        addr,
+        stop: Some(stop),
+        thread: Some(thread),
+        data_dir,
+        dir: None,
+    };
+    let client = Client::connect(addr)?;
+    Ok((client, server))
+}
+
+impl TestServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    // Another client, for tests that need more than one connection.
+    pub fn connect(&self) -> io::Result<Client> {
+        Client::connect(self.addr)
+    }
+
+    // Stops the server and waits for its store to shut down. Also done on
+    // drop, where errors are only printed.
+    pub fn shutdown(mut self) -> crate::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> crate::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            // Already gone if a client sent `Request::Shutdown`.
+            let _ = stop.send(());
+        }
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| io::Error::other("Test server thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            eprintln!("Test server failed to shut down: {}", e);
+        }
+    }
+}
+
+// A copy of a store's index, for putting entries back the way they were
+// once compaction has removed the segments they point at, as read repair
+// has to cope with.
+pub struct IndexCopy(HashMap<String, CommandPos>);
+
+impl KvStore {
+    pub fn copy_index(&self) -> IndexCopy {
+        IndexCopy(self.inner.read().index.clone())
+    }
+
+    // Puts back `key`'s entry from `copy` without touching any counts, as a
+    // compaction that missed it would have left it.
+    pub fn restore_index_entry(&self, copy: &IndexCopy, key: &str) {
+        if let Some(cmd_pos) = copy.0.get(key) {
+            self.inner.write().index.insert(key.to_string(), *cmd_pos);
+        }
+    }
+}
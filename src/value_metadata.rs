@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ContentType, KvStore, Result, now_millis};
+
+// What the store recorded about a value when it was written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValueMetadata {
+    // Milliseconds since the Unix epoch. Absent for records written before
+    // timestamps were; compaction gives those their segment's mtime.
+    pub written_at: Option<u64>,
+    pub content_type: Option<ContentType>,
+    pub expires_at: Option<u64>,
+}
+
+impl KvStore {
+    // Like `get`, along with the value's write time, tag and expiry.
+    pub fn get_with_metadata(&self, key: &str) -> Result<Option<(String, ValueMetadata)>> {
+        let inner = self.inner.read();
+        self.read_entry_locked(&inner, key)
+    }
+
+    // Writes a value with metadata recorded elsewhere, as a replica does
+    // with its primary's writes. A missing write time is taken to be now.
+    pub fn set_with_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        metadata: ValueMetadata,
+    ) -> Result<()> {
+        let mut inner = self.inner.write();
+        let written_at = metadata.written_at.unwrap_or_else(now_millis);
+        self.set_at_locked(&mut inner, key.into(), value.into(), written_at, metadata.content_type, metadata.expires_at)
+    }
+}
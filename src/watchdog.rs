@@ -0,0 +1,81 @@
+use std::sync::{Arc, atomic::Ordering};
+
+use crate::{DiskWatchdog, KvStore, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiskLevel {
+    Ok,
+    Soft,
+    Hard,
+}
+
+impl KvStore {
+    // Checks free space once right away, then keeps checking on a background
+    // thread that exits once every handle to the store has been dropped.
+    pub(crate) fn start_disk_watchdog(&self) -> Result<()> {
+        let watchdog = match self.options.disk_watchdog {
+            Some(watchdog) if !self.options.read_only => watchdog,
+            _ => return Ok(()),
+        };
+        let mut level = self.check_disk_space(&watchdog, DiskLevel::Ok)?;
+
+        let inner = Arc::downgrade(&self.inner);
+        let options = self.options.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(watchdog.interval);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let store = KvStore {
+                    inner,
+                    options: options.clone(),
+                };
+                match store.check_disk_space(&watchdog, level) {
+                    Ok(new_level) => level = new_level,
+                    Err(e) => eprintln!("Disk watchdog failed to check free space: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Acts only when the level changes, so a full disk does not produce a
+    // warning or a compaction on every tick. Space can drop past both
+    // thresholds between checks, so entering either one compacts.
+    fn check_disk_space(&self, watchdog: &DiskWatchdog, previous: DiskLevel) -> Result<DiskLevel> {
+        let directory = self.inner.read().directory.clone();
+        let available = fs4::available_space(&directory)?;
+        let level = if available < watchdog.hard_bytes {
+            DiskLevel::Hard
+        } else if available < watchdog.soft_bytes {
+            DiskLevel::Soft
+        } else {
+            DiskLevel::Ok
+        };
+        if level == previous {
+            return Ok(level);
+        }
+
+        let mut inner = self.inner.write();
+        inner.low_disk.store(level == DiskLevel::Hard, Ordering::Relaxed);
+        match level {
+            DiskLevel::Hard => eprintln!(
+                "Free space in {} is {} bytes, below the hard threshold; refusing writes",
+                directory.display(),
+                available
+            ),
+            DiskLevel::Soft => eprintln!(
+                "Warning: free space in {} is {} bytes, below the soft threshold",
+                directory.display(),
+                available
+            ),
+            DiskLevel::Ok => eprintln!("Free space in {} recovered", directory.display()),
+        }
+        // Compaction is the one thing the store can do to give space back.
+        if level != DiskLevel::Ok {
+            self.compact_locked(&mut inner)?;
+        }
+        Ok(level)
+    }
+}
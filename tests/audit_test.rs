@@ -0,0 +1,24 @@
+use bitkv_rs::audit::{self, AuditLog};
+
+#[test]
+fn test_audit_chain_detects_tampering() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let path = temp_dir.path().join("audit.log");
+    let mut log = AuditLog::open(&path).expect("open audit log");
+    log.record("127.0.0.1:1#1", r#"{"Set":{"key":"a","value":"1"}}"#, true)
+        .expect("record");
+    drop(log);
+
+    // Reopening continues the existing chain.
+    let mut log = AuditLog::open(&path).expect("reopen audit log");
+    log.record("127.0.0.1:1#2", r#"{"Remove":{"key":"a"}}"#, true)
+        .expect("record");
+    drop(log);
+    let last = audit::verify(&path).expect("verify").expect("entries");
+    assert_eq!(last.seq, 1);
+
+    let contents = std::fs::read_to_string(&path).expect("read log");
+    std::fs::write(&path, contents.replacen(r#"\"value\":\"1\""#, r#"\"value\":\"2\""#, 1)).expect("tamper");
+    let err = audit::verify(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
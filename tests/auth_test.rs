@@ -0,0 +1,79 @@
+use bitkv_rs::auth::{self, Audience, AuthError, Claims, TokenValidator};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock").as_secs()
+}
+
+fn claims(exp: u64) -> Claims {
+    Claims {
+        sub: Some("reporting".to_string()),
+        exp: Some(exp),
+        ..Claims::default()
+    }
+}
+
+#[test]
+fn test_validator_accepts_only_signed_current_tokens() {
+    let validator = TokenValidator::with_secret("s3cret");
+    let token = auth::sign_hs256(&claims(now() + 60), b"s3cret", None);
+    assert_eq!(validator.validate(&token).expect("valid").sub.as_deref(), Some("reporting"));
+
+    let forged = auth::sign_hs256(&claims(now() + 60), b"guess", None);
+    assert_eq!(validator.validate(&forged), Err(AuthError::BadSignature));
+    let expired = auth::sign_hs256(&claims(now() - 3600), b"s3cret", None);
+    assert_eq!(validator.validate(&expired), Err(AuthError::Expired));
+    let early = Claims {
+        nbf: Some(now() + 3600),
+        ..claims(now() + 7200)
+    };
+    let early = auth::sign_hs256(&early, b"s3cret", None);
+    assert_eq!(validator.validate(&early), Err(AuthError::NotYetValid));
+
+    // An unsigned token claiming `alg: none` must not get through.
+    let (signed, _) = token.rsplit_once('.').expect("signature");
+    let payload = signed.split('.').nth(1).expect("payload");
+    let unsigned = format!("eyJhbGciOiJub25lIn0.{}.", payload);
+    assert!(matches!(validator.validate(&unsigned), Err(AuthError::UnsupportedAlgorithm(_))));
+    assert!(matches!(validator.validate("not-a-token"), Err(AuthError::Malformed(_))));
+}
+
+#[test]
+fn test_jwks_keys_are_picked_by_id() {
+    // base64url of "old-key" and "new-key".
+    let jwks = r#"{"keys": [
+        {"kty": "oct", "kid": "old", "k": "b2xkLWtleQ"},
+        {"kty": "oct", "kid": "new", "k": "bmV3LWtleQ"},
+        {"kty": "EC", "kid": "ec", "crv": "P-256"}
+    ]}"#;
+    let validator = TokenValidator::from_jwks(jwks)
+        .expect("jwks")
+        .issuer("issuer")
+        .audience("kv");
+    let claims = Claims {
+        iss: Some("issuer".to_string()),
+        aud: Some(Audience::Many(vec!["other".to_string(), "kv".to_string()])),
+        ..claims(now() + 60)
+    };
+    for (kid, secret) in [("old", b"old-key"), ("new", b"new-key")] {
+        let token = auth::sign_hs256(&claims, secret, Some(kid));
+        assert!(validator.validate(&token).is_ok(), "{}", kid);
+    }
+    let mismatched = auth::sign_hs256(&claims, b"old-key", Some("new"));
+    assert_eq!(validator.validate(&mismatched), Err(AuthError::BadSignature));
+    let unknown = auth::sign_hs256(&claims, b"old-key", Some("gone"));
+    assert_eq!(validator.validate(&unknown), Err(AuthError::UnknownKey("gone".to_string())));
+
+    let other_issuer = Claims {
+        iss: Some("elsewhere".to_string()),
+        ..claims.clone()
+    };
+    let token = auth::sign_hs256(&other_issuer, b"new-key", Some("new"));
+    assert_eq!(validator.validate(&token), Err(AuthError::WrongIssuer));
+    let other_audience = Claims {
+        aud: Some(Audience::One("billing".to_string())),
+        ..claims
+    };
+    let token = auth::sign_hs256(&other_audience, b"new-key", Some("new"));
+    assert_eq!(validator.validate(&token), Err(AuthError::WrongAudience));
+}
@@ -0,0 +1,244 @@
+use bitkv_rs::{ScanOptions, value_checksum};
+use bitkv_rs::client::{CachedClient, Client, MockClient, ReadPreference, ReplicaSetClient};
+use bitkv_rs::codec::CodecKind;
+use bitkv_rs::protocol::Response;
+use bitkv_rs::testing::{TestServer, spawn_server};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_heartbeat_fails_fast_on_an_unresponsive_server() {
+    // Accepts the connection but never answers.
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let mut client = Client::connect(listener.local_addr().expect("addr")).expect("connect");
+    let _socket = listener.accept().expect("accept");
+
+    client.set_heartbeat(Some(Duration::from_millis(50)));
+    std::thread::sleep(Duration::from_millis(60));
+    let started = Instant::now();
+    let err = client.get("k").expect_err("no server to answer");
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(client.get("k").is_err(), "the connection is closed after a missed heartbeat");
+}
+
+#[test]
+fn test_client_refuses_a_value_that_fails_its_checksum() {
+    // Answers one request with a value whose checksum does not match, as
+    // if it had been corrupted on the way.
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let mut client = Client::connect(listener.local_addr().expect("addr")).expect("connect");
+    let (socket, _) = listener.accept().expect("accept");
+    let server = std::thread::spawn(move || {
+        let kind = CodecKind::Json;
+        let mut reader = io::BufReader::new(socket.try_clone().expect("clone"));
+        kind.read_frame(&mut reader).expect("read").expect("request");
+        let resp = Response::CheckedValue {
+            value: b"v".to_vec(),
+            checksum: value_checksum("v") ^ 1,
+        };
+        let mut socket = socket;
+        kind.write_frame(&mut socket, &kind.codec().encode_response(&resp).expect("encode")).expect("write");
+        socket
+    });
+
+    let err = client.get("k").expect_err("corrupted value accepted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    drop(server.join().expect("fake server"));
+}
+
+#[test]
+fn test_mock_client_serves_from_memory_and_fails_on_demand() {
+    let mut client = MockClient::new();
+    let control = client.clone();
+    client.set("user/1", "ann").expect("set");
+    client.set("user/2", "bob").expect("set");
+    client.set("other", "x").expect("set");
+    assert_eq!(client.append("user/2", "by").expect("append"), 5);
+    assert_eq!(client.get("user/1").expect("get"), Some("ann".to_string()));
+    let page = client
+        .scan(ScanOptions {
+            key_prefix: "user/".to_string(),
+            limit: 1,
+            ..ScanOptions::default()
+        })
+        .expect("scan");
+    assert_eq!(page.entries, vec![("user/1".to_string(), "ann".to_string())]);
+    assert_eq!(page.next, Some("user/1".to_string()));
+
+    control.fail_next(io::ErrorKind::TimedOut, "injected").expect("fail_next");
+    let err = client.get("user/1").expect_err("injected failure");
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    client.remove("other").expect("remove");
+    control.set_failing(Some(io::ErrorKind::ConnectionRefused)).expect("set_failing");
+    assert!(client.set("k", "v").is_err());
+    control.set_failing(None).expect("set_failing");
+
+    control.set_latency(Duration::from_millis(20)).expect("set_latency");
+    let started = Instant::now();
+    assert_eq!(client.get_many(["user/2", "k"]).expect("get_many"), vec![Some("bobby".to_string()), None]);
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(control.data().expect("data").len(), 2);
+}
+
+// Requests the server has answered on every connection. Each call adds one
+// of its own.
+fn commands_answered(observer: &mut Client) -> u64 {
+    observer.client_list().expect("client list").iter().map(|c| c.commands).sum()
+}
+
+#[test]
+fn test_cached_client_drops_entries_written_on_another_connection() {
+    let (mut writer, server) = spawn_server().expect("spawn server");
+    let mut observer = server.connect().expect("connect");
+    // Written before the cache starts watching, so no late event for it
+    // can stop the first get from being cached.
+    writer.set("k", "1").expect("set value");
+    let mut cached = CachedClient::connect(server.addr(), Duration::from_secs(3600)).expect("connect");
+
+    assert_eq!(cached.get("k").expect("get"), Some("1".to_string()));
+    let before = commands_answered(&mut observer);
+    assert_eq!(cached.get("k").expect("get"), Some("1".to_string()));
+    assert_eq!(commands_answered(&mut observer), before + 1, "repeated get not served from the cache");
+
+    writer.set("k", "2").expect("set value");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while cached.get("k").expect("get") != Some("2".to_string()) {
+        assert!(Instant::now() < deadline, "cached value outlived a write on another connection");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_cached_client_clears_everything_when_its_watch_lags() {
+    let (mut writer, server) = spawn_server().expect("spawn server");
+    let mut observer = server.connect().expect("connect");
+    // Written before the cache starts watching, so no late event for it
+    // can stop the first get from being cached.
+    writer.set("untouched", "1").expect("set value");
+    let mut cached = CachedClient::connect(server.addr(), Duration::from_secs(3600)).expect("connect");
+    // The watch has not seen any later write of this key, so only a
+    // lagged watch clearing the whole cache sends the next get to the
+    // server.
+    let served_by_server = |cached: &mut CachedClient, observer: &mut Client| {
+        let before = commands_answered(observer);
+        assert_eq!(cached.get("untouched").expect("get"), Some("1".to_string()));
+        commands_answered(observer) > before + 1
+    };
+    cached.get("untouched").expect("get");
+    assert!(!served_by_server(&mut cached, &mut observer));
+
+    // One request that changes more keys than the server buffers for a
+    // watcher, all published at once. The watcher usually falls behind on
+    // the first burst; a few more make it certain.
+    let keys: Vec<String> = (0..20_000).map(|i| format!("burst/{i:0>64}")).collect();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        writer.remove_many(&keys).expect("remove many");
+        std::thread::sleep(Duration::from_millis(50));
+        if served_by_server(&mut cached, &mut observer) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "watch never reported falling behind");
+    }
+    // The cache is still in use afterwards, so it was cleared rather than
+    // abandoned with its watch.
+    assert!(!served_by_server(&mut cached, &mut observer));
+}
+
+// Two unrelated servers standing in for a primary and its replica, each
+// holding a different value for "k" so a read shows where it was routed.
+fn primary_and_replica() -> (TestServer, TestServer) {
+    let (mut primary_client, primary) = spawn_server().expect("spawn primary");
+    let (mut replica_client, replica) = spawn_server().expect("spawn replica");
+    primary_client.set("k", "primary").expect("set value");
+    replica_client.set("k", "replica").expect("set value");
+    (primary, replica)
+}
+
+fn replica_set(primary: &TestServer, replica: &TestServer, preference: ReadPreference) -> ReplicaSetClient {
+    let replicas = [replica.addr().to_string()];
+    ReplicaSetClient::connect(primary.addr().to_string(), replicas, preference).expect("connect")
+}
+
+#[test]
+fn test_replica_set_client_routes_reads_by_preference() {
+    let (primary, replica) = primary_and_replica();
+
+    let mut client = replica_set(&primary, &replica, ReadPreference::Primary);
+    assert_eq!(client.get("k").expect("get"), Some("primary".to_string()));
+
+    let mut client = replica_set(&primary, &replica, ReadPreference::ReplicaPreferred);
+    assert_eq!(client.get("k").expect("get"), Some("replica".to_string()));
+    // Writes go to the primary whatever the preference.
+    client.set("w", "1").expect("set value");
+    assert_eq!(primary.connect().expect("connect").get("w").expect("get"), Some("1".to_string()));
+    assert_eq!(replica.connect().expect("connect").get("w").expect("get"), None);
+
+    // Both are on loopback, so either may measure nearer, but the choice
+    // sticks from one read to the next.
+    let mut client = replica_set(&primary, &replica, ReadPreference::Nearest);
+    let nearest = client.get("k").expect("get").expect("value");
+    for _ in 0..10 {
+        assert_eq!(client.get("k").expect("get"), Some(nearest.clone()));
+    }
+}
+
+#[test]
+fn test_replica_set_client_falls_back_when_a_replica_is_down() {
+    let (primary, replica) = primary_and_replica();
+    let mut preferred = replica_set(&primary, &replica, ReadPreference::ReplicaPreferred);
+    let mut nearest = replica_set(&primary, &replica, ReadPreference::Nearest);
+    assert_eq!(preferred.get("k").expect("get"), Some("replica".to_string()));
+    nearest.get("k").expect("get");
+
+    let replica_addr = replica.addr().to_string();
+    replica.shutdown().expect("shut down replica");
+    assert_eq!(preferred.get("k").expect("get"), Some("primary".to_string()));
+    assert_eq!(nearest.get("k").expect("get"), Some("primary".to_string()));
+
+    // A replica that cannot be reached at connect time is only tried, and
+    // skipped, on reads.
+    let primary_addr = primary.addr().to_string();
+    let mut client = ReplicaSetClient::connect(primary_addr, [replica_addr], ReadPreference::Nearest).expect("connect");
+    assert_eq!(client.get("k").expect("get"), Some("primary".to_string()));
+
+    // With the primary gone as well there is nowhere left to read from.
+    primary.shutdown().expect("shut down primary");
+    assert!(preferred.get("k").is_err(), "read answered with every endpoint down");
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Account {
+    id: u64,
+    name: String,
+    balance: i64,
+}
+
+#[test]
+fn test_typed_values_round_trip_as_json_and_msgpack() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    let account = Account {
+        id: 7,
+        name: "ann".to_string(),
+        balance: -250,
+    };
+
+    client.set_json("json/7", &account).expect("set json");
+    assert_eq!(client.get_json::<Account>("json/7").expect("get json"), Some(account.clone()));
+    assert_eq!(client.get_json::<Account>("json/missing").expect("get json"), None);
+
+    client.set_msgpack("msgpack/7", &account).expect("set msgpack");
+    assert_eq!(client.get_msgpack::<Account>("msgpack/7").expect("get msgpack"), Some(account));
+    // Stored as raw bytes, not re-encoded as text.
+    let stored = client.get_bytes("msgpack/7").expect("get bytes").expect("value");
+    assert_eq!(rmp_serde::from_slice::<Account>(&stored).expect("decode").id, 7);
+
+    // Decoding as the wrong type is an `InvalidData` error in both formats.
+    let err = client.get_json::<Vec<u32>>("json/7").expect_err("object decoded as a list");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = client.get_msgpack::<Vec<u32>>("msgpack/7").expect_err("map decoded as a list");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
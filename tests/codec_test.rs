@@ -0,0 +1,170 @@
+use bitkv_rs::{ScanOptions, SetOptions, value_checksum};
+use bitkv_rs::codec::CodecKind;
+use bitkv_rs::protocol::{Request, Response};
+use std::io::BufReader;
+use std::time::Duration;
+
+#[test]
+fn test_codecs_round_trip_framed_messages() {
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let mut buf = Vec::new();
+        let requests = [
+            Request::Set {
+                key: "k".to_string(),
+                value: b"line one\nline two".to_vec(),
+                checksum: None,
+            },
+            Request::Hello {
+                codec: CodecKind::Bincode,
+            },
+        ];
+        for req in &requests {
+            kind.write_frame(&mut buf, &codec.encode_request(req).unwrap()).unwrap();
+        }
+        kind.write_frame(&mut buf, &codec.encode_response(&Response::Value(b"v".to_vec())).unwrap())
+            .unwrap();
+
+        let mut reader = BufReader::new(buf.as_slice());
+        let frame = kind.read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(codec.decode_request(&frame).unwrap(), Request::Set { ref value, .. } if value == b"line one\nline two"));
+        let frame = kind.read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(codec.decode_request(&frame).unwrap(), Request::Hello { codec: CodecKind::Bincode }));
+        let frame = kind.read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(codec.decode_response(&frame).unwrap(), Response::Value(ref v) if v == b"v"));
+        assert!(kind.read_frame(&mut reader).unwrap().is_none(), "{:?}", kind);
+    }
+}
+
+#[test]
+fn test_values_travel_as_raw_bytes() {
+    let binary: Vec<u8> = (0..=255).collect();
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let frame = codec.encode_response(&Response::Value(binary.clone())).unwrap();
+        assert!(matches!(codec.decode_response(&frame).unwrap(), Response::Value(ref v) if *v == binary));
+        let checked = Response::CheckedValue {
+            value: binary.clone(),
+            checksum: value_checksum(&binary),
+        };
+        let decoded = codec.decode_response(&codec.encode_response(&checked).unwrap()).unwrap();
+        let expected = value_checksum(&binary);
+        assert!(matches!(decoded, Response::CheckedValue { ref value, checksum }
+            if *value == binary && checksum == expected));
+        if kind != CodecKind::Json {
+            // No base64 or per-byte encoding: just the bytes and a short prefix.
+            assert!(frame.len() < binary.len() + 16, "{:?} frame of {} bytes", kind, frame.len());
+        }
+    }
+
+    // JSON still carries text values as plain strings, as older clients sent them.
+    let json = CodecKind::Json.codec();
+    let frame = json.encode_response(&Response::Value(b"v".to_vec())).unwrap();
+    assert_eq!(frame, br#"{"Value":"v"}"#);
+    let req = json.decode_request(br#"{"Set":{"key":"k","value":"typed by hand"}}"#).unwrap();
+    assert!(matches!(req, Request::Set { ref value, checksum: None, .. } if value == b"typed by hand"));
+}
+
+#[test]
+fn test_idempotent_requests_round_trip_and_delegate() {
+    let req = Request::Idempotent {
+        token: "t1".to_string(),
+        request: Box::new(Request::Set {
+            key: "k".to_string(),
+            value: b"v".to_vec(),
+            checksum: None,
+        }),
+    };
+    assert!(req.is_mutating());
+    assert_eq!(req.written_keys(), vec!["k"]);
+
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let decoded = codec.decode_request(&codec.encode_request(&req).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, Request::Idempotent { ref token, ref request } if token == "t1" && matches!(**request, Request::Set { .. })),
+            "{:?}",
+            kind
+        );
+    }
+}
+
+#[test]
+fn test_authenticated_requests_round_trip_and_delegate() {
+    let req = Request::Authenticated {
+        token: "jwt".to_string(),
+        request: Box::new(Request::Get { key: "k".to_string() }),
+    };
+    assert!(req.is_read_only());
+    assert!(!req.is_mutating());
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let decoded = codec.decode_request(&codec.encode_request(&req).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, Request::Authenticated { ref token, ref request } if token == "jwt" && matches!(**request, Request::Get { .. })),
+            "{:?}",
+            kind
+        );
+    }
+}
+
+#[test]
+fn test_read_only_requests_exclude_writes_and_admin() {
+    assert!(Request::Get { key: "k".to_string() }.is_read_only());
+    assert!(Request::Info.is_read_only());
+    assert!(!Request::Remove { key: "k".to_string() }.is_read_only());
+    assert!(!Request::Shutdown.is_read_only());
+    assert!(!Request::SnapshotManifest.is_read_only());
+    let wrapped = Request::Idempotent {
+        token: "t".to_string(),
+        request: Box::new(Request::Get { key: "k".to_string() }),
+    };
+    assert!(!wrapped.is_read_only());
+}
+
+#[test]
+fn test_set_opts_round_trips() {
+    let options = SetOptions {
+        nx: true,
+        ttl: Some(Duration::from_millis(1500)),
+        ..SetOptions::default()
+    };
+    let req = Request::SetOpts {
+        key: "k".to_string(),
+        value: "v".to_string(),
+        options: options.clone(),
+        checksum: Some(value_checksum("v")),
+    };
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let decoded = codec.decode_request(&codec.encode_request(&req).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, Request::SetOpts { options: ref decoded, checksum: Some(c), .. }
+                if *decoded == options && c == value_checksum("v")),
+            "{:?}",
+            kind
+        );
+    }
+}
+
+#[test]
+fn test_scan_round_trips() {
+    let options = ScanOptions {
+        key_prefix: "user:".to_string(),
+        limit: 10,
+        min_value_len: Some(3),
+        value_regex: Some("^a.*z$".to_string()),
+        ..ScanOptions::default()
+    };
+    let req = Request::Scan { options: options.clone() };
+    assert!(req.is_read_only());
+    for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Bincode] {
+        let codec = kind.codec();
+        let decoded = codec.decode_request(&codec.encode_request(&req).unwrap()).unwrap();
+        assert!(
+            matches!(decoded, Request::Scan { options: ref decoded } if *decoded == options),
+            "{:?}",
+            kind
+        );
+    }
+}
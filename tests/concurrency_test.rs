@@ -36,6 +36,52 @@ fn test_cloned_store_updates_writer() {
     );
 }
 
+#[test]
+fn test_get_many_never_sees_half_a_transaction() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let mut writer = store.clone();
+    let handle = std::thread::spawn(move || {
+        for i in 0..200 {
+            writer
+                .transaction(|txn| {
+                    txn.set("a".to_string(), i.to_string())?;
+                    txn.set("b".to_string(), i.to_string())
+                })
+                .expect("transaction");
+        }
+    });
+
+    while !handle.is_finished() {
+        let values = store.get_many(["a", "b"]).expect("get_many");
+        assert_eq!(values[0], values[1], "torn read across a transaction");
+    }
+    handle.join().expect("writer thread");
+    assert_eq!(
+        store.get_many(["a", "b", "missing"]).expect("get_many"),
+        vec![Some("199".to_string()), Some("199".to_string()), None]
+    );
+}
+
+#[test]
+fn test_clones_follow_compaction_to_new_generation() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let mut compactor = store.clone();
+    let mut writer = store.clone();
+    for i in 0..50 {
+        writer.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+
+    compactor.compact().expect("compact");
+    let generation = store.stats().expect("stats").current_generation;
+    writer.set("after".to_string(), "compaction".to_string()).expect("set value");
+
+    let active = fs::read_to_string(temp_dir.path().join(format!("{}.db", generation))).expect("read active segment");
+    assert!(active.contains("\"after\""), "write went to a stale generation");
+    assert_eq!(store.get("after").expect("get"), Some("compaction".to_string()));
+}
+
 fn count_db_files(dir: PathBuf) -> usize {
     fs::read_dir(dir)
         .expect("read dir")
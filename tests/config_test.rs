@@ -0,0 +1,111 @@
+use bitkv_rs::auth::{self, Claims};
+use bitkv_rs::config::{AuthConfig, ConfigProblem, DiskWatchdogConfig, ServerConfig, SyncConfig};
+
+#[test]
+fn test_check_reports_each_bad_setting() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let config = ServerConfig {
+        data_dir: temp_dir.path().join("not/yet/created"),
+        ..ServerConfig::default()
+    };
+    assert_eq!(config.check(), Vec::new());
+
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "").expect("write file");
+    let config = ServerConfig {
+        address: "127.0.0.1:notaport".to_string(),
+        read_only_addresses: vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7001".to_string()],
+        data_dir: file.join("data"),
+        disk_watchdog: Some(DiskWatchdogConfig {
+            soft_bytes: 10,
+            hard_bytes: 20,
+        }),
+        max_pending_write_bytes: 0,
+        ..ServerConfig::default()
+    };
+    let issues: Vec<(String, &str)> = config
+        .check()
+        .iter()
+        .map(|issue| {
+            let kind = match issue.problem {
+                ConfigProblem::Unparseable { .. } => "unparseable",
+                ConfigProblem::BadAddress { .. } => "bad_address",
+                ConfigProblem::DuplicateAddress { .. } => "duplicate_address",
+                ConfigProblem::NotWritable { .. } => "not_writable",
+                ConfigProblem::OutOfRange { .. } => "out_of_range",
+                ConfigProblem::Invalid { .. } => "invalid",
+            };
+            (issue.field.clone(), kind)
+        })
+        .collect();
+    assert_eq!(
+        issues,
+        vec![
+            ("address".to_string(), "bad_address"),
+            ("read_only_addresses".to_string(), "duplicate_address"),
+            ("data_dir".to_string(), "not_writable"),
+            ("disk_watchdog.hard_bytes".to_string(), "out_of_range"),
+            ("max_pending_write_bytes".to_string(), "out_of_range"),
+        ]
+    );
+}
+
+#[test]
+fn test_sync_policy_parses_from_config() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let path = temp_dir.path().join("config.json");
+    std::fs::write(&path, r#"{"sync": {"mode": "every", "interval_ms": 0}}"#).expect("write config");
+    let config = ServerConfig::load(&path).expect("load config");
+    assert!(matches!(config.sync, SyncConfig::Every { interval_ms: 0 }));
+    assert!(config.check().iter().any(|issue| issue.field == "sync.interval_ms"));
+
+    std::fs::write(&path, r#"{"sync": {"mode": "always"}}"#).expect("write config");
+    let config = ServerConfig::load(&path).expect("load config");
+    assert!(matches!(config.sync, SyncConfig::Always));
+}
+
+#[test]
+fn test_auth_config_builds_a_token_validator() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let jwks = temp_dir.path().join("jwks.json");
+    std::fs::write(&jwks, r#"{"keys": [{"kty": "RSA", "kid": "r1", "n": "AQAB", "e": "AQAB"}]}"#).expect("write jwks");
+    let both = ServerConfig {
+        data_dir: temp_dir.path().to_path_buf(),
+        auth: Some(AuthConfig {
+            secret: Some("s3cret".to_string()),
+            jwks_path: Some(jwks.clone()),
+            ..AuthConfig::default()
+        }),
+        ..ServerConfig::default()
+    };
+    let rsa_only = ServerConfig {
+        auth: Some(AuthConfig {
+            jwks_path: Some(jwks),
+            ..AuthConfig::default()
+        }),
+        ..both.clone()
+    };
+    for config in [both, rsa_only] {
+        let issues = config.check();
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!(issues[0].field, "auth");
+    }
+
+    let config = ServerConfig {
+        data_dir: temp_dir.path().to_path_buf(),
+        auth: Some(AuthConfig {
+            secret: Some("s3cret".to_string()),
+            audience: Some("kv".to_string()),
+            ..AuthConfig::default()
+        }),
+        ..ServerConfig::default()
+    };
+    assert_eq!(config.check(), Vec::new());
+    let validator = config.token_validator().expect("validator").expect("auth is on");
+    let claims = Claims {
+        exp: Some(u64::MAX / 2),
+        ..Claims::default()
+    };
+    let token = auth::sign_hs256(&claims, b"s3cret", None);
+    assert!(validator.validate(&token).is_err(), "no audience");
+}
@@ -0,0 +1,39 @@
+use bitkv_rs::{KvStore, Options, RotationPolicy};
+use bitkv_rs::doctor::{self, Severity};
+
+fn problems(dir: &std::path::Path) -> Vec<String> {
+    doctor::examine(dir)
+        .expect("examine")
+        .into_iter()
+        .map(|finding| finding.problem)
+        .collect()
+}
+
+#[test]
+fn test_doctor_flags_tombstones_and_leftover_files() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    // One segment, so no compaction drops the tombstones first.
+    let options = Options::new().rotation(RotationPolicy::size(u64::MAX));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    for i in 0..600 {
+        store.set(format!("key{}", i), "value".to_string()).expect("set value");
+        store.remove(format!("key{}", i)).expect("remove key");
+    }
+    drop(store);
+    let before = problems(temp_dir.path());
+    assert!(before.iter().any(|p| p.contains("records are tombstones")), "{:?}", before);
+    assert!(!before.iter().any(|p| p.contains("manifest")), "{:?}", before);
+
+    std::fs::remove_file(temp_dir.path().join("MANIFEST")).expect("remove manifest");
+    std::fs::write(temp_dir.path().join("MANIFEST.tmp"), "{").expect("write leftover");
+    std::fs::write(temp_dir.path().join("old.db"), "").expect("write orphan");
+    let after = problems(temp_dir.path());
+    assert!(after.iter().any(|p| p == "manifest is missing"), "{:?}", after);
+    assert!(after.iter().any(|p| p.contains("MANIFEST.tmp")), "{:?}", after);
+    assert!(after.iter().any(|p| p.contains("old.db")), "{:?}", after);
+
+    std::fs::write(temp_dir.path().join("MANIFEST"), "not json").expect("write manifest");
+    let findings = doctor::examine(temp_dir.path()).expect("examine");
+    assert_eq!(findings[0].severity, Severity::Error);
+    assert!(findings[0].problem.starts_with("manifest is unreadable"));
+}
@@ -0,0 +1,208 @@
+use bitkv_rs::replication::{
+    self, ConflictResolver, LastWriteWins, ReplicaState, ReplicationEvent, ReplicationLog, Resolution, Staleness,
+};
+use bitkv_rs::config::ServerConfig;
+use bitkv_rs::testing::{spawn_server, spawn_server_in, spawn_server_with};
+use bitkv_rs::{ContentType, Hlc, HlcTimestamp, KvStore, SetOptions};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_replication_log_resumes_from_backlog() {
+    let mut log = ReplicationLog::new(3);
+    for i in 0..5 {
+        log.append(format!("k{}", i), Some(i.to_string()));
+    }
+    let run_id = log.run_id();
+
+    let missed = log.since(run_id, 3).expect("still in backlog");
+    assert_eq!(missed.iter().map(|c| c.seq).collect::<Vec<_>>(), vec![4, 5]);
+    assert_eq!(log.since(run_id, 5), Some(Vec::new()));
+    assert_eq!(log.since(0, 0), Some(Vec::new()));
+    // Change 2 fell out of the backlog, and another run's numbers mean nothing.
+    assert_eq!(log.since(run_id, 1), None);
+    assert_eq!(log.since(run_id + 1, 4), None);
+}
+
+#[test]
+fn test_replica_lag_and_staleness_bounds() {
+    let mut log = ReplicationLog::new(10);
+    let mut replica = ReplicaState::new("primary:6379");
+    let bound = Staleness {
+        max_records: Some(1),
+        max_millis: None,
+    };
+    assert_eq!(replica.lag(), (None, None));
+    assert!(replica.check(&bound).is_err());
+
+    // Without a saved position the data can't be vouched for.
+    let mut unknown = ReplicaState::new("primary:6379");
+    unknown.record(&ReplicationEvent::Heartbeat {
+        run_id: log.run_id(),
+        seq: log.seq(),
+    });
+    assert_eq!(unknown.lag(), (None, None));
+    assert_eq!(unknown.checkpoint(), None);
+
+    replica.resume_from(log.run_id(), log.seq());
+    replica.record(&ReplicationEvent::Heartbeat {
+        run_id: log.run_id(),
+        seq: log.seq(),
+    });
+    assert_eq!(replica.lag().0, Some(0));
+
+    let first = log.append("a".to_string(), Some("1".to_string()));
+    log.append("b".to_string(), None);
+    log.append("c".to_string(), None);
+    replica.record(&ReplicationEvent::Heartbeat {
+        run_id: log.run_id(),
+        seq: log.seq(),
+    });
+    replica.record(&ReplicationEvent::Change(first));
+    assert_eq!(replica.lag().0, Some(2));
+    assert!(replica.check(&bound).is_err());
+    assert_eq!(replica.position(), (log.run_id(), 1));
+    assert_eq!(replica.checkpoint(), Some((log.run_id(), 1)));
+
+    replica.record(&ReplicationEvent::Resync {
+        run_id: log.run_id(),
+        seq: log.seq(),
+    });
+    assert_eq!(replica.lag(), (None, None));
+    assert_eq!(replica.checkpoint(), None);
+    assert!(replica.check(&Staleness::default()).is_ok());
+}
+
+#[test]
+fn test_snapshot_manifest_describes_store_files() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    let log = Mutex::new(ReplicationLog::new(10));
+    log.lock().unwrap().append("a".to_string(), Some("1".to_string()));
+
+    let manifest = replication::snapshot_manifest(&store, &log).expect("manifest");
+    assert_eq!(manifest.seq, 1);
+    let db = manifest.files.iter().find(|f| f.name.ends_with(".db")).expect("a data file");
+    let bytes = replication::read_snapshot_chunk(&store, &db.name, 0, db.len).expect("read chunk");
+    assert_eq!(bytes.len() as u64, db.len);
+    assert!(replication::read_snapshot_chunk(&store, "../secret", 0, 10).is_err());
+
+    // Data files alone say nothing about which changes they hold.
+    assert!(replication::needs_bootstrap(temp_dir.path()).expect("check"));
+    replication::save_position(temp_dir.path(), Some((manifest.run_id, manifest.seq))).expect("save");
+    assert!(!replication::needs_bootstrap(temp_dir.path()).expect("check"));
+    assert_eq!(replication::saved_position(temp_dir.path()).expect("load"), Some((manifest.run_id, 1)));
+    replication::save_position(temp_dir.path(), None).expect("forget");
+    assert!(replication::needs_bootstrap(temp_dir.path()).expect("check"));
+    assert!(replication::needs_bootstrap(&temp_dir.path().join("fresh")).expect("check"));
+}
+
+#[test]
+fn test_hlc_stamps_order_writes_across_nodes() {
+    let mut clock = Hlc::new(1);
+    let first = clock.now();
+    let second = clock.now();
+    assert!(second > first);
+    // A node whose wall clock runs an hour ahead.
+    let remote = HlcTimestamp {
+        physical_ms: first.physical_ms + 3_600_000,
+        logical: 7,
+        node: 2,
+    };
+    let merged = clock.observe(remote);
+    assert!(merged > remote);
+    assert!(clock.now() > merged);
+
+    let mut primary = ReplicationLog::new(10);
+    let mut replica = ReplicationLog::new(10);
+    let a = primary.append("k".to_string(), Some("1".to_string()));
+    let b = primary.append("k".to_string(), Some("2".to_string()));
+    assert!(b.hlc > a.hlc);
+    let relayed = replica.relay(&b, b.value.clone(), b.metadata);
+    assert_eq!(relayed.hlc, b.hlc);
+    assert_eq!(replica.latest("k"), Some(&relayed));
+    let local = replica.append("k".to_string(), None);
+    assert!(local.hlc > b.hlc, "replica clock did not move past the primary's");
+
+    let resolver = LastWriteWins;
+    assert_eq!(resolver.resolve("k", &a, &b), Resolution::TakeIncoming);
+    assert_eq!(resolver.resolve("k", &local, &b), Resolution::KeepCurrent);
+}
+
+fn wait_until(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "replica did not catch up");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_restarted_replica_catches_up_on_missed_writes() {
+    let (mut primary, primary_server) = spawn_server().expect("spawn primary");
+    let replica_dir = tempfile::tempdir().expect("create temp dir");
+    let config = ServerConfig {
+        data_dir: replica_dir.path().to_path_buf(),
+        replica_of: Some(primary_server.addr().to_string()),
+        ..ServerConfig::default()
+    };
+    let (mut replica, replica_server) = spawn_server_in(config.clone()).expect("spawn replica");
+    primary.set("a", "1").expect("set");
+    wait_until(|| replica.get("a").expect("get") == Some("1".to_string()));
+    replica_server.shutdown().expect("stop replica");
+
+    primary.set("b", "2").expect("set");
+    primary.remove("a").expect("remove");
+    let (mut replica, _replica_server) = spawn_server_in(config).expect("restart replica");
+    wait_until(|| replica.get("a").expect("get").is_none());
+    assert_eq!(replica.get("b").expect("get"), Some("2".to_string()));
+    let info = replica.info().expect("info");
+    assert_eq!(info.replication.seq, 3);
+    assert_eq!(info.replication.lag_records, Some(0));
+}
+
+#[test]
+fn test_replica_keeps_expiry_and_content_type() {
+    let (mut primary, primary_server) = spawn_server().expect("spawn primary");
+    let config = ServerConfig {
+        replica_of: Some(primary_server.addr().to_string()),
+        ..ServerConfig::default()
+    };
+    let (mut replica, _replica_server) = spawn_server_with(config).expect("spawn replica");
+    let ttl = SetOptions {
+        ttl: Some(Duration::from_millis(500)),
+        ..SetOptions::default()
+    };
+    primary.set_opts("session", "s", ttl).expect("set with ttl");
+    primary.set_with_content_type("doc", "{}", ContentType::Json).expect("set tagged");
+
+    wait_until(|| replica.get("doc").expect("get").is_some());
+    assert_eq!(
+        replica.get_with_content_type("doc").expect("get tagged"),
+        Some(("{}".to_string(), Some(ContentType::Json)))
+    );
+    wait_until(|| replica.get("session").expect("get").is_none());
+}
+
+#[test]
+fn test_replica_without_a_saved_position_is_copied_again() {
+    let (mut primary, primary_server) = spawn_server().expect("spawn primary");
+    primary.set("a", "1").expect("set");
+    let replica_dir = tempfile::tempdir().expect("create temp dir");
+    {
+        let mut stale = KvStore::open(replica_dir.path().to_path_buf()).expect("open store");
+        stale.set("stale".to_string(), "x".to_string()).expect("set value");
+        stale.shutdown().expect("shut down");
+    }
+
+    let config = ServerConfig {
+        data_dir: replica_dir.path().to_path_buf(),
+        replica_of: Some(primary_server.addr().to_string()),
+        ..ServerConfig::default()
+    };
+    let (mut replica, _replica_server) = spawn_server_in(config).expect("spawn replica");
+    assert_eq!(replica.get("a").expect("get"), Some("1".to_string()));
+    assert_eq!(replica.get("stale").expect("get"), None);
+    assert!(replication::saved_position(replica_dir.path()).expect("load").is_some());
+}
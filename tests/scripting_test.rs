@@ -0,0 +1,34 @@
+#![cfg(feature = "scripting")]
+
+use bitkv_rs::{KvError, KvStore, scripting};
+
+#[test]
+fn test_eval_runs_script_against_store() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "5".to_string()).expect("set value");
+
+    let script = r#"
+        local total = tonumber(kv.get(KEYS[1])) + tonumber(ARGV[1])
+        kv.set(KEYS[2], tostring(total))
+        kv.remove(KEYS[1])
+        return total
+    "#;
+    let result = scripting::eval(&mut store, script, vec!["a".into(), "b".into()], vec!["3".into()])
+        .expect("eval");
+    assert_eq!(result, Some("8".to_string()));
+    assert_eq!(store.get("a").expect("get"), None);
+    assert_eq!(store.get("b").expect("get"), Some("8".to_string()));
+    assert_eq!(scripting::eval(&mut store, "return kv.get('a')", vec![], vec![]).expect("eval"), None);
+}
+
+#[test]
+fn test_eval_aborts_runaway_scripts() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+
+    let err = scripting::eval(&mut store, "while true do end", vec![], vec![]).unwrap_err();
+    assert!(matches!(err, KvError::Script(_)));
+    assert!(scripting::eval(&mut store, "return os.exit()", vec![], vec![]).is_err());
+    store.set("still".to_string(), "usable".to_string()).expect("store usable after abort");
+}
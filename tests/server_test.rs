@@ -0,0 +1,473 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitkv_rs::audit::{self, AuditEntry};
+use bitkv_rs::auth::{self, Claims};
+use bitkv_rs::client::{Client, KvClient, MockClient};
+use bitkv_rs::codec::CodecKind;
+use bitkv_rs::config::{AuthConfig, ServerConfig};
+use bitkv_rs::protocol::{Request, Response};
+use bitkv_rs::{ContentType, KvStore, MergeMode, Options, ScanOptions, SetOptions, SyncSummary, value_checksum};
+use bitkv_rs::self_test::{self, SelfTestConfig};
+use bitkv_rs::testing::{BLOCKING_THREADS, spawn_server, spawn_server_in, spawn_server_with};
+
+#[test]
+fn test_spawned_server_serves_clients_until_shutdown() {
+    let (mut client, server) = spawn_server().expect("spawn server");
+    client.set("a", "1").expect("set value");
+    client.set_bytes("b", b"2".to_vec()).expect("set bytes");
+    client.set_bytes("c", vec![0xff, 0x00]).expect("set bytes");
+
+    let mut other = server.connect().expect("connect");
+    assert_eq!(other.get("a").expect("get"), Some("1".to_string()));
+    assert_eq!(other.get_bytes("b").expect("get bytes"), Some(b"2".to_vec()));
+    assert_eq!(other.get_bytes("c").expect("get bytes"), Some(vec![0xff, 0x00]));
+    let err = other.get("c").expect_err("non-UTF-8 value read as a string");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let data_dir = server.data_dir().to_path_buf();
+    assert!(data_dir.join("MANIFEST").exists());
+    server.shutdown().expect("shut down");
+    assert!(client.get("a").is_err(), "server still answering");
+    assert!(!data_dir.exists(), "data dir left behind");
+}
+
+#[test]
+fn test_spawned_server_stops_on_a_shutdown_request() {
+    let (mut client, server) = spawn_server().expect("spawn server");
+    let addr = server.addr();
+    client.shutdown().expect("request shutdown");
+    server.shutdown().expect("join server");
+    assert!(Client::connect(addr).is_err(), "server still listening");
+}
+
+#[test]
+fn test_pipelined_gets_answer_in_request_order() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    // Enough keys to span several pipeline windows, with every seventh one
+    // never written so misses fall in the middle of a window.
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{i}")).collect();
+    for (i, key) in keys.iter().enumerate().filter(|(i, _)| i % 7 != 3) {
+        client.set(key.clone(), format!("value{i}")).expect("set value");
+    }
+
+    let values = client.get_pipeline(&keys).expect("pipelined get");
+    assert_eq!(values.len(), keys.len());
+    for (i, value) in values.iter().enumerate() {
+        let expected = (i % 7 != 3).then(|| format!("value{i}"));
+        assert_eq!(value, &expected, "wrong answer for key{i}");
+    }
+
+    let values = client.get_pipeline(["key0", "key3", "key1"]).expect("pipelined get");
+    assert_eq!(values, vec![Some("value0".to_string()), None, Some("value1".to_string())]);
+    // The connection stays in step for ordinary requests afterwards.
+    assert_eq!(client.get("key2").expect("get"), Some("value2".to_string()));
+}
+
+// Application code written against the trait, not any one client.
+fn move_prefix(client: &mut dyn KvClient, from: &str, to: &str) -> std::io::Result<usize> {
+    let page = client.scan(ScanOptions {
+        key_prefix: from.to_string(),
+        ..ScanOptions::default()
+    })?;
+    for (key, value) in &page.entries {
+        client.set(&key.replacen(from, to, 1), value)?;
+    }
+    let keys: Vec<&str> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+    client.remove_many(&keys)?;
+    Ok(keys.len())
+}
+
+#[test]
+fn test_clients_share_the_kv_client_trait() {
+    let (client, _server) = spawn_server().expect("spawn server");
+    let clients: Vec<Box<dyn KvClient>> = vec![Box::new(client), Box::new(MockClient::new())];
+    for mut client in clients {
+        client.set("old/a", "1").expect("set");
+        client.set("old/b", "2").expect("set");
+        assert_eq!(move_prefix(&mut client, "old/", "new/").expect("move"), 2);
+        assert_eq!(
+            client.get_many(&["old/a", "new/a", "new/b"]).expect("get_many"),
+            vec![None, Some("1".to_string()), Some("2".to_string())]
+        );
+        client.remove("new/a").expect("remove");
+        assert_eq!(client.get("new/a").expect("get"), None);
+    }
+}
+
+#[test]
+fn test_embedded_store_syncs_with_a_server() {
+    let config = ServerConfig {
+        sync_prefixes: vec!["notes/".to_string()],
+        ..ServerConfig::default()
+    };
+    let (mut client, _server) = spawn_server_with(config).expect("spawn server");
+    client.set("notes/server", "1").expect("set");
+    client.set("other", "1").expect("set");
+
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().sync_prefix("notes/", MergeMode::LastWriteWins);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("notes/edge".to_string(), "2".to_string()).expect("set value");
+    assert_eq!(store.sync(&mut client).expect("sync"), SyncSummary { received: 1, sent: 1 });
+    assert_eq!(store.get("notes/server").expect("get"), Some("1".to_string()));
+    assert_eq!(store.get("other").expect("get"), None);
+    assert_eq!(client.get("notes/edge").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_server_closes_a_client_that_stops_reading() {
+    let config = ServerConfig {
+        max_pending_write_bytes: 256 * 1024,
+        ..ServerConfig::default()
+    };
+    let (mut client, server) = spawn_server_with(config).expect("spawn server");
+    client.set("big", "x".repeat(64 * 1024)).expect("set");
+    let encoded = CodecKind::Json
+        .codec()
+        .encode_request(&Request::Get { key: "big".to_string() })
+        .expect("encode");
+    let mut frame = Vec::new();
+    CodecKind::Json.write_frame(&mut frame, &encoded).expect("frame");
+
+    // Pipelines gets and never reads a single answer.
+    let mut slow = TcpStream::connect(server.addr()).expect("connect");
+    for _ in 0..10_000 {
+        slow.write_all(&frame).expect("send request");
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.client_list().expect("client list").len() > 1 {
+        assert!(Instant::now() < deadline, "slow client never cut off");
+        assert_eq!(client.get("missing").expect("get"), None);
+    }
+}
+
+#[test]
+fn test_server_closes_idle_connections() {
+    let config = ServerConfig {
+        idle_timeout_secs: Some(1),
+        ..ServerConfig::default()
+    };
+    let (mut client, server) = spawn_server_with(config).expect("spawn server");
+    let mut busy = server.connect().expect("connect");
+    client.set("a", "1").expect("set");
+    // Requests keep a connection open past the timeout.
+    for _ in 0..3 {
+        std::thread::sleep(Duration::from_millis(500));
+        busy.ping().expect("ping");
+    }
+    assert!(client.get("a").is_err());
+    assert_eq!(busy.get("a").expect("get"), Some("1".to_string()));
+}
+
+#[test]
+fn test_server_renames_keys() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    client.set("a", "1").expect("set");
+    client.set("b", "2").expect("set");
+
+    let err = client.rename("a", "b", false).unwrap_err();
+    assert_eq!(err.to_string(), r#"Key "b" already exists"#);
+    assert_eq!(client.get("b").expect("get"), Some("2".to_string()));
+    client.rename("a", "c", false).expect("rename");
+    assert_eq!(client.get("a").expect("get"), None);
+    assert_eq!(client.get("c").expect("get"), Some("1".to_string()));
+    client.rename("c", "b", true).expect("rename over");
+    assert_eq!(client.get("b").expect("get"), Some("1".to_string()));
+    let err = client.rename("missing", "d", true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_server_copies_keys() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    client.set("a", "1").expect("set");
+
+    let err = client.copy("missing", "b", true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    client.copy("a", "b", false).expect("copy");
+    client.set("a", "2").expect("set");
+    let err = client.copy("a", "b", false).unwrap_err();
+    assert_eq!(err.to_string(), r#"Key "b" already exists"#);
+    assert_eq!(client.get("b").expect("get"), Some("1".to_string()));
+    client.copy("a", "b", true).expect("copy over");
+    assert_eq!(client.get("a").expect("get"), Some("2".to_string()));
+    assert_eq!(client.get("b").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_server_swaps_and_takes_values() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    assert_eq!(client.get_and_set("a", "1").expect("get and set"), None);
+    assert_eq!(client.get_and_set("a", "2").expect("get and set"), Some("1".to_string()));
+    assert_eq!(client.get("a").expect("get"), Some("2".to_string()));
+    assert_eq!(client.get_and_remove("a").expect("get and remove"), Some("2".to_string()));
+    assert_eq!(client.get_and_remove("a").expect("get and remove"), None);
+    assert_eq!(client.get("a").expect("get"), None);
+}
+
+#[test]
+fn test_server_appends_to_values() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    assert_eq!(client.append("a", "1").expect("append"), 1);
+    assert_eq!(client.append("a", "23").expect("append"), 3);
+    assert_eq!(client.get("a").expect("get"), Some("123".to_string()));
+}
+
+#[test]
+fn test_server_audits_writes_in_a_verifiable_chain() {
+    let audit_dir = tempfile::tempdir().expect("create temp dir");
+    let path = audit_dir.path().join("audit.log");
+    let config = ServerConfig {
+        audit_log: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let (mut client, _server) = spawn_server_with(config).expect("spawn server");
+    client.set("a", "1").expect("set");
+    client.set("b", "2").expect("set");
+    client.get("a").expect("get");
+    client.remove("a").expect("remove");
+
+    let last = audit::verify(&path).expect("verify").expect("entries");
+    assert_eq!(last.seq, 2);
+    let entries: Vec<AuditEntry> = std::fs::read_to_string(&path)
+        .expect("read audit log")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("parse entry"))
+        .collect();
+    assert_eq!(entries.len(), 3);
+    assert!(entries.iter().all(|entry| entry.ok));
+    // Reads are not audited.
+    assert!(entries[0].request.starts_with("{\"SetChecked\":{\"key\":\"a\""));
+    assert!(entries[1].request.starts_with("{\"SetChecked\":{\"key\":\"b\""));
+    assert_eq!(entries[2].request, "{\"Remove\":{\"key\":\"a\"}}");
+    assert_eq!(entries[1].prev_hash, entries[0].hash);
+    assert_eq!(entries[2].prev_hash, entries[1].hash);
+}
+
+// Reads of a FIFO posing as a segment hold a blocking thread each until
+// something opens it for writing.
+#[cfg(unix)]
+#[test]
+fn test_admin_requests_are_answered_while_data_requests_are_stuck() {
+    let (mut client, server) = spawn_server().expect("spawn server");
+    let fifo = server.data_dir().join("999999.db");
+    let made = std::process::Command::new("mkfifo").arg(&fifo).status().expect("run mkfifo");
+    assert!(made.success());
+    let stuck: Vec<_> = (0..BLOCKING_THREADS)
+        .map(|_| {
+            let mut client = server.connect().expect("connect");
+            std::thread::spawn(move || client.snapshot_chunk("999999.db", 0, 1))
+        })
+        .collect();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let mut admin = server.connect().expect("connect");
+    let (answered, answers) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = answered.send((admin.info().is_ok(), admin.client_list().map(|clients| clients.len())));
+    });
+    let answer = answers.recv_timeout(Duration::from_secs(5));
+
+    // Once open, the reads fail, as a FIFO can't seek.
+    drop(std::fs::OpenOptions::new().write(true).open(&fifo).expect("open fifo"));
+    for reader in stuck {
+        assert!(reader.join().expect("join").is_err());
+    }
+    let (info, clients) = answer.expect("admin requests queued behind data");
+    assert!(info);
+    assert!(clients.expect("client list") > BLOCKING_THREADS);
+    assert_eq!(client.get("a").expect("get"), None);
+}
+
+#[test]
+fn test_values_with_a_wrong_checksum_are_refused_and_not_stored() {
+    let (mut client, _server) = spawn_server().expect("spawn server");
+    let bad = value_checksum("v") ^ 1;
+    let corrupted = [
+        Request::SetChecked { key: "k".to_string(), value: "v".to_string(), checksum: bad },
+        Request::Set { key: "k".to_string(), value: b"v".to_vec(), checksum: Some(bad) },
+        Request::SetTagged {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            content_type: ContentType::Text,
+            checksum: Some(bad),
+        },
+        Request::SetOpts {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            options: SetOptions::default(),
+            checksum: Some(bad),
+        },
+        Request::GetSet { key: "k".to_string(), value: "v".to_string(), checksum: Some(bad) },
+        Request::Append { key: "k".to_string(), suffix: "v".to_string(), checksum: Some(bad) },
+    ];
+    for (i, req) in corrupted.into_iter().enumerate() {
+        let err = client.request_idempotent(format!("t{i}"), req).expect_err("corrupted value accepted");
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+    }
+    assert_eq!(client.get("k").expect("get"), None);
+    assert_eq!(client.info().expect("info").store.key_count, 0);
+
+    // Reads come back with the checksum of the value they carry.
+    client.set("k", "v").expect("set value");
+    let read = client.request_idempotent("read", Request::Get { key: "k".to_string() }).expect("get");
+    let expected = value_checksum("v");
+    assert!(matches!(read, Response::CheckedValue { ref value, checksum } if value == b"v" && checksum == expected));
+}
+
+#[test]
+fn test_read_only_listener_refuses_writes_and_admin_requests() {
+    // A free port for the read-only listener; the main one asks for port 0.
+    let port = std::net::TcpListener::bind("127.0.0.1:0").expect("bind").local_addr().expect("addr").port();
+    let read_only_addr = format!("127.0.0.1:{port}");
+    let config = ServerConfig {
+        read_only_addresses: vec![read_only_addr.clone()],
+        ..ServerConfig::default()
+    };
+    let (mut client, _server) = spawn_server_with(config).expect("spawn server");
+    client.set("k", "v").expect("set value");
+
+    let mut reader = Client::connect(read_only_addr.as_str()).expect("connect read-only");
+    let refused = |result: std::io::Result<()>| {
+        let err = result.expect_err("request accepted on a read-only listener");
+        assert!(err.to_string().contains("only serves reads"), "{}", err);
+    };
+    refused(reader.set("k", "changed"));
+    refused(reader.remove("k"));
+    refused(reader.eval("return 1", vec!["k".to_string()], Vec::new()).map(|_| ()));
+    refused(reader.compact());
+    refused(reader.client_list().map(|_| ()));
+    refused(reader.shutdown());
+
+    assert_eq!(reader.get("k").expect("get"), Some("v".to_string()));
+    assert_eq!(reader.scan(ScanOptions::default()).expect("scan").entries.len(), 1);
+    assert_eq!(reader.info().expect("info").store.key_count, 1);
+    // Nothing the refused requests asked for happened.
+    assert_eq!(client.get("k").expect("get"), Some("v".to_string()));
+    client.ping().expect("server still running");
+}
+
+#[test]
+fn test_killed_client_is_disconnected_while_others_keep_working() {
+    let (mut admin, server) = spawn_server().expect("spawn server");
+    let mut survivor = server.connect().expect("connect");
+    let mut victim = server.connect().expect("connect");
+    survivor.ping().expect("ping");
+    victim.set("k", "v").expect("set value");
+
+    let clients = admin.client_list().expect("client list");
+    assert_eq!(clients.len(), 3, "{:?}", clients);
+    // Ids count up as connections are accepted, so the last one is the victim's.
+    let victim_id = clients.iter().map(|c| c.id).max().expect("a client");
+    assert!(clients.iter().any(|c| c.id == victim_id && c.commands >= 1));
+
+    assert!(admin.client_kill(victim_id).expect("kill"));
+    assert!(victim.get("k").is_err(), "killed client still answered");
+    assert_eq!(survivor.get("k").expect("get"), Some("v".to_string()));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while admin.client_list().expect("client list").iter().any(|c| c.id == victim_id) {
+        assert!(Instant::now() < deadline, "killed client still listed");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!admin.client_kill(victim_id).expect("kill again"), "killed twice");
+}
+
+#[test]
+fn test_negotiated_codecs_serve_sets_and_gets() {
+    let (mut json, server) = spawn_server().expect("spawn server");
+    for kind in [CodecKind::MessagePack, CodecKind::Bincode] {
+        let mut client = Client::connect_with_codec(server.addr(), kind).expect("negotiate codec");
+        let key = format!("{:?}", kind);
+        client.set(key.as_str(), "text").expect("set value");
+        assert_eq!(client.get(key.as_str()).expect("get"), Some("text".to_string()));
+        client.set_bytes(format!("{key}/bin"), vec![0, 0xff, 7]).expect("set bytes");
+        assert_eq!(client.get_bytes(format!("{key}/bin")).expect("get bytes"), Some(vec![0, 0xff, 7]));
+        assert_eq!(client.get("missing").expect("get"), None);
+
+        // The same data reads back over a connection still speaking JSON.
+        assert_eq!(json.get(key.as_str()).expect("get"), Some("text".to_string()));
+        assert_eq!(json.get_bytes(format!("{key}/bin")).expect("get bytes"), Some(vec![0, 0xff, 7]));
+    }
+}
+
+#[test]
+fn test_idempotent_responses_stay_with_the_identity_that_made_them() {
+    let config = ServerConfig {
+        auth: Some(AuthConfig {
+            secret: Some("s3cret".to_string()),
+            ..AuthConfig::default()
+        }),
+        ..ServerConfig::default()
+    };
+    let (mut alice, server) = spawn_server_with(config).expect("spawn server");
+    let token_for = |sub: &str| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock").as_secs();
+        let claims = Claims {
+            sub: Some(sub.to_string()),
+            exp: Some(now + 3600),
+            ..Claims::default()
+        };
+        Some(auth::sign_hs256(&claims, b"s3cret", None))
+    };
+    let get_del = || Request::GetDel { key: "k".to_string() };
+    alice.set_bearer_token(token_for("alice"));
+    alice.set("k", "secret").expect("set");
+    let first = alice.request_idempotent("t1", get_del()).expect("get and remove");
+    assert!(matches!(first, Response::CheckedValue { ref value, .. } if value == b"secret"), "{:?}", first);
+    let retried = alice.request_idempotent("t1", get_del()).expect("retry");
+    assert!(matches!(retried, Response::CheckedValue { ref value, .. } if value == b"secret"), "{:?}", retried);
+
+    // Another identity reusing the token runs its own request.
+    let mut bob = server.connect().expect("connect");
+    bob.set_bearer_token(token_for("bob"));
+    let replayed = bob.request_idempotent("t1", get_del()).expect("get and remove");
+    assert!(matches!(replayed, Response::NotFound), "{:?}", replayed);
+    assert_eq!(bob.scan(ScanOptions::default()).expect("scan").entries, Vec::new());
+    assert_eq!(bob.info().expect("info").store.key_count, 0);
+}
+
+#[test]
+fn test_idempotent_retries_are_answered_across_a_restart() {
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let config = ServerConfig {
+        data_dir: dir.path().to_path_buf(),
+        ..ServerConfig::default()
+    };
+    let append = || Request::Append {
+        key: "log".to_string(),
+        suffix: "x".to_string(),
+        checksum: None,
+    };
+    let (mut client, server) = spawn_server_in(config.clone()).expect("spawn server");
+    let first = client.request_idempotent("t1", append()).expect("append");
+    assert!(matches!(first, Response::Length(1)), "{:?}", first);
+    server.shutdown().expect("shut down");
+
+    let (mut client, _server) = spawn_server_in(config).expect("restart server");
+    let retried = client.request_idempotent("t1", append()).expect("retry");
+    assert!(matches!(retried, Response::Length(1)), "{:?}", retried);
+    assert_eq!(client.get("log").expect("get"), Some("x".to_string()));
+    // The kept responses are not part of the keyspace.
+    assert_eq!(client.info().expect("info").store.key_count, 1);
+}
+
+#[test]
+fn test_self_test_reports_a_mixed_workload_and_cleans_up() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let directory = temp_dir.path().join("self-test");
+    let config = SelfTestConfig {
+        duration: Duration::from_millis(200),
+        threads: 2,
+        keys: 100,
+        value_size: 16,
+        read_ratio: 0.5,
+    };
+    let report = self_test::run(&directory, Options::new(), &config).expect("self-test");
+    assert_eq!(report.threads, 2);
+    assert!(report.gets > 0 && report.sets > 0, "{:?}", report);
+    assert!(report.ops_per_sec > 0.0);
+    assert!(report.write_latency.max_us >= report.write_latency.p50_us);
+    assert!(!directory.exists(), "scratch store left behind");
+}
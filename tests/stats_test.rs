@@ -0,0 +1,61 @@
+use bitkv_rs::stats::{self, ConnectionTable, LatencyHistogram, TopKeys};
+use std::time::Duration;
+
+#[test]
+fn test_latency_percentiles() {
+    let mut histogram = LatencyHistogram::default();
+    for _ in 0..99 {
+        histogram.record(Duration::from_micros(10));
+    }
+    histogram.record(Duration::from_millis(50));
+
+    let summary = histogram.summary();
+    assert!(summary.p50_us >= 10 && summary.p50_us < 20);
+    assert!(summary.p99_us < 20);
+    assert_eq!(summary.max_us, 50_000);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_resident_bytes_is_reported() {
+    let resident = stats::resident_bytes().expect("resident bytes");
+    assert!(resident > 0 && resident.is_multiple_of(1024), "{}", resident);
+}
+
+#[test]
+fn test_top_keys_keeps_heavy_hitters() {
+    let mut top = TopKeys::new(4);
+    for _ in 0..100 {
+        top.record("hot");
+    }
+    for i in 0..50 {
+        top.record(&format!("cold{}", i));
+    }
+
+    let keys = top.top(1);
+    assert_eq!(keys[0].0, "hot");
+    assert!(keys[0].1 >= 100);
+}
+
+#[test]
+fn test_connection_table_tracks_and_kills_clients() {
+    let mut table = ConnectionTable::default();
+    let (first, _) = table.register("127.0.0.1:4000".parse().unwrap());
+    let (second, _) = table.register("127.0.0.1:4001".parse().unwrap());
+    table.record(first, 20, 8);
+    table.record(first, 30, 8);
+    table.set_pending(first, 4096);
+
+    let clients = table.list();
+    assert_eq!(clients.len(), 2);
+    assert_eq!(clients[0].id, first);
+    assert_eq!(clients[0].commands, 2);
+    assert_eq!(clients[0].bytes_read, 50);
+    assert_eq!(clients[0].bytes_written, 16);
+    assert_eq!(clients[0].pending_write_bytes, 4096);
+    assert_eq!(clients[1].pending_write_bytes, 0);
+
+    assert!(table.kill(second));
+    assert!(!table.kill(second));
+    assert_eq!(table.list().len(), 1);
+}
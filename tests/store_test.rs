@@ -0,0 +1,1918 @@
+use bitkv_rs::{
+    Aggregate, CompactionEvent, ContentType, CorruptionKind, CorruptionPolicy, DiskWatchdog, EvictionPolicy,
+    Incompatibility, IntegrityCheck, JsonValidator, KvError, KvStore, Merge, MergeMode, OnDuplicate, Options,
+    ReadOnlyReason, RecordFormat, RotationPolicy, ScanOptions, Scrubber, SetOptions, SyncPolicy, SyncSummary,
+    value_checksum,
+};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_read_only_open_rejects_writes() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    drop(store);
+
+    let mut read_only = KvStore::open_read_only(temp_dir.path().to_path_buf()).expect("open read-only");
+    assert_eq!(read_only.get("a").expect("get value"), Some("1".to_string()));
+    let err = read_only.set("b".to_string(), "2".to_string()).unwrap_err();
+    assert!(matches!(err, KvError::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied));
+}
+
+#[test]
+fn test_keyspace_stats_by_prefix() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for key in ["users/1/name", "users/2/name", "orders/1", "plain"] {
+        store.set(key.to_string(), "v".to_string()).expect("set value");
+    }
+
+    let stats = store.keyspace_stats(2, '/').expect("keyspace stats");
+    let keys_for = |prefix: &str| stats.iter().find(|s| s.prefix == prefix).map(|s| s.keys);
+    assert_eq!(keys_for(""), Some(4));
+    assert_eq!(keys_for("users/"), Some(2));
+    assert_eq!(keys_for("users/1/"), Some(1));
+    assert_eq!(keys_for("orders/"), Some(1));
+    assert_eq!(keys_for("orders/1/"), None);
+}
+
+#[test]
+fn test_keyspace_stats_count_blob_separated_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().blob_threshold(1024);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("big/1".to_string(), "x".repeat(64 * 1024)).expect("set value");
+    store.set("small/1".to_string(), "v".to_string()).expect("set value");
+
+    let stats = store.keyspace_stats(1, '/').expect("keyspace stats");
+    let bytes_for = |prefix: &str| stats.iter().find(|s| s.prefix == prefix).map(|s| s.bytes).unwrap();
+    assert!(bytes_for("big/") > 64 * 1024);
+    assert!(bytes_for("small/") < 1024);
+    assert_eq!(bytes_for(""), bytes_for("big/") + bytes_for("small/"));
+}
+
+#[test]
+fn test_keyspace_stats_skip_expired_keys() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().retention("tmp/", Duration::from_millis(100));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set_with_ttl("ttl/short", "1", Duration::from_millis(100)).expect("set_with_ttl");
+    store.set_with_ttl("ttl/long", "1", Duration::from_secs(3600)).expect("set_with_ttl");
+    store.set("tmp/old".to_string(), "1".to_string()).expect("set value");
+    std::thread::sleep(Duration::from_millis(150));
+    store.set("tmp/new".to_string(), "1".to_string()).expect("set value");
+
+    let stats = store.keyspace_stats(1, '/').expect("keyspace stats");
+    let keys_for = |prefix: &str| stats.iter().find(|s| s.prefix == prefix).map(|s| s.keys);
+    assert_eq!(keys_for(""), Some(2));
+    assert_eq!(keys_for("ttl/"), Some(1));
+    assert_eq!(keys_for("tmp/"), Some(1));
+}
+
+#[test]
+fn test_prefix_validators_reject_invalid_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .validator("cfg/", JsonValidator::new().require_fields(["version"]))
+        .validator("num/", |_: &str, value: &str| {
+            value.parse::<i64>().map(|_| ()).map_err(|e| e.to_string())
+        });
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+
+    store
+        .set("cfg/app".to_string(), r#"{"version": 2}"#.to_string())
+        .expect("valid json");
+    store.set("num/a".to_string(), "42".to_string()).expect("valid number");
+    store.set("other".to_string(), "anything".to_string()).expect("no validator");
+
+    let err = store
+        .set("cfg/app".to_string(), r#"{"name": "x"}"#.to_string())
+        .unwrap_err();
+    assert!(matches!(err, KvError::InvalidValue { ref prefix, .. } if prefix == "cfg/"));
+    assert!(store.set("num/a".to_string(), "forty".to_string()).is_err());
+    assert_eq!(store.get("cfg/app").expect("get"), Some(r#"{"version": 2}"#.to_string()));
+}
+
+#[test]
+fn test_compaction_keeps_latest_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for round in 0..5 {
+        for i in 0..40 {
+            store
+                .set(format!("key{}", i), format!("value{}-{}", i, round))
+                .expect("set value");
+        }
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i)).expect("remove key");
+    }
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    for i in 0..40 {
+        let expected = (i >= 10).then(|| format!("value{}-4", i));
+        assert_eq!(store.get(&format!("key{}", i)).expect("get value"), expected);
+    }
+}
+
+fn wait_for_compaction(store: &KvStore) {
+    for _ in 0..500 {
+        if !store.stats().expect("stats").compacting {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("compaction did not finish");
+}
+
+#[test]
+fn test_compaction_splits_output_by_segment_size() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().rotation(RotationPolicy::size(2048));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    for i in 0..300 {
+        store.set(format!("key{}", i), "x".repeat(50)).expect("set value");
+    }
+    wait_for_compaction(&store);
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    drop(store);
+
+    let mut segments: Vec<(u64, u64)> = std::fs::read_dir(temp_dir.path())
+        .expect("read dir")
+        .filter_map(|entry| {
+            let path = entry.expect("dir entry").path();
+            let generation = path.file_stem()?.to_str()?.parse().ok()?;
+            (path.extension()? == "db").then(|| (generation, std::fs::metadata(&path).expect("metadata").len()))
+        })
+        .collect();
+    segments.sort();
+    segments.pop();
+    assert!(segments.len() > 3, "expected several compacted segments, got {:?}", segments);
+    for (generation, len) in &segments {
+        assert!(*len < 2048 + 200, "segment {} is {} bytes", generation, len);
+    }
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+    for i in 0..300 {
+        assert_eq!(store.get(&format!("key{}", i)).expect("get"), Some("x".repeat(50)));
+    }
+}
+
+#[test]
+fn test_compaction_listener_reports_replaced_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let (sender, events) = std::sync::mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    let options = Options::new()
+        .rotation(RotationPolicy::size(u64::MAX))
+        .on_compaction_complete(move |event: &CompactionEvent| {
+            sender.lock().unwrap().send(event.clone()).unwrap();
+        });
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), i.to_string()).expect("set value");
+    }
+    let segment = |generation: &u64| temp_dir.path().join(format!("{}.db", generation));
+    store.compact().expect("compact");
+    let event = events.recv_timeout(Duration::from_secs(5)).expect("compaction event");
+    assert!(!event.removed.is_empty());
+    assert!(!event.added.is_empty());
+    assert!(event.removed.iter().all(|generation| !segment(generation).exists()));
+    assert!(event.added.iter().all(|generation| segment(generation).exists()));
+    assert!(event.removed.iter().max() < event.added.iter().min());
+    assert_eq!(store.get("key9").expect("get"), Some("99".to_string()));
+}
+
+#[test]
+fn test_churn_prefixes_compact_hot_segments_alone() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let (sender, events) = std::sync::mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    let options = || {
+        let sender = std::sync::Mutex::new(sender.lock().unwrap().clone());
+        Options::new()
+            .rotation(RotationPolicy::size(4096))
+            .churn_prefix("session/")
+            .on_compaction_complete(move |event: &CompactionEvent| {
+                let _ = sender.lock().unwrap().send(event.clone());
+            })
+    };
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    for i in 0..30 {
+        store.set(format!("user/{}", i), "cold".to_string()).expect("set value");
+    }
+    store.set("session/old".to_string(), "stale".to_string()).expect("set value");
+
+    let mut i = 0;
+    let event = loop {
+        store.set(format!("session/{}", i % 5), i.to_string()).expect("set value");
+        i += 1;
+        if i == 50 {
+            assert!(store.stats().expect("stats").current_generation > 1);
+            store.remove("session/old").expect("remove");
+        }
+        if let Ok(event) = events.try_recv() {
+            break event;
+        }
+        assert!(i < 10_000, "no compaction");
+    };
+    wait_for_compaction(&store);
+    assert!(!event.removed.contains(&1), "cold segment rewritten: {:?}", event);
+    assert!(temp_dir.path().join("1.db").exists());
+    drop(store);
+    let _ = std::fs::remove_file(temp_dir.path().join("index.snapshot"));
+
+    // The tombstone outlives the hot segment it was in, so the older set
+    // in the cold segment stays dead, with or without hints.
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+    assert_eq!(store.get("session/old").expect("get"), None);
+    assert_eq!(store.get("user/7").expect("get"), Some("cold".to_string()));
+    for n in i - 5..i {
+        assert_eq!(store.get(&format!("session/{}", n % 5)).expect("get"), Some(n.to_string()));
+    }
+}
+
+#[test]
+fn test_cancelled_compaction_leaves_store_consistent() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for i in 0..200 {
+        store.set(format!("key{}", i % 50), format!("value{}", i)).expect("set value");
+    }
+    store.compact().expect("compact");
+    store.cancel_compaction().expect("cancel compaction");
+    assert!(!store.stats().expect("stats").compacting);
+
+    for i in 150..200 {
+        assert_eq!(store.get(&format!("key{}", i % 50)).expect("get"), Some(format!("value{}", i)));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    for i in 150..200 {
+        assert_eq!(store.get(&format!("key{}", i % 50)).expect("get"), Some(format!("value{}", i)));
+    }
+}
+
+#[test]
+fn test_open_background_and_timeout() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    // A compaction still deleting segments would race the reopen below.
+    wait_for_compaction(&store);
+    drop(store);
+
+    let handle = KvStore::open_background(temp_dir.path().to_path_buf(), Options::new());
+    assert!(handle.wait_timeout(std::time::Duration::from_secs(10)).expect("wait"));
+    assert!(handle.is_ready());
+    assert_eq!(handle.get_partial("key7").expect("partial get"), Some("value7".to_string()));
+    let store = handle.wait().expect("open in background");
+    assert_eq!(store.get("key99").expect("get"), Some("value99".to_string()));
+    drop(store);
+
+    let store = KvStore::open_with_timeout(
+        temp_dir.path().to_path_buf(),
+        Options::new(),
+        std::time::Duration::from_secs(10),
+    )
+    .expect("open with timeout");
+    assert_eq!(store.get("key0").expect("get"), Some("value0".to_string()));
+}
+
+#[test]
+fn test_fallback_scan_recovers_unindexed_records() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().fallback_scan(2);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("indexed".to_string(), "1".to_string()).expect("set value");
+
+    // Simulate a record the index never saw.
+    let newest = std::fs::read_dir(temp_dir.path())
+        .expect("read dir")
+        .filter_map(|entry| entry.ok()?.path().file_stem()?.to_str()?.parse::<u64>().ok())
+        .max()
+        .expect("a generation file");
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(format!("{}.db", newest)))
+        .expect("open segment");
+    std::io::Write::write_all(&mut file, br#"{"Set":{"key":"lost","value":"found"}}"#).expect("append record");
+
+    assert_eq!(store.get("lost").expect("get"), Some("found".to_string()));
+    assert_eq!(store.get("missing").expect("get"), None);
+    assert_eq!(store.stats().expect("stats").fallback_hits, 1);
+}
+
+#[test]
+fn test_time_based_rotation() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().rotation(RotationPolicy::interval(Duration::from_millis(500)));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), "x".repeat(100)).expect("set value");
+    }
+    let segments = store.stats().expect("stats").segment_count;
+    assert_eq!(segments, 1, "size alone must not rotate with an interval-only policy");
+
+    std::thread::sleep(Duration::from_millis(550));
+    store.set("late".to_string(), "1".to_string()).expect("set value");
+    assert_eq!(store.stats().expect("stats").segment_count, segments + 1);
+    assert_eq!(store.get("key0").expect("get"), Some("x".repeat(100)));
+}
+
+#[test]
+fn test_time_based_rotation_seals_idle_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().rotation(RotationPolicy::size(1024 * 1024).with_max_age(Duration::from_millis(200)));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("key".to_string(), "value".to_string()).expect("set value");
+    assert_eq!(store.stats().expect("stats").segment_count, 1);
+
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.stats().expect("stats").segment_count, 2);
+    // The new, empty, segment is left alone however old it gets.
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.stats().expect("stats").segment_count, 2);
+    assert_eq!(store.get("key").expect("get"), Some("value".to_string()));
+}
+
+#[test]
+fn test_retention_hides_and_compacts_old_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().retention("tmp/", Duration::from_millis(100));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    store.set("tmp/a".to_string(), "1".to_string()).expect("set value");
+    store.set("keep/a".to_string(), "2".to_string()).expect("set value");
+    assert_eq!(store.get("tmp/a").expect("get"), Some("1".to_string()));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("tmp/a").expect("get"), None);
+    assert_eq!(store.get("keep/a").expect("get"), Some("2".to_string()));
+
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    assert_eq!(store.stats().expect("stats").key_count, 1);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    assert_eq!(store.get("tmp/a").expect("get"), None);
+    assert_eq!(store.get("keep/a").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_remove_many_survives_reopen() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    let removed = store.remove_many(["key1", "key3", "absent"]).expect("remove many");
+    assert_eq!(removed, 2);
+    assert_eq!(store.get("key1").expect("get"), None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.get("key1").expect("get"), None);
+    assert_eq!(store.get("key3").expect("get"), None);
+    assert_eq!(store.get("key4").expect("get"), Some("value4".to_string()));
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    assert_eq!(store.stats().expect("stats").key_count, 3);
+}
+
+#[test]
+fn test_transaction_sees_its_own_writes() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("counter".to_string(), "1".to_string()).expect("set value");
+
+    let next = store
+        .transaction(|txn| {
+            let current: u64 = txn.get("counter")?.unwrap_or_default().parse().unwrap_or(0);
+            txn.set("counter".to_string(), (current + 1).to_string())?;
+            txn.remove("stale")?;
+            txn.get("counter")
+        })
+        .expect("transaction");
+    assert_eq!(next, Some("2".to_string()));
+    assert_eq!(store.get("counter").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_aggregates_track_writes_and_survive_compaction() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().aggregate("orders/");
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    for i in 0..50 {
+        store.set(format!("orders/{}", i % 10), i.to_string()).expect("set value");
+    }
+    store.set("orders/note".to_string(), "not a number".to_string()).expect("set value");
+    store.set("other/1".to_string(), "1000".to_string()).expect("set value");
+    store.remove_many(["orders/0", "orders/1"]).expect("remove many");
+    store.remove("orders/2").expect("remove");
+
+    // Live values are 43..=49 plus the note.
+    let expected = Aggregate {
+        count: 8,
+        sum: (43..50).sum::<i32>() as f64,
+    };
+    assert_eq!(store.aggregate("orders/").expect("aggregate"), Some(expected));
+    assert_eq!(store.aggregate("other/").expect("aggregate"), None);
+
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    assert_eq!(store.aggregate("orders/").expect("aggregate"), Some(expected));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    assert_eq!(store.aggregate("orders/").expect("aggregate"), Some(expected));
+}
+
+#[test]
+fn test_scan_filters_values_and_pages() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for (key, value) in [
+        ("event/1", "click:home"),
+        ("event/2", "view:home"),
+        ("event/3", "click:settings"),
+        ("event/4", "click:x"),
+        ("event/5", "click:about"),
+        ("other/1", "click:home"),
+    ] {
+        store.set(key.to_string(), value.to_string()).expect("set value");
+    }
+    store.remove("event/5").expect("remove");
+
+    let options = ScanOptions {
+        key_prefix: "event/".to_string(),
+        value_prefix: Some("click:".to_string()),
+        min_value_len: Some(8),
+        ..ScanOptions::default()
+    };
+    let page = store.scan(&options).expect("scan");
+    let keys: Vec<&str> = page.entries.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["event/1", "event/3"]);
+    assert_eq!(page.next, None);
+
+    let options = ScanOptions {
+        key_prefix: "event/".to_string(),
+        value_regex: Some("^(click|view):home$".to_string()),
+        limit: 1,
+        ..ScanOptions::default()
+    };
+    let first = store.scan(&options).expect("scan");
+    assert_eq!(first.entries, [("event/1".to_string(), "click:home".to_string())]);
+    assert_eq!(first.next.as_deref(), Some("event/1"));
+    let second = store
+        .scan(&ScanOptions {
+            start_after: first.next,
+            ..options.clone()
+        })
+        .expect("scan");
+    assert_eq!(second.entries, [("event/2".to_string(), "view:home".to_string())]);
+    let last = store
+        .scan(&ScanOptions {
+            start_after: second.next,
+            ..options
+        })
+        .expect("scan");
+    assert!(last.entries.is_empty());
+    assert_eq!(last.next, None);
+
+    let bad = ScanOptions {
+        value_regex: Some("(".to_string()),
+        ..ScanOptions::default()
+    };
+    assert!(store.scan(&bad).is_err());
+}
+
+#[test]
+fn test_low_disk_space_refuses_writes() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.set("a".to_string(), "2".to_string()).expect("set value");
+    drop(store);
+
+    // No disk has this much free space, so the hard threshold trips at open,
+    // without passing through the soft one first.
+    let options = Options::new().disk_watchdog(DiskWatchdog::new(u64::MAX, u64::MAX));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    let err = store.set("b".to_string(), "2".to_string()).unwrap_err();
+    assert!(matches!(err, KvError::ReadOnly(ReadOnlyReason::LowDisk)));
+    assert!(store.stats().expect("stats").low_disk);
+    assert_eq!(store.get("a").expect("get"), Some("2".to_string()));
+    wait_for_compaction(&store);
+    let stats = store.stats().expect("stats");
+    assert_eq!(stats.records_rewritten, 1, "no compaction to reclaim space");
+    assert!(stats.compaction_bytes_reclaimed > 0);
+}
+
+#[test]
+fn test_shutdown_snapshot_replays_only_newer_records() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    store.remove("key0").expect("remove");
+    store.shutdown().expect("shutdown");
+    assert!(temp_dir.path().join("index.snapshot").exists());
+    // Written after the snapshot, so it has to come from replay.
+    store.set("key1".to_string(), "late".to_string()).expect("set value");
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert!(!temp_dir.path().join("index.snapshot").exists());
+    assert_eq!(store.get("key0").expect("get"), None);
+    assert_eq!(store.get("key1").expect("get"), Some("late".to_string()));
+    assert_eq!(store.get("key49").expect("get"), Some("value49".to_string()));
+    assert_eq!(store.stats().expect("stats").key_count, 49);
+}
+
+#[test]
+fn test_sealed_segments_get_hints_that_reopen_uses() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().rotation(RotationPolicy::size(512));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    for i in 0..200 {
+        store.set(format!("key{}", i % 40), format!("value{}", i)).expect("set value");
+        if i % 7 == 0 {
+            store.remove(format!("key{}", (i + 1) % 40)).expect("remove");
+        }
+    }
+    wait_for_compaction(&store);
+    let expected: Vec<Option<String>> = (0..40)
+        .map(|i| store.get(&format!("key{}", i)).expect("get"))
+        .collect();
+    drop(store);
+
+    let mut generations: Vec<u64> = std::fs::read_dir(temp_dir.path())
+        .expect("read dir")
+        .filter_map(|entry| {
+            let path = entry.expect("dir entry").path();
+            (path.extension()? == "db").then_some(path.file_stem()?.to_str()?.parse().ok()?)
+        })
+        .collect();
+    generations.sort_unstable();
+    let active = generations.pop().expect("active segment");
+    assert!(!generations.is_empty());
+    for generation in &generations {
+        assert!(temp_dir.path().join(format!("{}.hint", generation)).exists(), "no hint for {}", generation);
+    }
+    assert!(!temp_dir.path().join(format!("{}.hint", active)).exists());
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+    for (i, value) in expected.iter().enumerate() {
+        assert_eq!(&store.get(&format!("key{}", i)).expect("get"), value);
+    }
+    drop(store);
+    // The previously active segment was replayed and sealed on reopen.
+    assert!(temp_dir.path().join(format!("{}.hint", active)).exists());
+}
+
+#[test]
+fn test_hints_replace_replay_until_the_segment_changes() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().rotation(RotationPolicy::size(u64::MAX));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    store.remove("key0").expect("remove");
+    drop(store);
+    // Sealing the first segment on reopen writes its hint.
+    drop(KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store"));
+    let segment = temp_dir.path().join("1.db");
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    // Same length, so the hint still applies and the garbage after the
+    // 8-byte header is never read.
+    let original = std::fs::read(&segment).expect("read segment");
+    let mut garbage = original.clone();
+    garbage[8..].fill(b'#');
+    std::fs::write(&segment, garbage).expect("overwrite segment");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open from hint");
+    assert_eq!(store.stats().expect("stats").key_count, 19);
+    drop(store);
+    // Verification needs the records themselves.
+    let verified = options().verify_on_load(CorruptionPolicy::Abort);
+    assert!(KvStore::open_with(temp_dir.path().to_path_buf(), verified).is_err());
+
+    std::fs::write(&segment, &original).expect("restore segment");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open from hint");
+    assert_eq!(store.get("key0").expect("get"), None);
+    assert_eq!(store.get("key7").expect("get"), Some("value7".to_string()));
+    drop(store);
+
+    // A segment that no longer matches its hint is replayed instead.
+    let mut grown = original;
+    grown.extend_from_slice(br#"{"Set":{"key":"extra","value":"x"}}"#);
+    std::fs::write(&segment, &grown).expect("grow segment");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open by replay");
+    assert_eq!(store.get("extra").expect("get"), Some("x".to_string()));
+    assert_eq!(store.get("key7").expect("get"), Some("value7".to_string()));
+}
+
+#[test]
+fn test_damaged_hints_fall_back_to_replay() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().rotation(RotationPolicy::size(u64::MAX));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.set("b".to_string(), "2".to_string()).expect("set value");
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+    store.set("a".to_string(), "3".to_string()).expect("set value");
+    drop(store);
+    drop(KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store"));
+    let (first, second) = (temp_dir.path().join("1.hint"), temp_dir.path().join("2.hint"));
+
+    let mut flipped = std::fs::read(&first).expect("read hint");
+    let last = flipped.len() - 1;
+    flipped[last] ^= 0x01;
+    std::fs::write(&first, &flipped).expect("write hint");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open by replay");
+    assert_eq!(store.get("a").expect("get"), Some("3".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+    drop(store);
+    assert_ne!(std::fs::read(&first).expect("read hint"), flipped, "bad hint was not rewritten");
+
+    // A hint for another segment is never applied, even as a whole file.
+    std::fs::copy(&second, &first).expect("copy hint");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open by replay");
+    assert_eq!(store.get("a").expect("get"), Some("3".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_large_values_go_to_blob_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().blob_threshold(256);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    let big = "b".repeat(4096);
+    for i in 0..20 {
+        store.set(format!("big{}", i), big.clone()).expect("set value");
+        store.set(format!("small{}", i), "s".to_string()).expect("set value");
+    }
+    assert_eq!(store.stats().expect("stats").blob_segment_count, 1);
+    assert_eq!(store.get("big3").expect("get"), Some(big.clone()));
+
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    drop(store);
+
+    let log_bytes: u64 = std::fs::read_dir(temp_dir.path())
+        .expect("read dir")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "db"))
+        .map(|entry| entry.metadata().expect("metadata").len())
+        .sum();
+    assert!(log_bytes < 20 * 4096, "large values must not be in the log");
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    assert_eq!(store.get("big19").expect("get"), Some(big));
+    assert_eq!(store.get("small19").expect("get"), Some("s".to_string()));
+}
+
+#[test]
+fn test_gc_blobs_rewrites_mostly_dead_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().blob_threshold(100);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    for round in 0..3 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("{}-{}", round, "v".repeat(200))).expect("set value");
+        }
+    }
+    let before = store.stats().expect("stats");
+    assert!(before.blob_live_bytes * 3 <= before.blob_total_bytes + 3);
+
+    let gc = store.gc_blobs().expect("gc blobs");
+    assert_eq!(gc.segments_rewritten, 1);
+    assert_eq!(gc.values_moved, 10);
+    let after = store.stats().expect("stats");
+    assert_eq!(after.blob_live_bytes, after.blob_total_bytes);
+    assert_eq!(after.blob_bytes_reclaimed, gc.bytes_reclaimed);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    for i in 0..10 {
+        assert_eq!(store.get(&format!("key{}", i)).expect("get"), Some(format!("2-{}", "v".repeat(200))));
+    }
+}
+
+#[test]
+fn test_import_batch_duplicate_policies() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "old".to_string()).expect("set value");
+
+    let pairs = || vec![("a", "new"), ("b", "1"), ("c", "2"), ("b", "3")];
+    let err = store.import_batch(pairs(), OnDuplicate::Error).unwrap_err();
+    assert!(matches!(err, KvError::DuplicateKey(ref key) if key == "a"));
+    assert_eq!(store.get("b").expect("get"), None);
+
+    let summary = store.import_batch(pairs(), OnDuplicate::Skip).expect("import");
+    assert_eq!((summary.inserted, summary.skipped, summary.overwritten), (2, 2, 0));
+    assert_eq!(store.get("a").expect("get"), Some("old".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("1".to_string()));
+
+    let summary = store.import_batch(pairs(), OnDuplicate::Overwrite).expect("import");
+    assert_eq!((summary.inserted, summary.skipped, summary.overwritten), (0, 0, 4));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.get("a").expect("get"), Some("new".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("3".to_string()));
+    assert_eq!(store.get("c").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_manifest_rejects_newer_format_versions() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), Options::new().blob_threshold(1024))
+        .expect("open store");
+    let metadata = store.metadata().expect("metadata");
+    assert!(metadata.features.contains("blob_segments"));
+    drop(store);
+
+    let manifest = temp_dir.path().join("MANIFEST");
+    let contents = std::fs::read_to_string(&manifest).expect("read manifest");
+    let bumped = contents.replace(
+        &format!("\"format_version\": {}", metadata.format_version),
+        "\"format_version\": 999",
+    );
+    std::fs::write(&manifest, bumped).expect("write manifest");
+    let result = KvStore::open(temp_dir.path().to_path_buf());
+    assert!(matches!(
+        result,
+        Err(KvError::Incompatible(Incompatibility::FormatVersion { found: 999, .. }))
+    ));
+}
+
+#[test]
+fn test_manifest_records_segment_formats() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    assert!(store.metadata().expect("metadata").segment_formats.is_empty());
+    drop(store);
+
+    let manifest = temp_dir.path().join("MANIFEST");
+    let mut value: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).expect("read manifest")).expect("parse manifest");
+    value["segment_formats"] = serde_json::json!({ "1": "json" });
+    std::fs::write(&manifest, value.to_string()).expect("write manifest");
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.metadata().expect("metadata").segment_format(1), RecordFormat::Json);
+    assert_eq!(store.get("a").expect("get"), Some("1".to_string()));
+    drop(store);
+
+    value["segment_formats"] = serde_json::json!({ "1": "unheard-of" });
+    std::fs::write(&manifest, value.to_string()).expect("write manifest");
+    let result = KvStore::open(temp_dir.path().to_path_buf());
+    assert!(matches!(result, Err(KvError::Incompatible(Incompatibility::Unreadable(_)))));
+}
+
+#[test]
+fn test_segment_headers_are_checked_on_open() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    drop(store);
+    let segment = temp_dir.path().join("1.db");
+    let mut contents = std::fs::read(&segment).expect("read segment");
+    assert_eq!(&contents[..6], b"BKV\0\x01\x00");
+    assert!(
+        KvStore::open(temp_dir.path().to_path_buf())
+            .expect("reopen store")
+            .metadata()
+            .expect("metadata")
+            .features
+            .contains("segment_headers")
+    );
+
+    contents[4] = 2;
+    std::fs::write(&segment, &contents).expect("write segment");
+    let result = KvStore::open(temp_dir.path().to_path_buf());
+    assert!(matches!(
+        result,
+        Err(KvError::Incompatible(Incompatibility::SegmentVersion { found: 2, supported: 1, .. }))
+    ));
+    std::fs::write(&segment, b"not a segment").expect("write segment");
+    assert!(KvStore::open(temp_dir.path().to_path_buf()).is_err());
+
+    // Segments from before headers start with their first record.
+    std::fs::write(&segment, br#"{"Set":{"key":"old","value":"v"}}"#).expect("write segment");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open headerless store");
+    assert_eq!(store.get("old").expect("get"), Some("v".to_string()));
+    store.set("new".to_string(), "w".to_string()).expect("set value");
+    drop(store);
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.get("old").expect("get"), Some("v".to_string()));
+    assert_eq!(store.get("new").expect("get"), Some("w".to_string()));
+}
+
+#[test]
+fn test_migrate_rewrites_every_segment_in_the_target_format() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    // A headerless segment from an older build, followed by current ones.
+    std::fs::write(temp_dir.path().join("1.db"), br#"{"Set":{"key":"old","value":"v"}}"#).expect("write segment");
+    let options = || Options::new().rotation(RotationPolicy::size(512));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    store.remove("key0").expect("remove");
+    wait_for_compaction(&store);
+    drop(store);
+
+    let summary = KvStore::migrate(temp_dir.path().to_path_buf(), RecordFormat::Binary).expect("migrate");
+    assert!(summary.segments_before > 2, "{:?}", summary);
+    for entry in std::fs::read_dir(temp_dir.path()).expect("read dir") {
+        let path = entry.expect("dir entry").path();
+        if path.extension().is_some_and(|ext| ext == "db") {
+            let contents = std::fs::read(&path).expect("read segment");
+            // Magic, version 1, then the binary-records flag.
+            assert_eq!(&contents[..8], b"BKV\0\x01\x00\x01\x00", "{}", path.display());
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert!(store.metadata().expect("metadata").segment_formats.values().all(|f| *f == RecordFormat::Binary));
+    assert_eq!(store.get("old").expect("get"), Some("v".to_string()));
+    assert_eq!(store.get("key0").expect("get"), None);
+    assert_eq!(store.get("key49").expect("get"), Some("value49".to_string()));
+    assert_eq!(store.stats().expect("stats").key_count, 50);
+}
+
+#[test]
+fn test_compaction_output_is_reproducible() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().join("a")).expect("open store");
+    for i in 0..300 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    for i in (0..300).step_by(3) {
+        store.set(format!("key{}", i), "changed".to_string()).expect("set value");
+        store.remove(format!("key{}", i + 1)).expect("remove");
+    }
+    // Let any compaction the writes started finish, so both sides compact the same segments.
+    wait_for_compaction(&store);
+    let mut fork = store.fork(temp_dir.path().join("b")).expect("fork store");
+
+    store.compact().expect("compact");
+    fork.compact().expect("compact fork");
+    wait_for_compaction(&store);
+    wait_for_compaction(&fork);
+    let a = non_empty_segments(&temp_dir.path().join("a"));
+    assert_eq!(a, non_empty_segments(&temp_dir.path().join("b")));
+    assert!(std::str::from_utf8(&a[0][8..]).expect("JSON segment").starts_with(r#"{"Set":{"key":"key0""#));
+}
+
+// Contents of each segment holding records, oldest first.
+fn non_empty_segments(dir: &std::path::Path) -> Vec<Vec<u8>> {
+    let mut segments: Vec<(u64, Vec<u8>)> = std::fs::read_dir(dir)
+        .expect("read dir")
+        .filter_map(|entry| {
+            let path = entry.expect("dir entry").path();
+            let generation = path.file_stem()?.to_str()?.parse().ok()?;
+            let contents = std::fs::read(&path).ok()?;
+            (path.extension()? == "db" && contents.len() > 8).then_some((generation, contents))
+        })
+        .collect();
+    segments.sort();
+    segments.into_iter().map(|(_, contents)| contents).collect()
+}
+
+#[test]
+fn test_rename_moves_value_atomically() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().aggregate("n/");
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    store.set("n/a".to_string(), "5".to_string()).expect("set value");
+    store.set("n/b".to_string(), "7".to_string()).expect("set value");
+
+    let err = store.rename("n/a", "n/b", false).unwrap_err();
+    assert!(matches!(err, KvError::DuplicateKey(ref key) if key == "n/b"));
+    assert!(matches!(store.rename("missing", "x", true), Err(KvError::KeyNotFound(_))));
+
+    store.rename("n/a", "n/b", true).expect("rename over existing");
+    store.rename("n/b", "other", false).expect("rename out of prefix");
+    assert_eq!(store.aggregate("n/").expect("aggregate").map(|a| a.count), Some(0));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+    assert_eq!(store.get("n/a").expect("get"), None);
+    assert_eq!(store.get("n/b").expect("get"), None);
+    assert_eq!(store.get("other").expect("get"), Some("5".to_string()));
+}
+
+#[test]
+fn test_copy_keeps_write_time() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().retention("tmp/", Duration::from_millis(200));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("tmp/a".to_string(), "1".to_string()).expect("set value");
+    store.set("tmp/b".to_string(), "2".to_string()).expect("set value");
+
+    assert!(matches!(store.copy("tmp/a", "tmp/b", false), Err(KvError::DuplicateKey(_))));
+    assert!(matches!(store.copy("tmp/none", "tmp/c", false), Err(KvError::KeyNotFound(_))));
+    std::thread::sleep(Duration::from_millis(120));
+    store.copy("tmp/a", "tmp/c", false).expect("copy");
+    assert_eq!(store.get("tmp/a").expect("get"), Some("1".to_string()));
+    assert_eq!(store.get("tmp/c").expect("get"), Some("1".to_string()));
+
+    // The copy expires with the original rather than 200ms after copying.
+    std::thread::sleep(Duration::from_millis(120));
+    assert_eq!(store.get("tmp/a").expect("get"), None);
+    assert_eq!(store.get("tmp/c").expect("get"), None);
+}
+
+#[test]
+fn test_get_and_remove_and_get_and_set() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+
+    assert_eq!(store.get_and_set("a", "1").expect("getset"), None);
+    assert_eq!(store.get_and_set("a", "2").expect("getset"), Some("1".to_string()));
+    assert_eq!(store.get_and_remove("a").expect("getdel"), Some("2".to_string()));
+    assert_eq!(store.get_and_remove("a").expect("getdel"), None);
+    assert_eq!(store.get("a").expect("get"), None);
+}
+
+#[test]
+fn test_append_accumulates_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+
+    assert_eq!(store.append("log", "a").expect("append"), 1);
+    assert_eq!(store.append("log", "bc").expect("append"), 3);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.append("log", "d").expect("append"), 4);
+    assert_eq!(store.get("log").expect("get"), Some("abcd".to_string()));
+}
+
+#[test]
+fn test_records_carry_write_timestamps() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let millis = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("clock after epoch")
+            .as_millis() as u64
+    };
+    let before = millis();
+    store
+        .set_with_content_type("doc", r#"{"a":1}"#, ContentType::Json)
+        .expect("set value");
+    store.set("gone".to_string(), "x".to_string()).expect("set value");
+    store.remove("gone").expect("remove");
+    let after = millis();
+
+    let (value, metadata) = store.get_with_metadata("doc").expect("get").expect("value");
+    assert_eq!(value, r#"{"a":1}"#);
+    assert!(metadata.written_at.is_some_and(|at| (before..=after).contains(&at)), "{:?}", metadata);
+    assert_eq!(metadata.content_type, Some(ContentType::Json));
+    assert_eq!(metadata.expires_at, None);
+    assert_eq!(store.get_with_metadata("gone").expect("get"), None);
+
+    let log = std::fs::read_to_string(temp_dir.path().join("1.db")).expect("read segment");
+    assert!(log.contains(r#"{"Remove":{"key":"gone","timestamp":"#), "{}", log);
+}
+
+#[test]
+fn test_content_type_survives_compaction() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store
+        .set_with_content_type("doc", r#"{"a":1}"#, ContentType::Json)
+        .expect("set value");
+    store.set("plain".to_string(), "x".to_string()).expect("set value");
+    store.rename("doc", "moved", false).expect("rename");
+
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(
+        store.get_with_content_type("moved").expect("get"),
+        Some((r#"{"a":1}"#.to_string(), Some(ContentType::Json)))
+    );
+    assert_eq!(store.get_with_content_type("plain").expect("get"), Some(("x".to_string(), None)));
+
+    // A plain write replaces the tag along with the value.
+    store.set("moved".to_string(), "text".to_string()).expect("set value");
+    assert_eq!(store.get_with_content_type("moved").expect("get"), Some(("text".to_string(), None)));
+}
+
+#[test]
+fn test_deadline_bounds_lock_wait() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+
+    let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+    let mut holder = store.clone();
+    let handle = std::thread::spawn(move || {
+        holder
+            .transaction(|_| {
+                locked_tx.send(()).expect("signal");
+                std::thread::sleep(Duration::from_millis(300));
+                Ok(())
+            })
+            .expect("transaction");
+    });
+    locked_rx.recv().expect("lock held");
+
+    let deadline = Instant::now() + Duration::from_millis(50);
+    assert!(matches!(store.get_with_deadline("a", deadline), Err(KvError::DeadlineExceeded)));
+    assert!(matches!(
+        store.set_with_deadline("a".to_string(), "2".to_string(), deadline),
+        Err(KvError::DeadlineExceeded)
+    ));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    assert_eq!(store.get_with_deadline("a", deadline).expect("get"), Some("1".to_string()));
+    handle.join().expect("join");
+    store.remove_with_deadline("a", deadline).expect("remove");
+    assert_eq!(store.get("a").expect("get"), None);
+}
+
+#[test]
+fn test_checksums_catch_corrupted_values() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let checksum = value_checksum("hello");
+    assert!(matches!(
+        store.set_checked("a".to_string(), "hello".to_string(), checksum + 1),
+        Err(KvError::ChecksumMismatch(_))
+    ));
+    assert_eq!(store.get("a").expect("get"), None);
+    store.set_checked("a".to_string(), "hello".to_string(), checksum).expect("set value");
+    drop(store);
+
+    // Flip the value in place, keeping the record well-formed.
+    for entry in std::fs::read_dir(temp_dir.path()).expect("read dir") {
+        let path = entry.expect("dir entry").path();
+        if path.extension().is_some_and(|ext| ext == "db") {
+            let contents = std::fs::read_to_string(&path).expect("read segment");
+            std::fs::write(&path, contents.replace("hello", "jello")).expect("write segment");
+        }
+    }
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert!(matches!(store.get("a"), Err(KvError::ChecksumMismatch(_))));
+}
+
+#[test]
+fn test_integrity_check_reports_bad_records() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("good".to_string(), "fine".to_string()).expect("set value");
+    store.set("bad".to_string(), "hello".to_string()).expect("set value");
+    drop(store);
+    for entry in std::fs::read_dir(temp_dir.path()).expect("read dir") {
+        let path = entry.expect("dir entry").path();
+        if path.extension().is_some_and(|ext| ext == "db") {
+            let contents = std::fs::read_to_string(&path).expect("read segment");
+            std::fs::write(&path, contents.replace("hello", "jello")).expect("write segment");
+        }
+    }
+
+    let options = Options::new().integrity_check(IntegrityCheck::Full);
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    let report = store.open_report().expect("open report");
+    assert_eq!(report.records_checked, 2);
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].key, "bad");
+    assert_eq!(store.get("good").expect("get"), Some("fine".to_string()));
+    drop(store);
+
+    let options = Options::new().integrity_check(IntegrityCheck::Sample(0.0));
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    assert_eq!(store.open_report().expect("open report").records_checked, 0);
+}
+
+#[test]
+fn test_verify_on_load_aborts_or_skips_corrupt_records() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "old".to_string()).expect("set value");
+    store.set("a".to_string(), "hello".to_string()).expect("set value");
+    store.set("b".to_string(), "fine".to_string()).expect("set value");
+    drop(store);
+    let segment = temp_dir.path().join("1.db");
+    let contents = std::fs::read_to_string(&segment).expect("read segment");
+    let offset = contents.find("hello").and_then(|at| contents[..at].rfind("{\"Set\"")).expect("record") as u64;
+    std::fs::write(&segment, contents.replace("hello", "jello") + "{\"Set\":}").expect("write segment");
+
+    let abort = Options::new().verify_on_load(CorruptionPolicy::Abort);
+    match KvStore::open_with(temp_dir.path().to_path_buf(), abort) {
+        Err(KvError::CorruptRecord(record)) => {
+            assert_eq!((record.generation, record.offset), (1, offset));
+            assert_eq!(record.kind, CorruptionKind::ChecksumMismatch);
+        }
+        other => panic!("expected a corrupt record, got {:?}", other.map(|_| ())),
+    }
+
+    let skip = Options::new().verify_on_load(CorruptionPolicy::Skip);
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), skip).expect("open store");
+    let kinds: Vec<_> = store
+        .open_report()
+        .expect("open report")
+        .corrupt_records
+        .iter()
+        .map(|record| (record.generation, record.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![(1, CorruptionKind::ChecksumMismatch), (1, CorruptionKind::Unreadable)]
+    );
+    assert_eq!(store.get("a").expect("get"), Some("old".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("fine".to_string()));
+}
+
+#[test]
+fn test_torn_tail_record_is_truncated_on_open() {
+    for (format, torn) in [
+        (RecordFormat::Json, &br#"{"Set":{"key":"c","val"#[..]),
+        (RecordFormat::Binary, &[0xB1, 40, 0, 0, 0][..]),
+    ] {
+        let temp_dir = tempfile::tempdir().expect("create temp dir");
+        let options = || Options::new().record_format(format);
+        let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+        store.set("a".to_string(), "1".to_string()).expect("set value");
+        store.set("b".to_string(), "2".to_string()).expect("set value");
+        drop(store);
+        let segment = temp_dir.path().join("1.db");
+        let mut contents = std::fs::read(&segment).expect("read segment");
+        let intact = contents.len() as u64;
+        contents.extend_from_slice(torn);
+        std::fs::write(&segment, &contents).expect("write segment");
+
+        // Read-only opens skip the partial record without touching the file.
+        let store = KvStore::open_read_only(temp_dir.path().to_path_buf()).expect("open read-only");
+        assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+        drop(store);
+        assert_eq!(std::fs::metadata(&segment).expect("stat segment").len(), contents.len() as u64);
+
+        let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+        let tail = store.open_report().expect("open report").truncated_tail.expect("truncated tail");
+        assert_eq!((tail.generation, tail.offset, tail.dropped_bytes), (1, intact, torn.len() as u64));
+        assert_eq!(std::fs::metadata(&segment).expect("stat segment").len(), intact);
+        assert_eq!(store.get("a").expect("get"), Some("1".to_string()));
+        assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("reopen store");
+        assert!(store.open_report().expect("open report").truncated_tail.is_none());
+    }
+}
+
+#[test]
+fn test_unclean_shutdown_is_reported_and_checked() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = || Options::new().check_after_unclean_shutdown(IntegrityCheck::Full);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    assert!(!store.open_report().expect("open report").unclean_shutdown);
+    store.set("key".to_string(), "value".to_string()).expect("set value");
+    store.shutdown().expect("shutdown");
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    let report = store.open_report().expect("open report");
+    assert!(!report.unclean_shutdown);
+    assert_eq!(report.records_checked, 0);
+    // A write after the shutdown makes the store dirty again.
+    store.shutdown().expect("shutdown");
+    store.set("key".to_string(), "newer".to_string()).expect("set value");
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options()).expect("open store");
+    let report = store.open_report().expect("open report");
+    assert!(report.unclean_shutdown);
+    assert_eq!(report.records_checked, 1);
+    assert_eq!(store.get("key").expect("get"), Some("newer".to_string()));
+}
+
+#[test]
+fn test_check_and_repair_salvages_records_around_damage() {
+    for format in [RecordFormat::Json, RecordFormat::Binary] {
+        let temp_dir = tempfile::tempdir().expect("create temp dir");
+        let segment = temp_dir.path().join("1.db");
+        let options = Options::new().record_format(format);
+        let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+        store.set("a".to_string(), "1".to_string()).expect("set value");
+        let damaged = std::fs::metadata(&segment).expect("stat segment").len() as usize;
+        store.set("b".to_string(), "2".to_string()).expect("set value");
+        store.set("c".to_string(), "3".to_string()).expect("set value");
+        drop(store);
+        let mut contents = std::fs::read(&segment).expect("read segment");
+        contents[damaged..damaged + 3].copy_from_slice(b"###");
+        std::fs::write(&segment, &contents).expect("write segment");
+
+        let summary = KvStore::check_and_repair(temp_dir.path().to_path_buf()).expect("repair");
+        assert_eq!(summary.segments_rewritten, vec![1]);
+        assert_eq!((summary.records_kept, summary.records_discarded), (2, 0));
+        assert!(summary.bytes_discarded > 0);
+        assert_eq!(summary.live_keys, 2);
+
+        let store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+        assert_eq!(store.get("a").expect("get"), Some("1".to_string()));
+        assert_eq!(store.get("b").expect("get"), None);
+        assert_eq!(store.get("c").expect("get"), Some("3".to_string()));
+        drop(store);
+        let summary = KvStore::check_and_repair(temp_dir.path().to_path_buf()).expect("repair again");
+        assert!(summary.segments_rewritten.is_empty());
+    }
+}
+
+#[test]
+fn test_scrubber_finds_and_restores_damaged_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let backup_dir = tempfile::tempdir().expect("create temp dir");
+    let segment = temp_dir.path().join("1.db");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.set("b".to_string(), "2".to_string()).expect("set value");
+    store.shutdown().expect("shutdown");
+    std::fs::copy(&segment, backup_dir.path().join("1.db")).expect("back up segment");
+    // Damage the value where the reopened store won't look at it.
+    let contents = std::fs::read_to_string(&segment).expect("read segment");
+    std::fs::write(&segment, contents.replace(r#""value":"2""#, r#""value":"9""#)).expect("write segment");
+
+    let scrubber = Scrubber::new(1024 * 1024)
+        .with_interval(Duration::from_millis(10))
+        .repair_from(backup_dir.path().to_path_buf());
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), Options::new().scrubber(scrubber)).expect("open");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.scrub_report().passes < 2 {
+        assert!(Instant::now() < deadline, "scrubber did not finish a pass");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let report = store.scrub_report();
+    assert_eq!(report.damaged.len(), 1, "{:?}", report);
+    assert_eq!(report.damaged[0].generation, 1);
+    assert_eq!(report.damaged[0].kind, CorruptionKind::ChecksumMismatch);
+    assert_eq!(report.repaired, vec![1]);
+    assert!(report.segments_scrubbed >= 2);
+    assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+}
+
+#[test]
+fn test_set_from_reader_streams_values_into_blob_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    // Odd-length prefix, so two-byte characters straddle read boundaries.
+    let value = format!("x{}\"\n", "é".repeat(300_000));
+    let has_blob_feature = |store: &KvStore| store.metadata().expect("metadata").features.contains("blob_segments");
+    assert!(!has_blob_feature(&store));
+    store
+        .set_from_reader("big", value.as_bytes(), value.len() as u64)
+        .expect("set_from_reader");
+    assert!(has_blob_feature(&store), "blob segment written before the manifest says so");
+    assert_eq!(store.get("big").expect("get"), Some(value.clone()));
+
+    let short = store.set_from_reader("short", &b"abc"[..], 10);
+    assert!(short.is_err(), "reader ended early");
+    let invalid = store.set_from_reader("invalid", &[b'a', 0xff, b'b'][..], 3);
+    assert!(invalid.is_err(), "value is not UTF-8");
+    store.set_from_reader("small", &b"abc"[..], 3).expect("set_from_reader");
+    assert_eq!(store.get("short").expect("get"), None);
+    assert_eq!(store.stats().expect("stats").blob_segment_count, 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert!(has_blob_feature(&store));
+    assert_eq!(store.get("big").expect("get"), Some(value));
+    assert_eq!(store.get("small").expect("get"), Some("abc".to_string()));
+}
+
+#[test]
+fn test_get_to_writer_streams_values_out() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().blob_threshold(1024);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    let big = "quote \" slash \\ tab\t nl\n ctl\u{1} é 🦀 ".repeat(10_000);
+    store.set("big".to_string(), big.clone()).expect("set value");
+    store
+        .set_from_reader("streamed", big.as_bytes(), big.len() as u64)
+        .expect("set_from_reader");
+    store.set("small".to_string(), "v".to_string()).expect("set value");
+
+    for key in ["big", "streamed", "small"] {
+        let mut out = Vec::new();
+        let len = store.get_to_writer(key, &mut out).expect("get_to_writer");
+        let expected = store.get(key).expect("get").expect("present");
+        assert_eq!(len, Some(expected.len() as u64));
+        assert_eq!(String::from_utf8(out).expect("UTF-8"), expected);
+    }
+    assert_eq!(store.get("big").expect("get"), Some(big));
+    assert_eq!(store.get_to_writer("missing", std::io::sink()).expect("get_to_writer"), None);
+}
+
+#[test]
+fn test_memory_usage_tracks_the_index_and_buffers() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let empty = store.memory_usage().expect("memory usage");
+    assert_eq!(empty.reader_buffer_bytes, 0);
+    assert!(empty.write_buffer_bytes > 0);
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "value".to_string()).expect("set value");
+    }
+    // A compaction finishing after the read would swap out the reader it
+    // opened.
+    wait_for_compaction(&store);
+    store.get("key0").expect("get");
+    let usage = store.memory_usage().expect("memory usage");
+    assert!(usage.index_bytes > empty.index_bytes + 1000 * "key0".len() as u64, "{:?}", usage);
+    assert!(usage.reader_buffer_bytes > 0);
+    assert!(usage.cache_bytes > 0, "active hint not counted");
+    assert_eq!(
+        usage.total(),
+        usage.index_bytes + usage.reader_buffer_bytes + usage.cache_bytes + usage.write_buffer_bytes
+    );
+}
+
+#[test]
+fn test_open_options_set_compaction_trigger_and_read_buffers() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .rotation(RotationPolicy::size(100))
+        .compact_after_segments(20)
+        .read_buffer(64 * 1024);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_string()).expect("set value");
+    }
+    let stats = store.stats().expect("stats");
+    assert!(stats.segment_count > 6, "{:?}", stats);
+    assert_eq!(stats.records_rewritten, 0);
+
+    assert_eq!(store.get("key0").expect("get"), Some("value".to_string()));
+    assert_eq!(store.memory_usage().expect("memory usage").reader_buffer_bytes, 64 * 1024);
+}
+
+#[test]
+fn test_dead_bytes_drive_compaction_by_garbage_ratio() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .rotation(RotationPolicy::size(200))
+        .compact_after_segments(100)
+        .compact_at_garbage_ratio(0.6);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    for i in 0..5 {
+        store.set(format!("key{}", i), "value".to_string()).expect("set value");
+    }
+    assert_eq!(store.stats().expect("stats").dead_bytes, 0);
+    store.set("key1".to_string(), "value".to_string()).expect("set value");
+    let segments = store.segment_stats().expect("segment stats");
+    let dead: u64 = segments.iter().map(|segment| segment.dead_bytes).sum();
+    assert!(dead > 0 && dead < segments.iter().map(|segment| segment.bytes).sum());
+    assert_eq!(store.stats().expect("stats").dead_bytes, dead);
+    assert_eq!(store.stats().expect("stats").records_rewritten, 0);
+
+    for i in 0..30 {
+        store.set("key0".to_string(), i.to_string()).expect("set value");
+    }
+    wait_for_compaction(&store);
+    let stats = store.stats().expect("stats");
+    assert!(stats.records_rewritten > 0, "{:?}", stats);
+    let segments = store.segment_stats().expect("segment stats");
+    assert_eq!(segments.len(), stats.segment_count);
+    assert_eq!(segments.iter().map(|segment| segment.dead_bytes).sum::<u64>(), stats.dead_bytes);
+    assert_eq!(store.get("key0").expect("get"), Some("29".to_string()));
+    assert_eq!(store.get("key4").expect("get"), Some("value".to_string()));
+}
+
+#[test]
+fn test_keys_lists_live_keys_in_order() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for key in ["b", "c", "a"] {
+        store.set(key.to_string(), "1".to_string()).expect("set value");
+    }
+    store.remove("c").expect("remove");
+    assert_eq!(store.keys().expect("keys"), vec!["a".to_string(), "b".to_string()]);
+}
+
+#[test]
+fn test_fork_shares_data_then_diverges() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let fork_dir = temp_dir.path().join("fork");
+    let options = Options::new().blob_threshold(64);
+    let mut store = KvStore::open_with(temp_dir.path().join("main"), options).expect("open store");
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+    store.set("big".to_string(), "x".repeat(100)).expect("set value");
+
+    let mut fork = store.fork(fork_dir.clone()).expect("fork");
+    store.set("key0".to_string(), "main".to_string()).expect("set value");
+    fork.set("key1".to_string(), "fork".to_string()).expect("set value");
+    fork.set("big".to_string(), "y".repeat(100)).expect("set value");
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+
+    assert_eq!(store.get("key1").expect("get"), Some("value1".to_string()));
+    assert_eq!(store.get("big").expect("get"), Some("x".repeat(100)));
+    assert_eq!(fork.get("key0").expect("get"), Some("value0".to_string()));
+    drop(fork);
+
+    let fork = KvStore::open(fork_dir).expect("reopen fork");
+    assert_eq!(fork.get("key1").expect("get"), Some("fork".to_string()));
+    assert_eq!(fork.get("key19").expect("get"), Some("value19".to_string()));
+    assert_eq!(fork.get("big").expect("get"), Some("y".repeat(100)));
+    assert!(store.fork(temp_dir.path().join("main")).is_err());
+}
+
+#[test]
+fn test_checkpoint_is_a_point_in_time_copy() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let checkpoint_dir = temp_dir.path().join("checkpoint");
+    let mut store = KvStore::open(temp_dir.path().join("main")).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.checkpoint(checkpoint_dir.clone()).expect("checkpoint");
+    store.set("a".to_string(), "2".to_string()).expect("set value");
+    store.set("b".to_string(), "3".to_string()).expect("set value");
+
+    let checkpoint = KvStore::open_read_only(checkpoint_dir).expect("open checkpoint");
+    assert_eq!(checkpoint.get("a").expect("get"), Some("1".to_string()));
+    assert_eq!(checkpoint.get("b").expect("get"), None);
+}
+
+#[test]
+fn test_store_survives_a_panic_while_locked() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+
+    let mut clone = store.clone();
+    let panicked = std::thread::spawn(move || {
+        clone
+            .transaction(|txn| -> bitkv_rs::Result<()> {
+                txn.set("b".to_string(), "2".to_string())?;
+                panic!("panic inside a transaction");
+            })
+            .ok();
+    })
+    .join();
+    assert!(panicked.is_err());
+
+    assert_eq!(store.get("a").expect("get"), Some("1".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+    store.set("c".to_string(), "3".to_string()).expect("set after panic");
+    assert_eq!(store.get("c").expect("get"), Some("3".to_string()));
+}
+
+#[test]
+fn test_segments_are_opened_on_first_read() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    for i in 0..4 {
+        let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+        store.set(format!("key{}", i), format!("value{}", i)).expect("set value");
+    }
+
+    let store = KvStore::open_read_only(temp_dir.path().to_path_buf()).expect("open store");
+    let stats = store.stats().expect("stats");
+    assert_eq!(stats.segment_count, 4);
+    assert_eq!(stats.open_segment_count, 0);
+
+    assert_eq!(store.get("key1").expect("get"), Some("value1".to_string()));
+    assert_eq!(store.get("key1").expect("get"), Some("value1".to_string()));
+    assert_eq!(store.stats().expect("stats").open_segment_count, 1);
+    assert_eq!(store.get("key3").expect("get"), Some("value3".to_string()));
+    assert_eq!(store.stats().expect("stats").open_segment_count, 2);
+}
+
+#[test]
+fn test_key_stats_count_accesses_and_persist() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new().track_access(Duration::from_millis(50));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.set("b".to_string(), "2".to_string()).expect("set value");
+    store.get("a").expect("get");
+    store.get_many(["a", "missing"]).expect("get_many");
+    store.remove("b").expect("remove");
+
+    let stats = store.key_stats("a").expect("key_stats").expect("stats for a");
+    assert_eq!(stats.access_count, 3);
+    assert!(stats.last_access_ms > 0);
+    assert_eq!(store.key_stats("b").expect("key_stats"), None);
+    assert_eq!(store.key_stats("missing").expect("key_stats"), None);
+
+    std::thread::sleep(Duration::from_millis(200));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    assert_eq!(store.key_stats("a").expect("key_stats"), Some(stats));
+
+    let untracked = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    untracked.get("a").expect("get");
+    assert_eq!(untracked.key_stats("a").expect("key_stats"), None);
+}
+
+#[test]
+fn test_bounded_store_evicts_by_policy() {
+    for (policy, evicted) in [(EvictionPolicy::Lru, "b"), (EvictionPolicy::Lfu, "c")] {
+        let temp_dir = tempfile::tempdir().expect("create temp dir");
+        let options = Options::new().max_live_keys(3).eviction_policy(policy);
+        let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+        for (key, reads) in [("a", 2), ("b", 1), ("c", 0)] {
+            store.set(key.to_string(), "v".to_string()).expect("set value");
+            std::thread::sleep(Duration::from_millis(2));
+            for _ in 0..reads {
+                store.get(key).expect("get");
+            }
+        }
+        std::thread::sleep(Duration::from_millis(2));
+        store.get("a").expect("get");
+        store.set("d".to_string(), "v".to_string()).expect("set value");
+
+        assert_eq!(store.get(evicted).expect("get"), None, "{:?}", policy);
+        let stats = store.stats().expect("stats");
+        assert_eq!((stats.key_count, stats.evicted_keys), (3, 1), "{:?}", policy);
+    }
+}
+
+#[test]
+fn test_byte_bound_evicts_expiring_keys_first() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .max_live_bytes(1000)
+        .retention("tmp/", Duration::from_secs(3600))
+        .eviction_policy(EvictionPolicy::TtlFirst);
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set("keep".to_string(), "x".repeat(300)).expect("set value");
+    store.set("tmp/a".to_string(), "x".repeat(300)).expect("set value");
+    store.set("tmp/b".to_string(), "x".repeat(300)).expect("set value");
+    store.set("new".to_string(), "x".repeat(300)).expect("set value");
+
+    assert!(store.stats().expect("stats").live_bytes <= 1000);
+    assert_eq!(store.get("tmp/a").expect("get"), None);
+    assert!(store.get("keep").expect("get").is_some());
+    assert!(store.get("new").expect("get").is_some());
+}
+
+#[test]
+fn test_set_opts_conditions_and_ttl() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    let nx = SetOptions {
+        nx: true,
+        ..SetOptions::default()
+    };
+    let xx = SetOptions {
+        xx: true,
+        get_old: true,
+        ..SetOptions::default()
+    };
+    assert!(!store.set_opts("k", "1", xx.clone()).expect("set_opts").written);
+    assert!(store.set_opts("k", "1", nx.clone()).expect("set_opts").written);
+    assert!(!store.set_opts("k", "2", nx).expect("set_opts").written);
+    let outcome = store.set_opts("k", "3", xx).expect("set_opts");
+    assert!(outcome.written);
+    assert_eq!(outcome.old, Some("1".to_string()));
+
+    let ttl = SetOptions {
+        ttl: Some(Duration::from_millis(100)),
+        ..SetOptions::default()
+    };
+    store.set_opts("short", "1", ttl.clone()).expect("set_opts");
+    store.set_opts("cleared", "1", ttl).expect("set_opts");
+    let keep_ttl = SetOptions {
+        keep_ttl: true,
+        ..SetOptions::default()
+    };
+    store.set_opts("short", "2", keep_ttl).expect("set_opts");
+    store.set_opts("cleared", "2", SetOptions::default()).expect("set_opts");
+    store.append("short", "x").expect("append");
+    assert_eq!(store.get("short").expect("get"), Some("2x".to_string()));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("short").expect("get"), None);
+    assert_eq!(store.get("cleared").expect("get"), Some("2".to_string()));
+    assert!(
+        store
+            .set_opts(
+                "k",
+                "v",
+                SetOptions {
+                    nx: true,
+                    xx: true,
+                    ..SetOptions::default()
+                }
+            )
+            .is_err()
+    );
+}
+
+#[test]
+fn test_set_with_ttl_expires_across_a_reopen() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set_with_ttl("short", "1", Duration::from_millis(100)).expect("set_with_ttl");
+    store.set_with_ttl("long", "1", Duration::from_secs(3600)).expect("set_with_ttl");
+    store.set("plain".to_string(), "1".to_string()).expect("set value");
+    assert_eq!(store.get("short").expect("get"), Some("1".to_string()));
+    // Without a shutdown the reopen replays the segment.
+    drop(store);
+
+    std::thread::sleep(Duration::from_millis(150));
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.get("short").expect("get"), None);
+    assert_eq!(store.get("long").expect("get"), Some("1".to_string()));
+    assert_eq!(store.get("plain").expect("get"), Some("1".to_string()));
+    assert_eq!(store.stats().expect("stats").key_count, 2);
+}
+
+#[test]
+fn test_expiration_sweep_removes_expired_keys() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .expiration_sweep(Duration::from_millis(20))
+        .retention("log/", Duration::from_millis(50));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+    store.set_with_ttl("short", "1", Duration::from_millis(50)).expect("set_with_ttl");
+    store.set_with_ttl("long", "1", Duration::from_secs(3600)).expect("set_with_ttl");
+    store.set("log/1".to_string(), "1".to_string()).expect("set value");
+    store.set("plain".to_string(), "1".to_string()).expect("set value");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.stats().expect("stats").expired_keys < 2 {
+        assert!(Instant::now() < deadline, "sweep did not remove the expired keys");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let stats = store.stats().expect("stats");
+    assert_eq!((stats.key_count, stats.expired_keys), (2, 2));
+    assert_eq!(store.sweep_expired().expect("sweep"), 0);
+    assert_eq!(store.keys().expect("keys"), vec!["long".to_string(), "plain".to_string()]);
+}
+
+#[test]
+fn test_stats_count_writes_and_compaction_work() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for i in 0..4 {
+        store.set("k".to_string(), i.to_string()).expect("set value");
+    }
+    store.set("gone".to_string(), "1".to_string()).expect("set value");
+    store.remove("gone").expect("remove");
+    store.remove_many(["k", "missing"]).expect("remove_many");
+    let stats = store.stats().expect("stats");
+    assert_eq!((stats.records_written, stats.tombstones_written), (8, 3));
+    assert_eq!((stats.records_rewritten, stats.compaction_bytes_reclaimed), (0, 0));
+
+    store.set("kept".to_string(), "1".to_string()).expect("set value");
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    let stats = store.stats().expect("stats");
+    assert!(stats.records_rewritten >= 1, "{:?}", stats);
+    assert!(stats.compaction_bytes_reclaimed > 0, "{:?}", stats);
+    assert_eq!(stats.records_written, 9);
+}
+
+#[test]
+fn test_aligned_records_never_straddle_a_block() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let options = Options::new()
+        .align_records(128)
+        .rotation(RotationPolicy::size(1024 * 1024));
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options.clone()).expect("open store");
+    for i in 0..40 {
+        store.set(format!("key{}", i), "x".repeat(i * 7)).expect("set value");
+    }
+    drop(store);
+
+    let mut log = std::fs::read(temp_dir.path().join("1.db")).expect("read segment");
+    // Blank out the segment header so offsets stay absolute.
+    log[..8].fill(b' ');
+    let mut stream = serde_json::Deserializer::from_slice(&log).into_iter::<serde_json::Value>();
+    let mut end = 0;
+    while let Some(record) = stream.next() {
+        record.expect("parse record");
+        let start = end + log[end..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+        end = stream.byte_offset();
+        let len = end - start;
+        assert!(start % 128 == 0 || start % 128 + len <= 128, "record at {} of {} bytes", start, len);
+    }
+
+    let store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("reopen store");
+    for i in 0..40 {
+        assert_eq!(store.get(&format!("key{}", i)).expect("get"), Some("x".repeat(i * 7)));
+    }
+}
+
+#[test]
+fn test_binary_records_mix_with_json_and_catch_corruption() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("json".to_string(), "1".to_string()).expect("set value");
+    drop(store);
+
+    let binary = || {
+        Options::new()
+            .record_format(RecordFormat::Binary)
+            .align_records(64)
+            .rotation(RotationPolicy::size(1024 * 1024))
+    };
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), binary()).expect("reopen store");
+    for i in 0..20 {
+        store.set(format!("key{}", i), "x".repeat(i * 5)).expect("set value");
+    }
+    store.remove("key0".to_string()).expect("remove key");
+    let metadata = store.metadata().expect("metadata");
+    assert!(metadata.features.contains("binary_records"));
+    assert_eq!(metadata.segment_format(1), RecordFormat::Json);
+    assert_eq!(metadata.segment_format(2), RecordFormat::Binary);
+    drop(store);
+
+    // Either format reads both kinds of segment.
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    assert_eq!(store.get("json").expect("get"), Some("1".to_string()));
+    assert_eq!(store.get("key0").expect("get"), None);
+    assert_eq!(store.get("key7").expect("get"), Some("x".repeat(35)));
+    drop(store);
+
+    // Compaction rewrites everything in the current format.
+    let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), binary()).expect("reopen store");
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    let metadata = store.metadata().expect("metadata");
+    let current = store.stats().expect("stats").current_generation;
+    assert!((1..=current).all(|generation| {
+        !temp_dir.path().join(format!("{}.db", generation)).exists()
+            || metadata.segment_format(generation) == RecordFormat::Binary
+    }));
+    assert_eq!(store.get("json").expect("get"), Some("1".to_string()));
+    store.set("last".to_string(), "y".repeat(40)).expect("set value");
+    drop(store);
+
+    let path = temp_dir.path().join(format!("{}.db", current));
+    let mut log = std::fs::read(&path).expect("read segment");
+    let last = log.len() - 1;
+    log[last] ^= 0xff;
+    std::fs::write(&path, log).expect("write segment");
+    let result = KvStore::open_with(temp_dir.path().to_path_buf(), binary());
+    assert!(matches!(result, Err(KvError::Io(ref e)) if e.kind() == std::io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn test_removes_reach_the_segment_before_returning() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "1".to_string()).expect("set value");
+    store.remove("a").expect("remove");
+    // Nothing is left sitting in the writer's buffer for another reader to miss.
+    let reader = KvStore::open_read_only(temp_dir.path().to_path_buf()).expect("open read-only");
+    assert_eq!(reader.get("a").expect("get"), None);
+}
+
+#[test]
+fn test_sync_policies_keep_writes_across_rotation() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::Every(Duration::from_millis(10)),
+        SyncPolicy::Never,
+    ];
+    for (round, policy) in policies.into_iter().enumerate() {
+        let options = Options::new()
+            .sync_policy(policy)
+            .blob_threshold(64)
+            .rotation(RotationPolicy::size(256));
+        let mut store = KvStore::open_with(temp_dir.path().to_path_buf(), options).expect("open store");
+        for i in 0..20 {
+            store
+                .set(format!("key{}-{}", round, i), "x".repeat(i * 5))
+                .expect("set value");
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        wait_for_compaction(&store);
+    }
+
+    let store = KvStore::open(temp_dir.path().to_path_buf()).expect("reopen store");
+    for round in 0..3 {
+        for i in 0..20 {
+            assert_eq!(
+                store.get(&format!("key{}-{}", round, i)).expect("get"),
+                Some("x".repeat(i * 5))
+            );
+        }
+    }
+}
+
+// Comma-separated sets, merged by union.
+struct Union;
+
+impl Merge for Union {
+    fn merge(&self, _key: &str, local: Option<&str>, remote: Option<&str>) -> Option<String> {
+        let items: std::collections::BTreeSet<&str> =
+            local.into_iter().chain(remote).flat_map(|v| v.split(',')).collect();
+        Some(items.into_iter().collect::<Vec<_>>().join(","))
+    }
+}
+
+#[test]
+fn test_sync_reconciles_concurrent_writes_by_prefix() {
+    let (dir_a, dir_b) = (tempfile::tempdir().expect("create temp dir"), tempfile::tempdir().expect("create temp dir"));
+    let options = || {
+        Options::new()
+            .sync_prefix("notes/", MergeMode::LastWriteWins)
+            .sync_prefix("tags/", MergeMode::Custom(std::sync::Arc::new(Union)))
+    };
+    let mut a = KvStore::open_with(dir_a.path().to_path_buf(), options()).expect("open store");
+    let mut b = KvStore::open_with(dir_b.path().to_path_buf(), options()).expect("open store");
+    a.set("notes/1".to_string(), "from a".to_string()).expect("set value");
+    a.set("tags/x".to_string(), "red".to_string()).expect("set value");
+    a.set("local".to_string(), "a only".to_string()).expect("set value");
+    b.set("tags/x".to_string(), "blue".to_string()).expect("set value");
+    std::thread::sleep(Duration::from_millis(5));
+    b.set("notes/1".to_string(), "from b".to_string()).expect("set value");
+
+    // `a` takes the later note and merges the tags, then sends the merge.
+    assert_eq!(a.sync(&mut b).expect("sync"), SyncSummary { received: 2, sent: 1 });
+    for store in [&a, &b] {
+        assert_eq!(store.get("notes/1").expect("get"), Some("from b".to_string()));
+        assert_eq!(store.get("tags/x").expect("get"), Some("blue,red".to_string()));
+    }
+    assert_eq!(b.get("local").expect("get"), None);
+    assert_eq!(b.sync(&mut a).expect("sync"), SyncSummary::default());
+
+    let seen = a.sync_vector().expect("sync vector");
+    drop(a);
+    let mut a = KvStore::open_with(dir_a.path().to_path_buf(), options()).expect("reopen store");
+    assert_eq!(a.sync_vector().expect("sync vector"), seen);
+    a.remove("notes/1").expect("remove");
+    assert_eq!(a.sync(&mut b).expect("sync"), SyncSummary { received: 0, sent: 1 });
+    assert_eq!(b.get("notes/1").expect("get"), None);
+}
+
+#[test]
+fn test_reads_relocate_entries_left_pointing_at_compacted_segments() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("a", "1b")] {
+        store.set(key.to_string(), value.to_string()).expect("set value");
+    }
+    let before = store.copy_index();
+    store.remove("c").expect("remove");
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+    for key in ["a", "b", "c"] {
+        store.restore_index_entry(&before, key);
+    }
+
+    assert_eq!(store.get("a").expect("get"), Some("1b".to_string()));
+    assert_eq!(store.get("b").expect("get"), Some("2".to_string()));
+    assert_eq!(store.get("c").expect("get"), None);
+    assert_eq!(store.stats().expect("stats").read_repairs, 3);
+    // Until a write fixes the index, the queued repair answers.
+    assert_eq!(store.get("a").expect("get"), Some("1b".to_string()));
+    assert_eq!(store.stats().expect("stats").read_repairs, 3);
+
+    store.set("z".to_string(), "26".to_string()).expect("set value");
+    assert_eq!(store.get("a").expect("get"), Some("1b".to_string()));
+    let stats = store.stats().expect("stats");
+    assert_eq!(stats.read_repairs, 3);
+    assert_eq!(stats.key_count, 3);
+}
+
+#[test]
+fn test_queued_read_repair_is_skipped_once_the_entry_changed() {
+    let temp_dir = tempfile::tempdir().expect("create temp dir");
+    let mut store = KvStore::open(temp_dir.path().to_path_buf()).expect("open store");
+    store.set("a".to_string(), "old".to_string()).expect("set value");
+    let oldest = store.copy_index();
+    store.set("a".to_string(), "new".to_string()).expect("set value");
+    let latest = store.copy_index();
+    store.compact().expect("compact");
+    wait_for_compaction(&store);
+
+    store.restore_index_entry(&latest, "a");
+    assert_eq!(store.get("a").expect("get"), Some("new".to_string()));
+    // The entry changes before the repair queued for it is applied.
+    store.restore_index_entry(&oldest, "a");
+    store.set("z".to_string(), "26".to_string()).expect("set value");
+    assert_eq!(store.get("a").expect("get"), Some("new".to_string()));
+    assert_eq!(store.stats().expect("stats").read_repairs, 2);
+
+    // A write to the key itself lands after any repair, never under it.
+    store.restore_index_entry(&latest, "a");
+    assert_eq!(store.get("a").expect("get"), Some("new".to_string()));
+    store.set("a".to_string(), "newest".to_string()).expect("set value");
+    assert_eq!(store.get("a").expect("get"), Some("newest".to_string()));
+}